@@ -1,6 +1,491 @@
+use std::fs;
+use std::io::{ self, Read, Write, Seek, SeekFrom, BufReader, BufWriter };
+use std::path::{ Component, Path, PathBuf };
+
+use byteorder::{ ReadBytesExt, WriteBytesExt, BigEndian };
 use clap::ArgMatches;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use serde_json::json;
+
+use crate::cli;
+use crate::crypt;
+use crate::basics::{ self, Store };
+
+// Layout of an archive file:
+// - ARCHIVE_MAGIC, u16 format, u32 header length, JSON header, u32 header CRC32C
+// - for each file: ENTRY_MARKER, u16 path length, path, u64 size,
+//   u32 CRC32C of the content, u64 compressed length, deflated content
+// - END_MARKER, u64 number of entries
+pub const ARCHIVE_MAGIC: &[u8; 8] = b"RIXARCH1";
+pub const ARCHIVE_FORMAT: u16 = 1;
+const ENTRY_MARKER: u8 = 1;
+const END_MARKER: u8 = 0;
+// Length of the size, CRC and compressed length of an entry
+const ENTRY_FIELDS: u64 = 20;
+// Largest read done at once while copying a file
+const COPY_SIZE: usize = 1024 * 1024;
+// File holding the whole content of the stores of the Archive type
+pub const ARCHIVE_FILE: &str = "store.orix";
+// Directories of a store that aren't part of its data
+const SKIPPED_DIRS: [&str; 3] = ["tmp", "logs", "lost+found"];
+
+pub struct ArchiveHeader {
+	pub format: u16,
+	pub version: (u16, u16, u16), // OrixDB version that wrote the archive
+	pub created: u64, // Unix time in milliseconds
+	pub store: Store // Manifest of the archived store
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct ArchiveEntry {
+	pub path: String, // Relative to the store's directory, with `/` separators
+	pub size: u64,
+	pub crc: u32,
+	pub compressed: u64,
+	pub offset: u64 // Position of the compressed content in the archive
+}
+
+fn invalid(what: &str) -> io::Error {
+	return io::Error::new(io::ErrorKind::InvalidData, what.to_owned());
+}
+
+
+// --> Writing archives
+// --------------------
+
+// Writes a relative path with `/` separators, whatever the platform
+pub fn slash_path(path: &Path) -> String {
+	let parts: Vec<String> = path.components()
+		.map(|c| c.as_os_str().to_string_lossy().into_owned())
+		.collect()
+	;
+	return parts.join("/");
+}
+
+// Lists the files of a store, relative to its directory, the manifest first.
+// The stores kept within it, like its backups and the ones being written
+// (with a `.part` extension), are left out.
+pub fn store_files(store_dir: &Path) -> io::Result<Vec<String>> {
+	let mut files = Vec::<String>::new();
+	let mut pending = vec![PathBuf::new()];
+	while let Some(relative) = pending.pop() {
+		for entry in fs::read_dir(store_dir.join(&relative))? {
+			let entry = entry?;
+			let name = entry.file_name().to_string_lossy().into_owned();
+			if relative.as_os_str().is_empty() && SKIPPED_DIRS.contains(&name.as_str()) {
+				continue;
+			}
+			let file_type = entry.file_type()?;
+			let nested = name.ends_with(".part") || entry.path().join("manifest.json").exists();
+			if file_type.is_dir() && !nested {
+				pending.push(relative.join(&name));
+			}
+			else if file_type.is_file() {
+				files.push(slash_path(&relative.join(&name)));
+			}
+		}
+	}
+	files.sort();
+	if let Some(position) = files.iter().position(|f| f == "manifest.json") {
+		let manifest = files.remove(position);
+		files.insert(0, manifest);
+	}
+	return Ok(files);
+}
+
+fn write_header(output: &mut impl Write, store: &Store) -> io::Result<()> {
+	let conf = basics::get_conf();
+	let header = serde_json::to_vec(&json!({
+		"orixdb": { "major": conf.major, "minor": conf.minor, "patch": conf.patch },
+		"created": basics::now_millis(),
+		"store": store
+	})).map_err(io::Error::other)?;
+	output.write_all(ARCHIVE_MAGIC)?;
+	output.write_u16::<BigEndian>(ARCHIVE_FORMAT)?;
+	output.write_u32::<BigEndian>(header.len() as u32)?;
+	output.write_all(&header)?;
+	output.write_u32::<BigEndian>(crc32c::crc32c(&header))?;
+	return Ok(());
+}
+
+// Compresses a file into the archive. The fields preceding the content
+// are filled once it is written, as the file is streamed.
+fn write_entry<W: Write + Seek>(
+	output: &mut W, source: &Path, path: &str
+) -> io::Result<ArchiveEntry> {
+	if path.len() > u16::MAX as usize { return Err(invalid("Path too long")); }
+	output.write_u8(ENTRY_MARKER)?;
+	output.write_u16::<BigEndian>(path.len() as u16)?;
+	output.write_all(path.as_bytes())?;
+	let fields = output.stream_position()?;
+	output.write_all(&[0u8; ENTRY_FIELDS as usize])?;
+
+	let mut file = fs::File::open(source)?;
+	let mut encoder = DeflateEncoder::new(&mut *output, Compression::default());
+	let mut buffer = vec![0u8; COPY_SIZE];
+	let (mut size, mut crc) = (0u64, 0u32);
+	loop {
+		let count = file.read(&mut buffer)?;
+		if count == 0 { break; }
+		crc = crc32c::crc32c_append(crc, &buffer[..count]);
+		size += count as u64;
+		encoder.write_all(&buffer[..count])?;
+	}
+	encoder.finish()?;
+
+	let end = output.stream_position()?;
+	let entry = ArchiveEntry {
+		path: path.to_owned(),
+		size,
+		crc,
+		compressed: end - fields - ENTRY_FIELDS,
+		offset: fields + ENTRY_FIELDS
+	};
+	output.seek(SeekFrom::Start(fields))?;
+	output.write_u64::<BigEndian>(entry.size)?;
+	output.write_u32::<BigEndian>(entry.crc)?;
+	output.write_u64::<BigEndian>(entry.compressed)?;
+	output.seek(SeekFrom::Start(end))?;
+	return Ok(entry);
+}
+
+// Writes an archive holding the given files of a store
+pub fn write_archive(
+	store_dir: &Path, store: &Store, files: &[String], target: &Path
+) -> io::Result<Vec<ArchiveEntry>> {
+	let mut output = BufWriter::new(crypt::File::create(target)?);
+	write_header(&mut output, store)?;
+	let mut entries = Vec::<ArchiveEntry>::new();
+	for path in files {
+		entries.push(write_entry(&mut output, &store_dir.join(path), path)?);
+	}
+	output.write_u8(END_MARKER)?;
+	output.write_u64::<BigEndian>(entries.len() as u64)?;
+	output.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+	return Ok(entries);
+}
+
+
+// --> Reading archives
+// --------------------
+
+pub struct ArchiveReader {
+	reader: BufReader<crypt::File>,
+	pub header: ArchiveHeader,
+	next: u64, // Position of the next entry
+	count: u64, // Number of entries read so far
+	ended: bool
+}
+
+// Whether an entry path stays within the store's directory once extracted
+pub fn is_safe_path(path: &str) -> bool {
+	return !path.is_empty() && Path::new(path).components()
+		.all(|c| matches!(c, Component::Normal(_)))
+	;
+}
+
+impl ArchiveReader {
+	pub fn open(path: &Path) -> io::Result<ArchiveReader> {
+		let mut reader = BufReader::new(crypt::File::open(path)?);
+		let mut magic = [0u8; 8];
+		reader.read_exact(&mut magic).map_err(|_| invalid("Not an OrixDB archive"))?;
+		if &magic != ARCHIVE_MAGIC { return Err(invalid("Not an OrixDB archive")); }
+		let format = reader.read_u16::<BigEndian>()?;
+		if format != ARCHIVE_FORMAT {
+			return Err(invalid(&format!("Unsupported archive format: {}", format)));
+		}
+		let length = reader.read_u32::<BigEndian>()?;
+		let mut bytes = vec![0u8; length as usize];
+		reader.read_exact(&mut bytes)?;
+		if reader.read_u32::<BigEndian>()? != crc32c::crc32c(&bytes) {
+			return Err(invalid("The archive header is corrupted"));
+		}
+
+		let value: serde_json::Value = serde_json::from_slice(&bytes)
+			.map_err(|_| invalid("Invalid archive header"))?
+		;
+		let number = |name: &str| value["orixdb"][name].as_u64().map(|n| n as u16);
+		let header = ArchiveHeader {
+			format,
+			version: (
+				number("major").ok_or(invalid("Invalid archive header"))?,
+				number("minor").ok_or(invalid("Invalid archive header"))?,
+				number("patch").ok_or(invalid("Invalid archive header"))?
+			),
+			created: value["created"].as_u64().unwrap_or(0),
+			store: serde_json::from_value(value["store"].clone())
+				.map_err(|_| invalid("Invalid store manifest in the archive header"))?
+		};
+		let next = reader.stream_position()?;
+		return Ok(ArchiveReader { reader, header, next, count: 0, ended: false });
+	}
+
+	// Reads the description of the next entry, without its content.
+	// Returns `None` once the end of the archive is reached.
+	pub fn next_entry(&mut self) -> io::Result<Option<ArchiveEntry>> {
+		if self.ended { return Ok(None); }
+		self.reader.seek(SeekFrom::Start(self.next))?;
+		let truncated = |_| invalid("The archive is truncated");
+		match self.reader.read_u8().map_err(truncated)? {
+			END_MARKER => {
+				let count = self.reader.read_u64::<BigEndian>().map_err(truncated)?;
+				if count != self.count {
+					return Err(invalid("The number of entries doesn't match the archive end"));
+				}
+				self.ended = true;
+				return Ok(None);
+			},
+			ENTRY_MARKER => {},
+			_ => return Err(invalid("Invalid entry marker"))
+		}
+
+		let length = self.reader.read_u16::<BigEndian>().map_err(truncated)?;
+		let mut bytes = vec![0u8; length as usize];
+		self.reader.read_exact(&mut bytes).map_err(truncated)?;
+		let path = String::from_utf8(bytes).map_err(|_| invalid("Invalid entry path"))?;
+		if !is_safe_path(&path) {
+			return Err(invalid(&format!("Unsafe entry path: {:?}", path)));
+		}
+		let size = self.reader.read_u64::<BigEndian>().map_err(truncated)?;
+		let crc = self.reader.read_u32::<BigEndian>().map_err(truncated)?;
+		let compressed = self.reader.read_u64::<BigEndian>().map_err(truncated)?;
+		let offset = self.reader.stream_position()?;
+
+		self.next = offset.checked_add(compressed).ok_or(invalid("Invalid entry length"))?;
+		self.count += 1;
+		return Ok(Some(ArchiveEntry { path, size, crc, compressed, offset }));
+	}
+
+	// Decompresses the content of an entry, and checks it against its size and CRC
+	pub fn extract(&mut self, entry: &ArchiveEntry, output: &mut impl Write) -> io::Result<()> {
+		self.reader.seek(SeekFrom::Start(entry.offset))?;
+		let mut decoder = DeflateDecoder::new((&mut self.reader).take(entry.compressed));
+		let mut buffer = vec![0u8; COPY_SIZE];
+		let (mut size, mut crc) = (0u64, 0u32);
+		loop {
+			let count = decoder.read(&mut buffer)
+				.map_err(|_| invalid(&format!("{}: corrupted content", entry.path)))?
+			;
+			if count == 0 { break; }
+			crc = crc32c::crc32c_append(crc, &buffer[..count]);
+			size += count as u64;
+			if size > entry.size {
+				return Err(invalid(&format!("{}: longer than expected", entry.path)));
+			}
+			output.write_all(&buffer[..count])?;
+		}
+		if size != entry.size {
+			return Err(invalid(&format!("{}: shorter than expected", entry.path)));
+		}
+		if crc != entry.crc {
+			return Err(invalid(&format!("{}: checksum mismatch", entry.path)));
+		}
+		return Ok(());
+	}
+}
+
+// Reads all the entries of an archive, optionally checking their content
+pub fn read_archive(
+	path: &Path, verify: bool
+) -> io::Result<(ArchiveHeader, Vec<ArchiveEntry>)> {
+	let mut archive = ArchiveReader::open(path)?;
+	let mut entries = Vec::<ArchiveEntry>::new();
+	while let Some(entry) = archive.next_entry()? {
+		if verify { archive.extract(&entry, &mut io::sink())?; }
+		entries.push(entry);
+	}
+	if entries.first().map(|e| e.path.as_str()) != Some("manifest.json") {
+		return Err(invalid("The archive doesn't start with a store manifest"));
+	}
+	return Ok((archive.header, entries));
+}
+
 
 pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
-	println!("{:#?}", matches);
-	return std::process::ExitCode::SUCCESS;
-}
\ No newline at end of file
+
+	// --> Checking the command line arguments
+	// ---------------------------------------
+
+	let path: PathBuf;
+	if matches.contains_id("path") {
+		path = PathBuf::from(matches.get_one::<String>("path").unwrap());
+	}
+	else { path = PathBuf::from(".").canonicalize().unwrap(); }
+	let list = *matches.get_one::<bool>("list").unwrap();
+	let verify = *matches.get_one::<bool>("verify").unwrap();
+
+
+	// --> Listing or verifying an existing archive
+	// --------------------------------------------
+
+	if list || verify {
+		let (header, entries) = match read_archive(&path, verify) {
+			Ok(read) => read,
+			Err(e) => {
+				cli::red_err(format!("Invalid archive {:?}: {}", path, e));
+				return std::process::ExitCode::FAILURE;
+			}
+		};
+		let (major, minor, patch) = header.version;
+		cli::cyan_out(format!(
+			"Store \"{}\" (id: {}, hash: {:?}), archived with OrixDB {}.{}.{} \
+			at {} (Unix ms), archive format {}.",
+			header.store.name, header.store.id, header.store.hash,
+			major, minor, patch, header.created, header.format
+		));
+		if list {
+			for entry in &entries {
+				println!("{:>12} {:>12}  {}", entry.size, entry.compressed, entry.path);
+			}
+		}
+		let total: u64 = entries.iter().map(|e| e.size).sum();
+		let summary = format!("{} files, {} bytes", entries.len(), total);
+		if verify { cli::green_out(format!("Archive verified: {}.", summary)); }
+		else { cli::white_out(format!("{}.", summary)); }
+		return std::process::ExitCode::SUCCESS;
+	}
+
+
+	// --> Archiving a store
+	// ---------------------
+
+	if !path.is_dir() {
+		cli::red_err(
+			"The path supplied doesn't lead to an existing directory.".to_owned()
+		);
+		return std::process::ExitCode::FAILURE;
+	}
+	let store = match basics::load_store(&path) {
+		Ok(store) => store,
+		Err(message) => {
+			cli::red_err(message);
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+	let target = match matches.get_one::<String>("output") {
+		Some(output) => PathBuf::from(output),
+		None => PathBuf::from(format!("{}.orix", store.id))
+	};
+	if target.exists() {
+		cli::red_err(format!("{:?} already exists.", target));
+		return std::process::ExitCode::FAILURE;
+	}
+
+	// The archive is only moved to its final path once complete
+	let mut partial = target.clone().into_os_string();
+	partial.push(".part");
+	let partial = PathBuf::from(partial);
+	let written = store_files(&path)
+		.and_then(|files| write_archive(&path, &store, &files, &partial))
+		.and_then(|entries| fs::rename(&partial, &target).map(|_| entries))
+	;
+	match written {
+		Ok(entries) => {
+			let total: u64 = entries.iter().map(|e| e.size).sum();
+			let size = fs::metadata(&target).map(|m| m.len()).unwrap_or(0);
+			cli::green_out(format!(
+				"Store \"{}\" archived in {:?}: {} files, {} bytes compressed to {}.",
+				store.name, target, entries.len(), total, size
+			));
+			return std::process::ExitCode::SUCCESS;
+		},
+		Err(e) => {
+			let _ = fs::remove_file(&partial);
+			cli::red_err(format!("Failed to archive the store: {}", e));
+			return std::process::ExitCode::FAILURE;
+		}
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::serve::testing;
+
+	// A store with a few entries, and an archive of it
+	fn archived(dir: &Path) -> (PathBuf, PathBuf) {
+		let store_dir = dir.join("store");
+		let mut engine = testing::create_store(&store_dir, json!({ "name": "archived" }));
+		for i in 0..20 {
+			let value = json!({ "n": i, "text": "x".repeat(i * 50) });
+			testing::mutate(&mut engine, "setKey", json!({ "key": format!("key{}", i), "value": value }))
+				.unwrap()
+			;
+		}
+		let store = engine.store;
+		let target = dir.join("store.orix");
+		let files = store_files(&store_dir).unwrap();
+		write_archive(&store_dir, &store, &files, &target).unwrap();
+		return (store_dir, target);
+	}
+
+	#[test]
+	fn round_trip() {
+		let dir = tempfile::tempdir().unwrap();
+		let (store_dir, target) = archived(dir.path());
+		let (header, entries) = read_archive(&target, true).unwrap();
+		assert_eq!(header.store.name, "archived");
+		assert_eq!(entries[0].path, "manifest.json");
+		assert_eq!(
+			entries.iter().map(|e| e.path.clone()).collect::<Vec<String>>(),
+			store_files(&store_dir).unwrap()
+		);
+
+		let mut reader = ArchiveReader::open(&target).unwrap();
+		while let Some(entry) = reader.next_entry().unwrap() {
+			let mut content = Vec::<u8>::new();
+			reader.extract(&entry, &mut content).unwrap();
+			assert_eq!(content, fs::read(store_dir.join(&entry.path)).unwrap(), "{}", entry.path);
+		}
+	}
+
+	#[test]
+	fn truncated_archive() {
+		let dir = tempfile::tempdir().unwrap();
+		let (_, target) = archived(dir.path());
+		let bytes = fs::read(&target).unwrap();
+		// Within the header, an entry, the end marker and the entry count
+		for length in [4, 30, bytes.len() / 2, bytes.len() - 9, bytes.len() - 1] {
+			fs::write(&target, &bytes[..length]).unwrap();
+			assert!(read_archive(&target, true).is_err(), "{} bytes", length);
+		}
+	}
+
+	#[test]
+	fn corrupted_archive() {
+		let dir = tempfile::tempdir().unwrap();
+		let (_, target) = archived(dir.path());
+		let bytes = fs::read(&target).unwrap();
+		let (_, entries) = read_archive(&target, true).unwrap();
+
+		let corrupt = |position: usize| {
+			let mut changed = bytes.clone();
+			changed[position] ^= 0x55;
+			fs::write(&target, &changed).unwrap();
+			return read_archive(&target, true).err().map(|e| e.to_string());
+		};
+		assert_eq!(corrupt(0).unwrap(), "Not an OrixDB archive");
+		assert_eq!(corrupt(20).unwrap(), "The archive header is corrupted");
+		let largest = entries.iter().max_by_key(|e| e.compressed).unwrap();
+		let error = corrupt((largest.offset + largest.compressed / 2) as usize).unwrap();
+		assert!(error.starts_with(&largest.path), "{}", error);
+		// The CRC of the content of the first entry
+		assert!(corrupt((entries[0].offset - 10) as usize).unwrap().contains("checksum mismatch"));
+	}
+
+	#[test]
+	fn nested_stores_are_left_out() {
+		let dir = tempfile::tempdir().unwrap();
+		let (store_dir, _) = archived(dir.path());
+		for nested in ["backups/old", "backups/new.part"] {
+			fs::create_dir_all(store_dir.join(nested)).unwrap();
+			fs::write(store_dir.join(nested).join("data"), "x").unwrap();
+		}
+		fs::write(store_dir.join("backups/old/manifest.json"), "{}").unwrap();
+		assert!(store_files(&store_dir).unwrap().iter().all(|f| !f.starts_with("backups/")));
+	}
+}
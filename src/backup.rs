@@ -0,0 +1,300 @@
+use std::fs;
+use std::io::{ self, Read, Write, BufReader, BufWriter };
+use std::net::TcpStream;
+use std::path::{ Path, PathBuf };
+use std::collections::HashMap;
+
+use byteorder::{ ReadBytesExt, WriteBytesExt, BigEndian };
+use clap::ArgMatches;
+use serde::{ Serialize, Deserialize };
+
+use crate::cli;
+use crate::basics;
+use crate::archive;
+
+// Layout of the incremental backups, in the `chain/` directory of a backup store:
+// - `state` holds the id of the chain, the sequence number of its last backup,
+//   and the size and modification time of each file of the store at that point
+// - `<sequence>.delta` holds the ranges of the files changed since the previous
+//   backup: DELTA_MAGIC, u32 header length, JSON header, u32 header CRC32C, then
+//   for each range: RANGE_MARKER, u16 path length, path, u64 offset, u64 length,
+//   u32 CRC32C, bytes; and finally END_MARKER, u64 number of ranges.
+pub const DELTA_MAGIC: &[u8; 8] = b"RIXDELT1";
+const RANGE_MARKER: u8 = 1;
+const END_MARKER: u8 = 0;
+
+// Size and modification time (in nanoseconds) of a file
+pub type Version = (u64, u64);
+
+// A backup of the chain, with the last mutation it holds
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+pub struct Step {
+	pub sequence: u64,
+	pub created: u64, // Unix time in milliseconds
+	pub lsn: u64 // Sequence number in the mutation logs, 0 without them
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChainState {
+	pub chain: String,
+	pub sequence: u64,
+	pub files: HashMap<String, Version>,
+	#[serde(default)]
+	pub steps: Vec<Step>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeltaHeader {
+	pub sequence: u64,
+	pub created: u64, // Unix time in milliseconds
+	pub sizes: HashMap<String, u64>, // New size of each changed file
+	pub removed: Vec<String>
+}
+
+fn invalid(what: &str) -> io::Error {
+	return io::Error::new(io::ErrorKind::InvalidData, what.to_owned());
+}
+
+pub fn file_version(path: &Path) -> io::Result<Version> {
+	let meta = fs::metadata(path)?;
+	let modified = meta.modified().ok()
+		.and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+		.map(|d| d.as_nanos() as u64)
+		.unwrap_or(0)
+	;
+	return Ok((meta.len(), modified));
+}
+
+pub fn load_state(backup_dir: &Path) -> io::Result<ChainState> {
+	let text = fs::read_to_string(backup_dir.join("chain/state"))?;
+	return serde_json::from_str(&text).map_err(|_| invalid("Invalid chain state"));
+}
+
+pub fn save_state(backup_dir: &Path, state: &ChainState) -> io::Result<()> {
+	let dir = backup_dir.join("chain");
+	fs::create_dir_all(&dir)?;
+	let text = serde_json::to_vec(state).map_err(io::Error::other)?;
+	fs::write(dir.join("state.part"), text)?;
+	return fs::rename(dir.join("state.part"), dir.join("state"));
+}
+
+pub fn delta_path(backup_dir: &Path, sequence: u64) -> PathBuf {
+	return backup_dir.join(format!("chain/{:08}.delta", sequence));
+}
+
+pub struct DeltaWriter {
+	output: BufWriter<fs::File>,
+	count: u64
+}
+
+impl DeltaWriter {
+	pub fn create(path: &Path, header: &DeltaHeader) -> io::Result<DeltaWriter> {
+		let mut output = BufWriter::new(fs::File::create(path)?);
+		let bytes = serde_json::to_vec(header).map_err(io::Error::other)?;
+		output.write_all(DELTA_MAGIC)?;
+		output.write_u32::<BigEndian>(bytes.len() as u32)?;
+		output.write_all(&bytes)?;
+		output.write_u32::<BigEndian>(crc32c::crc32c(&bytes))?;
+		return Ok(DeltaWriter { output, count: 0 });
+	}
+
+	pub fn range(&mut self, path: &str, offset: u64, bytes: &[u8]) -> io::Result<()> {
+		self.output.write_u8(RANGE_MARKER)?;
+		self.output.write_u16::<BigEndian>(path.len() as u16)?;
+		self.output.write_all(path.as_bytes())?;
+		self.output.write_u64::<BigEndian>(offset)?;
+		self.output.write_u64::<BigEndian>(bytes.len() as u64)?;
+		self.output.write_u32::<BigEndian>(crc32c::crc32c(bytes))?;
+		self.output.write_all(bytes)?;
+		self.count += 1;
+		return Ok(());
+	}
+
+	pub fn finish(mut self) -> io::Result<u64> {
+		self.output.write_u8(END_MARKER)?;
+		self.output.write_u64::<BigEndian>(self.count)?;
+		self.output.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+		return Ok(self.count);
+	}
+}
+
+// Reads a delta and checks all its ranges. When a store directory is
+// given, the changes are also applied to it.
+pub fn read_delta(path: &Path, target: Option<&Path>) -> io::Result<DeltaHeader> {
+	let mut reader = BufReader::new(fs::File::open(path)?);
+	let truncated = |_| invalid("The delta is truncated");
+	let mut magic = [0u8; 8];
+	reader.read_exact(&mut magic).map_err(truncated)?;
+	if &magic != DELTA_MAGIC { return Err(invalid("Not an OrixDB delta")); }
+	let length = reader.read_u32::<BigEndian>().map_err(truncated)?;
+	let mut bytes = vec![0u8; length as usize];
+	reader.read_exact(&mut bytes).map_err(truncated)?;
+	if reader.read_u32::<BigEndian>().map_err(truncated)? != crc32c::crc32c(&bytes) {
+		return Err(invalid("The delta header is corrupted"));
+	}
+	let header: DeltaHeader = serde_json::from_slice(&bytes)
+		.map_err(|_| invalid("Invalid delta header"))?
+	;
+	let paths = header.sizes.keys().chain(header.removed.iter());
+	if let Some(unsafe_path) = paths.into_iter().find(|p| !archive::is_safe_path(p)) {
+		return Err(invalid(&format!("Unsafe path: {:?}", unsafe_path)));
+	}
+
+	if let Some(target) = target {
+		for removed in &header.removed {
+			let path = target.join(removed);
+			if path.exists() { fs::remove_file(path)?; }
+		}
+	}
+	let mut count = 0u64;
+	loop {
+		match reader.read_u8().map_err(truncated)? {
+			END_MARKER => break,
+			RANGE_MARKER => {},
+			_ => return Err(invalid("Invalid range marker"))
+		}
+		let length = reader.read_u16::<BigEndian>().map_err(truncated)?;
+		let mut name = vec![0u8; length as usize];
+		reader.read_exact(&mut name).map_err(truncated)?;
+		let name = String::from_utf8(name).map_err(|_| invalid("Invalid range path"))?;
+		if !header.sizes.contains_key(&name) {
+			return Err(invalid(&format!("Unexpected range of {:?}", name)));
+		}
+		let offset = reader.read_u64::<BigEndian>().map_err(truncated)?;
+		let length = reader.read_u64::<BigEndian>().map_err(truncated)?;
+		let crc = reader.read_u32::<BigEndian>().map_err(truncated)?;
+		if offset.saturating_add(length) > header.sizes[&name] {
+			return Err(invalid(&format!("Range beyond the end of {:?}", name)));
+		}
+		let mut bytes = vec![0u8; length as usize];
+		reader.read_exact(&mut bytes).map_err(truncated)?;
+		if crc32c::crc32c(&bytes) != crc {
+			return Err(invalid(&format!("Checksum mismatch in a range of {:?}", name)));
+		}
+		if let Some(target) = target {
+			let path = target.join(&name);
+			if let Some(parent) = path.parent() { fs::create_dir_all(parent)?; }
+			let mut file = fs::OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+			io::Seek::seek(&mut file, io::SeekFrom::Start(offset))?;
+			file.write_all(&bytes)?;
+		}
+		count += 1;
+	}
+	if reader.read_u64::<BigEndian>().map_err(truncated)? != count {
+		return Err(invalid("The number of ranges doesn't match the delta end"));
+	}
+
+	if let Some(target) = target {
+		for (name, size) in &header.sizes {
+			let path = target.join(name);
+			if let Some(parent) = path.parent() { fs::create_dir_all(parent)?; }
+			fs::OpenOptions::new().create(true).truncate(false).write(true).open(path)?
+				.set_len(*size)?
+			;
+		}
+	}
+	return Ok(header);
+}
+
+// Asks a running server to back its store up, with a bare HTTP request
+fn request_backup(
+	port: u16, target: &str, incremental: bool
+) -> Result<(u16, serde_json::Value), String> {
+	let body = serde_json::json!({
+		"directory": target, "incremental": incremental
+	}).to_string();
+	let mut stream = TcpStream::connect(("127.0.0.1", port))
+		.map_err(|e| format!("Failed to reach the server on port {}: {}", port, e))?
+	;
+	write!(
+		stream,
+		"POST /backup HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nContent-Type: application/json\r\n\
+		Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+		port, body.len(), body
+	).map_err(|e| e.to_string())?;
+
+	let mut response = String::new();
+	stream.read_to_string(&mut response).map_err(|e| e.to_string())?;
+	let (head, content) = response.split_once("\r\n\r\n")
+		.ok_or("Invalid response from the server")?
+	;
+	let status = head.split(' ').nth(1).and_then(|s| s.parse::<u16>().ok())
+		.ok_or("Invalid response from the server")?
+	;
+	return Ok((status, serde_json::from_str(content).unwrap_or_default()));
+}
+
+pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
+
+	// --> Checking the command line arguments
+	// ---------------------------------------
+
+	let store_dir: PathBuf;
+	if matches.contains_id("directory") {
+		store_dir = PathBuf::from(matches.get_one::<String>("directory").unwrap());
+	}
+	else { store_dir = PathBuf::from(".").canonicalize().unwrap(); }
+
+	let port: u16;
+	if matches.contains_id("api-port") {
+		let port_text = matches.get_one::<String>("api-port").unwrap();
+		port = match port_text.parse::<u16>() {
+			Ok(port) => port,
+			Err(_) => {
+				cli::red_err("The API port must be a number.".to_owned());
+				return std::process::ExitCode::FAILURE;
+			}
+		};
+	}
+	else {
+		port = match basics::load_store(&store_dir) {
+			Ok(store) => store.defaults.api_port,
+			Err(message) => {
+				cli::red_err(message + "\nSet the port of the server with `--api-port`.");
+				return std::process::ExitCode::FAILURE;
+			}
+		};
+	}
+
+	// The server may run from another directory
+	let output = PathBuf::from(matches.get_one::<String>("output").unwrap());
+	let target = match std::env::current_dir() {
+		Ok(current) => current.join(output),
+		Err(e) => {
+			cli::red_err(format!("Failed to read the current directory: {}", e));
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+
+
+	// --> Requesting the backup
+	// -------------------------
+
+	let incremental = *matches.get_one::<bool>("incremental").unwrap();
+	match request_backup(port, &target.to_string_lossy(), incremental) {
+		Ok((201, body)) => {
+			let kind = match body["sequence"].as_u64() {
+				Some(sequence) if sequence > 0 => format!("Incremental backup {}", sequence),
+				_ => "Backup".to_owned()
+			};
+			cli::green_out(format!(
+				"{} made in {:?}: {} files, {} bytes.",
+				kind, target, body["files"], body["bytes"]
+			));
+			return std::process::ExitCode::SUCCESS;
+		},
+		Ok((status, body)) => {
+			cli::red_err(format!(
+				"The server refused the backup ({}): {}",
+				status, body["error"].as_str().unwrap_or("unknown error")
+			));
+			return std::process::ExitCode::FAILURE;
+		},
+		Err(message) => {
+			cli::red_err(message);
+			return std::process::ExitCode::FAILURE;
+		}
+	}
+}
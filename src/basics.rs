@@ -1,7 +1,13 @@
+use std::borrow::Cow;
+
 use serde::{ Serialize, Deserialize };
+use serde_json::Value;
 
 use crate::cli;
+use crate::geo;
+use crate::crypt::Cipher;
 
+#[allow(dead_code)]
 #[derive(Deserialize)]
 pub struct Conf {
 	pub name: String,
@@ -17,13 +23,20 @@ pub struct Conf {
 	pub max_file_size: u64
 }
 
+#[derive(Clone)]
 #[derive(Serialize, Deserialize)]
 pub struct Instance {
 	pub verbosity: bool,
 	pub api_port: u16,
 	pub api_scan: bool,
 	pub cluster_port: u16,
-	pub cluster_scan: bool
+	pub cluster_scan: bool,
+	// Whether the data files of the items are read through memory maps
+	#[serde(default)]
+	pub mapped_reads: bool,
+	// Memory budget of the cache of data file blocks, in MiB, 0 disabling it
+	#[serde(default)]
+	pub cache_size: u64
 }
 
 #[derive(Debug)]
@@ -47,6 +60,38 @@ pub enum StoreType {
 	Archive
 }
 
+// Backups a server makes on its own, declared in the manifest
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct BackupSchedule {
+	pub directory: String, // Relative to the store's directory, when not absolute
+	pub interval: u64, // Minutes between two backups
+	#[serde(default)]
+	pub keep: usize, // Number of full backups kept, 0 to keep them all
+	#[serde(default)]
+	pub increments: u64 // Number of incremental backups made after each full one
+}
+
+// Mutations logged for point-in-time recovery, declared in the manifest
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct MutationLogs {
+	pub retention: u64 // Hours the logs are kept, 0 to keep them forever
+}
+
+// Encryption at rest of the files of the store, declared in the manifest.
+// The manifest itself is never encrypted.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct Encryption {
+	pub cipher: Cipher,
+	pub key: String // Id of the key, in hexadecimal
+}
+
+#[derive(Clone)]
 #[derive(Serialize, Deserialize)]
 pub struct Store {
 	pub name: String,
@@ -58,7 +103,253 @@ pub struct Store {
 	pub ordering: bool,
 	pub checksumming: bool,
 	pub logging: LogLevel,
-	pub defaults: Instance
+	pub defaults: Instance,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub backups: Option<BackupSchedule>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub mutation_logs: Option<MutationLogs>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub encryption: Option<Encryption>
+}
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum DataType {
+	Null,
+	Boolean,
+	Integer,
+	Float,
+	Text,
+	Json,
+	GeoPoint,
+	Polygon,
+	BlobRef
+}
+
+impl DataType {
+	pub fn from_u8(value: u8) -> Option<DataType> {
+		return match value {
+			0 => Some(DataType::Null),
+			1 => Some(DataType::Boolean),
+			2 => Some(DataType::Integer),
+			3 => Some(DataType::Float),
+			4 => Some(DataType::Text),
+			5 => Some(DataType::Json),
+			6 => Some(DataType::GeoPoint),
+			7 => Some(DataType::Polygon),
+			8 => Some(DataType::BlobRef),
+			_ => None
+		};
+	}
+
+	pub fn to_u8(self) -> u8 {
+		return match self {
+			DataType::Null => 0,
+			DataType::Boolean => 1,
+			DataType::Integer => 2,
+			DataType::Float => 3,
+			DataType::Text => 4,
+			DataType::Json => 5,
+			DataType::GeoPoint => 6,
+			DataType::Polygon => 7,
+			DataType::BlobRef => 8
+		};
+	}
+
+	// The type recorded in a `data_type` byte, whatever its compression
+	pub fn of(data_type: u8) -> Option<DataType> {
+		return DataType::from_u8(data_type & !COMPRESSION_BITS);
+	}
+}
+
+// How the bytes of the items of a collection are compressed. Recorded in the
+// two high bits of the `data_type` byte of each item, as the compression of
+// a collection can change while it holds items.
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Compression {
+	None,
+	Lz4, // Faster
+	Zstd // Smaller
+}
+
+const COMPRESSION_BITS: u8 = 0b1100_0000;
+
+impl Compression {
+	pub fn from_u8(value: u8) -> Option<Compression> {
+		return match value {
+			0 => Some(Compression::None),
+			1 => Some(Compression::Lz4),
+			2 => Some(Compression::Zstd),
+			_ => None
+		};
+	}
+
+	pub fn to_u8(self) -> u8 {
+		return match self {
+			Compression::None => 0,
+			Compression::Lz4 => 1,
+			Compression::Zstd => 2
+		};
+	}
+
+	// The compression recorded in a `data_type` byte
+	pub fn of(data_type: u8) -> Option<Compression> {
+		return Compression::from_u8(data_type >> 6);
+	}
+
+	pub fn from_name(name: &str) -> Option<Compression> {
+		return match name.to_lowercase().as_str() {
+			"none" => Some(Compression::None),
+			"lz4" => Some(Compression::Lz4),
+			"zstd" => Some(Compression::Zstd),
+			_ => None
+		};
+	}
+
+	pub fn name(self) -> &'static str {
+		return match self {
+			Compression::None => "none",
+			Compression::Lz4 => "lz4",
+			Compression::Zstd => "zstd"
+		};
+	}
+}
+
+// Compresses the bytes of a value, and returns them with their `data_type`
+// byte. They are kept as they are when compressing doesn't make them smaller.
+pub fn compress(compression: Compression, data_type: DataType, data: Vec<u8>) -> (u8, Vec<u8>) {
+	let compressed = match compression {
+		Compression::None => None,
+		Compression::Lz4 => Some(lz4_flex::compress_prepend_size(&data)),
+		Compression::Zstd => zstd::bulk::compress(&data, 0).ok()
+	};
+	return match compressed {
+		Some(compressed) if compressed.len() < data.len() => (
+			data_type.to_u8() | compression.to_u8() << 6, compressed
+		),
+		_ => (data_type.to_u8(), data)
+	};
+}
+
+// The reverse of `compress`.
+// Returns `None` when the bytes can't be decompressed.
+pub fn decompress(data_type: u8, data: &[u8]) -> Option<Cow<'_, [u8]>> {
+	return match Compression::of(data_type)? {
+		Compression::None => Some(Cow::Borrowed(data)),
+		Compression::Lz4 => lz4_flex::decompress_size_prepended(data).ok().map(Cow::Owned),
+		Compression::Zstd => zstd::decode_all(data).ok().map(Cow::Owned)
+	};
+}
+
+// Turns a JSON value into the bytes stored in a data file,
+// along with the `data_type` byte recorded in the index.
+// GeoJSON points and polygons get a compact binary form:
+// the coordinates as big endian f64, preceded for polygons
+// by the number of rings and the length of each ring (u32).
+// Blob references (`{ "$blob": "<blob id>" }`) are stored as the id.
+pub fn encode_value(value: &Value) -> (DataType, Vec<u8>) {
+	if let Some(id) = blob_ref(value) {
+		return (DataType::BlobRef, id.as_bytes().to_vec());
+	}
+	if let Some((lon, lat)) = geo::parse_point(value) {
+		let mut bytes = lon.to_be_bytes().to_vec();
+		bytes.extend(lat.to_be_bytes());
+		return (DataType::GeoPoint, bytes);
+	}
+	if let Some(polygon) = geo::parse_polygon(value) {
+		let mut bytes = (polygon.len() as u32).to_be_bytes().to_vec();
+		for ring in polygon {
+			bytes.extend((ring.len() as u32).to_be_bytes());
+			for (lon, lat) in ring {
+				bytes.extend(lon.to_be_bytes());
+				bytes.extend(lat.to_be_bytes());
+			}
+		}
+		return (DataType::Polygon, bytes);
+	}
+	return match value {
+		Value::Null => (DataType::Null, Vec::new()),
+		Value::Bool(b) => (DataType::Boolean, vec![*b as u8]),
+		Value::Number(n) if n.is_i64() => (
+			DataType::Integer, n.as_i64().unwrap().to_be_bytes().to_vec()
+		),
+		Value::Number(n) => (
+			DataType::Float, n.as_f64().unwrap().to_be_bytes().to_vec()
+		),
+		Value::String(s) => (DataType::Text, s.as_bytes().to_vec()),
+		_ => (DataType::Json, serde_json::to_vec(value).unwrap())
+	};
+}
+
+// The reverse of `encode_value`, decompressing the bytes first if needed.
+// Returns `None` when the bytes don't match the data type
+pub fn decode_value(data_type: u8, data: &[u8]) -> Option<Value> {
+	let data = decompress(data_type, data)?;
+	let data = data.as_ref();
+	return match DataType::of(data_type)? {
+		DataType::Null => Some(Value::Null),
+		DataType::Boolean => Some(Value::Bool(*data.first()? != 0)),
+		DataType::Integer => Some(Value::from(
+			i64::from_be_bytes(data.try_into().ok()?)
+		)),
+		DataType::Float => Some(Value::from(
+			f64::from_be_bytes(data.try_into().ok()?)
+		)),
+		DataType::Text => Some(Value::String(
+			String::from_utf8(data.to_vec()).ok()?
+		)),
+		DataType::Json => serde_json::from_slice(data).ok(),
+		DataType::GeoPoint => {
+			let lon = f64::from_be_bytes(data.get(0..8)?.try_into().ok()?);
+			let lat = f64::from_be_bytes(data.get(8..16)?.try_into().ok()?);
+			Some(geo::point_json(lon, lat))
+		},
+		DataType::BlobRef => Some(serde_json::json!({
+			"$blob": String::from_utf8(data.to_vec()).ok()?
+		})),
+		DataType::Polygon => {
+			let u32_at = |at: usize| -> Option<u32> {
+				Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+			};
+			let f64_at = |at: usize| -> Option<f64> {
+				Some(f64::from_be_bytes(data.get(at..at + 8)?.try_into().ok()?))
+			};
+			let mut cursor = 4;
+			let mut polygon = geo::Polygon::new();
+			for _ in 0..u32_at(0)? {
+				let length = u32_at(cursor)?;
+				cursor += 4;
+				let mut ring = Vec::<(f64, f64)>::new();
+				for _ in 0..length {
+					ring.push((f64_at(cursor)?, f64_at(cursor + 8)?));
+					cursor += 16;
+				}
+				polygon.push(ring);
+			}
+			Some(geo::polygon_json(&polygon))
+		}
+	};
+}
+
+// Reads a blob reference: `{ "$blob": "<blob id>" }`
+pub fn blob_ref(value: &Value) -> Option<&str> {
+	let object = value.as_object()?;
+	if object.len() != 1 { return None; }
+	let id = object.get("$blob")?.as_str()?;
+	if id.len() != crate::faccess::ID_LENGTH { return None; }
+	return Some(id);
+}
+
+// Current Unix time in milliseconds
+pub fn now_millis() -> u64 {
+	return std::time::SystemTime::now()
+		.duration_since(std::time::UNIX_EPOCH)
+		.map(|d| d.as_millis() as u64)
+		.unwrap_or(0)
+	;
 }
 
 pub fn get_conf() -> Conf {
@@ -66,7 +357,7 @@ pub fn get_conf() -> Conf {
 	return serde_json::from_str(cfg_str).unwrap();
 }
 
-pub fn parse_port(port: &String, port_name: &str) -> (u16, bool) {
+pub fn parse_port(port: &str, port_name: &str) -> (u16, bool) {
 	let number_str;
 	let ellipsis_str;
 	let number;
@@ -75,7 +366,7 @@ pub fn parse_port(port: &String, port_name: &str) -> (u16, bool) {
 	if port.find(".").is_some() {
 		(number_str, ellipsis_str) = port.split_once(".").unwrap();
 		if
-			ellipsis_str.len() < 1 ||
+			ellipsis_str.is_empty() ||
 			! ellipsis_str.chars().all(|c: char| c == '.')
 		{
 			cli::red_err(
@@ -89,7 +380,7 @@ pub fn parse_port(port: &String, port_name: &str) -> (u16, bool) {
 	}
 	else {
 		ellipsis = false;
-		number_str = &*port;
+		number_str = port;
 	}
 	number = number_str.parse::<u16>();
 	test_0 = number.clone();
@@ -103,4 +394,18 @@ pub fn parse_port(port: &String, port_name: &str) -> (u16, bool) {
 	}
 
 	return (number.unwrap(), ellipsis);
-}
\ No newline at end of file
+}
+// Reads the manifest of a store
+pub fn load_store(store_dir: &std::path::Path) -> Result<Store, String> {
+	let mut path = store_dir.to_path_buf();
+	path.push("manifest.json");
+	if !path.exists() {
+		return Err("The store doesn't contain a manifest.".to_owned());
+	}
+	let text = std::fs::read_to_string(&path)
+		.map_err(|e| format!("Failed to read the manifest: {}", e))?
+	;
+	return serde_json::from_str(&text)
+		.map_err(|e| format!("Failed to parse the manifest: {}", e))
+	;
+}
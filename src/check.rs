@@ -1,6 +1,959 @@
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::collections::{ HashMap, HashSet };
+
 use clap::ArgMatches;
+use serde_json::{ Value, json };
+
+use crate::cli;
+use crate::lite;
+use crate::crypt;
+use crate::repair;
+use crate::archive::{ self, ARCHIVE_FILE };
+use crate::basics::{ self, Store, StoreType, DataType };
+use crate::faccess::{ self, Area, FileMeta, ID_LENGTH, POINT_SIZE, BUCKET_SIZE, BLOCK_SIZE };
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Severity {
+	Warning,
+	Error
+}
+
+// A problem found in a store
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Issue {
+	pub severity: Severity,
+	pub section: &'static str,
+	pub path: String, // Relative to the store's directory
+	pub message: String
+}
+
+// Everything found while checking a store
+#[derive(Debug)]
+#[derive(Default)]
+pub struct Report {
+	pub issues: Vec<Issue>,
+	pub files: u64, // Number of data files checked
+	pub entries: u64 // Number of index entries checked
+}
+
+impl Report {
+	fn error(&mut self, section: &'static str, path: &str, message: String) {
+		self.issues.push(Issue {
+			severity: Severity::Error, section, path: path.to_owned(), message
+		});
+	}
+
+	fn warning(&mut self, section: &'static str, path: &str, message: String) {
+		self.issues.push(Issue {
+			severity: Severity::Warning, section, path: path.to_owned(), message
+		});
+	}
+
+	pub fn errors(&self) -> usize {
+		return self.issues.iter().filter(|i| i.severity == Severity::Error).count();
+	}
+
+	pub fn warnings(&self) -> usize {
+		return self.issues.iter().filter(|i| i.severity == Severity::Warning).count();
+	}
+
+	pub fn to_json(&self) -> Value {
+		return json!({
+			"ok": self.errors() == 0,
+			"errors": self.errors(),
+			"warnings": self.warnings(),
+			"files": self.files,
+			"entries": self.entries,
+			"issues": self.issues.iter().map(|issue| json!({
+				"severity": match issue.severity {
+					Severity::Warning => "warning",
+					Severity::Error => "error"
+				},
+				"section": issue.section,
+				"path": issue.path,
+				"message": issue.message
+			})).collect::<Vec<Value>>()
+		});
+	}
+}
+
+// A range of a data file used by an entry of an index
+struct Used {
+	owner: String,
+	// Id written in the header of the record, in framed files
+	id: String,
+	file: String,
+	index: u64,
+	length: u64,
+	// Type of the value stored in the range, `None` for raw bytes
+	data_type: Option<u8>
+}
+
+
+// --> Manifest
+// ------------
+
+fn check_manifest(store_dir: &Path, report: &mut Report) -> Option<Store> {
+	let conf = basics::get_conf();
+	let mut path = store_dir.to_path_buf();
+	path.push("manifest.json");
+	if !path.exists() {
+		report.error("manifest", "manifest.json", "The manifest is missing.".to_owned());
+		return None;
+	}
+	let text = match fs::read_to_string(&path) {
+		Ok(text) => text,
+		Err(e) => {
+			report.error("manifest", "manifest.json", format!("Unreadable manifest: {}", e));
+			return None;
+		}
+	};
+	let store: Store = match serde_json::from_str(&text) {
+		Ok(store) => store,
+		Err(e) => {
+			report.error("manifest", "manifest.json", format!("Invalid manifest: {}", e));
+			return None;
+		}
+	};
+
+	if store.name.is_empty() {
+		report.error("manifest", "manifest.json", "The store has no name.".to_owned());
+	}
+	if store.id.is_empty() || !store.id.chars().all(
+		|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_".contains(c)
+	) {
+		report.error("manifest", "manifest.json", format!("Invalid store id: {:?}", store.id));
+	}
+	if store.major != conf.major {
+		report.error("manifest", "manifest.json", format!(
+			"The store's major version ({}) differs from the one of {} ({}).",
+			store.major, conf.display_name, conf.major
+		));
+	}
+	else if store.minor != conf.minor {
+		report.warning("manifest", "manifest.json", format!(
+			"The store's minor version ({}) differs from the one of {} ({}).",
+			store.minor, conf.display_name, conf.minor
+		));
+	}
+	return Some(store);
+}
+
+
+// --> Data files
+// --------------
+
+// Reports the blocks of a data file failing their checksum,
+// along with the entries stored in them
+fn check_checksums(
+	store_dir: &Path,
+	section: &'static str,
+	path: &Path,
+	used: &[&Used],
+	report: &mut Report
+) {
+	let relative = path.strip_prefix(store_dir).unwrap_or(path).display().to_string();
+	let blocks = match faccess::corrupted_blocks(store_dir, path) {
+		Ok(Some(blocks)) => blocks,
+		Ok(None) => {
+			if crypt::len(path).is_ok_and(|size| size > 0) {
+				report.warning(section, &relative, "No checksums for this file.".to_owned());
+			}
+			return;
+		},
+		Err(e) => {
+			return report.error(section, &relative, format!("Unreadable checksums: {}", e));
+		}
+	};
+	for block in blocks {
+		let (start, end) = (block * BLOCK_SIZE, (block + 1) * BLOCK_SIZE);
+		let owners = used.iter()
+			.filter(|u| u.length > 0 && u.index < end && u.index + u.length > start)
+			.map(|u| u.owner.as_str())
+			.collect::<Vec<&str>>()
+		;
+		report.error(section, &relative, format!(
+			"Block {} (bytes {} to {}) failed its checksum{}.",
+			block, start, end - 1,
+			if owners.is_empty() { String::new() }
+			else { format!(", affecting: {}", owners.join(", ")) }
+		));
+	}
+}
+
+// Reports the checksum files whose data file doesn't exist anymore
+fn check_checksum_orphans(store_dir: &Path, dir: &Path, report: &mut Report) {
+	let entries = match fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(_) => return
+	};
+	for entry in entries.flatten() {
+		let path = entry.path();
+		if path.is_dir() {
+			check_checksum_orphans(store_dir, &path, report);
+			continue;
+		}
+		let relative = path.strip_prefix(store_dir.join("checksums")).unwrap();
+		if !store_dir.join(relative).is_file() {
+			report.warning(
+				"checksums",
+				&path.strip_prefix(store_dir).unwrap().display().to_string(),
+				"Orphaned checksums: the data file doesn't exist.".to_owned()
+			);
+		}
+	}
+}
+
+// Checks the data files of an area against the ranges its index uses
+fn check_area(
+	store_dir: &Path,
+	area: Area,
+	files: &HashMap<String, FileMeta>,
+	used: &[Used],
+	checksums: bool,
+	report: &mut Report
+) {
+	let section = area.dir();
+	let mut dir = store_dir.to_path_buf();
+	dir.push(section);
+
+	// The files of the table, with their actual size
+	let mut sizes = HashMap::<&str, u64>::new();
+	for (id, meta) in files {
+		report.files += 1;
+		let relative = format!("{}/{}", section, id);
+		let size = match crypt::len(&dir.join(id)) {
+			Ok(size) => size,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+				report.error(section, &relative, "Data file missing.".to_owned());
+				continue;
+			},
+			Err(e) => {
+				report.error(section, &relative, format!("Unreadable data file: {}", e));
+				continue;
+			}
+		};
+		if size < meta.size {
+			report.error(section, &relative, format!(
+				"The file holds {} bytes, but {} are recorded in the index.", size, meta.size
+			));
+		}
+		else if size > meta.size {
+			report.warning(section, &relative, format!(
+				"{} bytes beyond the size recorded in the index.", size - meta.size
+			));
+		}
+		sizes.insert(id, size);
+	}
+
+	// The ranges of every entry
+	let mut spans = HashMap::<&str, Vec<(u64, u64, &str)>>::new();
+	for range in used {
+		report.entries += 1;
+		let relative = format!("{}/{}", section, range.file);
+		let recorded = match files.get(&range.file) {
+			Some(meta) => meta.size,
+			None => {
+				report.error(section, &relative, format!(
+					"{} is stored in a file missing from the index.", range.owner
+				));
+				continue;
+			}
+		};
+		let end = match range.index.checked_add(range.length) {
+			Some(end) if end <= recorded => end,
+			_ => {
+				report.error(section, &relative, format!(
+					"{} lies beyond the end of the file ({} + {} > {}).",
+					range.owner, range.index, range.length, recorded
+				));
+				continue;
+			}
+		};
+		if range.length > 0 {
+			spans.entry(&range.file).or_default().push((range.index, end, &range.owner));
+		}
+
+		// The header of the record, in the files having them
+		if files[&range.file].framed && sizes.get(range.file.as_str()).is_some_and(|s| *s >= end) {
+			let header = faccess::read_header(&dir.join(&range.file), range.index)
+				.ok().flatten()
+			;
+			match header {
+				Some((_, header)) if header.live && header.length == range.length
+					&& header.tag.id == range.id => {},
+				Some(_) => report.error(section, &relative, format!(
+					"The record header of {} doesn't match the index.", range.owner
+				)),
+				None => report.error(section, &relative, format!(
+					"{} has no valid record header.", range.owner
+				))
+			}
+		}
+
+		let data_type = match range.data_type {
+			Some(data_type) => data_type,
+			None => continue
+		};
+		if DataType::of(data_type).is_none() {
+			report.error(section, &relative, format!(
+				"{} has an unknown data type ({}).", range.owner, data_type
+			));
+			continue;
+		}
+		if sizes.get(range.file.as_str()).is_none_or(|size| *size < end) { continue; }
+		let decoded = faccess::read_data(&dir.join(&range.file), range.index, range.length)
+			.ok().and_then(|data| basics::decode_value(data_type, &data))
+		;
+		if decoded.is_none() {
+			report.error(section, &relative, format!(
+				"The value of {} can't be decoded.", range.owner
+			));
+		}
+	}
+
+	// Ranges sharing bytes. Each range is compared with the one reaching the
+	// furthest before it, as a long range can overlap several of the next ones.
+	let mut reaches = HashMap::<&str, Vec<(u64, &str)>>::new();
+	for (file, list) in spans.iter_mut() {
+		list.sort();
+		let reach = reaches.entry(file).or_default();
+		for (start, end, owner) in list.iter() {
+			let previous = reach.last().copied();
+			if let Some((_, other)) = previous.filter(|(furthest, _)| start < furthest) {
+				report.error(section, &format!("{}/{}", section, file), format!(
+					"{} and {} overlap.", other, owner
+				));
+			}
+			reach.push(match previous {
+				Some((furthest, other)) if furthest >= *end => (furthest, other),
+				_ => (*end, owner)
+			});
+		}
+	}
+
+	// Holes, which must lie within their file, out of every range
+	for (id, meta) in files {
+		let relative = format!("{}/{}", section, id);
+		let mut holes = meta.holes.iter().collect::<Vec<(&u64, &u64)>>();
+		holes.sort();
+		for (index, length) in holes {
+			let end = index.saturating_add(*length);
+			if end > meta.size {
+				report.error(section, &relative, format!(
+					"The hole at {} ({} bytes) goes past the end of the file ({} bytes).",
+					index, length, meta.size
+				));
+			}
+			let (list, reach) = match (spans.get(id.as_str()), reaches.get(id.as_str())) {
+				(Some(list), Some(reach)) => (list, reach),
+				_ => continue
+			};
+			let before = list.partition_point(|(start, _, _)| *start < end);
+			let covering = reach[..before].last().filter(|(furthest, _)| furthest > index);
+			if let Some((_, owner)) = covering {
+				report.error(section, &relative, format!(
+					"The hole at {} ({} bytes) overlaps {}.", index, length, owner
+				));
+			}
+		}
+	}
+
+	if checksums {
+		for id in sizes.keys() {
+			let in_file = used.iter().filter(|u| u.file == *id).collect::<Vec<&Used>>();
+			check_checksums(store_dir, section, &dir.join(id), &in_file, report);
+		}
+	}
+
+	check_orphans(&dir, section, |name| files.contains_key(name), false, report);
+}
+
+// Reports the entries of a directory named like ids but unknown to its index
+fn check_orphans(
+	dir: &Path,
+	section: &'static str,
+	known: impl Fn(&str) -> bool,
+	directories: bool,
+	report: &mut Report
+) {
+	let entries = match fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(_) => return
+	};
+	for entry in entries.flatten() {
+		let name = entry.file_name().to_string_lossy().into_owned();
+		if name.len() != ID_LENGTH || entry.path().is_dir() != directories { continue; }
+		if !known(&name) {
+			report.warning(section, &format!("{}/{}", section, name), format!(
+				"Orphaned {}: not referenced by the index.",
+				if directories { "directory" } else { "data file" }
+			));
+		}
+	}
+}
+
+// Whether the index of an area can be checked. The areas added after
+// the first versions are created on the first start of the server.
+fn has_index(store_dir: &Path, dir: &'static str, optional: bool, report: &mut Report) -> bool {
+	let mut path = store_dir.to_path_buf();
+	path.push(dir);
+	path.push("rixindex");
+	if path.exists() { return true; }
+	let relative = format!("{}/rixindex", dir);
+	if optional {
+		report.warning(dir, &relative, "Index missing, it will be created by the server.".to_owned());
+	}
+	else {
+		report.error(dir, &relative, "Index missing.".to_owned());
+	}
+	return false;
+}
+
+
+// --> Indexes
+// -----------
+
+fn check_singletons(store_dir: &Path, checksums: bool, report: &mut Report) {
+	if !has_index(store_dir, "singletons", false, report) { return; }
+	let index = match faccess::load_singletons(store_dir) {
+		Ok(index) => index,
+		Err(e) => {
+			return report.error("singletons", "singletons/rixindex", format!("Unreadable index: {}", e));
+		}
+	};
+	let used = index.singletons.iter().map(|(id, meta)| Used {
+		owner: format!("Singleton {:?} ({})", meta.name, id),
+		id: id.clone(),
+		file: meta.file.clone(),
+		index: meta.index,
+		length: meta.data_length,
+		data_type: Some(meta.data_type)
+	}).collect::<Vec<Used>>();
+	check_area(store_dir, Area::Singletons, &index.files, &used, checksums, report);
+}
+
+// Returns the ids of the items, for the checks of the other areas
+fn check_collections(store_dir: &Path, checksums: bool, report: &mut Report) -> Option<HashSet<String>> {
+	if !has_index(store_dir, "collections", false, report) { return None; }
+	let index = match faccess::load_collections(store_dir) {
+		Ok(index) => index,
+		Err(e) => {
+			report.error("collections", "collections/rixindex", format!("Unreadable index: {}", e));
+			return None;
+		}
+	};
+	for (id, meta) in &index.items {
+		if !index.list.contains_key(&meta.collection) {
+			report.error("collections", "collections/rixindex", format!(
+				"Item {} belongs to an unknown collection ({}).", id, meta.collection
+			));
+		}
+	}
+	let used = index.items.iter().map(|(id, meta)| Used {
+		owner: format!("Item {}", id),
+		id: id.clone(),
+		file: meta.file.clone(),
+		index: meta.index,
+		length: meta.data_length,
+		data_type: Some(meta.data_type)
+	}).collect::<Vec<Used>>();
+	check_area(store_dir, Area::Collections, &index.files, &used, checksums, report);
+
+	if let Err(e) = faccess::load_geoindex(store_dir, &index) {
+		report.error("collections", "collections/geoindex", format!("Unreadable index: {}", e));
+	}
+	return Some(index.items.into_keys().collect());
+}
+
+fn check_edges(
+	store_dir: &Path, items: Option<&HashSet<String>>, checksums: bool, report: &mut Report
+) {
+	if !has_index(store_dir, "edges", true, report) { return; }
+	let index = match faccess::load_edges(store_dir) {
+		Ok(index) => index,
+		Err(e) => {
+			return report.error("edges", "edges/rixindex", format!("Unreadable index: {}", e));
+		}
+	};
+
+	// Both ends of every edge, and the adjacency lists
+	let listed = |lists: &HashMap<String, Vec<String>>, item: &str, edge: &String| {
+		return lists.get(item).is_some_and(|list| list.contains(edge));
+	};
+	for (id, meta) in &index.edges {
+		if let Some(items) = items {
+			for end in [&meta.from, &meta.to] {
+				if !items.contains(end) {
+					report.error("edges", "edges/rixindex", format!(
+						"Edge {} links a missing item ({}).", id, end
+					));
+				}
+			}
+		}
+		if !listed(&index.outgoing, &meta.from, id) || !listed(&index.incoming, &meta.to, id) {
+			report.error("edges", "edges/adjacency", format!(
+				"Edge {} is missing from the adjacency lists.", id
+			));
+		}
+	}
+	for lists in [&index.outgoing, &index.incoming] {
+		for (item, list) in lists {
+			for edge in list.iter().filter(|e| !index.edges.contains_key(*e)) {
+				report.error("edges", "edges/adjacency", format!(
+					"The adjacency lists of item {} hold an unknown edge ({}).", item, edge
+				));
+			}
+		}
+	}
+
+	let used = index.edges.iter().map(|(id, meta)| Used {
+		owner: format!("Edge {}", id),
+		id: id.clone(),
+		file: meta.file.clone(),
+		index: meta.index,
+		length: meta.data_length,
+		data_type: Some(meta.data_type)
+	}).collect::<Vec<Used>>();
+	check_area(store_dir, Area::Edges, &index.files, &used, checksums, report);
+}
+
+fn check_keyvalues(store_dir: &Path, checksums: bool, report: &mut Report) {
+	if !has_index(store_dir, "keyvalues", true, report) { return; }
+	let index = match faccess::load_keyvalues(store_dir) {
+		Ok(index) => index,
+		Err(e) => {
+			return report.error("keyvalues", "keyvalues/rixindex", format!("Unreadable index: {}", e));
+		}
+	};
+	let used = index.entries.iter().map(|(key, meta)| Used {
+		owner: format!("Key {:?}", key),
+		id: key.clone(),
+		file: meta.file.clone(),
+		index: meta.index,
+		length: meta.data_length,
+		data_type: Some(meta.data_type)
+	}).collect::<Vec<Used>>();
+	check_area(store_dir, Area::KeyValues, &index.files, &used, checksums, report);
+}
+
+// Returns the ids of the blobs, for the checks of the references to them
+fn check_blobs(store_dir: &Path, checksums: bool, report: &mut Report) -> Option<HashSet<String>> {
+	if !has_index(store_dir, "blobs", true, report) { return None; }
+	let index = match faccess::load_blobs(store_dir) {
+		Ok(index) => index,
+		Err(e) => {
+			report.error("blobs", "blobs/rixindex", format!("Unreadable index: {}", e));
+			return None;
+		}
+	};
+	let mut used = Vec::<Used>::new();
+	for (id, meta) in &index.blobs {
+		let total = meta.chunks.iter().map(|c| c.length).sum::<u64>();
+		if total != meta.size {
+			report.error("blobs", "blobs/rixindex", format!(
+				"The chunks of blob {} hold {} bytes instead of {}.", id, total, meta.size
+			));
+		}
+		for (number, chunk) in meta.chunks.iter().enumerate() {
+			used.push(Used {
+				owner: format!("Chunk {} of blob {}", number, id),
+				id: id.clone(),
+				file: chunk.file.clone(),
+				index: chunk.index,
+				length: chunk.length,
+				data_type: None
+			});
+		}
+	}
+	check_area(store_dir, Area::Blobs, &index.files, &used, checksums, report);
+	return Some(index.blobs.into_keys().collect());
+}
+
+// Reports the values of the items referencing deleted blobs
+fn check_blob_refs(store_dir: &Path, blobs: &HashSet<String>, report: &mut Report) {
+	let index = match faccess::load_collections(store_dir) {
+		Ok(index) => index,
+		Err(_) => return
+	};
+	let blob_refs = index.items.iter()
+		.filter(|(_, m)| DataType::of(m.data_type) == Some(DataType::BlobRef))
+	;
+	for (id, meta) in blob_refs {
+		let mut path = store_dir.to_path_buf();
+		path.push("collections");
+		path.push(&meta.file);
+		let target = faccess::read_data(&path, meta.index, meta.data_length).ok()
+			.and_then(|data| basics::decompress(meta.data_type, &data).map(|d| d.into_owned()))
+			.and_then(|data| String::from_utf8(data).ok())
+		;
+		if let Some(target) = target.filter(|t| !blobs.contains(t)) {
+			report.warning("blobs", "collections/rixindex", format!(
+				"Item {} references a deleted blob ({}).", id, target
+			));
+		}
+	}
+}
+
+fn check_timeseries(store_dir: &Path, checksums: bool, report: &mut Report) {
+	if !has_index(store_dir, "timeseries", true, report) { return; }
+	let index = match faccess::load_series_list(store_dir) {
+		Ok(index) => index,
+		Err(e) => {
+			return report.error("timeseries", "timeseries/rixindex", format!("Unreadable index: {}", e));
+		}
+	};
+
+	let mut dir = store_dir.to_path_buf();
+	dir.push("timeseries");
+	for (id, meta) in &index.series {
+		report.entries += 1;
+		if meta.window == 0 {
+			report.error("timeseries", "timeseries/rixindex", format!(
+				"Series {:?} ({}) has an empty window.", meta.name, id
+			));
+			continue;
+		}
+		let entries = match fs::read_dir(dir.join(id)) {
+			Ok(entries) => entries,
+			Err(_) => continue // No point was appended yet
+		};
+		for entry in entries.flatten() {
+			let name = entry.file_name().to_string_lossy().into_owned();
+			let relative = format!("timeseries/{}/{}", id, name);
+			let size = crypt::len(&entry.path()).unwrap_or(0);
+			report.files += 1;
+			if checksums {
+				check_checksums(store_dir, "timeseries", &entry.path(), &[], report);
+			}
+
+			if let Some(interval) = name.strip_prefix("rollup-") {
+				if !meta.rollups.iter().any(|r| r.interval.to_string() == interval) {
+					report.warning("timeseries", &relative, "Rollup unknown to its series.".to_owned());
+				}
+				if !size.is_multiple_of(BUCKET_SIZE) {
+					report.error("timeseries", &relative, "Truncated rollup bucket.".to_owned());
+				}
+				continue;
+			}
+			let start = match name.parse::<u64>() {
+				Ok(start) => start,
+				Err(_) => {
+					report.warning("timeseries", &relative, "Unexpected file.".to_owned());
+					continue;
+				}
+			};
+			if !size.is_multiple_of(POINT_SIZE) {
+				report.warning("timeseries", &relative, format!(
+					"Partial point of {} bytes, dropped on the next start of the server.",
+					size % POINT_SIZE
+				));
+			}
+			let points = match faccess::read_points(&entry.path()) {
+				Ok(points) => points,
+				Err(e) => {
+					report.error("timeseries", &relative, format!("Unreadable window: {}", e));
+					continue;
+				}
+			};
+			let outside = points.iter()
+				.filter(|(time, _)| *time < start || *time - start >= meta.window)
+				.count()
+			;
+			if start % meta.window != 0 || outside > 0 {
+				report.error("timeseries", &relative, format!(
+					"{} points lie outside of the window.", outside
+				));
+			}
+		}
+	}
+
+	check_orphans(&dir, "timeseries", |name| index.series.contains_key(name), true, report);
+}
+
+// Checks a whole store, without modifying it
+// The content of archive stores is checked against the sizes
+// and checksums of their archive file
+fn check_archive(store_dir: &Path, report: &mut Report) {
+	match archive::read_archive(&store_dir.join(ARCHIVE_FILE), true) {
+		Ok((_, entries)) => report.files = entries.len() as u64,
+		Err(e) => report.error("archive", ARCHIVE_FILE, format!("Invalid archive: {}", e))
+	}
+}
+
+pub fn verify(store_dir: &Path) -> Report {
+	let mut report = Report::default();
+	let checksums = match check_manifest(store_dir, &mut report) {
+		Some(store) if store.kind == StoreType::Archive => {
+			check_archive(store_dir, &mut report);
+			return report;
+		},
+		Some(store) => {
+			if let Err(message) = crypt::unlock(store_dir, &store, None) {
+				report.error("manifest", "manifest.json", message);
+				return report;
+			}
+			store.checksumming
+		},
+		None => return report
+	};
+
+	check_singletons(store_dir, checksums, &mut report);
+	let items = check_collections(store_dir, checksums, &mut report);
+	check_edges(store_dir, items.as_ref(), checksums, &mut report);
+	check_keyvalues(store_dir, checksums, &mut report);
+	if let Some(blobs) = check_blobs(store_dir, checksums, &mut report) {
+		check_blob_refs(store_dir, &blobs, &mut report);
+	}
+	check_timeseries(store_dir, checksums, &mut report);
+	if checksums {
+		check_checksum_orphans(store_dir, &store_dir.join("checksums"), &mut report);
+	}
+	return report;
+}
 
 pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
-	println!("{:#?}", matches);
+
+	// --> Checking the command line arguments
+	// ---------------------------------------
+
+	let store_dir: PathBuf;
+	if matches.contains_id("directory") {
+		store_dir = PathBuf::from(matches.get_one::<String>("directory").unwrap());
+
+		if !store_dir.is_dir() {
+			cli::red_err(
+				"The path supplied doesn't lead to an existing directory.".to_owned()
+			);
+			return std::process::ExitCode::FAILURE;
+		}
+	}
+	else { store_dir = PathBuf::from(".").canonicalize().unwrap(); }
+	let as_json = *matches.get_one::<bool>("json").unwrap();
+	let repair = *matches.get_one::<bool>("repair").unwrap();
+
+
+	// --> Repairing the store
+	// -----------------------
+
+	if repair {
+		let mut manifest_report = Report::default();
+		let store = match check_manifest(&store_dir, &mut manifest_report) {
+			Some(store) => store,
+			None => {
+				cli::red_err(
+					"A store can't be repaired without a valid manifest.".to_owned()
+				);
+				return std::process::ExitCode::FAILURE;
+			}
+		};
+		// The indexes of a served store are rewritten by its server
+		let _lock = match lite::lock(&store_dir) {
+			Ok(file) => file,
+			Err(message) => {
+				cli::red_err(message);
+				return std::process::ExitCode::FAILURE;
+			}
+		};
+		match repair::repair(&store_dir, &store) {
+			Ok(notes) => {
+				// The JSON report alone goes to the standard output
+				for note in notes {
+					if as_json { cli::yellow_err(note); }
+					else { cli::yellow_out(note); }
+				}
+			},
+			Err(message) => {
+				cli::red_err(message);
+				return std::process::ExitCode::FAILURE;
+			}
+		}
+	}
+
+
+	// --> Checking the store and printing the report
+	// ----------------------------------------------
+
+	let report = verify(&store_dir);
+
+	if as_json {
+		println!("{}", serde_json::to_string_pretty(&report.to_json()).unwrap());
+	}
+	else {
+		for issue in &report.issues {
+			let line = format!("[{}] {}: {}", issue.section, issue.path, issue.message);
+			match issue.severity {
+				Severity::Error => cli::red_err(line),
+				Severity::Warning => cli::yellow_err(line)
+			}
+		}
+		let summary = format!(
+			"{} data files and {} entries checked: {} errors, {} warnings.",
+			report.files, report.entries, report.errors(), report.warnings()
+		);
+		if report.errors() == 0 { cli::green_out(summary); }
+		else { cli::red_err(summary); }
+	}
+
+	if report.errors() > 0 { return std::process::ExitCode::FAILURE; }
 	return std::process::ExitCode::SUCCESS;
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::{ Seek, SeekFrom, Write };
+	use serde_json::json;
+	use crate::serve::testing;
+	use super::*;
+
+	fn messages(report: &Report, severity: Severity) -> Vec<String> {
+		return report.issues.iter()
+			.filter(|issue| issue.severity == severity)
+			.map(|issue| format!("{}: {}", issue.path, issue.message))
+			.collect()
+		;
+	}
+
+	#[test]
+	fn index_and_data_file_mismatches() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut engine = testing::create_store(dir.path(), json!({}));
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "notes" })).unwrap();
+		let ids = (0..3)
+			.map(|n| testing::insert(&mut engine, "notes", json!({ "n": n })))
+			.collect::<Vec<String>>()
+		;
+		let report = verify(dir.path());
+		assert_eq!((report.errors(), report.warnings()), (0, 0));
+		assert_eq!(report.entries, 3);
+
+		// An item pointing past the end of its file, and one sharing the
+		// bytes of another
+		let file = engine.collections.items[&ids[0]].file.clone();
+		let size = engine.collections.files[&file].size;
+		let second = engine.collections.items[&ids[1]].index;
+		engine.collections.items.get_mut(&ids[0]).unwrap().index = size;
+		engine.collections.items.get_mut(&ids[2]).unwrap().index = second + 1;
+		engine.save_index(Area::Collections).unwrap();
+		let report = verify(dir.path());
+		let errors = messages(&report, Severity::Error);
+		assert!(errors.iter().any(|e| e.contains(&format!("Item {} lies beyond the end", ids[0]))));
+		assert!(errors.iter().any(|e| e.ends_with("overlap.")));
+		assert!(errors.iter().any(|e| e.contains(&format!("The value of Item {}", ids[2]))));
+
+		// Data files missing, grown or unknown to the index
+		let path = dir.path().join("collections").join(&file);
+		let data = fs::read(&path).unwrap();
+		fs::remove_file(&path).unwrap();
+		assert!(messages(&verify(dir.path()), Severity::Error)
+			.contains(&format!("collections/{}: Data file missing.", file))
+		);
+		fs::write(&path, [&data[..], b"tail"].concat()).unwrap();
+		fs::write(dir.path().join("collections").join(faccess::new_id()), b"").unwrap();
+		let warnings = messages(&verify(dir.path()), Severity::Warning);
+		assert_eq!(warnings.len(), 2, "{:?}", warnings);
+		assert!(warnings.contains(&format!(
+			"collections/{}: 4 bytes beyond the size recorded in the index.", file
+		)));
+		assert!(warnings.iter().any(|w| w.ends_with("Orphaned data file: not referenced by the index.")));
+
+		// A manifest which can't be read stops the check
+		fs::write(dir.path().join("manifest.json"), "{").unwrap();
+		let report = verify(dir.path());
+		assert_eq!(report.issues.len(), 1);
+		assert!(report.issues[0].message.starts_with("Invalid manifest"));
+	}
+
+	#[test]
+	fn checksum_mismatches() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut engine = testing::create_store(dir.path(), json!({}));
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "notes" })).unwrap();
+		let text = "x".repeat(3000);
+		let ids = (0..4)
+			.map(|n| testing::insert(&mut engine, "notes", json!({ "n": n, "text": text })))
+			.collect::<Vec<String>>()
+		;
+		let report = verify(dir.path());
+		assert_eq!((report.errors(), report.warnings()), (0, 0));
+
+		// Flips a byte of the third item, in the second block of its file
+		let meta = engine.collections.items[&ids[2]].clone();
+		let offset = meta.index + meta.data_length / 2;
+		assert_eq!(offset / BLOCK_SIZE, 1);
+		let path = engine.data_path(Area::Collections, &meta.file);
+		let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+		file.seek(SeekFrom::Start(offset)).unwrap();
+		file.write_all(b"y").unwrap();
+		drop(file);
+
+		let read = engine.read_data(Area::Collections, &meta.file, meta.index, meta.data_length);
+		assert!(read.unwrap_err().contains("Checksum mismatch in block 1 (bytes 4096 to 8191)"));
+		let first = engine.collections.items[&ids[0]].clone();
+		let read = engine.read_data(Area::Collections, &first.file, first.index, first.data_length);
+		assert!(read.is_ok());
+		let report = verify(dir.path());
+		assert_eq!(report.errors(), 1);
+		let message = &report.issues[0].message;
+		assert!(message.starts_with("Block 1 (bytes 4096 to 8191) failed its checksum"));
+
+		// A data file without checksums only gets a warning
+		fs::remove_file(faccess::checksum_path(dir.path(), &path)).unwrap();
+		let report = verify(dir.path());
+		assert_eq!((report.errors(), report.warnings()), (0, 1));
+	}
+
+	#[test]
+	fn repairs_wait_for_the_lock() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut engine = testing::create_store(dir.path(), json!({}));
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "notes" })).unwrap();
+		testing::insert(&mut engine, "notes", json!({ "n": 1 }));
+		let command = clap::Command::new("check")
+			.arg(clap::Arg::new("directory"))
+			.arg(clap::Arg::new("json").long("json").action(clap::ArgAction::SetTrue))
+			.arg(clap::Arg::new("repair").long("repair").action(clap::ArgAction::SetTrue))
+		;
+		let args = ["check", dir.path().to_str().unwrap(), "--repair"];
+
+		let lock = lite::lock(dir.path()).unwrap();
+		fs::write(dir.path().join("collections/rixindex"), b"").unwrap();
+		assert_eq!(main(&command.clone().get_matches_from(args)), std::process::ExitCode::FAILURE);
+		assert_eq!(fs::metadata(dir.path().join("collections/rixindex")).unwrap().len(), 0);
+		drop(lock);
+		assert_eq!(main(&command.get_matches_from(args)), std::process::ExitCode::SUCCESS);
+		assert_eq!(testing::open_store(dir.path()).collections.items.len(), 1);
+	}
+
+	#[test]
+	fn overlaps_and_holes() {
+		let dir = tempfile::tempdir().unwrap();
+		fs::create_dir_all(dir.path().join("collections")).unwrap();
+		fs::write(dir.path().join("collections/data"), vec![0u8; 100]).unwrap();
+		let files = HashMap::from([("data".to_owned(), FileMeta {
+			size: 100,
+			holes: HashMap::from([(35, 5), (10, 20), (90, 20)]),
+			framed: false
+		})]);
+		let used = [("a", 0, 30), ("b", 20, 5), ("c", 25, 10)].map(|(id, index, length)| Used {
+			owner: format!("Item {}", id),
+			id: id.to_owned(),
+			file: "data".to_owned(),
+			index,
+			length,
+			data_type: None
+		});
+		let mut report = Report::default();
+		check_area(dir.path(), Area::Collections, &files, &used, false, &mut report);
+		assert_eq!(messages(&report, Severity::Error), [
+			"collections/data: Item a and Item b overlap.",
+			"collections/data: Item a and Item c overlap.",
+			"collections/data: The hole at 10 (20 bytes) overlaps Item c.",
+			"collections/data: The hole at 90 (20 bytes) goes past the end of the file (100 bytes)."
+		]);
+	}
+}
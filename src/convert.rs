@@ -1,6 +1,389 @@
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+use std::collections::HashMap;
+
 use clap::ArgMatches;
 
+use crate::cli;
+use crate::copy;
+use crate::lite;
+use crate::check;
+use crate::restore;
+use crate::serve;
+use crate::archive::{ self, ARCHIVE_FILE };
+use crate::faccess;
+use crate::basics::{ self, Store, StoreType };
+
+// Entries of a store's directory which hold its data. Anything else, such as
+// the manifest, the logs or the backups kept within the store, is left alone.
+const DATA_ENTRIES: [&str; 9] = [
+	"singletons", "collections", "edges", "keyvalues", "timeseries",
+	"blobs", "checksums", "chain", ARCHIVE_FILE
+];
+
+// Where the new content of a store is prepared
+fn staging_dir(store_dir: &Path) -> PathBuf {
+	return store_dir.join("tmp/convert");
+}
+
+// Data entries of a directory. A directory holding a manifest is a store of its
+// own, such as a backup, and never counts as data.
+fn entries(dir: &Path) -> io::Result<Vec<String>> {
+	let mut names = Vec::<String>::new();
+	for entry in fs::read_dir(dir)? {
+		let name = entry?.file_name().to_string_lossy().into_owned();
+		if !DATA_ENTRIES.contains(&name.as_str()) { continue; }
+		if dir.join(&name).join("manifest.json").exists() { continue; }
+		names.push(name);
+	}
+	return Ok(names);
+}
+
+// Replaces the content of a store by the one prepared in its staging directory.
+// The current entries listed in `removed`, and the ones the staging directory
+// replaces, are moved aside first. The manifest is written last, then the old
+// content is deleted.
+pub fn swap_in(
+	store_dir: &Path, staged: Option<&Path>, removed: &[String], store: &Store
+) -> io::Result<()> {
+	let aside = store_dir.join("tmp/replaced");
+	if aside.exists() { fs::remove_dir_all(&aside)?; }
+	fs::create_dir_all(&aside)?;
+	let incoming = match staged {
+		Some(staged) => entries(staged)?,
+		None => Vec::new()
+	};
+	for name in removed.iter().chain(&incoming) {
+		let path = store_dir.join(name);
+		if path.exists() { fs::rename(&path, aside.join(name))?; }
+	}
+	if let Some(staged) = staged {
+		for name in &incoming {
+			fs::rename(staged.join(name), store_dir.join(name))?;
+		}
+	}
+
+	let text = serde_json::to_string_pretty(store).map_err(io::Error::other)?;
+	faccess::write_atomic(store_dir, &store_dir.join("manifest.json"), text.as_bytes())?;
+	fs::remove_dir_all(&aside)?;
+	if let Some(staged) = staged { fs::remove_dir_all(staged)?; }
+	return Ok(());
+}
+
+// Data directories of a store, as opposed to its metadata and its backups
+pub fn data_entries(store_dir: &Path) -> io::Result<Vec<String>> {
+	return entries(store_dir);
+}
+
+// Archive -> Live: the archive file is verified, then extracted
+fn expand(store_dir: &Path, store: &mut Store) -> Result<(), String> {
+	let path = store_dir.join(ARCHIVE_FILE);
+	archive::read_archive(&path, true)
+		.map_err(|e| format!("Invalid archive {:?}: {}", path, e))?
+	;
+	let staged = staging_dir(store_dir);
+	store.kind = StoreType::Live;
+	let expanded = fs::create_dir_all(&staged)
+		.and_then(|_| restore::extract_archive(&path, &staged))
+		.and_then(|_| restore::create_dirs(&staged))
+		.and_then(|_| swap_in(store_dir, Some(&staged), &[ARCHIVE_FILE.to_owned()], store))
+	;
+	return expanded.map_err(|e| format!("Failed to expand the archive: {}", e));
+}
+
+// Backup -> Live: the incremental backups of the chain are replayed
+fn replay_chain(store_dir: &Path, store: &mut Store) -> Result<(), String> {
+	let last = restore::check_chain(store_dir, None)?;
+	let staged = staging_dir(store_dir);
+	store.kind = StoreType::Live;
+	let replayed = fs::create_dir_all(&staged)
+		.and_then(|_| restore::copy_backup(store_dir, &staged, last))
+		.and_then(|_| restore::create_dirs(&staged))
+		.and_then(|_| data_entries(store_dir))
+		.and_then(|removed| swap_in(store_dir, Some(&staged), &removed, store))
+	;
+	return replayed.map_err(|e| format!("Failed to replay the backup chain: {}", e));
+}
+
+// Live -> Archive: the entries are rewritten without the free space of the data
+// files, then compressed in a single archive file which replaces them
+fn compact(store_dir: &Path, store: &mut Store) -> Result<(), String> {
+	let staged = staging_dir(store_dir);
+	let compacted = staged.join("store");
+	let source = serve::open_engine(
+		basics::get_conf(), store_dir.to_path_buf(), basics::load_store(store_dir)?, false
+	)?;
+	let mut copied_store = basics::load_store(store_dir)?;
+	copied_store.backups = None;
+	copied_store.mutation_logs = None;
+	fs::create_dir_all(&compacted).map_err(|e| e.to_string())?;
+	copy::prepare_target(&compacted, &copied_store)?;
+	let mut target = serve::open_engine(
+		basics::get_conf(), compacted.clone(), copied_store, false
+	)?;
+	copy::copy_store(&source, &mut target, None, false, false)?;
+
+	store.kind = StoreType::Archive;
+	store.backups = None;
+	store.mutation_logs = None;
+	let output = staged.join("archive");
+	let archived = archive::store_files(&compacted)
+		.and_then(|files| {
+			fs::create_dir_all(&output)?;
+			archive::write_archive(&compacted, store, &files, &output.join(ARCHIVE_FILE))
+		})
+		.and_then(|_| fs::remove_dir_all(&compacted))
+		.and_then(|_| data_entries(store_dir))
+		.and_then(|removed| swap_in(store_dir, Some(&output), &removed, store))
+		.and_then(|_| fs::remove_dir_all(&staged))
+	;
+	return archived.map_err(|e| format!("Failed to archive the store: {}", e));
+}
+
+// Live -> Lite: the checksums and the ordering are dropped,
+// along with the backups and the mutation logs
+fn strip(store_dir: &Path, store: &mut Store) -> Result<(), String> {
+	store.kind = StoreType::Lite;
+	store.ordering = false;
+	store.checksumming = false;
+	store.backups = None;
+	store.mutation_logs = None;
+	let stripped = swap_in(store_dir, None, &["checksums".to_owned()], store)
+		.and_then(|_| fs::create_dir_all(store_dir.join("checksums")))
+	;
+	return stripped.map_err(|e| format!("Failed to convert the store: {}", e));
+}
+
+// Converts a store to another type, going through the Live type
+fn convert(store_dir: &Path, mut store: Store, kind: StoreType) -> Result<(), String> {
+	match store.kind {
+		StoreType::Archive => expand(store_dir, &mut store)?,
+		StoreType::Backup => replay_chain(store_dir, &mut store)?,
+		StoreType::Live | StoreType::Lite => {}
+	}
+	match kind {
+		StoreType::Archive => compact(store_dir, &mut store)?,
+		StoreType::Lite => strip(store_dir, &mut store)?,
+		StoreType::Live | StoreType::Backup if store.kind != kind => {
+			store.kind = kind;
+			// The backups of a backup store would be made by nobody
+			if kind == StoreType::Backup { store.backups = None; }
+			swap_in(store_dir, None, &[], &store)
+				.map_err(|e| format!("Failed to convert the store: {}", e))?
+			;
+		},
+		_ => {}
+	}
+	return Ok(());
+}
+
 pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
-	println!("{:#?}", matches);
+
+	// --> Checking the command line arguments
+	// ---------------------------------------
+
+	let kinds = HashMap::from([
+		("live", StoreType::Live),
+		("lite", StoreType::Lite),
+		("backup", StoreType::Backup),
+		("archive", StoreType::Archive)
+	]);
+	let kind = match kinds.get(matches.get_one::<String>("type").unwrap().as_str()) {
+		Some(kind) => *kind,
+		None => {
+			cli::red_err(
+				"The store type must have one of the ".to_owned()
+				+ "authorized values.\n(Try: `orixdb help convert` to know more...)"
+			);
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+
+	let store_dir: PathBuf;
+	if matches.contains_id("directory") {
+		store_dir = PathBuf::from(matches.get_one::<String>("directory").unwrap());
+	}
+	else { store_dir = PathBuf::from(".").canonicalize().unwrap(); }
+	let store = match basics::load_store(&store_dir) {
+		Ok(store) => store,
+		Err(message) => {
+			cli::red_err(message);
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+	if store.kind == kind {
+		cli::yellow_err(format!("The store is already of the {:?} type.", kind));
+		return std::process::ExitCode::SUCCESS;
+	}
+	// The store must not be served, nor embedded, while its files are replaced
+	let _lock = match lite::lock(&store_dir) {
+		Ok(file) => file,
+		Err(message) => {
+			cli::red_err(message);
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+	if staging_dir(&store_dir).exists() || store_dir.join("tmp/replaced").exists() {
+		cli::red_err(
+			"A previous conversion of the store was interrupted.\n".to_owned()
+			+ "Please check the store, then remove `tmp/convert` and `tmp/replaced`."
+		);
+		return std::process::ExitCode::FAILURE;
+	}
+
+
+	// --> Checking the store before converting it
+	// -------------------------------------------
+
+	let report = check::verify(&store_dir);
+	if report.errors() > 0 {
+		for issue in report.issues.iter().filter(|i| i.severity == check::Severity::Error) {
+			cli::red_err(format!("[{}] {}: {}", issue.section, issue.path, issue.message));
+		}
+		cli::red_err("The store is corrupted, it was not converted.".to_owned());
+		return std::process::ExitCode::FAILURE;
+	}
+
+
+	// --> Converting the store
+	// ------------------------
+
+	let from = store.kind;
+	if let Err(message) = convert(&store_dir, store, kind) {
+		// Once the content started being swapped, it is left for inspection
+		if !store_dir.join("tmp/replaced").exists() {
+			let _ = fs::remove_dir_all(staging_dir(&store_dir));
+		}
+		cli::red_err(message);
+		return std::process::ExitCode::FAILURE;
+	}
+	let report = check::verify(&store_dir);
+	if report.errors() > 0 {
+		cli::red_err(format!(
+			"The converted store has {} errors. Run `orixdb check` on it for details.",
+			report.errors()
+		));
+		return std::process::ExitCode::FAILURE;
+	}
+	cli::green_out(format!("Store converted from the {:?} to the {:?} type.", from, kind));
 	return std::process::ExitCode::SUCCESS;
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::{ json, Value };
+	use crate::serve::{ query, testing };
+
+	// A Live store with a few entries, some of them overwritten
+	fn populated(store_dir: &Path) -> Store {
+		let mut engine = testing::create_store(store_dir, json!({}));
+		for i in 0..10 {
+			let args = json!({ "key": format!("key{}", i), "value": "x".repeat(i * 100) });
+			testing::mutate(&mut engine, "setKey", args).unwrap();
+		}
+		testing::mutate(&mut engine, "setKey", json!({ "key": "key3", "value": 3 })).unwrap();
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "notes" })).unwrap();
+		testing::insert(&mut engine, "notes", json!({ "text": "hello" }));
+		return engine.store;
+	}
+
+	fn values(store_dir: &Path) -> Vec<Value> {
+		let engine = testing::open_store(store_dir);
+		let now = basics::now_millis();
+		let mut values = (0..10)
+			.map(|i| query::keyvalue(&engine, &format!("key{}", i), now).unwrap())
+			.collect::<Vec<Value>>()
+		;
+		for id in engine.collections.items.keys() {
+			values.push(query::item_data(&engine, id).unwrap());
+		}
+		return values;
+	}
+
+	#[test]
+	fn archive_round_trip() {
+		let dir = tempfile::tempdir().unwrap();
+		let store = populated(dir.path());
+		let expected = values(dir.path());
+		convert(dir.path(), store, StoreType::Archive).unwrap();
+		let store = basics::load_store(dir.path()).unwrap();
+		assert_eq!(store.kind, StoreType::Archive);
+		let mut kept = fs::read_dir(dir.path()).unwrap()
+			.map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+			.collect::<Vec<String>>()
+		;
+		kept.sort();
+		assert_eq!(kept, ["manifest.json", ARCHIVE_FILE, "tmp"]);
+		assert_eq!(check::verify(dir.path()).errors(), 0);
+
+		convert(dir.path(), store, StoreType::Live).unwrap();
+		assert_eq!(basics::load_store(dir.path()).unwrap().kind, StoreType::Live);
+		assert!(!dir.path().join(ARCHIVE_FILE).exists());
+		assert_eq!(check::verify(dir.path()).errors(), 0);
+		assert_eq!(values(dir.path()), expected);
+	}
+
+	#[test]
+	fn backup_round_trip() {
+		let dir = tempfile::tempdir().unwrap();
+		let store = populated(dir.path());
+		let expected = values(dir.path());
+		convert(dir.path(), store, StoreType::Backup).unwrap();
+		let store = basics::load_store(dir.path()).unwrap();
+		assert_eq!(store.kind, StoreType::Backup);
+		assert!(store.backups.is_none());
+		convert(dir.path(), store, StoreType::Live).unwrap();
+		assert_eq!(check::verify(dir.path()).errors(), 0);
+		assert_eq!(values(dir.path()), expected);
+	}
+
+	#[test]
+	fn lite_conversion() {
+		let dir = tempfile::tempdir().unwrap();
+		let store = populated(dir.path());
+		let expected = values(dir.path());
+		assert!(fs::read_dir(dir.path().join("checksums")).unwrap().next().is_some());
+		convert(dir.path(), store, StoreType::Lite).unwrap();
+		let store = basics::load_store(dir.path()).unwrap();
+		assert_eq!(store.kind, StoreType::Lite);
+		assert!(!store.checksumming && !store.ordering);
+		assert!(fs::read_dir(dir.path().join("checksums")).unwrap().next().is_none());
+		assert_eq!(check::verify(dir.path()).errors(), 0);
+		assert_eq!(values(dir.path()), expected);
+	}
+
+	#[test]
+	fn kept_backups() {
+		let dir = tempfile::tempdir().unwrap();
+		let store = populated(dir.path());
+		let backup = dir.path().join("backups").join(format!("{}-1700000000000", store.id));
+		fs::create_dir_all(backup.join("collections")).unwrap();
+		fs::write(backup.join("manifest.json"), "{}").unwrap();
+
+		convert(dir.path(), store, StoreType::Archive).unwrap();
+		let store = basics::load_store(dir.path()).unwrap();
+		convert(dir.path(), store, StoreType::Live).unwrap();
+		assert!(backup.join("manifest.json").exists());
+		assert!(backup.join("collections").is_dir());
+	}
+
+	#[test]
+	fn conversions_wait_for_the_lock() {
+		let dir = tempfile::tempdir().unwrap();
+		populated(dir.path());
+		let command = clap::Command::new("convert")
+			.arg(clap::Arg::new("type"))
+			.arg(clap::Arg::new("directory"))
+		;
+		let args = ["convert", "archive", dir.path().to_str().unwrap()];
+
+		let lock = lite::lock(dir.path()).unwrap();
+		assert_eq!(main(&command.clone().get_matches_from(args)), std::process::ExitCode::FAILURE);
+		assert_eq!(basics::load_store(dir.path()).unwrap().kind, StoreType::Live);
+		drop(lock);
+		assert_eq!(main(&command.get_matches_from(args)), std::process::ExitCode::SUCCESS);
+		assert_eq!(basics::load_store(dir.path()).unwrap().kind, StoreType::Archive);
+	}
+}
@@ -1,6 +1,440 @@
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::collections::{ HashMap, HashSet };
+
 use clap::ArgMatches;
 
+use crate::cli;
+use crate::check;
+use crate::restore;
+use crate::faccess::{
+	self, Area, Chunk, RecordTag, BlobMeta, SingletonMeta, CollectionMeta, EdgeMeta,
+	KeyValueMeta
+};
+use crate::basics::{ self, DataType, StoreType };
+use crate::serve::{ self, Engine };
+
+// Number of entries of each kind copied
+#[derive(Debug)]
+#[derive(Default)]
+pub struct Copied {
+	singletons: usize,
+	collections: usize,
+	items: usize,
+	edges: usize,
+	keys: usize,
+	blobs: usize,
+	series: usize
+}
+
+// Sorts entries by location, so that the copy keeps their order in the data files
+fn by_location<T>(
+	entries: &HashMap<String, T>, location: impl Fn(&T) -> (&str, u64)
+) -> Vec<(&String, &T)> {
+	let mut sorted = entries.iter().collect::<Vec<(&String, &T)>>();
+	sorted.sort_by(|a, b| location(a.1).cmp(&location(b.1)));
+	return sorted;
+}
+
+// Sorts entries by location, or by the time they were written when `ordered`
+// is set. Records without a header (legacy ones) come first.
+fn in_order<'a, T>(
+	source: &Engine, area: Area, entries: &'a HashMap<String, T>,
+	location: impl Fn(&T) -> (&str, u64), ordered: bool
+) -> Vec<(&'a String, &'a T)> {
+	let mut sorted = by_location(entries, &location);
+	if ordered {
+		sorted.sort_by_cached_key(|(_, meta)| {
+			let (file, index) = location(meta);
+			faccess::read_header(&source.data_path(area, file), index).ok().flatten()
+				.map(|(_, header)| header.stamp).unwrap_or(0)
+		});
+	}
+	return sorted;
+}
+
+// A new id for an entry, or its own one when the ids are kept
+fn id_for<T>(id: &str, taken: &HashMap<String, T>, fresh: bool) -> String {
+	return if fresh { faccess::new_id_in(taken) } else { id.to_owned() };
+}
+
+fn copy_singletons(
+	source: &Engine, target: &mut Engine, fresh: bool, ordered: bool
+) -> Result<usize, String> {
+	let singletons = in_order(
+		source, Area::Singletons, &source.singletons.singletons, |m| (&m.file, m.index), ordered
+	);
+	for (id, meta) in &singletons {
+		let data = source.read_data(Area::Singletons, &meta.file, meta.index, meta.data_length)?;
+		let new_id = id_for(id, &target.singletons.singletons, fresh);
+		let (file, index) = target.write_data(Area::Singletons, RecordTag {
+			id: new_id.clone(), owner: meta.name.clone(), data_type: meta.data_type
+		}, &data).map_err(|e| format!("Failed to copy the singleton {}: {}", id, e))?;
+		target.singletons.singletons.insert(new_id, SingletonMeta {
+			file, index, ..(*meta).clone()
+		});
+	}
+	return Ok(singletons.len());
+}
+
+// Copies the given collections, with their items and the edges between them.
+// Returns the numbers of collections, items and edges copied.
+fn copy_collections(
+	source: &Engine, target: &mut Engine, names: Option<&HashSet<String>>, fresh: bool,
+	ordered: bool
+) -> Result<(usize, usize, usize), String> {
+	let mut collections = HashMap::<&str, String>::new();
+	for (id, name) in &source.collections.list {
+		if names.is_some_and(|names| !names.contains(name)) { continue; }
+		let new_id = id_for(id, &target.collections.list, fresh);
+		target.collections.list.insert(new_id.clone(), name.clone());
+		if let Some(compression) = source.collections.compression.get(id) {
+			target.collections.compression.insert(new_id.clone(), *compression);
+		}
+		collections.insert(id, new_id);
+	}
+
+	let mut items = HashMap::<&str, String>::new();
+	let sorted = in_order(
+		source, Area::Collections, &source.collections.items, |m| (&m.file, m.index), ordered
+	);
+	for (id, meta) in sorted {
+		let collection = match collections.get(meta.collection.as_str()) {
+			Some(collection) => collection.clone(),
+			None => continue
+		};
+		let data = source.read_data(Area::Collections, &meta.file, meta.index, meta.data_length)?;
+		let new_id = id_for(id, &target.collections.items, fresh);
+		let (file, index) = target.write_data(Area::Collections, RecordTag {
+			id: new_id.clone(), owner: collection.clone(), data_type: meta.data_type
+		}, &data).map_err(|e| format!("Failed to copy the item {}: {}", id, e))?;
+		target.collections.items.insert(new_id.clone(), CollectionMeta {
+			collection, data_type: meta.data_type, file, index, data_length: meta.data_length
+		});
+		items.insert(id, new_id);
+	}
+
+	// Edges leading out of the copied items are dropped
+	let mut edges = 0;
+	let sorted = in_order(
+		source, Area::Edges, &source.edges.edges, |m| (&m.file, m.index), ordered
+	);
+	for (id, meta) in sorted {
+		let (from, to) = match (items.get(meta.from.as_str()), items.get(meta.to.as_str())) {
+			(Some(from), Some(to)) => (from.clone(), to.clone()),
+			_ => continue
+		};
+		let data = source.read_data(Area::Edges, &meta.file, meta.index, meta.data_length)?;
+		let new_id = id_for(id, &target.edges.edges, fresh);
+		let (file, index) = target.write_data(Area::Edges, RecordTag {
+			id: new_id.clone(), owner: meta.label.clone(), data_type: meta.data_type
+		}, &data).map_err(|e| format!("Failed to copy the edge {}: {}", id, e))?;
+		target.edges.edges.insert(new_id.clone(), EdgeMeta {
+			from, to, file, index, ..meta.clone()
+		});
+		target.edges.link(&new_id);
+		edges += 1;
+	}
+	return Ok((collections.len(), items.len(), edges));
+}
+
+// Keys are names rather than ids, and are kept. Expired keys are left out.
+fn copy_keyvalues(source: &Engine, target: &mut Engine, ordered: bool) -> Result<usize, String> {
+	let now = basics::now_millis();
+	let mut copied = 0;
+	let sorted = in_order(
+		source, Area::KeyValues, &source.keyvalues.entries, |m| (&m.file, m.index), ordered
+	);
+	for (key, meta) in sorted {
+		if source.keyvalues.live(key, now).is_none() { continue; }
+		let data = source.read_data(Area::KeyValues, &meta.file, meta.index, meta.data_length)?;
+		let (file, index) = target.write_data(Area::KeyValues, RecordTag {
+			id: key.clone(), owner: String::new(), data_type: meta.data_type
+		}, &data).map_err(|e| format!("Failed to copy the key {}: {}", key, e))?;
+		target.keyvalues.entries.insert(key.clone(), KeyValueMeta {
+			file, index, ..meta.clone()
+		});
+		copied += 1;
+	}
+	return Ok(copied);
+}
+
+// Blobs keep their ids, as items refer to them in their data
+fn copy_blobs(source: &Engine, target: &mut Engine) -> Result<usize, String> {
+	let mut ids = source.blobs.blobs.keys().collect::<Vec<&String>>();
+	ids.sort();
+	for id in &ids {
+		let meta = &source.blobs.blobs[*id];
+		let mut chunks = Vec::<Chunk>::new();
+		for (number, chunk) in meta.chunks.iter().enumerate() {
+			let data = source.read_data(Area::Blobs, &chunk.file, chunk.index, chunk.length)?;
+			let (file, index) = target.write_data(Area::Blobs, RecordTag {
+				id: (*id).clone(), owner: number.to_string(), data_type: DataType::Null.to_u8()
+			}, &data).map_err(|e| format!("Failed to copy the blob {}: {}", id, e))?;
+			chunks.push(Chunk { file, index, length: chunk.length });
+		}
+		target.blobs.blobs.insert((*id).clone(), BlobMeta {
+			content_type: meta.content_type.clone(), size: meta.size, chunks
+		});
+	}
+	return Ok(ids.len());
+}
+
+// The windows and rollups of each series are copied in the directory of its new id
+fn copy_series(source: &Engine, target: &mut Engine, fresh: bool) -> Result<usize, String> {
+	for (id, meta) in &source.timeseries.series {
+		let new_id = id_for(id, &target.timeseries.series, fresh);
+		let mut files = meta.windows.keys()
+			.map(|start| (source.window_path(id, *start), target.window_path(&new_id, *start)))
+			.collect::<Vec<(PathBuf, PathBuf)>>()
+		;
+		files.extend(meta.rollups.iter().map(|rollup| (
+			source.rollup_path(id, rollup.interval), target.rollup_path(&new_id, rollup.interval)
+		)));
+		for (from, to) in files {
+			if !from.exists() { continue; }
+			source.verify_file(&from)?;
+			let copied = fs::create_dir_all(to.parent().unwrap())
+				.and_then(|_| fs::copy(&from, &to))
+				.and_then(|_| match target.store.checksumming {
+					true => faccess::rebuild_checksums(&target.store_dir, &to),
+					false => Ok(())
+				})
+			;
+			copied.map_err(|e| format!("Failed to copy {:?}: {}", from, e))?;
+		}
+		target.timeseries.series.insert(new_id, meta.clone());
+	}
+	return Ok(source.timeseries.series.len());
+}
+
+// Writes the entries of a store into the empty one of `target`, without the
+// free space of the data files. The entries get new ids when `fresh` is set,
+// and are written in the order of their last write when `ordered` is.
+pub fn copy_store(
+	source: &Engine, target: &mut Engine, names: Option<&HashSet<String>>, fresh: bool,
+	ordered: bool
+) -> Result<Copied, String> {
+	let mut copied = Copied {
+		singletons: copy_singletons(source, target, fresh, ordered)?,
+		..Copied::default()
+	};
+	(copied.collections, copied.items, copied.edges) =
+		copy_collections(source, target, names, fresh, ordered)?
+	;
+	copied.keys = copy_keyvalues(source, target, ordered)?;
+	copied.blobs = copy_blobs(source, target)?;
+	copied.series = copy_series(source, target, fresh)?;
+	for area in [
+		Area::Singletons, Area::Collections, Area::Edges, Area::KeyValues, Area::Blobs
+	] {
+		target.save_index(area)?;
+	}
+	target.save_series()?;
+	target.rebuild_geoindex()?;
+	return Ok(copied);
+}
+
+// Writes the manifest and the directories of the empty copy
+pub fn prepare_target(target_dir: &Path, store: &basics::Store) -> Result<(), String> {
+	let text = serde_json::to_string_pretty(store).map_err(|e| e.to_string())?;
+	let prepared = fs::write(target_dir.join("manifest.json"), text).and_then(|_| {
+		for dir in restore::STORE_DIRS {
+			fs::create_dir_all(target_dir.join(dir))?;
+		}
+		for dir in ["singletons", "collections", "edges", "keyvalues", "timeseries", "blobs"] {
+			fs::File::create(target_dir.join(dir).join("rixindex"))?;
+		}
+		Ok(())
+	});
+	return prepared.map_err(|e| format!("Failed to prepare the copy: {}", e));
+}
+
 pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
-	println!("{:#?}", matches);
+
+	// --> Checking the command line arguments
+	// ---------------------------------------
+
+	let source_dir: PathBuf;
+	if matches.contains_id("directory") {
+		source_dir = PathBuf::from(matches.get_one::<String>("directory").unwrap());
+	}
+	else { source_dir = PathBuf::from(".").canonicalize().unwrap(); }
+	let source_store = match basics::load_store(&source_dir) {
+		Ok(store) => store,
+		Err(message) => {
+			cli::red_err(message);
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+
+	let target_dir = PathBuf::from(matches.get_one::<String>("output").unwrap());
+	if target_dir.is_file() {
+		cli::red_err(
+			"The copy path resolves to a file.\n".to_owned()
+			+ "A store can't be copied in a file but in a directory."
+		);
+		return std::process::ExitCode::FAILURE;
+	}
+	let created = !target_dir.exists();
+	if !created && target_dir.read_dir().map(|mut d| d.next().is_some()).unwrap_or(true) {
+		cli::red_err(
+			"The copy directory is not empty.\n".to_owned()
+			+ "Then a store can't be copied there."
+		);
+		return std::process::ExitCode::FAILURE;
+	}
+
+	let mut store = match basics::load_store(&source_dir) {
+		Ok(store) => store,
+		Err(message) => {
+			cli::red_err(message);
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+	store.name = matches.get_one::<String>("name").cloned()
+		.unwrap_or(format!("{} (copy)", source_store.name))
+	;
+	store.id = matches.get_one::<String>("id").cloned()
+		.unwrap_or(slug::slugify(&store.name))
+	;
+	if store.id == source_store.id || !store.id.chars().all(
+		|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_".contains(c)
+	) {
+		cli::red_err(
+			"The id of the copy must differ from the one of the store, and contain\n".to_owned()
+			+ "only lowercase alphanumeric characters, dashes and underscores."
+		);
+		return std::process::ExitCode::FAILURE;
+	}
+	store.hash = faccess::new_id();
+	if store.kind == StoreType::Backup || store.kind == StoreType::Archive {
+		store.kind = StoreType::Live;
+	}
+	// The backups of the copy must not go with the ones of the store
+	store.backups = None;
+
+	let names = matches.get_one::<String>("collections").map(|names| {
+		names.split(',').map(|n| n.trim().to_owned()).filter(|n| !n.is_empty())
+			.collect::<HashSet<String>>()
+	});
+
+
+	// --> Copying the entries with new ids
+	// ------------------------------------
+
+	let source = match serve::open_engine(
+		basics::get_conf(), source_dir.clone(), source_store, false
+	) {
+		Ok(engine) => engine,
+		Err(message) => {
+			cli::red_err(message);
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+	if let Some(names) = &names {
+		let unknown = names.iter().filter(|n| source.collections.find(n).is_none())
+			.cloned().collect::<Vec<String>>()
+		;
+		if !unknown.is_empty() {
+			cli::red_err(format!("Unknown collections: {}", unknown.join(", ")));
+			return std::process::ExitCode::FAILURE;
+		}
+	}
+
+	let name = store.name.clone();
+	let copied = fs::create_dir_all(&target_dir)
+		.map_err(|e| e.to_string())
+		.and_then(|_| prepare_target(&target_dir, &store))
+		.and_then(|_| serve::open_engine(basics::get_conf(), target_dir.clone(), store, false))
+		.and_then(|mut target| copy_store(&source, &mut target, names.as_ref(), true, false))
+	;
+	let copied = match copied {
+		Ok(copied) => copied,
+		Err(message) => {
+			restore::clean_target(&target_dir, created);
+			cli::red_err(format!("Failed to copy the store: {}\nNothing was kept.", message));
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+
+	let report = check::verify(&target_dir);
+	if report.errors() > 0 {
+		cli::red_err(format!(
+			"The copy has {} errors. Run `orixdb check` on it for details.",
+			report.errors()
+		));
+		return std::process::ExitCode::FAILURE;
+	}
+	cli::green_out(format!(
+		"Store \"{}\" copied in {:?}: {} singletons, {} collections, {} items, {} edges, \
+		{} keys, {} blobs and {} time series.",
+		name, target_dir, copied.singletons, copied.collections, copied.items,
+		copied.edges, copied.keys, copied.blobs, copied.series
+	));
 	return std::process::ExitCode::SUCCESS;
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::{ json, Value };
+	use crate::serve::{ query, testing };
+
+	#[test]
+	fn copies_get_fresh_ids() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut source = testing::create_store(&dir.path().join("source"), json!({}));
+		testing::mutate(&mut source, "setSingleton", json!({ "name": "config", "value": 1 })).unwrap();
+		testing::mutate(&mut source, "setKey", json!({ "key": "count", "value": 3 })).unwrap();
+		for name in ["people", "places"] {
+			testing::mutate(&mut source, "createCollection", json!({ "name": name })).unwrap();
+		}
+		let people = (0..3)
+			.map(|n| testing::insert(&mut source, "people", json!({ "n": n })))
+			.collect::<Vec<String>>()
+		;
+		let place = testing::insert(&mut source, "places", json!({ "city": "Lyon" }));
+		for (from, to) in [(0, &people[1]), (1, &people[2]), (2, &place)] {
+			let args = json!({ "from": people[from], "to": to, "label": "knows" });
+			testing::mutate(&mut source, "createEdge", args).unwrap();
+		}
+
+		// Only the people, and the edges between them
+		let target_dir = dir.path().join("target");
+		let mut store = testing::open_store(&dir.path().join("source")).store;
+		store.id = "target".to_owned();
+		fs::create_dir_all(&target_dir).unwrap();
+		prepare_target(&target_dir, &store).unwrap();
+		let mut target = testing::open_store(&target_dir);
+		let names = HashSet::from(["people".to_owned()]);
+		let copied = copy_store(&source, &mut target, Some(&names), true, false).unwrap();
+		assert_eq!((copied.singletons, copied.collections, copied.items), (1, 1, 3));
+		assert_eq!((copied.edges, copied.keys), (2, 1));
+		drop(target);
+
+		let target = testing::open_store(&target_dir);
+		assert_eq!(check::verify(&target_dir).errors(), 0);
+		let fresh = |ids: Vec<&String>, taken: Vec<&String>| ids.iter().all(|id| !taken.contains(id));
+		assert!(fresh(
+			target.singletons.singletons.keys().collect(), source.singletons.singletons.keys().collect()
+		));
+		assert!(fresh(
+			target.collections.list.keys().collect(), source.collections.list.keys().collect()
+		));
+		assert!(fresh(
+			target.collections.items.keys().collect(), source.collections.items.keys().collect()
+		));
+		assert_eq!(query::singleton_value(&target, "config").unwrap(), json!(1));
+		assert_eq!(query::keyvalue(&target, "count", basics::now_millis()).unwrap(), json!(3));
+
+		// The edges link the copies of the items they linked
+		let n = |engine: &Engine, id: &str| query::item_data(engine, id).unwrap()["n"].clone();
+		let mut edges = target.edges.edges.values()
+			.map(|edge| (n(&target, &edge.from), n(&target, &edge.to)))
+			.collect::<Vec<(Value, Value)>>()
+		;
+		edges.sort_by_key(|edge| edge.0.as_u64());
+		assert_eq!(edges, [(json!(0), json!(1)), (json!(1), json!(2))]);
+	}
+}
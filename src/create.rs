@@ -6,7 +6,7 @@ use clap::ArgMatches;
 use crate::cli;
 use crate::basics::{ self, Instance, LogLevel, StoreType, Store };
 
-fn check_id(id: &String) -> bool {
+fn check_id(id: &str) -> bool {
 	if !id.chars().all(
 		|c: char| {
 			(c.is_ascii_alphabetic() && c.is_lowercase())
@@ -64,8 +64,13 @@ pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
 			api_port: 7900,
 			api_scan: false,
 			cluster_port: 7979,
-			cluster_scan: false
-		}
+			cluster_scan: false,
+			mapped_reads: false,
+			cache_size: 0
+		},
+		backups: None,
+		mutation_logs: None,
+		encryption: None
 	};
 
 	println!("\
@@ -187,7 +192,7 @@ pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
 	}
 	else {
 		store.name = inquire::Text::new("Store name: ")
-			.with_default(&*store_dir).prompt().unwrap()
+			.with_default(&store_dir).prompt().unwrap()
 		;
 	}
 
@@ -196,7 +201,7 @@ pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
 	}
 	else {
 		store.id = inquire::Text::new("Store id: ")
-			.with_default(&*slug::slugify(store.name.clone()))
+			.with_default(&slug::slugify(store.name.clone()))
 			.prompt().unwrap()
 		;
 		if !check_id(&store.id) { return std::process::ExitCode::FAILURE }
@@ -214,24 +219,33 @@ pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
 		store.kind = store_type_options[store_type];
 	}
 
-	if *matches.get_one::<bool>("ordering").unwrap() {
-		store.ordering = true;
-		println!("✔ Automatic data ordering: Yes");
+	// Lite stores trade the integrity features for speed
+	if store.kind == StoreType::Lite {
+		store.ordering = false;
+		store.checksumming = false;
+		println!("✔ Automatic data ordering: No (Lite store)");
+		println!("✔ Data checksumming: No (Lite store)");
 	}
 	else {
-		store.ordering = inquire::Confirm::new("Automatic data ordering ?")
-			.with_default(false).prompt().unwrap()
-		;
-	}
+		if *matches.get_one::<bool>("ordering").unwrap() {
+			store.ordering = true;
+			println!("✔ Automatic data ordering: Yes");
+		}
+		else {
+			store.ordering = inquire::Confirm::new("Automatic data ordering ?")
+				.with_default(false).prompt().unwrap()
+			;
+		}
 
-	if *matches.get_one::<bool>("checksumming").unwrap() {
-		store.checksumming = true;
-		println!("✔ Data checksumming: Yes");
-	}
-	else {
-		store.checksumming = inquire::Confirm::new("Data checksumming ?")
-			.with_default(true).prompt().unwrap()
-		;
+		if *matches.get_one::<bool>("checksumming").unwrap() {
+			store.checksumming = true;
+			println!("✔ Data checksumming: Yes");
+		}
+		else {
+			store.checksumming = inquire::Confirm::new("Data checksumming ?")
+				.with_default(true).prompt().unwrap()
+			;
+		}
 	}
 
 	if matches.contains_id("logging") {
@@ -355,6 +369,70 @@ pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
 		return std::process::ExitCode::FAILURE;
 	}
 
+	let mut store_edges = store_path.clone();
+	store_edges.push("edges");
+	try_fs = std::fs::create_dir_all(&store_edges);
+	if try_fs.is_err() {
+		more_errors();
+		return std::process::ExitCode::FAILURE;
+	}
+
+	let mut edges_index = store_edges;
+	edges_index.push("rixindex");
+	try_fs_file = std::fs::File::create(edges_index);
+	if try_fs_file.is_err() {
+		more_errors();
+		return std::process::ExitCode::FAILURE;
+	}
+
+	let mut store_keyvalues = store_path.clone();
+	store_keyvalues.push("keyvalues");
+	try_fs = std::fs::create_dir_all(&store_keyvalues);
+	if try_fs.is_err() {
+		more_errors();
+		return std::process::ExitCode::FAILURE;
+	}
+
+	let mut keyvalues_index = store_keyvalues;
+	keyvalues_index.push("rixindex");
+	try_fs_file = std::fs::File::create(keyvalues_index);
+	if try_fs_file.is_err() {
+		more_errors();
+		return std::process::ExitCode::FAILURE;
+	}
+
+	let mut store_series = store_path.clone();
+	store_series.push("timeseries");
+	try_fs = std::fs::create_dir_all(&store_series);
+	if try_fs.is_err() {
+		more_errors();
+		return std::process::ExitCode::FAILURE;
+	}
+
+	let mut series_index = store_series;
+	series_index.push("rixindex");
+	try_fs_file = std::fs::File::create(series_index);
+	if try_fs_file.is_err() {
+		more_errors();
+		return std::process::ExitCode::FAILURE;
+	}
+
+	let mut store_blobs = store_path.clone();
+	store_blobs.push("blobs");
+	try_fs = std::fs::create_dir_all(&store_blobs);
+	if try_fs.is_err() {
+		more_errors();
+		return std::process::ExitCode::FAILURE;
+	}
+
+	let mut blobs_index = store_blobs;
+	blobs_index.push("rixindex");
+	try_fs_file = std::fs::File::create(blobs_index);
+	if try_fs_file.is_err() {
+		more_errors();
+		return std::process::ExitCode::FAILURE;
+	}
+
 	let mut store_checks = store_path.clone();
 	store_checks.push("checksums");
 	try_fs = std::fs::create_dir_all(&store_checks);
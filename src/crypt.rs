@@ -0,0 +1,770 @@
+use std::fs;
+use std::io::{ self, Read, Write, Seek, SeekFrom };
+use std::path::{ Path, PathBuf };
+use std::sync::RwLock;
+
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::{ Aead, KeyInit, Payload };
+use chacha20poly1305::ChaCha20Poly1305;
+use rand::Rng;
+use serde::{ Serialize, Deserialize };
+
+use crate::basics::Store;
+use crate::faccess::BLOCK_SIZE;
+
+// Encryption at rest. The files of an encrypted store are sealed: SEAL_MAGIC,
+// the u8 cipher, the id of the key (8 bytes), a random file id (16 bytes),
+// then the content by blocks of BLOCK_SIZE bytes (the last one shorter), each
+// stored as a random nonce (12 bytes), the encrypted block and its tag (16
+// bytes). The file id and the number of each block are authenticated along
+// with it, so that blocks can't be swapped. The offsets seen through `File`
+// are the ones of the content, so that the rest of the code is unchanged.
+pub const SEAL_MAGIC: &[u8; 8] = b"RIXSEAL1";
+const HEADER_SIZE: u64 = 33;
+const NONCE_SIZE: u64 = 12;
+const TAG_SIZE: u64 = 16;
+const SEALED_BLOCK: u64 = BLOCK_SIZE + NONCE_SIZE + TAG_SIZE;
+
+// Environment variables giving the key of an encrypted store, either in
+// hexadecimal or as the path of a key file
+pub const KEY_VAR: &str = "ORIXDB_KEY";
+pub const KEY_FILE_VAR: &str = "ORIXDB_KEY_FILE";
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum Cipher {
+	Aes256Gcm,
+	ChaCha20Poly1305
+}
+
+impl Cipher {
+	pub fn from_u8(value: u8) -> Option<Cipher> {
+		return match value {
+			0 => Some(Cipher::Aes256Gcm),
+			1 => Some(Cipher::ChaCha20Poly1305),
+			_ => None
+		};
+	}
+
+	pub fn to_u8(self) -> u8 {
+		return match self {
+			Cipher::Aes256Gcm => 0,
+			Cipher::ChaCha20Poly1305 => 1
+		};
+	}
+
+	pub fn from_name(name: &str) -> Option<Cipher> {
+		return match name {
+			"aes-256-gcm" => Some(Cipher::Aes256Gcm),
+			"chacha20-poly1305" => Some(Cipher::ChaCha20Poly1305),
+			_ => None
+		};
+	}
+
+	pub fn name(self) -> &'static str {
+		return match self {
+			Cipher::Aes256Gcm => "aes-256-gcm",
+			Cipher::ChaCha20Poly1305 => "chacha20-poly1305"
+		};
+	}
+}
+
+enum Sealer {
+	Aes(Box<Aes256Gcm>),
+	ChaCha(ChaCha20Poly1305)
+}
+
+impl Sealer {
+	fn seal(&self, nonce: &[u8], plain: &[u8], aad: &[u8]) -> Vec<u8> {
+		let payload = Payload { msg: plain, aad };
+		let sealed = match self {
+			Sealer::Aes(cipher) => cipher.encrypt(nonce.into(), payload),
+			Sealer::ChaCha(cipher) => cipher.encrypt(nonce.into(), payload)
+		};
+		return sealed.expect("The encryption of a block failed");
+	}
+
+	fn open(&self, nonce: &[u8], sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+		let payload = Payload { msg: sealed, aad };
+		return match self {
+			Sealer::Aes(cipher) => cipher.decrypt(nonce.into(), payload),
+			Sealer::ChaCha(cipher) => cipher.decrypt(nonce.into(), payload)
+		}.ok();
+	}
+}
+
+// A 256 bits key, and the cipher it is used with
+#[derive(Clone)]
+pub struct Key {
+	pub id: [u8; 8],
+	pub cipher: Cipher,
+	secret: [u8; 32]
+}
+
+impl Key {
+	pub fn new(cipher: Cipher, secret: [u8; 32]) -> Key {
+		let mut key = Key { id: [0u8; 8], cipher, secret };
+		// The id is the start of the tag of an empty message: it tells
+		// the keys apart without revealing anything about them
+		let tag = key.sealer().seal(&[0u8; NONCE_SIZE as usize], &[], b"orixdb key id");
+		key.id.copy_from_slice(&tag[..8]);
+		return key;
+	}
+
+	pub fn generate(cipher: Cipher) -> Key {
+		return Key::new(cipher, rand::thread_rng().gen());
+	}
+
+	pub fn id_hex(&self) -> String {
+		return to_hex(&self.id);
+	}
+
+	pub fn secret_hex(&self) -> String {
+		return to_hex(&self.secret);
+	}
+
+	fn sealer(&self) -> Sealer {
+		return match self.cipher {
+			Cipher::Aes256Gcm => Sealer::Aes(Box::new(Aes256Gcm::new(&self.secret.into()))),
+			Cipher::ChaCha20Poly1305 => Sealer::ChaCha(ChaCha20Poly1305::new(&self.secret.into()))
+		};
+	}
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+	return bytes.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+	if !text.len().is_multiple_of(2) || !text.is_ascii() { return None; }
+	return (0..text.len()).step_by(2)
+		.map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+		.collect()
+	;
+}
+
+
+// --> Keys of the process
+// -----------------------
+
+// The keys loaded, and the directories whose new files are sealed,
+// with the id of the key they are sealed with
+struct Keyring {
+	keys: Vec<Key>,
+	roots: Vec<(PathBuf, [u8; 8])>
+}
+
+static KEYRING: RwLock<Keyring> = RwLock::new(Keyring { keys: Vec::new(), roots: Vec::new() });
+
+// Makes a key usable to read the files sealed with it
+pub fn install(key: Key) {
+	let mut keyring = KEYRING.write().unwrap();
+	if !keyring.keys.iter().any(|k| k.id == key.id) { keyring.keys.push(key); }
+}
+
+// A key loaded, else the one given by the environment when it matches
+fn find_key(cipher: Cipher, id: &[u8]) -> Option<Key> {
+	let found = KEYRING.read().unwrap().keys.iter()
+		.find(|k| k.id == id && k.cipher == cipher).cloned()
+	;
+	return found.or_else(|| {
+		let key = load_key(cipher, None).ok().filter(|key| key.id == id)?;
+		install(key.clone());
+		Some(key)
+	});
+}
+
+// Seals the files created from now on in a directory, or in the ones under it
+pub fn seal_under(dir: &Path, key_id: [u8; 8]) -> io::Result<()> {
+	let dir = dir.canonicalize()?;
+	let mut keyring = KEYRING.write().unwrap();
+	keyring.roots.retain(|(root, _)| *root != dir);
+	keyring.roots.push((dir, key_id));
+	return Ok(());
+}
+
+// The key new files are sealed with at a path, if any
+fn writer_key(path: &Path) -> Option<Key> {
+	let parent = path.parent()?.canonicalize().ok()?;
+	let keyring = KEYRING.read().unwrap();
+	let id = keyring.roots.iter()
+		.filter(|(root, _)| parent.starts_with(root))
+		.max_by_key(|(root, _)| root.as_os_str().len())
+		.map(|(_, id)| *id)?
+	;
+	return keyring.keys.iter().find(|k| k.id == id).cloned();
+}
+
+// Reads a key file: either the 32 bytes of the key, or their hexadecimal
+pub fn read_key(cipher: Cipher, path: &Path) -> Result<Key, String> {
+	let bytes = fs::read(path)
+		.map_err(|e| format!("Failed to read the key file {:?}: {}", path, e))?
+	;
+	let secret = match bytes.len() {
+		32 => Some(bytes),
+		_ => std::str::from_utf8(&bytes).ok().and_then(|text| from_hex(text.trim()))
+	};
+	return match secret.and_then(|s| <[u8; 32]>::try_from(s).ok()) {
+		Some(secret) => Ok(Key::new(cipher, secret)),
+		None => Err(format!(
+			"The key file {:?} must hold 32 bytes, or 64 hexadecimal digits.", path
+		))
+	};
+}
+
+// Loads the key given by a key file, else by the environment
+pub fn load_key(cipher: Cipher, key_file: Option<&Path>) -> Result<Key, String> {
+	if let Some(path) = key_file { return read_key(cipher, path); }
+	if let Some(path) = std::env::var_os(KEY_FILE_VAR) { return read_key(cipher, Path::new(&path)); }
+	let text = std::env::var(KEY_VAR).map_err(|_| format!(
+		"The store is encrypted: its key must be given with a key file, \
+		or in the {} or {} environment variables.", KEY_FILE_VAR, KEY_VAR
+	))?;
+	return match from_hex(text.trim()).and_then(|s| <[u8; 32]>::try_from(s).ok()) {
+		Some(secret) => Ok(Key::new(cipher, secret)),
+		None => Err(format!("{} must hold 64 hexadecimal digits.", KEY_VAR))
+	};
+}
+
+// Loads the key of an encrypted store, so that its files can be read, and
+// so that the ones written in it are sealed. Nothing is done for the other
+// stores.
+pub fn unlock(store_dir: &Path, store: &Store, key_file: Option<&Path>) -> Result<(), String> {
+	let encryption = match &store.encryption {
+		Some(encryption) => encryption,
+		None => return Ok(())
+	};
+	let id = from_hex(&encryption.key).and_then(|id| <[u8; 8]>::try_from(id).ok())
+		.ok_or("The key id of the manifest is invalid.")?
+	;
+	if find_key(encryption.cipher, &id).is_none() {
+		let key = load_key(encryption.cipher, key_file)?;
+		if key.id != id {
+			return Err(format!(
+				"The key given ({}) isn't the one of the store ({}).", key.id_hex(), encryption.key
+			));
+		}
+		install(key);
+	}
+	return seal_under(store_dir, id)
+		.map_err(|e| format!("Failed to unlock {:?}: {}", store_dir, e))
+	;
+}
+
+
+// --> Sealed files
+// ----------------
+
+// Size of the content of a sealed file, from the size of the file
+pub fn plain_len(size: u64) -> u64 {
+	let body = size.saturating_sub(HEADER_SIZE);
+	let rest = body % SEALED_BLOCK;
+	return body / SEALED_BLOCK * BLOCK_SIZE + rest.saturating_sub(NONCE_SIZE + TAG_SIZE);
+}
+
+// Size of a sealed file, from the size of its content
+fn sealed_len(length: u64) -> u64 {
+	let rest = length % BLOCK_SIZE;
+	let last = if rest > 0 { rest + NONCE_SIZE + TAG_SIZE } else { 0 };
+	return HEADER_SIZE + length / BLOCK_SIZE * SEALED_BLOCK + last;
+}
+
+// Where a block of the content lies in a sealed file (start and end)
+pub fn sealed_span(block: u64) -> (u64, u64) {
+	let start = HEADER_SIZE + block * SEALED_BLOCK;
+	return (start, start + SEALED_BLOCK);
+}
+
+pub fn is_sealed(path: &Path) -> bool {
+	let mut start = [0u8; SEAL_MAGIC.len()];
+	return fs::File::open(path)
+		.and_then(|mut file| file.read_exact(&mut start))
+		.is_ok_and(|_| &start == SEAL_MAGIC)
+	;
+}
+
+fn missing_key(id: &[u8]) -> io::Error {
+	return io::Error::new(io::ErrorKind::PermissionDenied, format!(
+		"The file is encrypted with the key {}, which wasn't given", to_hex(id)
+	));
+}
+
+// Reads the header of a sealed file, returning the key and the file id
+fn read_seal_header(header: &[u8]) -> io::Result<(Key, [u8; 16])> {
+	let invalid = io::Error::new(io::ErrorKind::InvalidData, "Invalid encryption header");
+	if header.len() < HEADER_SIZE as usize || &header[..8] != SEAL_MAGIC { return Err(invalid); }
+	let cipher = Cipher::from_u8(header[8]).ok_or(invalid)?;
+	let key = find_key(cipher, &header[9..17]).ok_or(missing_key(&header[9..17]))?;
+	let mut file_id = [0u8; 16];
+	file_id.copy_from_slice(&header[17..33]);
+	return Ok((key, file_id));
+}
+
+struct Seal {
+	sealer: Sealer,
+	file_id: [u8; 16],
+	length: u64, // Of the content
+	block: Option<(u64, Vec<u8>)> // The last block read or written, decrypted
+}
+
+impl Seal {
+	// Data authenticated along with a block
+	fn aad(&self, number: u64) -> [u8; 24] {
+		let mut aad = [0u8; 24];
+		aad[..16].copy_from_slice(&self.file_id);
+		aad[16..].copy_from_slice(&number.to_be_bytes());
+		return aad;
+	}
+
+	fn load(&mut self, file: &mut fs::File, number: u64) -> io::Result<&[u8]> {
+		if self.block.as_ref().is_none_or(|(n, _)| *n != number) {
+			let length = (self.length - number * BLOCK_SIZE).min(BLOCK_SIZE);
+			let mut sealed = vec![0u8; (length + NONCE_SIZE + TAG_SIZE) as usize];
+			file.seek(SeekFrom::Start(sealed_span(number).0))?;
+			file.read_exact(&mut sealed)?;
+			let (nonce, sealed) = sealed.split_at(NONCE_SIZE as usize);
+			let plain = self.sealer.open(nonce, sealed, &self.aad(number))
+				.ok_or(io::Error::new(io::ErrorKind::InvalidData, format!(
+					"Block {} failed its authentication", number
+				)))?
+			;
+			self.block = Some((number, plain));
+		}
+		return Ok(&self.block.as_ref().unwrap().1);
+	}
+
+	fn store(&mut self, file: &mut fs::File, number: u64, plain: Vec<u8>) -> io::Result<()> {
+		let nonce: [u8; NONCE_SIZE as usize] = rand::thread_rng().gen();
+		let mut sealed = nonce.to_vec();
+		sealed.extend(self.sealer.seal(&nonce, &plain, &self.aad(number)));
+		file.seek(SeekFrom::Start(sealed_span(number).0))?;
+		file.write_all(&sealed)?;
+		self.length = self.length.max(number * BLOCK_SIZE + plain.len() as u64);
+		self.block = Some((number, plain));
+		return Ok(());
+	}
+
+	// Writes bytes at a position of the content, zeros filling the gap
+	// from its current end
+	fn write_at(&mut self, file: &mut fs::File, position: u64, data: &[u8]) -> io::Result<()> {
+		let end = position + data.len() as u64;
+		let mut cursor = self.length.min(position);
+		while cursor < end {
+			let number = cursor / BLOCK_SIZE;
+			let start = number * BLOCK_SIZE;
+			let mut plain = match start < self.length {
+				true => self.load(file, number)?.to_vec(),
+				false => Vec::new()
+			};
+			let stop = (end - start).min(BLOCK_SIZE) as usize;
+			if plain.len() < stop { plain.resize(stop, 0); }
+			let from = cursor.max(position);
+			if from < start + stop as u64 {
+				plain[(from - start) as usize..stop].copy_from_slice(
+					&data[(from - position) as usize..(start + stop as u64 - position) as usize]
+				);
+			}
+			self.store(file, number, plain)?;
+			cursor = start + stop as u64;
+		}
+		return Ok(());
+	}
+
+	fn set_len(&mut self, file: &mut fs::File, length: u64) -> io::Result<()> {
+		while self.length < length {
+			let gap = (length - self.length).min(BLOCK_SIZE);
+			self.write_at(file, self.length, &vec![0u8; gap as usize])?;
+		}
+		if length == self.length { return Ok(()); }
+		let number = length / BLOCK_SIZE;
+		let rest = (length % BLOCK_SIZE) as usize;
+		let last = match rest > 0 {
+			true => Some(self.load(file, number)?[..rest].to_vec()),
+			false => None
+		};
+		self.length = length;
+		self.block = None;
+		if let Some(last) = last { self.store(file, number, last)?; }
+		return file.set_len(sealed_len(length));
+	}
+}
+
+// A file of a store, sealed or not. The files are sealed when they are
+// created in an encrypted store, the existing ones being read and written
+// the way they are stored.
+pub struct File {
+	file: fs::File,
+	seal: Option<Seal>,
+	position: u64, // In the content of a sealed file
+	append: bool
+}
+
+impl File {
+	fn new(file: fs::File, path: &Path, writable: bool) -> io::Result<File> {
+		let mut file = File { file, seal: None, position: 0, append: false };
+		let size = file.file.metadata()?.len();
+		if size == 0 && writable {
+			if let Some(key) = writer_key(path) {
+				let file_id: [u8; 16] = rand::thread_rng().gen();
+				let mut header = SEAL_MAGIC.to_vec();
+				header.push(key.cipher.to_u8());
+				header.extend_from_slice(&key.id);
+				header.extend_from_slice(&file_id);
+				file.file.write_all(&header)?;
+				file.seal = Some(Seal { sealer: key.sealer(), file_id, length: 0, block: None });
+			}
+			return Ok(file);
+		}
+		if size >= HEADER_SIZE {
+			let mut header = [0u8; HEADER_SIZE as usize];
+			file.file.read_exact(&mut header)?;
+			if &header[..8] == SEAL_MAGIC {
+				let (key, file_id) = read_seal_header(&header)?;
+				file.seal = Some(Seal {
+					sealer: key.sealer(), file_id, length: plain_len(size), block: None
+				});
+				return Ok(file);
+			}
+			file.file.seek(SeekFrom::Start(0))?;
+		}
+		return Ok(file);
+	}
+
+	pub fn open(path: &Path) -> io::Result<File> {
+		return File::new(fs::File::open(path)?, path, false);
+	}
+
+	// Opens a file to read and write it, creating it if it's missing
+	pub fn open_rw(path: &Path) -> io::Result<File> {
+		let file = fs::OpenOptions::new()
+			.create(true).truncate(false).read(true).write(true).open(path)?
+		;
+		return File::new(file, path, true);
+	}
+
+	pub fn create(path: &Path) -> io::Result<File> {
+		let file = fs::OpenOptions::new()
+			.create(true).truncate(true).read(true).write(true).open(path)?
+		;
+		return File::new(file, path, true);
+	}
+
+	// Opens a file to add bytes at its end, creating it if it's missing
+	pub fn append(path: &Path) -> io::Result<File> {
+		let mut file = File::open_rw(path)?;
+		file.append = true;
+		return Ok(file);
+	}
+
+	pub fn is_sealed(&self) -> bool {
+		return self.seal.is_some();
+	}
+
+	// Size of the content
+	pub fn len(&self) -> io::Result<u64> {
+		return match &self.seal {
+			Some(seal) => Ok(seal.length),
+			None => Ok(self.file.metadata()?.len())
+		};
+	}
+
+	pub fn is_empty(&self) -> io::Result<bool> {
+		return Ok(self.len()? == 0);
+	}
+
+	pub fn set_len(&mut self, length: u64) -> io::Result<()> {
+		return match &mut self.seal {
+			Some(seal) => seal.set_len(&mut self.file, length),
+			None => self.file.set_len(length)
+		};
+	}
+
+	pub fn sync_data(&self) -> io::Result<()> {
+		return self.file.sync_data();
+	}
+
+	pub fn sync_all(&self) -> io::Result<()> {
+		return self.file.sync_all();
+	}
+}
+
+impl Read for File {
+	fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+		let seal = match &mut self.seal {
+			Some(seal) => seal,
+			None => return self.file.read(buffer)
+		};
+		if buffer.is_empty() || self.position >= seal.length { return Ok(0); }
+		let offset = (self.position % BLOCK_SIZE) as usize;
+		let block = seal.load(&mut self.file, self.position / BLOCK_SIZE)?;
+		let length = buffer.len().min(block.len() - offset);
+		buffer[..length].copy_from_slice(&block[offset..offset + length]);
+		self.position += length as u64;
+		return Ok(length);
+	}
+}
+
+impl Write for File {
+	fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+		let seal = match &mut self.seal {
+			Some(seal) => seal,
+			None => {
+				if self.append { self.file.seek(SeekFrom::End(0))?; }
+				return self.file.write(buffer);
+			}
+		};
+		if self.append { self.position = seal.length; }
+		seal.write_at(&mut self.file, self.position, buffer)?;
+		self.position += buffer.len() as u64;
+		return Ok(buffer.len());
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		return self.file.flush();
+	}
+}
+
+impl Seek for File {
+	fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
+		let seal = match &self.seal {
+			Some(seal) => seal,
+			None => return self.file.seek(from)
+		};
+		let position = match from {
+			SeekFrom::Start(position) => Some(position),
+			SeekFrom::End(offset) => seal.length.checked_add_signed(offset),
+			SeekFrom::Current(offset) => self.position.checked_add_signed(offset)
+		};
+		self.position = position.ok_or(io::Error::new(
+			io::ErrorKind::InvalidInput, "Seeking before the start of the file"
+		))?;
+		return Ok(self.position);
+	}
+}
+
+// The content of a file, sealed or not
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+	let mut file = File::open(path)?;
+	let mut content = Vec::<u8>::with_capacity(file.len()? as usize);
+	file.read_to_end(&mut content)?;
+	return Ok(content);
+}
+
+pub fn read_to_string(path: &Path) -> io::Result<String> {
+	return String::from_utf8(read(path)?)
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+	;
+}
+
+pub fn write(path: &Path, content: &[u8]) -> io::Result<()> {
+	return File::create(path)?.write_all(content);
+}
+
+// Size of the content of a file, sealed or not
+pub fn len(path: &Path) -> io::Result<u64> {
+	return File::open(path)?.len();
+}
+
+// The content of a sealed file held in memory, or the bytes themselves
+// when they aren't sealed
+pub fn unseal(bytes: &[u8]) -> io::Result<Vec<u8>> {
+	if bytes.len() < HEADER_SIZE as usize || &bytes[..8] != SEAL_MAGIC { return Ok(bytes.to_vec()); }
+	let (key, file_id) = read_seal_header(bytes)?;
+	let seal = Seal { sealer: key.sealer(), file_id, length: 0, block: None };
+	let mut content = Vec::<u8>::with_capacity(plain_len(bytes.len() as u64) as usize);
+	for (number, block) in bytes[HEADER_SIZE as usize..].chunks(SEALED_BLOCK as usize).enumerate() {
+		let plain = (block.len() > NONCE_SIZE as usize)
+			.then(|| {
+				let (nonce, sealed) = block.split_at(NONCE_SIZE as usize);
+				seal.sealer.open(nonce, sealed, &seal.aad(number as u64))
+			})
+			.flatten()
+			.ok_or(io::Error::new(io::ErrorKind::InvalidData, format!(
+				"Block {} failed its authentication", number
+			)))?
+		;
+		content.extend(plain);
+	}
+	return Ok(content);
+}
+
+// Rewrites a file through the store's `tmp/` directory, sealing it with the
+// key of the store, or leaving it unsealed when the store has none
+pub fn reseal(store_dir: &Path, path: &Path) -> io::Result<u64> {
+	let mut temp = store_dir.join("tmp");
+	fs::create_dir_all(&temp)?;
+	temp.push(crate::faccess::new_id());
+	let mut source = File::open(path)?;
+	let mut target = File::create(&temp)?;
+	let copied = io::copy(&mut source, &mut target)
+		.and_then(|copied| target.sync_all().map(|_| copied))
+		.and_then(|copied| fs::rename(&temp, path).map(|_| copied))
+	;
+	if copied.is_err() { let _ = fs::remove_file(&temp); }
+	return copied;
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+	use crate::serve::testing;
+
+	const CIPHERS: [Cipher; 2] = [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305];
+
+	// A directory whose new files are sealed with a new key
+	fn sealed_dir(cipher: Cipher) -> (tempfile::TempDir, Key) {
+		let dir = tempfile::tempdir().unwrap();
+		let key = Key::generate(cipher);
+		install(key.clone());
+		seal_under(dir.path(), key.id).unwrap();
+		return (dir, key);
+	}
+
+	// Three full blocks and a shorter one
+	fn content() -> Vec<u8> {
+		return (0..3 * BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+	}
+
+	#[test]
+	fn round_trip() {
+		for cipher in CIPHERS {
+			let (dir, key) = sealed_dir(cipher);
+			let path = dir.path().join("data");
+			write(&path, &content()).unwrap();
+
+			assert!(is_sealed(&path));
+			let raw = fs::read(&path).unwrap();
+			assert_eq!(raw.len() as u64, sealed_len(content().len() as u64));
+			assert_eq!(raw[8], cipher.to_u8());
+			assert_eq!(&raw[9..17], &key.id);
+			assert!(!raw.windows(64).any(|w| w == &content()[..64]));
+			assert_eq!(len(&path).unwrap(), content().len() as u64);
+			assert_eq!(read(&path).unwrap(), content());
+			assert_eq!(unseal(&raw).unwrap(), content());
+		}
+	}
+
+	#[test]
+	fn partial_block_writes() {
+		for cipher in CIPHERS {
+			let (dir, _) = sealed_dir(cipher);
+			let path = dir.path().join("data");
+			write(&path, &content()).unwrap();
+			let mut expected = content();
+
+			// Across the first two blocks, then past the end of the content
+			let mut file = File::open_rw(&path).unwrap();
+			file.seek(SeekFrom::Start(BLOCK_SIZE - 10)).unwrap();
+			file.write_all(&[0xAA; 30]).unwrap();
+			expected[(BLOCK_SIZE - 10) as usize..(BLOCK_SIZE + 20) as usize].fill(0xAA);
+			let end = expected.len() as u64;
+			file.seek(SeekFrom::Start(end + 50)).unwrap();
+			file.write_all(b"tail").unwrap();
+			expected.extend([0u8; 50]);
+			expected.extend(b"tail");
+			drop(file);
+			assert_eq!(read(&path).unwrap(), expected);
+
+			// Cut within a block
+			let mut file = File::open_rw(&path).unwrap();
+			file.set_len(BLOCK_SIZE + 5).unwrap();
+			drop(file);
+			expected.truncate((BLOCK_SIZE + 5) as usize);
+			assert_eq!(read(&path).unwrap(), expected);
+			assert_eq!(fs::metadata(&path).unwrap().len(), sealed_len(BLOCK_SIZE + 5));
+		}
+	}
+
+	#[test]
+	fn tampered_block() {
+		for cipher in CIPHERS {
+			let (dir, _) = sealed_dir(cipher);
+			let path = dir.path().join("data");
+			write(&path, &content()).unwrap();
+			let mut raw = fs::read(&path).unwrap();
+			raw[(sealed_span(1).0 + NONCE_SIZE + 5) as usize] ^= 1;
+			fs::write(&path, &raw).unwrap();
+
+			let mut file = File::open(&path).unwrap();
+			let mut block = vec![0u8; BLOCK_SIZE as usize];
+			file.read_exact(&mut block).unwrap();
+			assert_eq!(block, content()[..BLOCK_SIZE as usize]);
+			let error = file.read_exact(&mut block).unwrap_err();
+			assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+			assert!(read(&path).is_err());
+			assert!(unseal(&raw).is_err());
+		}
+	}
+
+	#[test]
+	fn swapped_blocks() {
+		let (dir, _) = sealed_dir(Cipher::Aes256Gcm);
+		let path = dir.path().join("data");
+		write(&path, &content()).unwrap();
+		let mut raw = fs::read(&path).unwrap();
+		let (first, second) = (sealed_span(0), sealed_span(1));
+		let block = raw[first.0 as usize..first.1 as usize].to_vec();
+		raw.copy_within(second.0 as usize..second.1 as usize, first.0 as usize);
+		raw[second.0 as usize..second.1 as usize].copy_from_slice(&block);
+		fs::write(&path, &raw).unwrap();
+		assert_eq!(read(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn wrong_key() {
+		for cipher in CIPHERS {
+			let (dir, _) = sealed_dir(cipher);
+			let path = dir.path().join("data");
+			write(&path, &content()).unwrap();
+
+			// A key which isn't loaded
+			let mut raw = fs::read(&path).unwrap();
+			raw[9..17].copy_from_slice(&Key::generate(cipher).id);
+			fs::write(&path, &raw).unwrap();
+			assert_eq!(File::open(&path).err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+
+			// Another key, loaded, which fails to authenticate the blocks
+			let other = Key::generate(cipher);
+			install(other.clone());
+			raw[9..17].copy_from_slice(&other.id);
+			fs::write(&path, &raw).unwrap();
+			assert_eq!(read(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+		}
+	}
+
+	#[test]
+	fn key_files() {
+		let dir = tempfile::tempdir().unwrap();
+		let key = Key::generate(Cipher::ChaCha20Poly1305);
+		let path = dir.path().join("key");
+		fs::write(&path, key.secret_hex() + "\n").unwrap();
+		assert_eq!(read_key(key.cipher, &path).unwrap().id, key.id);
+		fs::write(&path, key.secret).unwrap();
+		assert_eq!(read_key(key.cipher, &path).unwrap().id, key.id);
+		// The same secret gives another key with another cipher
+		assert_ne!(read_key(Cipher::Aes256Gcm, &path).unwrap().id, key.id);
+		fs::write(&path, "0123").unwrap();
+		assert!(read_key(key.cipher, &path).is_err());
+	}
+
+	#[test]
+	fn unlock_with_wrong_key() {
+		let dir = tempfile::tempdir().unwrap();
+		let store_dir = dir.path().join("store");
+		let mut store = testing::create_store(&store_dir, json!({})).store;
+		let key = Key::generate(Cipher::Aes256Gcm);
+		store.encryption = Some(crate::basics::Encryption {
+			cipher: key.cipher, key: key.id_hex()
+		});
+		let path = dir.path().join("key");
+		fs::write(&path, Key::generate(key.cipher).secret_hex()).unwrap();
+		let error = unlock(&store_dir, &store, Some(&path)).unwrap_err();
+		assert!(error.contains("isn't the one of the store"), "{}", error);
+		fs::write(&path, key.secret_hex()).unwrap();
+		unlock(&store_dir, &store, Some(&path)).unwrap();
+	}
+}
@@ -0,0 +1,241 @@
+use std::fs;
+use std::io::{ self, Write };
+use std::path::{ Path, PathBuf };
+
+use clap::ArgMatches;
+
+use crate::cli;
+use crate::lite;
+use crate::faccess;
+use crate::crypt::{ self, Cipher, Key };
+use crate::basics::{ self, Encryption, StoreType };
+
+// Lists the files of a store to seal, relative to its directory: all but the
+// manifest, the temporary files, and the stores kept within (like backups)
+fn sealed_files(store_dir: &Path) -> io::Result<Vec<PathBuf>> {
+	let mut files = Vec::<PathBuf>::new();
+	let mut pending = vec![PathBuf::new()];
+	while let Some(relative) = pending.pop() {
+		for entry in fs::read_dir(store_dir.join(&relative))? {
+			let entry = entry?;
+			let path = relative.join(entry.file_name());
+			if path == Path::new("manifest.json") || path == Path::new("tmp") { continue; }
+			let file_type = entry.file_type()?;
+			if file_type.is_dir() && !entry.path().join("manifest.json").exists() {
+				pending.push(path);
+			}
+			else if file_type.is_file() { files.push(path); }
+		}
+	}
+	files.sort();
+	return Ok(files);
+}
+
+// Writes a new key in hexadecimal, readable by its owner alone
+fn write_key_file(path: &Path, key: &Key) -> io::Result<()> {
+	let mut options = fs::OpenOptions::new();
+	options.write(true).create_new(true);
+	#[cfg(unix)]
+	std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+	let mut file = options.open(path)?;
+	writeln!(file, "{}", key.secret_hex())?;
+	return file.sync_all();
+}
+
+pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
+
+	// --> Checking the command line arguments
+	// ---------------------------------------
+
+	let store_dir: PathBuf;
+	if matches.contains_id("directory") {
+		store_dir = PathBuf::from(matches.get_one::<String>("directory").unwrap());
+	}
+	else { store_dir = PathBuf::from(".").canonicalize().unwrap(); }
+	let mut store = match basics::load_store(&store_dir) {
+		Ok(store) => store,
+		Err(message) => {
+			cli::red_err(message);
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+	if store.kind == StoreType::Backup || store.kind == StoreType::Archive {
+		cli::red_err(
+			"Only live and lite stores can be encrypted.".to_owned()
+			+ " The backups and archives of an encrypted store are encrypted too."
+		);
+		return std::process::ExitCode::FAILURE;
+	}
+	// The store must not be served, nor embedded, while its files are sealed
+	let _lock = match lite::lock(&store_dir) {
+		Ok(file) => file,
+		Err(message) => {
+			cli::red_err(message);
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+	let cipher = match matches.get_one::<String>("cipher") {
+		Some(name) => match Cipher::from_name(name) {
+			Some(cipher) => cipher,
+			None => {
+				cli::red_err(format!(
+					"Unknown cipher {:?}: it must be aes-256-gcm or chacha20-poly1305.", name
+				));
+				return std::process::ExitCode::FAILURE;
+			}
+		},
+		None => store.encryption.as_ref().map(|e| e.cipher).unwrap_or(Cipher::Aes256Gcm)
+	};
+	let key_file = matches.get_one::<String>("key-file").map(PathBuf::from);
+	let new_key_file = PathBuf::from(matches.get_one::<String>("new-key-file").unwrap());
+
+
+	// --> Loading the current key and the new one
+	// -------------------------------------------
+
+	if let Err(message) = crypt::unlock(&store_dir, &store, key_file.as_deref()) {
+		cli::red_err(message);
+		return std::process::ExitCode::FAILURE;
+	}
+	let key = match new_key_file.exists() {
+		true => crypt::read_key(cipher, &new_key_file),
+		false => {
+			let key = Key::generate(cipher);
+			write_key_file(&new_key_file, &key)
+				.map(|_| {
+					cli::yellow_out(format!("A new key was written in {:?}.", new_key_file));
+					key
+				})
+				.map_err(|e| format!("Failed to write the key file {:?}: {}", new_key_file, e))
+		}
+	};
+	let key = match key {
+		Ok(key) => key,
+		Err(message) => {
+			cli::red_err(message);
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+	let previous = store.encryption.as_ref().map(|e| e.key.clone());
+	if previous.as_deref() == Some(key.id_hex().as_str()) {
+		cli::red_err("The store is already encrypted with this key.".to_owned());
+		return std::process::ExitCode::FAILURE;
+	}
+	crypt::install(key.clone());
+	if let Err(e) = crypt::seal_under(&store_dir, key.id) {
+		cli::red_err(format!("Failed to open {:?}: {}", store_dir, e));
+		return std::process::ExitCode::FAILURE;
+	}
+
+
+	// --> Sealing the files with the new key
+	// --------------------------------------
+
+	// The files sealed with either key can be read in the meantime, so that
+	// the command can be run again after a failure, with the same keys
+	let files = match sealed_files(&store_dir) {
+		Ok(files) => files,
+		Err(e) => {
+			cli::red_err(format!("Failed to list the files of the store: {}", e));
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+	let mut bytes = 0u64;
+	for relative in &files {
+		match crypt::reseal(&store_dir, &store_dir.join(relative)) {
+			Ok(length) => bytes += length,
+			Err(e) => {
+				cli::red_err(format!(
+					"Failed to encrypt {}: {}\n\
+					Run the command again with the same keys to finish the encryption.",
+					relative.display(), e
+				));
+				return std::process::ExitCode::FAILURE;
+			}
+		}
+	}
+
+	store.encryption = Some(Encryption { cipher, key: key.id_hex() });
+	let written = serde_json::to_string_pretty(&store).map_err(io::Error::other)
+		.and_then(|text| faccess::write_atomic(
+			&store_dir, &store_dir.join("manifest.json"), text.as_bytes()
+		))
+	;
+	if let Err(e) = written {
+		cli::red_err(format!("Failed to write the manifest: {}", e));
+		return std::process::ExitCode::FAILURE;
+	}
+	match previous {
+		Some(previous) => cli::green_out(format!(
+			"Key of the store rotated from {} to {}: {} files, {} bytes encrypted again.\n\
+			The previous key is still needed for the backups and archives made before.",
+			previous, key.id_hex(), files.len(), bytes
+		)),
+		None => cli::green_out(format!(
+			"Store encrypted with {} and the key {}: {} files, {} bytes.",
+			cipher.name(), key.id_hex(), files.len(), bytes
+		))
+	}
+	return std::process::ExitCode::SUCCESS;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+	use crate::serve::testing;
+
+	#[test]
+	fn seals_the_files_of_the_store_only() {
+		let dir = tempfile::tempdir().unwrap();
+		let store_dir = dir.path().join("store");
+		let mut engine = testing::create_store(&store_dir, json!({}));
+		testing::mutate(&mut engine, "setKey", json!({ "key": "greeting", "value": "hello" })).unwrap();
+		drop(engine);
+		fs::create_dir_all(store_dir.join("backups/old")).unwrap();
+		fs::write(store_dir.join("backups/old/manifest.json"), "{}").unwrap();
+		fs::write(store_dir.join("tmp/leftover"), "x").unwrap();
+
+		let files = sealed_files(&store_dir).unwrap();
+		assert!(files.contains(&PathBuf::from("keyvalues/rixindex")));
+		assert!(files.iter().all(|f| !f.starts_with("backups") && !f.starts_with("tmp")));
+		assert!(!files.contains(&PathBuf::from("manifest.json")));
+
+		let key = Key::generate(Cipher::ChaCha20Poly1305);
+		crypt::install(key.clone());
+		crypt::seal_under(&store_dir, key.id).unwrap();
+		let before = files.iter()
+			.map(|f| crypt::read(&store_dir.join(f)).unwrap())
+			.collect::<Vec<Vec<u8>>>()
+		;
+		for (relative, content) in files.iter().zip(before) {
+			let path = store_dir.join(relative);
+			assert_eq!(crypt::reseal(&store_dir, &path).unwrap(), content.len() as u64);
+			assert!(crypt::is_sealed(&path));
+			assert_eq!(crypt::read(&path).unwrap(), content);
+		}
+		assert!(!crypt::is_sealed(&store_dir.join("manifest.json")));
+	}
+
+	#[test]
+	fn encryptions_wait_for_the_lock() {
+		let dir = tempfile::tempdir().unwrap();
+		let store_dir = dir.path().join("store");
+		testing::create_store(&store_dir, json!({}));
+		let key_file = dir.path().join("store.key");
+		let command = clap::Command::new("encrypt")
+			.arg(clap::Arg::new("new-key-file"))
+			.arg(clap::Arg::new("directory"))
+			.arg(clap::Arg::new("cipher").long("cipher"))
+			.arg(clap::Arg::new("key-file").long("key-file"))
+		;
+
+		let _lock = lite::lock(&store_dir).unwrap();
+		let matches = command.get_matches_from([
+			"encrypt", key_file.to_str().unwrap(), store_dir.to_str().unwrap()
+		]);
+		assert_eq!(main(&matches), std::process::ExitCode::FAILURE);
+		assert!(!key_file.exists());
+		assert!(basics::load_store(&store_dir).unwrap().encryption.is_none());
+	}
+}
@@ -0,0 +1,1678 @@
+use std::fs;
+use std::io::{ self, Read, Write, Seek, SeekFrom };
+use std::path::{ Path, PathBuf };
+use std::collections::{ HashMap, BTreeMap };
+
+use byteorder::{ ReadBytesExt, WriteBytesExt, BigEndian };
+use rand::Rng;
+
+use crate::crypt;
+use crate::geo::GeoIndex;
+use crate::basics::Compression;
+
+// Length of every file, item, singleton and edge id
+pub const ID_LENGTH: usize = 12;
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Default)]
+pub struct FileMeta {
+	pub size: u64,
+	pub holes: HashMap<
+		u64, // index
+		u64 // length
+	>,
+	// Whether the records of the file have headers. Not saved in the index:
+	// read from the start of the file at load time.
+	pub framed: bool
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct SingletonMeta {
+	pub name: String,
+	pub data_type: u8,
+	pub file: String,
+	pub index: u64,
+	pub data_length: u64
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct CollectionMeta {
+	pub collection: String,
+	pub data_type: u8,
+	pub file: String,
+	pub index: u64,
+	pub data_length: u64
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct EdgeMeta {
+	pub label: String,
+	pub from: String,
+	pub to: String,
+	pub data_type: u8,
+	pub file: String,
+	pub index: u64,
+	pub data_length: u64
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct KeyValueMeta {
+	pub data_type: u8,
+	pub file: String,
+	pub index: u64,
+	pub data_length: u64,
+	pub expiry: u64 // Unix time in milliseconds, 0 when the key never expires
+}
+
+#[derive(Debug)]
+#[derive(Default)]
+pub struct SingletonIndex {
+	// Map relating each singleton file name to its metadata
+	pub files: HashMap<String, FileMeta>,
+	// Map relating each singleton id to its metadata
+	pub singletons: HashMap<String, SingletonMeta>
+}
+
+#[derive(Debug)]
+#[derive(Default)]
+pub struct CollectionIndex {
+	// Map relating each collection id to its name
+	pub list: HashMap<String, String>,
+	// Map relating each collection file name to its metadata
+	pub files: HashMap<String, FileMeta>,
+	// Map relating each collection item id to its location
+	pub items: HashMap<String, CollectionMeta>,
+	// Map relating the id of each compressed collection to its compression
+	pub compression: HashMap<String, Compression>
+}
+
+#[derive(Debug)]
+#[derive(Default)]
+pub struct EdgeIndex {
+	// Map relating each edge file name to its metadata
+	pub files: HashMap<String, FileMeta>,
+	// Map relating each edge id to its metadata
+	pub edges: HashMap<String, EdgeMeta>,
+	// Adjacency lists: item id => ids of the edges leaving it
+	pub outgoing: HashMap<String, Vec<String>>,
+	// Adjacency lists: item id => ids of the edges reaching it
+	pub incoming: HashMap<String, Vec<String>>
+}
+
+#[derive(Debug)]
+#[derive(Default)]
+pub struct KeyValueIndex {
+	// Map relating each key-value file name to its metadata
+	pub files: HashMap<String, FileMeta>,
+	// Map relating each key to the location of its value
+	pub entries: HashMap<String, KeyValueMeta>
+}
+
+// A piece of a blob, stored in a data file
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Chunk {
+	pub file: String,
+	pub index: u64,
+	pub length: u64
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct BlobMeta {
+	pub content_type: String,
+	pub size: u64,
+	pub chunks: Vec<Chunk>
+}
+
+#[derive(Debug)]
+#[derive(Default)]
+pub struct BlobIndex {
+	// Map relating each blob file name to its metadata
+	pub files: HashMap<String, FileMeta>,
+	// Map relating each blob id to its chunks
+	pub blobs: HashMap<String, BlobMeta>
+}
+
+// A downsampled copy of a time series
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Rollup {
+	pub interval: u64, // Length of each bucket, in milliseconds
+	pub retention: u64 // How long buckets are kept, in milliseconds (0: forever)
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct SeriesMeta {
+	pub name: String,
+	pub window: u64, // Time span covered by each data file, in milliseconds
+	pub retention: u64, // How long points are kept, in milliseconds (0: forever)
+	pub rollups: Vec<Rollup>,
+	pub rolled_until: u64, // Windows starting before this are rolled up
+	// Map relating each window start to its number of points.
+	// Not saved in the index: read from the data files at load time.
+	pub windows: BTreeMap<u64, u64>
+}
+
+#[derive(Debug)]
+#[derive(Default)]
+pub struct SeriesIndex {
+	// Map relating each series id to its metadata
+	pub series: HashMap<String, SeriesMeta>
+}
+
+// One bucket of a rollup
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+pub struct Bucket {
+	pub start: u64,
+	pub min: f64,
+	pub max: f64,
+	pub sum: f64,
+	pub count: u64
+}
+
+// The parts of a store holding data files, named after their directory
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq, Eq, Hash)]
+pub enum Area {
+	Singletons,
+	Collections,
+	Edges,
+	KeyValues,
+	Blobs
+}
+
+impl Area {
+	pub fn dir(self) -> &'static str {
+		return match self {
+			Area::Singletons => "singletons",
+			Area::Collections => "collections",
+			Area::Edges => "edges",
+			Area::KeyValues => "keyvalues",
+			Area::Blobs => "blobs"
+		};
+	}
+}
+
+// A location in a data file
+pub struct Range<'a> {
+	pub file: &'a str,
+	pub index: u64,
+	pub length: u64,
+	// Length of the record header before `index`, in framed files
+	pub header: u64
+}
+
+impl SingletonIndex {
+	pub fn update_holes(&mut self) {
+		let ranges = self.singletons.iter().map(|(id, meta)| Range {
+			file: &meta.file, index: meta.index, length: meta.data_length,
+			header: header_length(id, &meta.name)
+		}).collect::<Vec<Range>>();
+		compute_holes(&mut self.files, &ranges);
+	}
+
+	pub fn find(&self, name: &str) -> Option<&String> {
+		return self.singletons.iter()
+			.find(|(_, meta)| meta.name == name)
+			.map(|(id, _)| id)
+		;
+	}
+}
+
+impl CollectionIndex {
+	pub fn update_holes(&mut self) {
+		let ranges = self.items.iter().map(|(id, meta)| Range {
+			file: &meta.file, index: meta.index, length: meta.data_length,
+			header: header_length(id, &meta.collection)
+		}).collect::<Vec<Range>>();
+		compute_holes(&mut self.files, &ranges);
+	}
+
+	pub fn find(&self, name: &str) -> Option<&String> {
+		return self.list.iter()
+			.find(|(_, coll_name)| *coll_name == name)
+			.map(|(id, _)| id)
+		;
+	}
+
+	// How the items written in a collection are compressed
+	pub fn compression_of(&self, id: &str) -> Compression {
+		return self.compression.get(id).copied().unwrap_or(Compression::None);
+	}
+}
+
+impl EdgeIndex {
+	pub fn update_holes(&mut self) {
+		let ranges = self.edges.iter().map(|(id, meta)| Range {
+			file: &meta.file, index: meta.index, length: meta.data_length,
+			header: header_length(id, &meta.label)
+		}).collect::<Vec<Range>>();
+		compute_holes(&mut self.files, &ranges);
+	}
+
+	pub fn link(&mut self, id: &str) {
+		let meta = &self.edges[id];
+		self.outgoing.entry(meta.from.clone()).or_default().push(id.to_owned());
+		self.incoming.entry(meta.to.clone()).or_default().push(id.to_owned());
+	}
+
+	pub fn unlink(&mut self, id: &str) {
+		let meta = &self.edges[id];
+		for (map, item) in [
+			(&mut self.outgoing, &meta.from), (&mut self.incoming, &meta.to)
+		] {
+			if let Some(list) = map.get_mut(item) {
+				list.retain(|e| e != id);
+				if list.is_empty() { map.remove(item); }
+			}
+		}
+	}
+}
+
+impl KeyValueIndex {
+	pub fn update_holes(&mut self) {
+		let ranges = self.entries.iter().map(|(key, meta)| Range {
+			file: &meta.file, index: meta.index, length: meta.data_length,
+			header: header_length(key, "")
+		}).collect::<Vec<Range>>();
+		compute_holes(&mut self.files, &ranges);
+	}
+
+	// Returns the entry of a key, unless it has expired
+	pub fn live(&self, key: &str, now: u64) -> Option<&KeyValueMeta> {
+		return self.entries.get(key)
+			.filter(|meta| meta.expiry == 0 || meta.expiry > now)
+		;
+	}
+}
+
+impl BlobIndex {
+	pub fn update_holes(&mut self) {
+		let mut ranges = Vec::<Range>::new();
+		for (id, meta) in &self.blobs {
+			for (number, chunk) in meta.chunks.iter().enumerate() {
+				ranges.push(Range {
+					file: &chunk.file, index: chunk.index, length: chunk.length,
+					header: header_length(id, &number.to_string())
+				});
+			}
+		}
+		compute_holes(&mut self.files, &ranges);
+	}
+}
+
+impl SeriesIndex {
+	pub fn find(&self, name: &str) -> Option<&String> {
+		return self.series.iter()
+			.find(|(_, meta)| meta.name == name)
+			.map(|(id, _)| id)
+		;
+	}
+}
+
+
+// --> Generic helpers
+// -------------------
+
+fn invalid(what: &str) -> io::Error {
+	return io::Error::new(io::ErrorKind::InvalidData, what.to_owned());
+}
+
+pub fn new_id() -> String {
+	const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+	let mut rng = rand::thread_rng();
+	return (0..ID_LENGTH)
+		.map(|_| CHARS[rng.gen_range(0..CHARS.len())] as char)
+		.collect()
+	;
+}
+
+// Generates an id which is not yet a key of `taken`
+pub fn new_id_in<T>(taken: &HashMap<String, T>) -> String {
+	loop {
+		let id = new_id();
+		if !taken.contains_key(&id) { return id; }
+	}
+}
+
+pub fn read_id(reader: &mut impl Read) -> io::Result<String> {
+	let mut buffer = [0u8; ID_LENGTH];
+	reader.read_exact(&mut buffer)?;
+	return String::from_utf8(buffer.to_vec()).map_err(|_| invalid("Invalid id"));
+}
+
+pub fn write_id(writer: &mut impl Write, id: &str) -> io::Result<()> {
+	if id.len() != ID_LENGTH { return Err(invalid("Invalid id")); }
+	return writer.write_all(id.as_bytes());
+}
+
+pub fn read_name(reader: &mut impl Read) -> io::Result<String> {
+	let length = reader.read_u8()?;
+	let mut buffer = vec![0u8; length as usize];
+	reader.read_exact(&mut buffer)?;
+	return String::from_utf8(buffer).map_err(|_| invalid("Invalid name"));
+}
+
+pub fn write_name(writer: &mut impl Write, name: &str) -> io::Result<()> {
+	if name.len() > u8::MAX as usize { return Err(invalid("Name too long")); }
+	writer.write_u8(name.len() as u8)?;
+	return writer.write_all(name.as_bytes());
+}
+
+fn read_files(reader: &mut impl Read) -> io::Result<HashMap<String, FileMeta>> {
+	let mut files = HashMap::<String, FileMeta>::new();
+	let number = reader.read_u64::<BigEndian>()?;
+	for _ in 0..number {
+		let id = read_id(reader)?;
+		let size = reader.read_u64::<BigEndian>()?;
+		files.insert(id, FileMeta { size, holes: HashMap::new(), framed: false });
+	}
+	return Ok(files);
+}
+
+fn write_files(
+	writer: &mut impl Write, files: &HashMap<String, FileMeta>
+) -> io::Result<()> {
+	writer.write_u64::<BigEndian>(files.len() as u64)?;
+	for (id, meta) in files {
+		write_id(writer, id)?;
+		writer.write_u64::<BigEndian>(meta.size)?;
+	}
+	return Ok(());
+}
+
+// Marks the files starting with `DATA_MAGIC` as framed
+fn detect_framing(store_dir: &Path, area: Area, files: &mut HashMap<String, FileMeta>) {
+	let mut path = store_dir.to_path_buf();
+	path.push(area.dir());
+	for (id, meta) in files.iter_mut() {
+		path.push(id);
+		meta.framed = is_framed(&path);
+		path.pop();
+	}
+}
+
+// Opens an index file, or returns `None` when it is empty
+// (as freshly created by the `create` subcommand)
+fn open_index(path: &Path) -> io::Result<Option<io::BufReader<crypt::File>>> {
+	let file = crypt::File::open(path)?;
+	if file.is_empty()? { return Ok(None); }
+	return Ok(Some(io::BufReader::new(file)));
+}
+
+// Writes a whole file through the store's `tmp/` directory,
+// so that readers never see it half written
+pub fn write_atomic(
+	store_dir: &Path, target: &Path, content: &[u8]
+) -> io::Result<()> {
+	let mut temp = store_dir.to_path_buf();
+	temp.push("tmp");
+	fs::create_dir_all(&temp)?;
+	temp.push(new_id());
+	// The manifest tells whether the store is encrypted: it is never sealed
+	if target.ends_with("manifest.json") {
+		let mut file = fs::File::create(&temp)?;
+		file.write_all(content)?;
+		file.sync_all()?;
+	}
+	else {
+		let mut file = crypt::File::create(&temp)?;
+		file.write_all(content)?;
+		file.sync_all()?;
+	}
+	return fs::rename(temp, target);
+}
+
+// Recomputes the holes of every file from the ranges in use
+pub fn compute_holes(files: &mut HashMap<String, FileMeta>, ranges: &[Range]) {
+	let mut used = HashMap::<&str, Vec<(u64, u64)>>::new();
+	for range in ranges {
+		let header = match files.get(range.file) {
+			Some(meta) if meta.framed => range.header,
+			_ => 0
+		};
+		used.entry(range.file).or_default()
+			.push((range.index.saturating_sub(header), range.length + header))
+		;
+	}
+	for (id, meta) in files.iter_mut() {
+		meta.holes.clear();
+		let mut cursor = if meta.framed { DATA_MAGIC.len() as u64 } else { 0 };
+		let mut spans = used.remove(id.as_str()).unwrap_or_default();
+		spans.sort();
+		for (index, length) in spans {
+			if index > cursor { meta.holes.insert(cursor, index - cursor); }
+			cursor = cursor.max(index + length);
+		}
+		if meta.size > cursor { meta.holes.insert(cursor, meta.size - cursor); }
+	}
+}
+
+
+// --> Data files
+// --------------
+
+pub fn read_data(path: &Path, index: u64, length: u64) -> io::Result<Vec<u8>> {
+	let mut file = crypt::File::open(path)?;
+	file.seek(SeekFrom::Start(index))?;
+	let mut buffer = vec![0u8; length as usize];
+	file.read_exact(&mut buffer)?;
+	return Ok(buffer);
+}
+
+pub fn write_data(path: &Path, index: u64, data: &[u8]) -> io::Result<()> {
+	return write_bytes(path, index, data, true);
+}
+
+// Writes some bytes in a data file, leaving the flush to the disk
+// to the system unless `sync` is set
+pub fn write_bytes(path: &Path, index: u64, data: &[u8], sync: bool) -> io::Result<()> {
+	let mut file = crypt::File::open_rw(path)?;
+	file.seek(SeekFrom::Start(index))?;
+	file.write_all(data)?;
+	if sync { file.sync_data()?; }
+	return Ok(());
+}
+
+// Finds a place for `length` bytes: the smallest hole that fits,
+// else the end of a file that can still grow, else a new file.
+// Files without record headers are never written to anymore.
+pub fn allocate(
+	files: &mut HashMap<String, FileMeta>, length: u64, max_file_size: u64
+) -> (String, u64) {
+	let mut best: Option<(String, u64, u64)> = None;
+	for (id, meta) in files.iter().filter(|(_, meta)| meta.framed) {
+		for (index, hole) in &meta.holes {
+			if *hole >= length && best.as_ref().is_none_or(|b| *hole < b.2) {
+				best = Some((id.clone(), *index, *hole));
+			}
+		}
+	}
+	if let Some((id, index, hole)) = best {
+		let meta = files.get_mut(&id).unwrap();
+		meta.holes.remove(&index);
+		if hole > length { meta.holes.insert(index + length, hole - length); }
+		return (id, index);
+	}
+
+	let growable = files.iter()
+		.filter(|(_, meta)| meta.framed && meta.size + length <= max_file_size)
+		.min_by_key(|(_, meta)| meta.size)
+		.map(|(id, _)| id.clone())
+	;
+	let id = growable.unwrap_or_else(|| {
+		let id = new_id_in(files);
+		files.insert(id.clone(), FileMeta {
+			size: DATA_MAGIC.len() as u64, holes: HashMap::new(), framed: true
+		});
+		id
+	});
+	let meta = files.get_mut(&id).unwrap();
+	let index = meta.size;
+	meta.size += length;
+	return (id, index);
+}
+
+// Finds a place for `length` bytes after all the others, leaving the holes
+// alone so that the entries stay in the order they were written: the end of
+// the tail file while it can grow, else a new file
+pub fn append(
+	files: &mut HashMap<String, FileMeta>, tail: Option<&str>, length: u64, max_file_size: u64
+) -> (String, u64) {
+	let growable = tail
+		.filter(|id| files.get(*id)
+			.is_some_and(|meta| meta.framed && meta.size + length <= max_file_size)
+		)
+		.map(|id| id.to_owned())
+	;
+	let id = growable.unwrap_or_else(|| {
+		let id = new_id_in(files);
+		files.insert(id.clone(), FileMeta {
+			size: DATA_MAGIC.len() as u64, holes: HashMap::new(), framed: true
+		});
+		id
+	});
+	let meta = files.get_mut(&id).unwrap();
+	let index = meta.size;
+	meta.size += length;
+	return (id, index);
+}
+
+// Marks a range as free, merging it with the neighbouring holes
+pub fn release(
+	files: &mut HashMap<String, FileMeta>, file: &str, index: u64, length: u64
+) {
+	if length == 0 { return; }
+	let meta = match files.get_mut(file) {
+		Some(meta) => meta,
+		None => return
+	};
+	let mut start = index;
+	let mut end = index + length;
+	if let Some(next) = meta.holes.remove(&end) { end += next; }
+	let previous = meta.holes.iter()
+		.find(|(i, l)| **i + **l == start)
+		.map(|(i, _)| *i)
+	;
+	if let Some(previous) = previous {
+		meta.holes.remove(&previous);
+		start = previous;
+	}
+	meta.holes.insert(start, end - start);
+}
+
+
+// --> Singletons index
+// --------------------
+
+// Reads the index of the singletons, without looking at the data files
+pub fn read_singletons(reader: &mut impl Read) -> io::Result<SingletonIndex> {
+	let mut index = SingletonIndex {
+		files: read_files(reader)?,
+		singletons: HashMap::new()
+	};
+	let number = reader.read_u64::<BigEndian>()?;
+	for _ in 0..number {
+		let name = read_name(reader)?;
+		let id = read_id(reader)?;
+		let meta = SingletonMeta {
+			name,
+			data_type: reader.read_u8()?,
+			file: read_id(reader)?,
+			index: reader.read_u64::<BigEndian>()?,
+			data_length: reader.read_u64::<BigEndian>()?
+		};
+		index.singletons.insert(id, meta);
+	}
+	return Ok(index);
+}
+
+pub fn load_singletons(store_dir: &Path) -> io::Result<SingletonIndex> {
+	let mut path = store_dir.to_path_buf();
+	path.push("singletons/rixindex");
+	let mut index = match open_index(&path)? {
+		Some(mut reader) => read_singletons(&mut reader)?,
+		None => return Ok(SingletonIndex::default())
+	};
+	detect_framing(store_dir, Area::Singletons, &mut index.files);
+	index.update_holes();
+	return Ok(index);
+}
+
+pub fn save_singletons(store_dir: &Path, index: &SingletonIndex) -> io::Result<()> {
+	let mut content = Vec::<u8>::new();
+	write_files(&mut content, &index.files)?;
+	content.write_u64::<BigEndian>(index.singletons.len() as u64)?;
+	for (id, meta) in &index.singletons {
+		write_name(&mut content, &meta.name)?;
+		write_id(&mut content, id)?;
+		content.write_u8(meta.data_type)?;
+		write_id(&mut content, &meta.file)?;
+		content.write_u64::<BigEndian>(meta.index)?;
+		content.write_u64::<BigEndian>(meta.data_length)?;
+	}
+
+	let mut path = store_dir.to_path_buf();
+	path.push("singletons/rixindex");
+	return write_atomic(store_dir, &path, &content);
+}
+
+
+// --> Collections index
+// ---------------------
+
+// Reads as many collection names as possible from the start of the index,
+// which may be damaged further
+pub fn load_collection_list(store_dir: &Path) -> HashMap<String, String> {
+	let mut list = HashMap::<String, String>::new();
+	let mut path = store_dir.to_path_buf();
+	path.push("collections/rixindex");
+	let mut reader = match open_index(&path) {
+		Ok(Some(reader)) => reader,
+		_ => return list
+	};
+	let number = reader.read_u64::<BigEndian>().unwrap_or(0);
+	for _ in 0..number {
+		let id = match read_id(&mut reader) {
+			Ok(id) => id,
+			Err(_) => break
+		};
+		match read_name(&mut reader) {
+			Ok(name) => list.insert(id, name),
+			Err(_) => break
+		};
+	}
+	return list;
+}
+
+// Reads the index of the collections, without looking at the data files
+pub fn read_collections(reader: &mut impl Read) -> io::Result<CollectionIndex> {
+	let mut index = CollectionIndex::default();
+	let number = reader.read_u64::<BigEndian>()?;
+	for _ in 0..number {
+		let id = read_id(reader)?;
+		index.list.insert(id, read_name(reader)?);
+	}
+	index.files = read_files(reader)?;
+	let number = reader.read_u64::<BigEndian>()?;
+	for _ in 0..number {
+		let id = read_id(reader)?;
+		let meta = CollectionMeta {
+			collection: read_id(reader)?,
+			data_type: reader.read_u8()?,
+			file: read_id(reader)?,
+			index: reader.read_u64::<BigEndian>()?,
+			data_length: reader.read_u64::<BigEndian>()?
+		};
+		index.items.insert(id, meta);
+	}
+	// The compression of the collections, which the older indexes lack
+	let number = match reader.read_u64::<BigEndian>() {
+		Ok(number) => number,
+		Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => 0,
+		Err(e) => return Err(e)
+	};
+	for _ in 0..number {
+		let id = read_id(reader)?;
+		let compression = Compression::from_u8(reader.read_u8()?)
+			.ok_or(invalid("Unknown compression"))?
+		;
+		index.compression.insert(id, compression);
+	}
+	return Ok(index);
+}
+
+pub fn load_collections(store_dir: &Path) -> io::Result<CollectionIndex> {
+	let mut path = store_dir.to_path_buf();
+	path.push("collections/rixindex");
+	let mut index = match open_index(&path)? {
+		Some(mut reader) => read_collections(&mut reader)?,
+		None => return Ok(CollectionIndex::default())
+	};
+	detect_framing(store_dir, Area::Collections, &mut index.files);
+	index.update_holes();
+	return Ok(index);
+}
+
+pub fn save_collections(
+	store_dir: &Path, index: &CollectionIndex
+) -> io::Result<()> {
+	let mut content = Vec::<u8>::new();
+	content.write_u64::<BigEndian>(index.list.len() as u64)?;
+	for (id, name) in &index.list {
+		write_id(&mut content, id)?;
+		write_name(&mut content, name)?;
+	}
+	write_files(&mut content, &index.files)?;
+	content.write_u64::<BigEndian>(index.items.len() as u64)?;
+	for (id, meta) in &index.items {
+		write_id(&mut content, id)?;
+		write_id(&mut content, &meta.collection)?;
+		content.write_u8(meta.data_type)?;
+		write_id(&mut content, &meta.file)?;
+		content.write_u64::<BigEndian>(meta.index)?;
+		content.write_u64::<BigEndian>(meta.data_length)?;
+	}
+	content.write_u64::<BigEndian>(index.compression.len() as u64)?;
+	for (id, compression) in &index.compression {
+		write_id(&mut content, id)?;
+		content.write_u8(compression.to_u8())?;
+	}
+
+	let mut path = store_dir.to_path_buf();
+	path.push("collections/rixindex");
+	return write_atomic(store_dir, &path, &content);
+}
+
+
+// --> Edges index and adjacency lists
+// -----------------------------------
+
+fn read_adjacency(
+	reader: &mut impl Read
+) -> io::Result<HashMap<String, Vec<String>>> {
+	let mut lists = HashMap::<String, Vec<String>>::new();
+	let number = reader.read_u64::<BigEndian>()?;
+	for _ in 0..number {
+		let item = read_id(reader)?;
+		let length = reader.read_u64::<BigEndian>()?;
+		let mut list = Vec::<String>::new();
+		for _ in 0..length { list.push(read_id(reader)?); }
+		lists.insert(item, list);
+	}
+	return Ok(lists);
+}
+
+fn write_adjacency(
+	writer: &mut impl Write, lists: &HashMap<String, Vec<String>>
+) -> io::Result<()> {
+	writer.write_u64::<BigEndian>(lists.len() as u64)?;
+	for (item, list) in lists {
+		write_id(writer, item)?;
+		writer.write_u64::<BigEndian>(list.len() as u64)?;
+		for edge in list { write_id(writer, edge)?; }
+	}
+	return Ok(());
+}
+
+// Reads the index of the edges, without looking at the data files
+pub fn read_edges(reader: &mut impl Read) -> io::Result<EdgeIndex> {
+	let mut index = EdgeIndex {
+		files: read_files(reader)?,
+		..EdgeIndex::default()
+	};
+	let number = reader.read_u64::<BigEndian>()?;
+	for _ in 0..number {
+		let id = read_id(reader)?;
+		let meta = EdgeMeta {
+			label: read_name(reader)?,
+			from: read_id(reader)?,
+			to: read_id(reader)?,
+			data_type: reader.read_u8()?,
+			file: read_id(reader)?,
+			index: reader.read_u64::<BigEndian>()?,
+			data_length: reader.read_u64::<BigEndian>()?
+		};
+		index.edges.insert(id, meta);
+	}
+	return Ok(index);
+}
+
+pub fn load_edges(store_dir: &Path) -> io::Result<EdgeIndex> {
+	let mut path = store_dir.to_path_buf();
+	path.push("edges/rixindex");
+	let mut index = match open_index(&path)? {
+		Some(mut reader) => read_edges(&mut reader)?,
+		None => return Ok(EdgeIndex::default())
+	};
+	detect_framing(store_dir, Area::Edges, &mut index.files);
+	index.update_holes();
+
+	path.set_file_name("adjacency");
+	let mut adjacency = if path.exists() { open_index(&path)? } else { None };
+	read_adjacency_lists(&mut index, adjacency.as_mut().map(|r| r as &mut dyn Read))?;
+	return Ok(index);
+}
+
+// Reads the adjacency lists of the edges. They are rebuilt from the edges
+// when their file is missing or empty.
+pub fn read_adjacency_lists(
+	index: &mut EdgeIndex, reader: Option<&mut dyn Read>
+) -> io::Result<()> {
+	match reader {
+		Some(mut reader) => {
+			index.outgoing = read_adjacency(&mut reader)?;
+			index.incoming = read_adjacency(&mut reader)?;
+		},
+		None => {
+			let ids = index.edges.keys().cloned().collect::<Vec<String>>();
+			for id in ids { index.link(&id); }
+		}
+	}
+	return Ok(());
+}
+
+pub fn save_edges(store_dir: &Path, index: &EdgeIndex) -> io::Result<()> {
+	let mut content = Vec::<u8>::new();
+	write_files(&mut content, &index.files)?;
+	content.write_u64::<BigEndian>(index.edges.len() as u64)?;
+	for (id, meta) in &index.edges {
+		write_id(&mut content, id)?;
+		write_name(&mut content, &meta.label)?;
+		write_id(&mut content, &meta.from)?;
+		write_id(&mut content, &meta.to)?;
+		content.write_u8(meta.data_type)?;
+		write_id(&mut content, &meta.file)?;
+		content.write_u64::<BigEndian>(meta.index)?;
+		content.write_u64::<BigEndian>(meta.data_length)?;
+	}
+
+	let mut path = store_dir.to_path_buf();
+	path.push("edges/rixindex");
+	write_atomic(store_dir, &path, &content)?;
+
+	content.clear();
+	write_adjacency(&mut content, &index.outgoing)?;
+	write_adjacency(&mut content, &index.incoming)?;
+	path.set_file_name("adjacency");
+	return write_atomic(store_dir, &path, &content);
+}
+
+
+// --> Key-value index
+// -------------------
+
+// Reads the index of the key-value entries, without looking at the data files
+pub fn read_keyvalues(reader: &mut impl Read) -> io::Result<KeyValueIndex> {
+	let mut index = KeyValueIndex {
+		files: read_files(reader)?,
+		entries: HashMap::new()
+	};
+	let number = reader.read_u64::<BigEndian>()?;
+	for _ in 0..number {
+		let key = read_name(reader)?;
+		let meta = KeyValueMeta {
+			data_type: reader.read_u8()?,
+			file: read_id(reader)?,
+			index: reader.read_u64::<BigEndian>()?,
+			data_length: reader.read_u64::<BigEndian>()?,
+			expiry: reader.read_u64::<BigEndian>()?
+		};
+		index.entries.insert(key, meta);
+	}
+	return Ok(index);
+}
+
+pub fn load_keyvalues(store_dir: &Path) -> io::Result<KeyValueIndex> {
+	let mut path = store_dir.to_path_buf();
+	path.push("keyvalues/rixindex");
+	let mut index = match open_index(&path)? {
+		Some(mut reader) => read_keyvalues(&mut reader)?,
+		None => return Ok(KeyValueIndex::default())
+	};
+	detect_framing(store_dir, Area::KeyValues, &mut index.files);
+	index.update_holes();
+	return Ok(index);
+}
+
+pub fn save_keyvalues(store_dir: &Path, index: &KeyValueIndex) -> io::Result<()> {
+	let mut content = Vec::<u8>::new();
+	write_files(&mut content, &index.files)?;
+	content.write_u64::<BigEndian>(index.entries.len() as u64)?;
+	for (key, meta) in &index.entries {
+		write_name(&mut content, key)?;
+		content.write_u8(meta.data_type)?;
+		write_id(&mut content, &meta.file)?;
+		content.write_u64::<BigEndian>(meta.index)?;
+		content.write_u64::<BigEndian>(meta.data_length)?;
+		content.write_u64::<BigEndian>(meta.expiry)?;
+	}
+
+	let mut path = store_dir.to_path_buf();
+	path.push("keyvalues/rixindex");
+	return write_atomic(store_dir, &path, &content);
+}
+
+
+// --> Time series
+// ---------------
+
+// Every point is stored as a big endian u64 time and f64 value
+pub const POINT_SIZE: u64 = 16;
+// Every rollup bucket is stored as a big endian u64 start,
+// f64 minimum, f64 maximum, f64 sum and u64 count
+pub const BUCKET_SIZE: u64 = 40;
+
+// Reads the series of the index, without looking at their data files
+pub fn read_series_list(reader: &mut impl Read) -> io::Result<SeriesIndex> {
+	let mut index = SeriesIndex::default();
+	let number = reader.read_u64::<BigEndian>()?;
+	for _ in 0..number {
+		let id = read_id(reader)?;
+		let mut meta = SeriesMeta {
+			name: read_name(reader)?,
+			window: reader.read_u64::<BigEndian>()?,
+			retention: reader.read_u64::<BigEndian>()?,
+			rollups: Vec::new(),
+			rolled_until: 0,
+			windows: BTreeMap::new()
+		};
+		let rollups = reader.read_u8()?;
+		for _ in 0..rollups {
+			meta.rollups.push(Rollup {
+				interval: reader.read_u64::<BigEndian>()?,
+				retention: reader.read_u64::<BigEndian>()?
+			});
+		}
+		meta.rolled_until = reader.read_u64::<BigEndian>()?;
+		index.series.insert(id, meta);
+	}
+	return Ok(index);
+}
+
+pub fn load_series_list(store_dir: &Path) -> io::Result<SeriesIndex> {
+	let mut path = store_dir.to_path_buf();
+	path.push("timeseries/rixindex");
+	return match open_index(&path)? {
+		Some(mut reader) => read_series_list(&mut reader),
+		None => Ok(SeriesIndex::default())
+	};
+}
+
+pub fn load_timeseries(store_dir: &Path) -> io::Result<SeriesIndex> {
+	let mut index = load_series_list(store_dir)?;
+
+	// The windows are the data files named after their start time.
+	// A point half written during a crash is dropped.
+	let mut path = store_dir.to_path_buf();
+	path.push("timeseries");
+	for (id, meta) in index.series.iter_mut() {
+		path.push(id);
+		if path.is_dir() {
+			for entry in fs::read_dir(&path)? {
+				let entry = entry?;
+				let start = match entry.file_name().to_str()
+					.and_then(|n| n.parse::<u64>().ok())
+				{
+					Some(start) => start,
+					None => continue
+				};
+				let size = crypt::len(&entry.path())?;
+				if size % POINT_SIZE != 0 {
+					crypt::File::open_rw(&entry.path())?.set_len(size - size % POINT_SIZE)?;
+					if checksum_path(store_dir, &entry.path()).exists() {
+						update_checksums(store_dir, &entry.path(), size - size % POINT_SIZE, 0)?;
+					}
+				}
+				meta.windows.insert(start, size / POINT_SIZE);
+			}
+		}
+		path.pop();
+	}
+	return Ok(index);
+}
+
+pub fn save_timeseries(store_dir: &Path, index: &SeriesIndex) -> io::Result<()> {
+	let mut content = Vec::<u8>::new();
+	content.write_u64::<BigEndian>(index.series.len() as u64)?;
+	for (id, meta) in &index.series {
+		write_id(&mut content, id)?;
+		write_name(&mut content, &meta.name)?;
+		content.write_u64::<BigEndian>(meta.window)?;
+		content.write_u64::<BigEndian>(meta.retention)?;
+		content.write_u8(meta.rollups.len() as u8)?;
+		for rollup in &meta.rollups {
+			content.write_u64::<BigEndian>(rollup.interval)?;
+			content.write_u64::<BigEndian>(rollup.retention)?;
+		}
+		content.write_u64::<BigEndian>(meta.rolled_until)?;
+	}
+
+	let mut path = store_dir.to_path_buf();
+	path.push("timeseries/rixindex");
+	return write_atomic(store_dir, &path, &content);
+}
+
+// Returns the offset of the first point appended
+pub fn append_points(path: &Path, points: &[(u64, f64)]) -> io::Result<u64> {
+	let mut content = Vec::<u8>::with_capacity(points.len() * POINT_SIZE as usize);
+	for (time, value) in points {
+		content.write_u64::<BigEndian>(*time)?;
+		content.write_f64::<BigEndian>(*value)?;
+	}
+	let mut file = crypt::File::append(path)?;
+	let offset = file.len()?;
+	file.write_all(&content)?;
+	file.sync_data()?;
+	return Ok(offset);
+}
+
+// Reads all the points of a window, sorted by time
+pub fn read_points(path: &Path) -> io::Result<Vec<(u64, f64)>> {
+	return decode_points(&crypt::read(path)?);
+}
+
+// Decodes the content of a window, sorted by time
+pub fn decode_points(mut content: &[u8]) -> io::Result<Vec<(u64, f64)>> {
+	let count = content.len() as u64 / POINT_SIZE;
+	let mut points = Vec::<(u64, f64)>::with_capacity(count as usize);
+	for _ in 0..count {
+		points.push((
+			content.read_u64::<BigEndian>()?,
+			content.read_f64::<BigEndian>()?
+		));
+	}
+	points.sort_by_key(|p| p.0);
+	return Ok(points);
+}
+
+pub fn read_buckets(path: &Path) -> io::Result<Vec<Bucket>> {
+	if !path.exists() { return Ok(Vec::new()); }
+	return decode_buckets(&crypt::read(path)?);
+}
+
+pub fn decode_buckets(mut content: &[u8]) -> io::Result<Vec<Bucket>> {
+	let count = content.len() as u64 / BUCKET_SIZE;
+	let mut buckets = Vec::<Bucket>::with_capacity(count as usize);
+	for _ in 0..count {
+		buckets.push(Bucket {
+			start: content.read_u64::<BigEndian>()?,
+			min: content.read_f64::<BigEndian>()?,
+			max: content.read_f64::<BigEndian>()?,
+			sum: content.read_f64::<BigEndian>()?,
+			count: content.read_u64::<BigEndian>()?
+		});
+	}
+	return Ok(buckets);
+}
+
+fn encode_buckets(buckets: &[Bucket]) -> io::Result<Vec<u8>> {
+	let mut content = Vec::<u8>::with_capacity(buckets.len() * BUCKET_SIZE as usize);
+	for bucket in buckets {
+		content.write_u64::<BigEndian>(bucket.start)?;
+		content.write_f64::<BigEndian>(bucket.min)?;
+		content.write_f64::<BigEndian>(bucket.max)?;
+		content.write_f64::<BigEndian>(bucket.sum)?;
+		content.write_u64::<BigEndian>(bucket.count)?;
+	}
+	return Ok(content);
+}
+
+// Returns the offset of the first bucket appended
+pub fn append_buckets(path: &Path, buckets: &[Bucket]) -> io::Result<u64> {
+	let content = encode_buckets(buckets)?;
+	let mut file = crypt::File::append(path)?;
+	let offset = file.len()?;
+	file.write_all(&content)?;
+	file.sync_data()?;
+	return Ok(offset);
+}
+
+pub fn write_buckets(
+	store_dir: &Path, path: &Path, buckets: &[Bucket]
+) -> io::Result<()> {
+	return write_atomic(store_dir, path, &encode_buckets(buckets)?);
+}
+
+// Groups sorted points into buckets of `interval` milliseconds
+pub fn downsample(points: &[(u64, f64)], interval: u64) -> Vec<Bucket> {
+	let mut buckets = Vec::<Bucket>::new();
+	for (time, value) in points {
+		let start = time - time % interval;
+		match buckets.last_mut() {
+			Some(bucket) if bucket.start == start => {
+				bucket.min = bucket.min.min(*value);
+				bucket.max = bucket.max.max(*value);
+				bucket.sum += value;
+				bucket.count += 1;
+			},
+			_ => buckets.push(Bucket {
+				start, min: *value, max: *value, sum: *value, count: 1
+			})
+		}
+	}
+	return buckets;
+}
+
+
+// --> Geo-point index of the collections
+// --------------------------------------
+
+// Returns `None` when the file doesn't exist yet,
+// so that the index gets rebuilt from the items
+pub fn load_geoindex(
+	store_dir: &Path, collections: &CollectionIndex
+) -> io::Result<Option<HashMap<String, GeoIndex>>> {
+	let mut path = store_dir.to_path_buf();
+	path.push("collections/geoindex");
+	if !path.exists() { return Ok(None); }
+	return match open_index(&path)? {
+		Some(mut reader) => read_geoindex(&mut reader, collections).map(Some),
+		None => Ok(Some(HashMap::new()))
+	};
+}
+
+// Reads the geo-points of the items, sorted by collection
+pub fn read_geoindex(
+	reader: &mut impl Read, collections: &CollectionIndex
+) -> io::Result<HashMap<String, GeoIndex>> {
+	let mut indexes = HashMap::<String, GeoIndex>::new();
+	let number = reader.read_u64::<BigEndian>()?;
+	for _ in 0..number {
+		let item = read_id(reader)?;
+		let fields = reader.read_u8()?;
+		let mut points = Vec::<(String, f64, f64)>::new();
+		for _ in 0..fields {
+			points.push((
+				read_name(reader)?,
+				reader.read_f64::<BigEndian>()?,
+				reader.read_f64::<BigEndian>()?
+			));
+		}
+		// The points of an item whose insertion was interrupted are dropped
+		let collection = match collections.items.get(&item) {
+			Some(meta) => meta.collection.clone(),
+			None => continue
+		};
+		indexes.entry(collection).or_default().insert_points(&item, points);
+	}
+	return Ok(indexes);
+}
+
+pub fn save_geoindex(
+	store_dir: &Path, indexes: &HashMap<String, GeoIndex>
+) -> io::Result<()> {
+	let mut content = Vec::<u8>::new();
+	let items = indexes.values().flat_map(|index| index.points.iter())
+		.collect::<Vec<_>>()
+	;
+	content.write_u64::<BigEndian>(items.len() as u64)?;
+	for (item, points) in items {
+		if points.len() > u8::MAX as usize {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput, format!("Too many geo-points for the item {}", item)
+			));
+		}
+		write_id(&mut content, item)?;
+		content.write_u8(points.len() as u8)?;
+		for (field, lon, lat) in points {
+			write_name(&mut content, field)?;
+			content.write_f64::<BigEndian>(*lon)?;
+			content.write_f64::<BigEndian>(*lat)?;
+		}
+	}
+
+	let mut path = store_dir.to_path_buf();
+	path.push("collections/geoindex");
+	return write_atomic(store_dir, &path, &content);
+}
+
+
+// --> Blobs index
+// ---------------
+
+// Reads the index of the blobs, without looking at the data files
+pub fn read_blobs(reader: &mut impl Read) -> io::Result<BlobIndex> {
+	let mut index = BlobIndex {
+		files: read_files(reader)?,
+		blobs: HashMap::new()
+	};
+	let number = reader.read_u64::<BigEndian>()?;
+	for _ in 0..number {
+		let id = read_id(reader)?;
+		let mut meta = BlobMeta {
+			content_type: read_name(reader)?,
+			size: reader.read_u64::<BigEndian>()?,
+			chunks: Vec::new()
+		};
+		let chunks = reader.read_u64::<BigEndian>()?;
+		for _ in 0..chunks {
+			meta.chunks.push(Chunk {
+				file: read_id(reader)?,
+				index: reader.read_u64::<BigEndian>()?,
+				length: reader.read_u64::<BigEndian>()?
+			});
+		}
+		index.blobs.insert(id, meta);
+	}
+	return Ok(index);
+}
+
+pub fn load_blobs(store_dir: &Path) -> io::Result<BlobIndex> {
+	let mut path = store_dir.to_path_buf();
+	path.push("blobs/rixindex");
+	let mut index = match open_index(&path)? {
+		Some(mut reader) => read_blobs(&mut reader)?,
+		None => return Ok(BlobIndex::default())
+	};
+	detect_framing(store_dir, Area::Blobs, &mut index.files);
+	index.update_holes();
+	return Ok(index);
+}
+
+pub fn save_blobs(store_dir: &Path, index: &BlobIndex) -> io::Result<()> {
+	let mut content = Vec::<u8>::new();
+	write_files(&mut content, &index.files)?;
+	content.write_u64::<BigEndian>(index.blobs.len() as u64)?;
+	for (id, meta) in &index.blobs {
+		write_id(&mut content, id)?;
+		write_name(&mut content, &meta.content_type)?;
+		content.write_u64::<BigEndian>(meta.size)?;
+		content.write_u64::<BigEndian>(meta.chunks.len() as u64)?;
+		for chunk in &meta.chunks {
+			write_id(&mut content, &chunk.file)?;
+			content.write_u64::<BigEndian>(chunk.index)?;
+			content.write_u64::<BigEndian>(chunk.length)?;
+		}
+	}
+
+	let mut path = store_dir.to_path_buf();
+	path.push("blobs/rixindex");
+	return write_atomic(store_dir, &path, &content);
+}
+
+
+// --> Checksums
+// -------------
+
+// Every data file is split in blocks of this size, each having
+// a big endian CRC32C stored in the checksum file of the data file
+pub const BLOCK_SIZE: u64 = 4 * 1024;
+
+// Checksum file of a data file: its path in the store, under `checksums/`
+pub fn checksum_path(store_dir: &Path, data_path: &Path) -> PathBuf {
+	let relative = data_path.strip_prefix(store_dir).unwrap_or(data_path);
+	let mut path = store_dir.to_path_buf();
+	path.push("checksums");
+	path.push(relative);
+	return path;
+}
+
+fn read_checksums(path: &Path) -> io::Result<Vec<u32>> {
+	let bytes = match crypt::read(path) {
+		Ok(bytes) => bytes,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(e) => return Err(e)
+	};
+	return Ok(bytes.chunks_exact(4)
+		.map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+		.collect()
+	);
+}
+
+// Recomputes the checksums of the blocks holding the bytes from `index`
+// to `index + length`, along with the ones of the blocks having none yet
+pub fn update_checksums(
+	store_dir: &Path, data_path: &Path, index: u64, length: u64
+) -> io::Result<()> {
+	let mut data = crypt::File::open(data_path)?;
+	let size = data.len()?;
+	let path = checksum_path(store_dir, data_path);
+	fs::create_dir_all(path.parent().unwrap())?;
+	let mut sums = crypt::File::open_rw(&path)?;
+
+	let blocks = size.div_ceil(BLOCK_SIZE);
+	let existing = sums.len()? / 4;
+	let start = (index / BLOCK_SIZE).min(existing);
+	let end = if existing < blocks { blocks }
+		else { index.saturating_add(length).div_ceil(BLOCK_SIZE).min(blocks) }
+	;
+	let mut content = Vec::<u8>::new();
+	let mut buffer = vec![0u8; BLOCK_SIZE as usize];
+	data.seek(SeekFrom::Start(start * BLOCK_SIZE))?;
+	for block in start..end {
+		let length = (size - block * BLOCK_SIZE).min(BLOCK_SIZE) as usize;
+		data.read_exact(&mut buffer[..length])?;
+		content.write_u32::<BigEndian>(crc32c::crc32c(&buffer[..length]))?;
+	}
+	sums.seek(SeekFrom::Start(start * 4))?;
+	sums.write_all(&content)?;
+	sums.set_len(blocks * 4)?;
+	return sums.sync_data();
+}
+
+// Recomputes all the checksums of a data file
+pub fn rebuild_checksums(store_dir: &Path, data_path: &Path) -> io::Result<()> {
+	let path = checksum_path(store_dir, data_path);
+	if path.exists() { fs::remove_file(&path)?; }
+	return update_checksums(store_dir, data_path, 0, 0);
+}
+
+pub fn remove_checksums(store_dir: &Path, data_path: &Path) -> io::Result<()> {
+	let path = checksum_path(store_dir, data_path);
+	if path.is_dir() { return fs::remove_dir_all(path); }
+	if path.exists() { return fs::remove_file(path); }
+	return Ok(());
+}
+
+fn mismatch(store_dir: &Path, data_path: &Path, block: u64) -> io::Error {
+	let relative = data_path.strip_prefix(store_dir).unwrap_or(data_path);
+	return io::Error::new(io::ErrorKind::InvalidData, format!(
+		"Checksum mismatch in block {} (bytes {} to {}) of {}",
+		block, block * BLOCK_SIZE, (block + 1) * BLOCK_SIZE - 1, relative.display()
+	));
+}
+
+// Checks consecutive blocks of a data file, starting at the block `first`,
+// against its checksums. The blocks past the checksummed ones are skipped.
+pub fn check_blocks(
+	store_dir: &Path, data_path: &Path, first: u64, blocks: &[u8]
+) -> io::Result<()> {
+	let sums = read_checksums(&checksum_path(store_dir, data_path))?;
+	for (number, block) in blocks.chunks(BLOCK_SIZE as usize).enumerate() {
+		let block_id = first + number as u64;
+		let expected = match sums.get(block_id as usize) {
+			Some(sum) => *sum,
+			None => break
+		};
+		if crc32c::crc32c(block) != expected {
+			return Err(mismatch(store_dir, data_path, block_id));
+		}
+	}
+	return Ok(());
+}
+
+// Same as `read_data`, but checks the blocks holding the bytes read.
+// The blocks having no checksum yet are not checked.
+pub fn read_verified(
+	store_dir: &Path, data_path: &Path, index: u64, length: u64
+) -> io::Result<Vec<u8>> {
+	let mut file = crypt::File::open(data_path)?;
+	let size = file.len()?;
+	let end = index.checked_add(length)
+		.filter(|end| *end <= size)
+		.ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))?
+	;
+	let first = index / BLOCK_SIZE;
+	let start = first * BLOCK_SIZE;
+	let stop = (end.div_ceil(BLOCK_SIZE) * BLOCK_SIZE).min(size);
+	let mut buffer = vec![0u8; (stop - start) as usize];
+	file.seek(SeekFrom::Start(start))?;
+	file.read_exact(&mut buffer)?;
+
+	check_blocks(store_dir, data_path, first, &buffer)?;
+	buffer.drain(..(index - start) as usize);
+	buffer.truncate(length as usize);
+	return Ok(buffer);
+}
+
+// Reads a block of a data file, shorter than the others at the end of the
+// file, verified against its checksum when `store_dir` is given
+pub fn read_block(store_dir: Option<&Path>, data_path: &Path, block: u64) -> io::Result<Vec<u8>> {
+	let mut file = crypt::File::open(data_path)?;
+	let start = block * BLOCK_SIZE;
+	let size = file.len()?;
+	if start >= size { return Err(io::Error::from(io::ErrorKind::UnexpectedEof)); }
+	let mut buffer = vec![0u8; (size - start).min(BLOCK_SIZE) as usize];
+	file.seek(SeekFrom::Start(start))?;
+	file.read_exact(&mut buffer)?;
+	if let Some(store_dir) = store_dir { check_blocks(store_dir, data_path, block, &buffer)?; }
+	return Ok(buffer);
+}
+
+// Same as `read_verified`, for a whole file
+pub fn verify_file(store_dir: &Path, data_path: &Path) -> io::Result<()> {
+	let size = crypt::len(data_path)?;
+	read_verified(store_dir, data_path, 0, size)?;
+	return Ok(());
+}
+
+// Lists the blocks of a data file failing their checksum or having none.
+// Returns `None` when the file has no checksum file.
+pub fn corrupted_blocks(store_dir: &Path, data_path: &Path) -> io::Result<Option<Vec<u64>>> {
+	return corrupted_blocks_in(store_dir, data_path, 0, u64::MAX);
+}
+
+// Same as `corrupted_blocks`, for `count` blocks from the `first` one
+pub fn corrupted_blocks_in(
+	store_dir: &Path, data_path: &Path, first: u64, count: u64
+) -> io::Result<Option<Vec<u64>>> {
+	let mut sums = match crypt::File::open(&checksum_path(store_dir, data_path)) {
+		Ok(sums) => io::BufReader::new(sums),
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+		Err(e) => return Err(e)
+	};
+	let mut reader = io::BufReader::new(crypt::File::open(data_path)?);
+	let size = reader.get_ref().len()?;
+	let known = sums.get_ref().len()? / 4;
+	let end = size.div_ceil(BLOCK_SIZE).min(first.saturating_add(count));
+	reader.seek(SeekFrom::Start(first * BLOCK_SIZE))?;
+	sums.seek(SeekFrom::Start(first.min(known) * 4))?;
+
+	let mut corrupted = Vec::<u64>::new();
+	let mut buffer = vec![0u8; BLOCK_SIZE as usize];
+	for block in first..end {
+		let length = (size - block * BLOCK_SIZE).min(BLOCK_SIZE) as usize;
+		reader.read_exact(&mut buffer[..length])?;
+		let expected = match block < known {
+			true => Some(sums.read_u32::<BigEndian>()?),
+			false => None
+		};
+		if expected != Some(crc32c::crc32c(&buffer[..length])) {
+			corrupted.push(block);
+		}
+	}
+	return Ok(Some(corrupted));
+}
+
+
+// --> Record headers
+// ------------------
+
+// Start of the data files whose records have headers
+pub const DATA_MAGIC: &[u8; 8] = b"RIXDATA1";
+const RECORD_MAGIC: &[u8; 4] = b"RIXR";
+const LIVE: u8 = 1;
+const FREED: u8 = 0;
+
+// What a record header tells about the entry it belongs to
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct RecordTag {
+	pub id: String, // Item, singleton, edge or blob id, or key
+	pub owner: String, // Collection id, singleton name, edge label or chunk number
+	pub data_type: u8
+}
+
+// A record header, as read from a data file
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct RecordHeader {
+	pub tag: RecordTag,
+	pub live: bool,
+	pub stamp: u64, // Unix time of the write, in milliseconds
+	pub length: u64,
+	pub payload_crc: u32
+}
+
+// Every header is: the record magic, a u8 state (live or freed), the u8
+// data type, the u64 stamp, the u64 payload length, the payload CRC32C,
+// the id and owner (as names), the CRC32C of the header (state excluded)
+// and the u16 length of the whole header, just before the payload.
+pub fn header_length(id: &str, owner: &str) -> u64 {
+	return 34 + id.len() as u64 + owner.len() as u64;
+}
+
+pub fn is_framed(path: &Path) -> bool {
+	let mut start = [0u8; DATA_MAGIC.len()];
+	return crypt::File::open(path)
+		.and_then(|mut file| file.read_exact(&mut start))
+		.is_ok_and(|_| &start == DATA_MAGIC)
+	;
+}
+
+fn header_crc(header: &[u8]) -> u32 {
+	let crc = crc32c::crc32c(&header[..4]);
+	return crc32c::crc32c_append(crc, &header[5..header.len() - 6]);
+}
+
+// The header followed by the payload
+pub fn encode_record(tag: &RecordTag, stamp: u64, data: &[u8]) -> io::Result<Vec<u8>> {
+	let length = header_length(&tag.id, &tag.owner);
+	let mut record = Vec::<u8>::with_capacity(length as usize + data.len());
+	record.write_all(RECORD_MAGIC)?;
+	record.write_u8(LIVE)?;
+	record.write_u8(tag.data_type)?;
+	record.write_u64::<BigEndian>(stamp)?;
+	record.write_u64::<BigEndian>(data.len() as u64)?;
+	record.write_u32::<BigEndian>(crc32c::crc32c(data))?;
+	write_name(&mut record, &tag.id)?;
+	write_name(&mut record, &tag.owner)?;
+	record.write_u32::<BigEndian>(0)?;
+	record.write_u16::<BigEndian>(length as u16)?;
+	let crc = header_crc(&record);
+	record[length as usize - 6..length as usize - 2].copy_from_slice(&crc.to_be_bytes());
+	record.extend_from_slice(data);
+	return Ok(record);
+}
+
+// Reads a header from its first bytes, checking its integrity
+pub fn decode_header(bytes: &[u8]) -> Option<RecordHeader> {
+	if bytes.len() < 34 || &bytes[..4] != RECORD_MAGIC { return None; }
+	let mut reader = &bytes[4..];
+	let state = reader.read_u8().ok()?;
+	let data_type = reader.read_u8().ok()?;
+	let stamp = reader.read_u64::<BigEndian>().ok()?;
+	let length = reader.read_u64::<BigEndian>().ok()?;
+	let payload_crc = reader.read_u32::<BigEndian>().ok()?;
+	let id = read_name(&mut reader).ok()?;
+	let owner = read_name(&mut reader).ok()?;
+	let crc = reader.read_u32::<BigEndian>().ok()?;
+	let total = reader.read_u16::<BigEndian>().ok()? as u64;
+	if total != header_length(&id, &owner) { return None; }
+	if header_crc(&bytes[..total as usize]) != crc { return None; }
+	if state != LIVE && state != FREED { return None; }
+	return Some(RecordHeader {
+		tag: RecordTag { id, owner, data_type },
+		live: state == LIVE,
+		stamp,
+		length,
+		payload_crc
+	});
+}
+
+// Reads the header of the record whose payload starts at `index`,
+// and returns it with its own starting index
+pub fn read_header(path: &Path, index: u64) -> io::Result<Option<(u64, RecordHeader)>> {
+	if index < 34 { return Ok(None); }
+	let mut file = crypt::File::open(path)?;
+	file.seek(SeekFrom::Start(index - 2))?;
+	let length = file.read_u16::<BigEndian>()? as u64;
+	if length < 34 || length > index { return Ok(None); }
+	let mut bytes = vec![0u8; length as usize];
+	file.seek(SeekFrom::Start(index - length))?;
+	file.read_exact(&mut bytes)?;
+	return Ok(decode_header(&bytes).map(|header| (index - length, header)));
+}
+
+// Marks the record whose header starts at `start` as freed,
+// so that it is not recovered when the index is rebuilt
+pub fn mark_freed(path: &Path, start: u64) -> io::Result<()> {
+	return write_data(path, start + RECORD_MAGIC.len() as u64, &[FREED]);
+}
+
+// A record found while scanning a data file
+#[derive(Debug)]
+pub struct ScannedRecord {
+	pub start: u64, // Index of the header
+	pub index: u64, // Index of the payload
+	pub header: RecordHeader,
+	// Whether the payload matches the CRC of the header
+	pub intact: bool
+}
+
+// Finds all the records of a framed data file, live or freed.
+// The bytes between them are treated as free space.
+pub fn scan_records(path: &Path) -> io::Result<Vec<ScannedRecord>> {
+	let mut file = crypt::File::open(path)?;
+	let size = file.len()?;
+	let mut records = Vec::<ScannedRecord>::new();
+	let mut window = vec![0u8; 64 * 1024];
+	let mut position = DATA_MAGIC.len() as u64;
+	while position + 34 <= size {
+		// Looking for the next record magic
+		file.seek(SeekFrom::Start(position))?;
+		let filled = (size - position).min(window.len() as u64) as usize;
+		file.read_exact(&mut window[..filled])?;
+		let found = window[..filled].windows(RECORD_MAGIC.len())
+			.position(|w| w == RECORD_MAGIC)
+		;
+		let offset = match found {
+			Some(offset) => offset as u64,
+			None => {
+				position += (filled as u64).saturating_sub(RECORD_MAGIC.len() as u64 - 1).max(1);
+				continue;
+			}
+		};
+		let start = position + offset;
+
+		// Checking the header found, and the payload following it
+		let mut bytes = vec![0u8; (size - start).min(34 + 2 * u8::MAX as u64) as usize];
+		file.seek(SeekFrom::Start(start))?;
+		file.read_exact(&mut bytes)?;
+		let header = match decode_header(&bytes) {
+			Some(header) => header,
+			None => {
+				position = start + 1;
+				continue;
+			}
+		};
+		let index = start + header_length(&header.tag.id, &header.tag.owner);
+		if index + header.length > size {
+			position = start + 1;
+			continue;
+		}
+		let mut crc = 0u32;
+		let mut remaining = header.length;
+		file.seek(SeekFrom::Start(index))?;
+		while remaining > 0 {
+			let part = remaining.min(window.len() as u64) as usize;
+			file.read_exact(&mut window[..part])?;
+			crc = crc32c::crc32c_append(crc, &window[..part]);
+			remaining -= part as u64;
+		}
+		let intact = crc == header.payload_crc;
+		position = index + header.length;
+		records.push(ScannedRecord { start, index, header, intact });
+	}
+	return Ok(records);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn tag(id: &str) -> RecordTag {
+		return RecordTag { id: id.to_owned(), owner: "owner".to_owned(), data_type: 3 };
+	}
+
+	#[test]
+	fn record_headers() {
+		let record = encode_record(&tag("item"), 1234, b"payload").unwrap();
+		let length = header_length("item", "owner") as usize;
+		assert_eq!(&record[..4], RECORD_MAGIC);
+		assert_eq!(&record[length..], b"payload");
+		assert_eq!(u16::from_be_bytes([record[length - 2], record[length - 1]]), length as u16);
+
+		let header = decode_header(&record).unwrap();
+		assert_eq!((header.tag.id.as_str(), header.tag.owner.as_str()), ("item", "owner"));
+		assert_eq!(header.tag.data_type, 3);
+		assert!(header.live);
+		assert_eq!(header.stamp, 1234);
+		assert_eq!(header.length, 7);
+		assert_eq!(header.payload_crc, crc32c::crc32c(b"payload"));
+
+		// The state is left out of the CRC, for records to be freed in place
+		let mut freed = record.clone();
+		freed[4] = FREED;
+		assert!(!decode_header(&freed).unwrap().live);
+		freed[4] = 2;
+		assert!(decode_header(&freed).is_none());
+
+		// Any other byte of the header breaks its CRC
+		for i in (0..length).filter(|i| *i != 4) {
+			let mut tampered = record.clone();
+			tampered[i] ^= 0x10;
+			assert!(decode_header(&tampered).is_none(), "byte {}", i);
+		}
+		assert!(decode_header(&record[..length - 1]).is_none());
+	}
+
+	#[test]
+	fn scanned_records() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("data");
+		let mut content = DATA_MAGIC.to_vec();
+		let mut starts = Vec::<u64>::new();
+		for (i, payload) in [&b"first"[..], b"RIXR within", b"third"].iter().enumerate() {
+			starts.push(content.len() as u64);
+			content.extend(encode_record(&tag(&format!("item{}", i)), i as u64, payload).unwrap());
+			content.extend(b"free space");
+		}
+		let last = content.len() - 12; // Within the payload of the third record
+		content[last] ^= 0xff;
+		fs::write(&path, &content).unwrap();
+		assert!(is_framed(&path));
+
+		let records = scan_records(&path).unwrap();
+		assert_eq!(records.iter().map(|r| r.start).collect::<Vec<u64>>(), starts);
+		assert_eq!(records.iter().map(|r| r.intact).collect::<Vec<bool>>(), [true, true, false]);
+		assert_eq!(read_data(&path, records[1].index, 11).unwrap(), b"RIXR within");
+
+		let (start, header) = read_header(&path, records[1].index).unwrap().unwrap();
+		assert_eq!((start, header.tag.id.as_str()), (starts[1], "item1"));
+		mark_freed(&path, start).unwrap();
+		assert!(!read_header(&path, records[1].index).unwrap().unwrap().1.live);
+		assert!(!scan_records(&path).unwrap()[1].header.live);
+		assert!(read_header(&path, records[1].index - 1).unwrap().is_none());
+	}
+}
@@ -0,0 +1,237 @@
+use std::collections::{ HashMap, HashSet, BTreeSet };
+
+use serde_json::{ Value, json };
+
+// Precision of the geohashes stored in the indexes (about 4 cm)
+pub const HASH_PRECISION: usize = 12;
+// Largest number of cells looked up for a single area
+const MAX_CELLS: usize = 64;
+const BASE32: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+// Mean radius of the Earth, in meters
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+// A polygon, as a list of linear rings of (longitude, latitude) pairs
+pub type Polygon = Vec<Vec<(f64, f64)>>;
+
+// The indexed geo-points of a collection
+#[derive(Debug)]
+#[derive(Default)]
+pub struct GeoIndex {
+	// Map relating each item id to its geo-point fields
+	pub points: HashMap<String, Vec<(String, f64, f64)>>,
+	// Geohash of each point, with the id of its item
+	cells: BTreeSet<(String, String)>
+}
+
+fn valid(lon: f64, lat: f64) -> bool {
+	return (-180.0..=180.0).contains(&lon) && (-90.0..=90.0).contains(&lat);
+}
+
+fn pair(value: &Value) -> Option<(f64, f64)> {
+	let list = value.as_array()?;
+	if list.len() != 2 { return None; }
+	let (lon, lat) = (list[0].as_f64()?, list[1].as_f64()?);
+	if !valid(lon, lat) { return None; }
+	return Some((lon, lat));
+}
+
+// Reads a GeoJSON point: `{ "type": "Point", "coordinates": [lon, lat] }`
+pub fn parse_point(value: &Value) -> Option<(f64, f64)> {
+	let object = value.as_object()?;
+	if object.len() != 2 || object.get("type")? != "Point" { return None; }
+	return pair(object.get("coordinates")?);
+}
+
+// Reads a GeoJSON polygon: `{ "type": "Polygon", "coordinates": [[[lon, lat], ...], ...] }`
+pub fn parse_polygon(value: &Value) -> Option<Polygon> {
+	let object = value.as_object()?;
+	if object.len() != 2 || object.get("type")? != "Polygon" { return None; }
+	let mut polygon = Polygon::new();
+	for ring in object.get("coordinates")?.as_array()? {
+		let ring = ring.as_array()?.iter().map(pair).collect::<Option<Vec<_>>>()?;
+		if ring.len() < 4 || ring.first() != ring.last() { return None; }
+		polygon.push(ring);
+	}
+	if polygon.is_empty() { return None; }
+	return Some(polygon);
+}
+
+pub fn point_json(lon: f64, lat: f64) -> Value {
+	return json!({ "type": "Point", "coordinates": [lon, lat] });
+}
+
+pub fn polygon_json(polygon: &Polygon) -> Value {
+	return json!({
+		"type": "Polygon",
+		"coordinates": polygon.iter().map(|ring| {
+			Value::Array(ring.iter().map(|(lon, lat)| json!([lon, lat])).collect())
+		}).collect::<Vec<Value>>()
+	});
+}
+
+pub fn geohash(lon: f64, lat: f64, precision: usize) -> String {
+	let (mut lon_range, mut lat_range) = ((-180.0, 180.0), (-90.0, 90.0));
+	let mut hash = String::with_capacity(precision);
+	let mut even = true;
+	let mut bits = 0u8;
+	let mut count = 0;
+	while hash.len() < precision {
+		let (range, value) = if even { (&mut lon_range, lon) }
+			else { (&mut lat_range, lat) }
+		;
+		let middle = (range.0 + range.1) / 2.0;
+		bits <<= 1;
+		if value >= middle { bits |= 1; range.0 = middle; }
+		else { range.1 = middle; }
+		even = !even;
+		count += 1;
+		if count == 5 {
+			hash.push(BASE32[bits as usize] as char);
+			bits = 0;
+			count = 0;
+		}
+	}
+	return hash;
+}
+
+// Width and height, in degrees, of the geohash cells of a given precision
+fn cell_size(precision: usize) -> (f64, f64) {
+	let bits = 5 * precision as i32;
+	let lon_bits = (bits + 1) / 2;
+	return (360.0 / 2f64.powi(lon_bits), 180.0 / 2f64.powi(bits - lon_bits));
+}
+
+// The geohash prefixes covering a bounding box
+fn covering(west: f64, south: f64, east: f64, north: f64) -> Vec<String> {
+	if west > east {
+		// The box crosses the antimeridian
+		let mut cells = covering(west, south, 180.0, north);
+		cells.extend(covering(-180.0, south, east, north));
+		return cells;
+	}
+	for precision in (1..=HASH_PRECISION).rev() {
+		let (width, height) = cell_size(precision);
+		let columns = |l: f64| ((l + 180.0) / width).floor().min(360.0 / width - 1.0) as i64;
+		let rows = |l: f64| ((l + 90.0) / height).floor().min(180.0 / height - 1.0) as i64;
+		let (x0, x1, y0, y1) = (columns(west), columns(east), rows(south), rows(north));
+		if precision > 1 && ((x1 - x0 + 1) * (y1 - y0 + 1)) as usize > MAX_CELLS {
+			continue;
+		}
+		let mut cells = Vec::<String>::new();
+		for x in x0..=x1 {
+			for y in y0..=y1 {
+				cells.push(geohash(
+					-180.0 + (x as f64 + 0.5) * width,
+					-90.0 + (y as f64 + 0.5) * height,
+					precision
+				));
+			}
+		}
+		return cells;
+	}
+	return Vec::new();
+}
+
+// Great-circle distance between two points, in meters
+pub fn distance(from: (f64, f64), to: (f64, f64)) -> f64 {
+	let (lon1, lat1) = (from.0.to_radians(), from.1.to_radians());
+	let (lon2, lat2) = (to.0.to_radians(), to.1.to_radians());
+	let a = ((lat2 - lat1) / 2.0).sin().powi(2)
+		+ lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2)
+	;
+	return 2.0 * EARTH_RADIUS * a.sqrt().min(1.0).asin();
+}
+
+// A bounding box containing the circle of `radius` meters around a point
+pub fn bounding_box(center: (f64, f64), radius: f64) -> (f64, f64, f64, f64) {
+	let delta_lat = (radius / EARTH_RADIUS).to_degrees();
+	let (south, north) = (center.1 - delta_lat, center.1 + delta_lat);
+	if south <= -90.0 || north >= 90.0 {
+		return (-180.0, south.max(-90.0), 180.0, north.min(90.0));
+	}
+	let delta_lon = (
+		(radius / EARTH_RADIUS).sin() / center.1.to_radians().cos()
+	).min(1.0).asin().to_degrees();
+	if delta_lon >= 180.0 { return (-180.0, south, 180.0, north); }
+	let mut west = center.0 - delta_lon;
+	let mut east = center.0 + delta_lon;
+	if west < -180.0 { west += 360.0; }
+	if east > 180.0 { east -= 360.0; }
+	return (west, south, east, north);
+}
+
+pub fn in_box(point: (f64, f64), area: (f64, f64, f64, f64)) -> bool {
+	let (west, south, east, north) = area;
+	let lon_ok = if west <= east { point.0 >= west && point.0 <= east }
+		else { point.0 >= west || point.0 <= east }
+	;
+	return lon_ok && point.1 >= south && point.1 <= north;
+}
+
+// Checks that the geo-points of an item fit in the index, which keeps
+// up to 255 of them per item
+pub fn check_points(data: &Value) -> Result<(), String> {
+	let count = data.as_object().map_or(0, |object| object.iter()
+		.filter(|(k, v)| k.len() <= u8::MAX as usize && parse_point(v).is_some())
+		.count()
+	);
+	if count > u8::MAX as usize {
+		return Err(format!("An item can't have more than {} geo-point fields.", u8::MAX));
+	}
+	return Ok(());
+}
+
+impl GeoIndex {
+	// Indexes the top-level geo-point fields of an item
+	pub fn insert(&mut self, item: &str, data: &Value) {
+		let fields = match data.as_object() {
+			Some(object) => object.iter()
+				.filter(|(k, _)| k.len() <= u8::MAX as usize)
+				.filter_map(|(k, v)| parse_point(v).map(|(lon, lat)| (k.clone(), lon, lat)))
+				.take(u8::MAX as usize)
+				.collect::<Vec<_>>(),
+			None => return
+		};
+		self.insert_points(item, fields);
+	}
+
+	pub fn insert_points(&mut self, item: &str, fields: Vec<(String, f64, f64)>) {
+		if fields.is_empty() { return; }
+		for (_, lon, lat) in &fields {
+			self.cells.insert((geohash(*lon, *lat, HASH_PRECISION), item.to_owned()));
+		}
+		self.points.insert(item.to_owned(), fields);
+	}
+
+	// Returns whether the item had indexed points
+	pub fn remove(&mut self, item: &str) -> bool {
+		let fields = match self.points.remove(item) {
+			Some(fields) => fields,
+			None => return false
+		};
+		for (_, lon, lat) in fields {
+			self.cells.remove(&(geohash(lon, lat, HASH_PRECISION), item.to_owned()));
+		}
+		return true;
+	}
+
+	pub fn point(&self, item: &str, field: &str) -> Option<(f64, f64)> {
+		return self.points.get(item)?.iter()
+			.find(|(f, _, _)| f == field)
+			.map(|(_, lon, lat)| (*lon, *lat))
+		;
+	}
+
+	// Items which may have a point in the bounding box
+	pub fn candidates(&self, area: (f64, f64, f64, f64)) -> HashSet<String> {
+		let mut items = HashSet::<String>::new();
+		for prefix in covering(area.0, area.1, area.2, area.3) {
+			let start = (prefix.clone(), String::new());
+			for (hash, item) in self.cells.range(start..) {
+				if !hash.starts_with(&prefix) { break; }
+				items.insert(item.clone());
+			}
+		}
+		return items;
+	}
+}
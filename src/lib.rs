@@ -0,0 +1,24 @@
+// OrixDB, as a library: the modules behind the `orixdb` command,
+// and `lite` to embed Lite stores in other programs
+
+pub mod cli;
+pub mod basics;
+pub mod faccess;
+pub mod crypt;
+pub mod geo;
+pub mod workers;
+pub mod repair;
+
+pub mod create;
+pub mod serve;
+pub mod optimize;
+pub mod upgrade;
+pub mod check;
+pub mod archive;
+pub mod restore;
+pub mod copy;
+pub mod convert;
+pub mod backup;
+pub mod encrypt;
+
+pub mod lite;
@@ -0,0 +1,190 @@
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::sync::RwLock;
+
+use serde_json::{ Map, Value };
+
+use crate::copy;
+use crate::faccess;
+use crate::basics::{ self, Store, StoreType, LogLevel, Instance };
+use crate::serve::{ self, Engine, query, transac };
+
+// A Lite store opened in the calling process, without a server. Queries and
+// mutations are the ones of the GraphQL API. The data writes aren't synced
+// to the disk one by one, so the last ones can be lost on a system crash.
+pub struct Lite {
+	engine: RwLock<Engine>,
+	// Held while the store is open, so that a single process uses it.
+	// The system releases it along with the file, even after a crash.
+	_lock: fs::File
+}
+
+// Takes the lock of a store, failing if another process holds it.
+// Servers hold it for any kind of store, and so do the commands rewriting one.
+pub fn lock(store_dir: &Path) -> Result<fs::File, String> {
+	let path = store_dir.join("tmp/lock");
+	let file = fs::create_dir_all(store_dir.join("tmp"))
+		.and_then(|_| fs::OpenOptions::new().create(true).truncate(false).write(true).open(&path))
+		.map_err(|e| format!("Failed to open {:?}: {}", path, e))?
+	;
+	if file.try_lock().is_err() {
+		return Err("The store is open in another process.".to_owned());
+	}
+	return Ok(file);
+}
+
+impl Lite {
+	// Creates an empty Lite store in a directory which has no store yet
+	pub fn create(store_dir: &Path, name: &str) -> Result<Lite, String> {
+		if store_dir.join("manifest.json").exists() {
+			return Err(format!("{:?} already contains a store.", store_dir));
+		}
+		let conf = basics::get_conf();
+		let store = Store {
+			name: name.to_owned(),
+			id: slug::slugify(name),
+			hash: faccess::new_id(),
+			major: conf.major,
+			minor: conf.minor,
+			kind: StoreType::Lite,
+			ordering: false,
+			checksumming: false,
+			logging: LogLevel::Normal,
+			defaults: Instance {
+				verbosity: false,
+				api_port: 7900,
+				api_scan: false,
+				cluster_port: 7979,
+				cluster_scan: false,
+				mapped_reads: false,
+				cache_size: 0
+			},
+			backups: None,
+			mutation_logs: None,
+			encryption: None
+		};
+		fs::create_dir_all(store_dir)
+			.map_err(|e| format!("Failed to create {:?}: {}", store_dir, e))?
+		;
+		copy::prepare_target(store_dir, &store)?;
+		return Lite::open(store_dir);
+	}
+
+	pub fn open(store_dir: &Path) -> Result<Lite, String> {
+		let conf = basics::get_conf();
+		let store = basics::load_store(store_dir)?;
+		if store.kind != StoreType::Lite {
+			return Err(format!(
+				"The store is of the {:?} type, only Lite stores can be embedded.", store.kind
+			));
+		}
+		if store.major != conf.major {
+			return Err(
+				"The major version of the store differs from the one of ".to_owned()
+				+ &conf.display_name + ". Please use the `upgrade` subcommand first."
+			);
+		}
+		let lock = lock(store_dir)?;
+		let engine = serve::open_engine(conf, store_dir.to_path_buf(), store, false)?;
+		return Ok(Lite { engine: RwLock::new(engine), _lock: lock });
+	}
+
+	pub fn store(&self) -> Store {
+		return self.engine.read().unwrap().store.clone();
+	}
+
+	pub fn dir(&self) -> PathBuf {
+		return self.engine.read().unwrap().store_dir.clone();
+	}
+
+	// Runs a GraphQL request, and returns its response with `data` and `errors`
+	pub fn execute(&self, source: &str, variables: &Map<String, Value>) -> Value {
+		return query::execute(&self.engine, source, variables, None);
+	}
+
+	pub fn get_key(&self, key: &str) -> Result<Value, String> {
+		let engine = self.engine.read().unwrap();
+		return query::keyvalue(&engine, key, basics::now_millis());
+	}
+
+	// Sets a key, for the given number of seconds when `ttl` is set
+	pub fn set_key(&self, key: &str, value: Value, ttl: Option<u64>) -> Result<(), String> {
+		let mut args = Map::from_iter([
+			("key".to_owned(), Value::from(key)),
+			("value".to_owned(), value)
+		]);
+		if let Some(ttl) = ttl { args.insert("ttl".to_owned(), Value::from(ttl)); }
+		return transac::mutate(&mut self.engine.write().unwrap(), "setKey", &args).map(|_| ());
+	}
+
+	// Deletes a key, and tells if it was set
+	pub fn delete_key(&self, key: &str) -> Result<bool, String> {
+		let args = Map::from_iter([("key".to_owned(), Value::from(key))]);
+		let deleted = transac::mutate(&mut self.engine.write().unwrap(), "deleteKey", &args)?;
+		return Ok(matches!(deleted, query::Resolved::Value(Value::Bool(true))));
+	}
+
+	// Removes the expired keys and rolls up the closed windows of the series,
+	// which the background workers do for served stores
+	pub fn maintain(&self) -> Result<(), String> {
+		let mut engine = self.engine.write().unwrap();
+		let now = basics::now_millis();
+		transac::expire_keys(&mut engine, now)?;
+		transac::roll_series(&mut engine, now)?;
+		return Ok(());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+	use crate::serve::testing;
+
+	#[test]
+	fn embedded_stores() {
+		let dir = tempfile::tempdir().unwrap();
+		let store_dir = dir.path().join("lite");
+		let lite = Lite::create(&store_dir, "My Lite").unwrap();
+		let store = lite.store();
+		assert_eq!((store.id.as_str(), store.kind), ("my-lite", StoreType::Lite));
+		assert!(!store.ordering && !store.checksumming);
+		assert_eq!(lite.dir(), store_dir);
+		assert!(Lite::create(&store_dir, "Again").is_err());
+
+		lite.set_key("greeting", json!("hello"), None).unwrap();
+		lite.set_key("session", json!(1), Some(3600)).unwrap();
+		assert_eq!(lite.get_key("greeting").unwrap(), "hello");
+		assert!(lite.delete_key("session").unwrap());
+		assert!(!lite.delete_key("session").unwrap());
+		lite.maintain().unwrap();
+
+		let created = lite.execute(
+			"mutation { createCollection(name: \"notes\") { name } }", &Map::new()
+		);
+		assert_eq!(created["data"]["createCollection"]["name"], "notes");
+		let variables = Map::from_iter([("text".to_owned(), json!("first"))]);
+		let inserted = lite.execute("mutation ($text: String) { \
+			insertItem(collection: \"notes\", data: { text: $text }) { id } }", &variables
+		);
+		assert!(inserted["data"]["insertItem"]["id"].is_string(), "{}", inserted);
+
+		// A single process uses the store at once
+		let message = Lite::open(&store_dir).err().unwrap();
+		assert_eq!(message, "The store is open in another process.");
+		drop(lite);
+		let lite = Lite::open(&store_dir).unwrap();
+		assert_eq!(lite.get_key("greeting").unwrap(), "hello");
+		let items = lite.execute("{ items(collection: \"notes\") { data } }", &Map::new());
+		assert_eq!(items["data"]["items"][0]["data"]["text"], "first");
+	}
+
+	#[test]
+	fn only_lite_stores_are_embedded() {
+		let dir = tempfile::tempdir().unwrap();
+		drop(testing::create_store(dir.path(), json!({})));
+		let message = Lite::open(dir.path()).err().unwrap();
+		assert!(message.starts_with("The store is of the Live type"));
+		assert!(!dir.path().join("tmp/lock").exists());
+	}
+}
@@ -2,18 +2,10 @@ use std::collections::HashMap;
 
 use clap::{ Command, Arg, ArgAction, ArgMatches };
 
-mod cli;
-mod basics;
-
-mod create;
-mod serve;
-mod optimize;
-mod upgrade;
-mod check;
-mod archive;
-mod restore;
-mod copy;
-mod convert;
+use orixdb::basics;
+use orixdb::{
+	create, serve, optimize, upgrade, check, archive, restore, copy, convert, backup, encrypt
+};
 
 fn main() -> std::process::ExitCode {
 	let sub_commands: HashMap<
@@ -27,16 +19,18 @@ fn main() -> std::process::ExitCode {
 		("archive", archive::main as fn(&ArgMatches) -> std::process::ExitCode),
 		("restore", restore::main as fn(&ArgMatches) -> std::process::ExitCode),
 		("copy", copy::main as fn(&ArgMatches) -> std::process::ExitCode),
-		("convert", convert::main as fn(&ArgMatches) -> std::process::ExitCode)
+		("convert", convert::main as fn(&ArgMatches) -> std::process::ExitCode),
+		("backup", backup::main as fn(&ArgMatches) -> std::process::ExitCode),
+		("encrypt", encrypt::main as fn(&ArgMatches) -> std::process::ExitCode)
 	]);
 
 	let conf = basics::get_conf();
 	let matches = Command::new(conf.display_name)
 		.version(format!(
 			"{}.{}.{}",
-			conf.major.to_string(),
-			conf.minor.to_string(),
-			conf.patch.to_string()
+			conf.major,
+			conf.minor,
+			conf.patch
 		))
 		.author(conf.author)
 		.about(conf.description)
@@ -140,7 +134,8 @@ fn main() -> std::process::ExitCode {
 					.required(false)
 					.help("Folder to serve content from.")
 					.long_help("\
-						Folder containing the store to serve content from.\
+						Folder containing the store to serve content from.\n\
+						Archive stores are served in read-only mode.\
 					")
 			)
 			.arg(
@@ -178,34 +173,450 @@ fn main() -> std::process::ExitCode {
 						for intra-cluster connections.\
 					")
 			)
+			.arg(
+				Arg::new("mmap")
+					.long("mmap")
+					.action(ArgAction::SetTrue)
+					.required(false)
+					.help("Read the singletons and collections through memory maps.")
+					.long_help("\
+						Read the data files of the singletons and collections\n\
+						through memory maps, sparing a system call and a copy\n\
+						per read. Files are mapped again as they grow. Not\n\
+						available for encrypted stores, nor with the cache.\
+					")
+			)
+			.arg(
+				Arg::new("cache-size")
+					.long("cache-size")
+					.required(false)
+					.help("Memory budget of the cache of data file blocks, in MiB.")
+					.long_help("\
+						Memory budget of the cache of data file blocks, in MiB.\n\
+						The blocks read last are kept in memory, the least\n\
+						recently used being evicted beyond the budget. 0\n\
+						disables the cache, which can't be used with `--mmap`. Defaults to the cache size of the\n\
+						store's manifest.\
+					")
+			)
+			.arg(
+				Arg::new("key-file")
+					.long("key-file")
+					.short('k')
+					.required(false)
+					.help("The file holding the key of an encrypted store.")
+					.long_help("\
+						The file holding the key of an encrypted store:\n\
+						32 bytes, or 64 hexadecimal digits. Without it, the\n\
+						key is read from the file named by ORIXDB_KEY_FILE,\n\
+						else from ORIXDB_KEY, in hexadecimal.\
+					")
+			)
 		)
 
 		.subcommand(Command::new("optimize")
 			.about("To optimize the data organization of a store.")
+			.arg(
+				Arg::new("directory")
+					.required(false)
+					.help("Folder of the store to optimize.")
+					.long_help("\
+						Folder containing the store to optimize. It must\n\
+						not be served while it is optimized. If this arg\n\
+						is not provided, then the current directory is used.\
+					")
+			)
+			.arg(
+				Arg::new("ordered")
+					.long("ordered")
+					.short('o')
+					.action(ArgAction::SetTrue)
+					.required(false)
+					.help("Whether or not the entries are packed in insertion order.")
+					.long_help("\
+						Packs the entries in the order of their last\n\
+						write, rather than in their current order in\n\
+						the data files.\
+					")
+			)
 		)
 
 		.subcommand(Command::new("upgrade")
 			.about("To upgrade a store from a old version to a new one.")
+			.arg(
+				Arg::new("directory")
+					.required(false)
+					.help("Folder of the store to upgrade.")
+					.long_help("\
+						Folder containing the store to upgrade. It must\n\
+						not be served during the upgrade. If this arg\n\
+						is not provided, then the current directory is used.\
+					")
+			)
+			.arg(
+				Arg::new("dry-run")
+					.long("dry-run")
+					.short('n')
+					.action(ArgAction::SetTrue)
+					.required(false)
+					.help("Whether or not the changes are only listed.")
+					.long_help("\
+						Lists the migrations the store needs, and what\n\
+						each of them would change, without changing it.\
+					")
+			)
+			.arg(
+				Arg::new("backup")
+					.long("backup")
+					.short('b')
+					.required(false)
+					.help("The archive file to back the store up in.")
+					.long_help("\
+						The archive file the store is backed up in before\n\
+						the upgrade. If it's not set, the backup is made in\n\
+						logs/upgrades/<major>.<minor>-<time>.orix, within the store.\
+					")
+			)
 		)
 
 		.subcommand(Command::new("check")
 			.about("To check if all the data in a store in correct (not corrupted).")
+			.arg(
+				Arg::new("directory")
+					.required(false)
+					.help("Folder of the store to check.")
+					.long_help("\
+						Folder containing the store to check.\n\
+						If this arg is not provided, then\n\
+						the current directory is used.\
+					")
+			)
+			.arg(
+				Arg::new("json")
+					.long("json")
+					.short('j')
+					.action(ArgAction::SetTrue)
+					.required(false)
+					.help("Whether or not the report is printed as JSON.")
+					.long_help("\
+						Prints the report as a JSON object on the\n\
+						standard output, for scripts and monitoring.\n\
+						The exit code is non-zero when errors are found.\
+					")
+			)
+			.arg(
+				Arg::new("repair")
+					.long("repair")
+					.short('r')
+					.action(ArgAction::SetTrue)
+					.required(false)
+					.help("Whether or not the indexes are rebuilt before checking.")
+					.long_help("\
+						Rebuilds the indexes of the singletons and of the\n\
+						collections from the headers of the records in\n\
+						their data files. Corrupted records are moved to\n\
+						the `lost+found/` directory of the store.\n\
+						A store can't be repaired while it is served.\
+					")
+			)
 		)
 
 		.subcommand(Command::new("archive")
 			.about("To create an archive of a store.")
+			.arg(
+				Arg::new("path")
+					.required(false)
+					.help("Folder of the store to archive, or archive to inspect.")
+					.long_help("\
+						Folder containing the store to archive.\n\
+						With `--list` or `--verify`, the archive file\n\
+						to inspect instead. If this arg is not provided,\n\
+						then the current directory is used.\
+					")
+			)
+			.arg(
+				Arg::new("output")
+					.long("output")
+					.short('o')
+					.required(false)
+					.help("The archive file to create.")
+					.long_help("\
+						The archive file to create. If it's not set,\n\
+						it is defaulted to `<store id>.orix`\n\
+						in the current directory.\
+					")
+			)
+			.arg(
+				Arg::new("list")
+					.long("list")
+					.short('l')
+					.action(ArgAction::SetTrue)
+					.required(false)
+					.help("Whether or not the files of an archive are listed.")
+					.long_help("\
+						Prints the store and the files held by an\n\
+						archive, without extracting it.\
+					")
+			)
+			.arg(
+				Arg::new("verify")
+					.long("verify")
+					.short('v')
+					.action(ArgAction::SetTrue)
+					.required(false)
+					.help("Whether or not an archive is verified.")
+					.long_help("\
+						Decompresses every file of an archive in memory\n\
+						and checks it against its size and checksum,\n\
+						without extracting anything.\
+					")
+			)
 		)
 
 		.subcommand(Command::new("restore")
 			.about("To restore an archive or a backup store.")
+			.arg(
+				Arg::new("source")
+					.required(true)
+					.help("Archive file or backup store to restore.")
+					.long_help("\
+						Archive file, or folder of a backup store,\n\
+						to restore. It is fully verified before\n\
+						anything is written.\
+					")
+			)
+			.arg(
+				Arg::new("directory")
+					.required(false)
+					.help("Folder to restore the store in.")
+					.long_help("\
+						Folder to restore the store in. It must be\n\
+						empty or not exist yet. If this arg is not\n\
+						provided, then the current directory is used.\
+					")
+			)
+			.arg(
+				Arg::new("sequence")
+					.long("sequence")
+					.short('s')
+					.required(false)
+					.help("The last incremental backup to replay.")
+					.long_help("\
+						The sequence number of the last incremental\n\
+						backup to replay over a backup store, 0 for its\n\
+						full backup alone. All of them are replayed\n\
+						by default.\
+					")
+			)
+			.arg(
+				Arg::new("until")
+					.long("until")
+					.short('u')
+					.required(false)
+					.help("The time to restore the store at.")
+					.long_help("\
+						Restores the store as it was at the given Unix\n\
+						time, in milliseconds. The latest backup made\n\
+						before it is restored, then the mutations logged\n\
+						since are replayed up to that time. The source\n\
+						may then also be a folder of backup stores.\
+					")
+			)
+			.arg(
+				Arg::new("logs")
+					.long("logs")
+					.short('l')
+					.required(false)
+					.help("The mutation logs to replay, with `--until`.")
+					.long_help("\
+						Folder of the store whose mutations are replayed,\n\
+						or of its mutation logs (`logs/mutations/`). The\n\
+						store keeps them when its manifest has a\n\
+						`mutation_logs` retention policy.\
+					")
+			)
 		)
 
 		.subcommand(Command::new("copy")
 			.about("To create a duplicate of a store with different IDs.")
+			.arg(
+				Arg::new("output")
+					.required(true)
+					.help("Folder to create the copy in.")
+					.long_help("\
+						Folder to create the copy in.\n\
+						It must be empty or not exist yet.\
+					")
+			)
+			.arg(
+				Arg::new("directory")
+					.required(false)
+					.help("Folder of the store to copy.")
+					.long_help("\
+						Folder containing the store to copy. It must\n\
+						not be written to during the copy. If this arg\n\
+						is not provided, then the current directory is used.\
+					")
+			)
+			.arg(
+				Arg::new("name")
+					.long("name")
+					.short('n')
+					.required(false)
+					.help("The name of the copy.")
+					.long_help("\
+						The name of the copy. If it's not set, it is\n\
+						defaulted to the store's name followed by \"(copy)\".\
+					")
+			)
+			.arg(
+				Arg::new("id")
+					.long("id")
+					.short('i')
+					.required(false)
+					.help("The id of the copy.")
+					.long_help("\
+						The id of the copy, which must differ from the\n\
+						store's one. If it's not set, it is defaulted\n\
+						to the name's slug.\
+					")
+			)
+			.arg(
+				Arg::new("collections")
+					.long("collections")
+					.short('c')
+					.required(false)
+					.help("The collections to copy, separated by commas.")
+					.long_help("\
+						The names of the collections to copy, separated\n\
+						by commas. The edges are copied when both of their\n\
+						items are. All the collections are copied by default.\
+					")
+			)
 		)
 
 		.subcommand(Command::new("convert")
 			.about("To convert a store from one type to another")
+			.arg(
+				Arg::new("type")
+					.required(true)
+					.help("The type to convert the store to.")
+					.long_help("\
+						The type to convert the store to: \"live\",\n\
+						\"lite\" (no checksums nor ordering), \"backup\"\n\
+						or \"archive\" (compacted, compressed and\n\
+						read-only). The store must not be served\n\
+						during a conversion.\
+					")
+			)
+			.arg(
+				Arg::new("directory")
+					.required(false)
+					.help("Folder of the store to convert.")
+					.long_help("\
+						Folder containing the store to convert.\n\
+						If this arg is not provided, then\n\
+						the current directory is used.\
+					")
+			)
+		)
+
+		.subcommand(Command::new("backup")
+			.about("To make a backup store of a running server, without stopping it.")
+			.arg(
+				Arg::new("output")
+					.required(true)
+					.help("Folder to create the backup store in.")
+					.long_help("\
+						Folder to create the backup store in.\n\
+						It must be empty or not exist yet.\
+					")
+			)
+			.arg(
+				Arg::new("directory")
+					.required(false)
+					.help("Folder of the served store.")
+					.long_help("\
+						Folder containing the served store, whose manifest\n\
+						gives the API port of the server. If this arg is not\n\
+						provided, then the current directory is used.\
+					")
+			)
+			.arg(
+				Arg::new("api-port")
+					.long("api-port")
+					.short('a')
+					.required(false)
+					.help("The api port of the running server.")
+					.long_help("\
+						The port on which the running server listens\n\
+						for client connections, when it differs from\n\
+						the default one of the store.\
+					")
+			)
+			.arg(
+				Arg::new("incremental")
+					.long("incremental")
+					.short('i')
+					.action(ArgAction::SetTrue)
+					.required(false)
+					.help("Whether or not only the changes are backed up.")
+					.long_help("\
+						When the output folder already holds a backup\n\
+						of the store, only the changes made since its\n\
+						last backup are added to it, as a new step of\n\
+						its chain. Otherwise a full backup is made.\
+					")
+			)
+		)
+
+		.subcommand(Command::new("encrypt")
+			.about("To encrypt the files of a store, or to change the key they are encrypted with.")
+			.arg(
+				Arg::new("new-key-file")
+					.required(true)
+					.help("The file holding the new key.")
+					.long_help("\
+						The file holding the key to encrypt the store with:\n\
+						32 bytes, or 64 hexadecimal digits. When the file\n\
+						doesn't exist, it is created with a random key.\
+					")
+			)
+			.arg(
+				Arg::new("directory")
+					.required(false)
+					.help("Folder of the store to encrypt.")
+					.long_help("\
+						Folder containing the store to encrypt. It must\n\
+						not be served while it is encrypted. If this arg\n\
+						is not provided, then the current directory is used.\
+					")
+			)
+			.arg(
+				Arg::new("cipher")
+					.long("cipher")
+					.short('c')
+					.required(false)
+					.help("The cipher of the encryption.")
+					.long_help("\
+						The cipher the files are encrypted with:\n\
+						aes-256-gcm (the default) or chacha20-poly1305.\n\
+						The current one is kept when it's not set.\
+					")
+			)
+			.arg(
+				Arg::new("key-file")
+					.long("key-file")
+					.short('k')
+					.required(false)
+					.help("The file holding the current key of the store.")
+					.long_help("\
+						The file holding the current key of an encrypted\n\
+						store, whose files are encrypted again with the new\n\
+						key. Without it, the key is read from the file named\n\
+						by ORIXDB_KEY_FILE, else from ORIXDB_KEY.\
+					")
+			)
 		)
 
 		.get_matches();
@@ -1,6 +1,206 @@
+use std::fs;
+use std::path::{ Path, PathBuf };
+
 use clap::ArgMatches;
 
+use crate::cli;
+use crate::copy;
+use crate::lite;
+use crate::check;
+use crate::convert;
+use crate::serve::{ self, Engine };
+use crate::basics::{ self, Store, StoreType };
+
+// Where the rewritten content of a store is prepared
+fn staging_dir(store_dir: &Path) -> PathBuf {
+	return store_dir.join("tmp/optimize");
+}
+
+// Number of data files of a store and their total size, in bytes
+fn footprint(engine: &Engine) -> (usize, u64) {
+	let files = engine.data_files();
+	let size = files.iter()
+		.filter_map(|path| fs::metadata(path).ok())
+		.map(|meta| meta.len())
+		.sum()
+	;
+	return (files.len(), size);
+}
+
+// Rewrites all the entries of a store in new data files, without holes and
+// filled up to the largest file size, then replaces the current ones with
+// them. The ids are kept. The entries are written in the order of their
+// last write when `ordered` is set, else in their current order.
+pub fn rewrite(store_dir: &Path, store: &Store, ordered: bool) -> Result<(), String> {
+	let staged = staging_dir(store_dir);
+	let rewritten = serve::open_engine(
+		basics::get_conf(), store_dir.to_path_buf(), basics::load_store(store_dir)?, false
+	).and_then(|source| {
+		fs::create_dir_all(&staged).map_err(|e| e.to_string())?;
+		copy::prepare_target(&staged, store)?;
+		let mut target = serve::open_engine(
+			basics::get_conf(), staged.clone(), basics::load_store(&staged)?, false
+		)?;
+		copy::copy_store(&source, &mut target, None, false, ordered)?;
+		return convert::data_entries(store_dir)
+			.and_then(|removed| convert::swap_in(store_dir, Some(&staged), &removed, store))
+			.map_err(|e| format!("Failed to replace the data files: {}", e))
+		;
+	});
+	// Once the content started being swapped, it is left for inspection
+	if rewritten.is_err() && !store_dir.join("tmp/replaced").exists() {
+		let _ = fs::remove_dir_all(&staged);
+	}
+	return rewritten;
+}
+
 pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
-	println!("{:#?}", matches);
+
+	// --> Checking the command line arguments
+	// ---------------------------------------
+
+	let ordered = *matches.get_one::<bool>("ordered").unwrap();
+	let store_dir: PathBuf;
+	if matches.contains_id("directory") {
+		store_dir = PathBuf::from(matches.get_one::<String>("directory").unwrap());
+	}
+	else { store_dir = PathBuf::from(".").canonicalize().unwrap(); }
+	let store = match basics::load_store(&store_dir) {
+		Ok(store) => store,
+		Err(message) => {
+			cli::red_err(message);
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+	if store.kind == StoreType::Backup || store.kind == StoreType::Archive {
+		cli::red_err(
+			"Only live and lite stores can be optimized.".to_owned()
+			+ " Archives are compacted already."
+		);
+		return std::process::ExitCode::FAILURE;
+	}
+	// The store must not be served, nor embedded, while its files are rewritten
+	let _lock = match lite::lock(&store_dir) {
+		Ok(file) => file,
+		Err(message) => {
+			cli::red_err(message);
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+	if staging_dir(&store_dir).exists() || store_dir.join("tmp/replaced").exists() {
+		cli::red_err(
+			"A previous rewrite of the store was interrupted.\n".to_owned()
+			+ "Please check the store, then remove `tmp/optimize` and `tmp/replaced`."
+		);
+		return std::process::ExitCode::FAILURE;
+	}
+
+
+	// --> Checking the store before rewriting it
+	// ------------------------------------------
+
+	let report = check::verify(&store_dir);
+	if report.errors() > 0 {
+		for issue in report.issues.iter().filter(|i| i.severity == check::Severity::Error) {
+			cli::red_err(format!("[{}] {}: {}", issue.section, issue.path, issue.message));
+		}
+		cli::red_err("The store is corrupted, it was not optimized.".to_owned());
+		return std::process::ExitCode::FAILURE;
+	}
+	let before = match serve::open_engine(
+		basics::get_conf(), store_dir.clone(), basics::load_store(&store_dir).unwrap(), false
+	) {
+		Ok(engine) => footprint(&engine),
+		Err(message) => {
+			cli::red_err(message);
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+
+
+	// --> Rewriting the store
+	// -----------------------
+
+	if let Err(message) = rewrite(&store_dir, &store, ordered) {
+		cli::red_err(message);
+		return std::process::ExitCode::FAILURE;
+	}
+	let after = serve::open_engine(
+		basics::get_conf(), store_dir.clone(), store, false
+	).map(|engine| footprint(&engine)).unwrap_or((0, 0));
+	let report = check::verify(&store_dir);
+	if report.errors() > 0 {
+		cli::red_err(format!(
+			"The optimized store has {} errors. Run `orixdb check` on it for details.",
+			report.errors()
+		));
+		return std::process::ExitCode::FAILURE;
+	}
+	cli::green_out(format!(
+		"Store optimized: {} data files of {} bytes, now {} files of {} bytes ({} bytes reclaimed).",
+		before.0, before.1, after.0, after.1, before.1.saturating_sub(after.1)
+	));
 	return std::process::ExitCode::SUCCESS;
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+	use crate::serve::{ query, testing };
+
+	#[test]
+	fn reclaims_space() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut engine = testing::create_store(dir.path(), json!({}));
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "notes" })).unwrap();
+		let ids = (0..20)
+			.map(|n| testing::insert(&mut engine, "notes", json!({ "n": n, "text": "x".repeat(2000) })))
+			.collect::<Vec<String>>()
+		;
+		for id in ids.iter().filter(|id| ids.iter().position(|i| i == *id).unwrap() % 4 != 0) {
+			testing::mutate(&mut engine, "deleteItem", json!({ "id": id })).unwrap();
+		}
+		let before = footprint(&engine);
+		let store = engine.store;
+		let backup = dir.path().join(format!("backups/{}-1700000000000", store.id));
+		fs::create_dir_all(&backup).unwrap();
+		fs::write(backup.join("manifest.json"), "{}").unwrap();
+
+		rewrite(dir.path(), &store, false).unwrap();
+		assert!(!staging_dir(dir.path()).exists());
+		assert!(!dir.path().join("tmp/replaced").exists());
+		assert!(backup.join("manifest.json").exists());
+		assert_eq!(check::verify(dir.path()).errors(), 0);
+
+		let engine = testing::open_store(dir.path());
+		let after = footprint(&engine);
+		assert!(after.1 * 3 < before.1, "{:?} then {:?}", before, after);
+		assert_eq!(engine.collections.items.len(), 5);
+		for (n, id) in ids.iter().enumerate().step_by(4) {
+			assert_eq!(query::item_data(&engine, id).unwrap()["n"], n);
+		}
+	}
+
+	#[test]
+	fn optimizations_wait_for_the_lock() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut engine = testing::create_store(dir.path(), json!({}));
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "notes" })).unwrap();
+		let id = testing::insert(&mut engine, "notes", json!({ "text": "x".repeat(2000) }));
+		testing::mutate(&mut engine, "deleteItem", json!({ "id": id })).unwrap();
+		let before = footprint(&engine);
+		let command = clap::Command::new("optimize")
+			.arg(clap::Arg::new("directory"))
+			.arg(clap::Arg::new("ordered").long("ordered").action(clap::ArgAction::SetTrue))
+		;
+		let args = ["optimize", dir.path().to_str().unwrap()];
+
+		let lock = lite::lock(dir.path()).unwrap();
+		assert_eq!(main(&command.clone().get_matches_from(args)), std::process::ExitCode::FAILURE);
+		assert_eq!(footprint(&testing::open_store(dir.path())), before);
+		drop(lock);
+		assert_eq!(main(&command.get_matches_from(args)), std::process::ExitCode::SUCCESS);
+		assert!(footprint(&testing::open_store(dir.path())).1 < before.1);
+	}
+}
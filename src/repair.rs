@@ -0,0 +1,362 @@
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+use std::collections::{ HashMap, HashSet };
+
+use crate::crypt;
+use crate::basics::Store;
+use crate::faccess::{
+	self, Area, FileMeta, ID_LENGTH, SingletonIndex, SingletonMeta,
+	CollectionIndex, CollectionMeta
+};
+
+// An entry of an index, as found in the data files or in the old index
+struct Entry {
+	id: String,
+	owner: String,
+	data_type: u8,
+	file: String,
+	index: u64,
+	length: u64,
+	stamp: u64
+}
+
+// Lists the data files of an area, with their actual size
+fn data_files(store_dir: &Path, area: Area) -> io::Result<HashMap<String, FileMeta>> {
+	let mut files = HashMap::<String, FileMeta>::new();
+	let mut dir = store_dir.to_path_buf();
+	dir.push(area.dir());
+	for entry in fs::read_dir(&dir)? {
+		let entry = entry?;
+		let name = entry.file_name().to_string_lossy().into_owned();
+		if name.len() != ID_LENGTH || !entry.file_type()?.is_file() { continue; }
+		files.insert(name, FileMeta {
+			size: crypt::len(&entry.path())?,
+			holes: HashMap::new(),
+			framed: faccess::is_framed(&entry.path())
+		});
+	}
+	return Ok(files);
+}
+
+// Copies some bytes of a data file to `lost+found/`, and returns the copy's path
+fn quarantine(
+	store_dir: &Path, area: Area, file: &str, start: u64, length: u64
+) -> io::Result<PathBuf> {
+	let mut source = store_dir.to_path_buf();
+	source.push(area.dir());
+	source.push(file);
+	let bytes = faccess::read_data(&source, start, length)?;
+
+	let mut target = store_dir.to_path_buf();
+	target.push("lost+found");
+	target.push(area.dir());
+	fs::create_dir_all(&target)?;
+	target.push(format!("{}-{}", file, start));
+	crypt::write(&target, &bytes)?;
+	return Ok(target);
+}
+
+// Finds the entries of an area from the headers of its records.
+// The entries of the files without headers are taken from the old index.
+fn rebuild_area(
+	store_dir: &Path, area: Area, known: Vec<Entry>, notes: &mut Vec<String>
+) -> io::Result<(HashMap<String, FileMeta>, HashMap<String, Entry>)> {
+	let files = data_files(store_dir, area)?;
+	let mut dir = store_dir.to_path_buf();
+	dir.push(area.dir());
+	let mut found = HashMap::<String, Entry>::new();
+	let mut quarantined = HashSet::<String>::new();
+
+	for (file, _) in files.iter().filter(|(_, meta)| meta.framed) {
+		let path = dir.join(file);
+		for record in faccess::scan_records(&path)? {
+			if !record.header.live { continue; }
+			let length = record.index + record.header.length - record.start;
+			if !record.intact {
+				let copy = quarantine(store_dir, area, file, record.start, length)?;
+				faccess::mark_freed(&path, record.start)?;
+				notes.push(format!(
+					"Corrupted record of {} moved to {:?}", record.header.tag.id, copy
+				));
+				quarantined.insert(record.header.tag.id);
+				continue;
+			}
+			let entry = Entry {
+				id: record.header.tag.id,
+				owner: record.header.tag.owner,
+				data_type: record.header.tag.data_type,
+				file: file.clone(),
+				index: record.index,
+				length: record.header.length,
+				stamp: record.header.stamp
+			};
+			// Two versions of an entry remain when a crash happened
+			// during an update: the latest one is kept
+			let stale = match found.get(&entry.id) {
+				Some(other) if other.stamp >= entry.stamp => Some((entry, record.start)),
+				_ => found.insert(entry.id.clone(), entry).map(|other| {
+					let header = faccess::header_length(&other.id, &other.owner);
+					let start = other.index - header;
+					(other, start)
+				})
+			};
+			if let Some((old, start)) = stale {
+				faccess::mark_freed(&dir.join(&old.file), start)?;
+				notes.push(format!("Outdated version of {} discarded", old.id));
+			}
+		}
+	}
+
+	for entry in known {
+		if found.contains_key(&entry.id) || quarantined.contains(&entry.id) { continue; }
+		match files.get(&entry.file) {
+			Some(meta) if entry.index.saturating_add(entry.length) > meta.size => {
+				notes.push(format!("{} dropped: it lies beyond the end of its file", entry.id));
+			},
+			Some(meta) if !meta.framed => {
+				found.insert(entry.id.clone(), entry);
+			},
+			Some(_) => {
+				let copy = quarantine(store_dir, area, &entry.file, entry.index, entry.length)?;
+				notes.push(format!(
+					"{} has no valid record, its bytes were moved to {:?}", entry.id, copy
+				));
+			},
+			None => {
+				notes.push(format!("{} dropped: its data file is missing", entry.id));
+			}
+		}
+	}
+	return Ok((files, found));
+}
+
+fn repair_singletons(store_dir: &Path, notes: &mut Vec<String>) -> io::Result<SingletonIndex> {
+	let known = faccess::load_singletons(store_dir)
+		.map(|index| index.singletons.into_iter().map(|(id, meta)| Entry {
+			id,
+			owner: meta.name,
+			data_type: meta.data_type,
+			file: meta.file,
+			index: meta.index,
+			length: meta.data_length,
+			stamp: 0
+		}).collect())
+		.unwrap_or_default()
+	;
+	let (files, found) = rebuild_area(store_dir, Area::Singletons, known, notes)?;
+
+	let mut index = SingletonIndex { files, singletons: HashMap::new() };
+	let mut by_name = HashMap::<String, (String, u64)>::new();
+	for (id, entry) in found {
+		if let Some((other, stamp)) = by_name.get(&entry.owner) {
+			if *stamp >= entry.stamp { continue; }
+			index.singletons.remove(other);
+		}
+		by_name.insert(entry.owner.clone(), (id.clone(), entry.stamp));
+		index.singletons.insert(id, SingletonMeta {
+			name: entry.owner,
+			data_type: entry.data_type,
+			file: entry.file,
+			index: entry.index,
+			data_length: entry.length
+		});
+	}
+	// The holes are the gaps left between the records kept
+	index.update_holes();
+	faccess::save_singletons(store_dir, &index)?;
+	notes.push(format!("Singletons index rebuilt: {} singletons", index.singletons.len()));
+	return Ok(index);
+}
+
+fn repair_collections(store_dir: &Path, notes: &mut Vec<String>) -> io::Result<CollectionIndex> {
+	let loaded = faccess::load_collections(store_dir).ok();
+	// The items keep their own compression, only the settings can be lost
+	let compression = loaded.as_ref()
+		.map(|index| index.compression.clone())
+		.unwrap_or_default()
+	;
+	let known = loaded
+		.map(|index| index.items.into_iter().map(|(id, meta)| Entry {
+			id,
+			owner: meta.collection,
+			data_type: meta.data_type,
+			file: meta.file,
+			index: meta.index,
+			length: meta.data_length,
+			stamp: 0
+		}).collect())
+		.unwrap_or_default()
+	;
+	let (files, found) = rebuild_area(store_dir, Area::Collections, known, notes)?;
+
+	let mut index = CollectionIndex {
+		list: faccess::load_collection_list(store_dir),
+		files,
+		items: HashMap::new(),
+		compression
+	};
+	for (id, entry) in found {
+		if entry.owner.len() != ID_LENGTH { continue; }
+		if !index.list.contains_key(&entry.owner) {
+			let name = format!("recovered-{}", entry.owner);
+			notes.push(format!("Collection {} recovered as {}", entry.owner, name));
+			index.list.insert(entry.owner.clone(), name);
+		}
+		index.items.insert(id, CollectionMeta {
+			collection: entry.owner,
+			data_type: entry.data_type,
+			file: entry.file,
+			index: entry.index,
+			data_length: entry.length
+		});
+	}
+	index.update_holes();
+	faccess::save_collections(store_dir, &index)?;
+
+	// The geo-point index is rebuilt by the server from the items
+	let mut geoindex = store_dir.to_path_buf();
+	geoindex.push("collections/geoindex");
+	if geoindex.exists() { fs::remove_file(geoindex)?; }
+	notes.push(format!(
+		"Collections index rebuilt: {} collections, {} items",
+		index.list.len(), index.items.len()
+	));
+	return Ok(index);
+}
+
+// Rebuilds the indexes of the singletons and of the collections from
+// their data files, and returns a description of what was done
+pub fn repair(store_dir: &Path, store: &Store) -> Result<Vec<String>, String> {
+	crypt::unlock(store_dir, store, None)?;
+	let mut notes = Vec::<String>::new();
+	for area in [Area::Singletons, Area::Collections] {
+		let repaired = match area {
+			Area::Singletons => repair_singletons(store_dir, &mut notes).map(|_| ()),
+			_ => repair_collections(store_dir, &mut notes).map(|_| ())
+		};
+		repaired.map_err(|e| format!("Failed to repair the {}: {}", area.dir(), e))?;
+
+		// The data was checked against the record headers
+		if store.checksumming {
+			let files = data_files(store_dir, area).map_err(|e| e.to_string())?;
+			for file in files.keys() {
+				let mut path = store_dir.to_path_buf();
+				path.push(area.dir());
+				path.push(file);
+				faccess::rebuild_checksums(store_dir, &path).map_err(|e| format!(
+					"Failed to rebuild the checksums of {}/{}: {}", area.dir(), file, e
+				))?;
+			}
+		}
+	}
+	return Ok(notes);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+	use crate::serve::testing;
+
+	// A store with a singleton and a collection of a few items
+	fn populated(dir: &Path) -> (PathBuf, Store) {
+		let store_dir = dir.join("store");
+		let mut engine = testing::create_store(&store_dir, json!({ "checksumming": false }));
+		let value = json!({ "name": "settings", "value": { "theme": "dark" } });
+		testing::mutate(&mut engine, "setSingleton", value).unwrap();
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "cities" })).unwrap();
+		for i in 0..10 {
+			testing::insert(&mut engine, "cities", json!({ "n": i }));
+		}
+		return (store_dir, engine.store);
+	}
+
+	// The first item of the store, with the path of its data file
+	// and the index of its record header
+	fn first_item(store_dir: &Path) -> (String, CollectionMeta, PathBuf, u64) {
+		let index = faccess::load_collections(store_dir).unwrap();
+		let (id, meta) = index.items.into_iter().min_by(|a, b| a.0.cmp(&b.0)).unwrap();
+		let path = store_dir.join("collections").join(&meta.file);
+		let start = meta.index - faccess::header_length(&id, &meta.collection);
+		return (id, meta, path, start);
+	}
+
+	#[test]
+	fn intact_store() {
+		let dir = tempfile::tempdir().unwrap();
+		let (store_dir, store) = populated(dir.path());
+		let before = faccess::load_collections(&store_dir).unwrap().items;
+		let notes = repair(&store_dir, &store).unwrap();
+		assert_eq!(notes, [
+			"Singletons index rebuilt: 1 singletons",
+			"Collections index rebuilt: 1 collections, 10 items"
+		]);
+		let after = faccess::load_collections(&store_dir).unwrap().items;
+		assert_eq!(after.len(), 10);
+		for (id, meta) in before {
+			assert_eq!((after[&id].file.as_str(), after[&id].index), (meta.file.as_str(), meta.index));
+		}
+		assert!(!store_dir.join("lost+found").exists());
+	}
+
+	#[test]
+	fn corrupted_payload() {
+		let dir = tempfile::tempdir().unwrap();
+		let (store_dir, store) = populated(dir.path());
+		let (id, meta, path, start) = first_item(&store_dir);
+		let byte = faccess::read_data(&path, meta.index, 1).unwrap()[0];
+		faccess::write_data(&path, meta.index, &[byte ^ 0xff]).unwrap();
+		let record = faccess::read_data(&path, start, meta.index + meta.data_length - start)
+			.unwrap()
+		;
+
+		let notes = repair(&store_dir, &store).unwrap();
+		let copy = store_dir.join(format!("lost+found/collections/{}-{}", meta.file, start));
+		assert!(notes.contains(&format!("Corrupted record of {} moved to {:?}", id, copy)));
+		assert!(notes.contains(&"Collections index rebuilt: 1 collections, 9 items".to_owned()));
+		assert_eq!(fs::read(&copy).unwrap(), record);
+		assert_eq!(fs::read_dir(copy.parent().unwrap()).unwrap().count(), 1);
+
+		// The record is freed, for the next repairs to leave it alone
+		assert!(!faccess::read_header(&path, meta.index).unwrap().unwrap().1.live);
+		assert!(!faccess::load_collections(&store_dir).unwrap().items.contains_key(&id));
+		let notes = repair(&store_dir, &store).unwrap();
+		assert!(!notes.iter().any(|n| n.contains(&id)));
+	}
+
+	#[test]
+	fn unreadable_header() {
+		let dir = tempfile::tempdir().unwrap();
+		let (store_dir, store) = populated(dir.path());
+		let (id, meta, path, start) = first_item(&store_dir);
+		// The stamp of the record, covered by the CRC of its header
+		faccess::write_data(&path, start + 6, &[0xff]).unwrap();
+		let payload = faccess::read_data(&path, meta.index, meta.data_length).unwrap();
+
+		let notes = repair(&store_dir, &store).unwrap();
+		let copy = store_dir.join(format!("lost+found/collections/{}-{}", meta.file, meta.index));
+		assert!(notes.contains(&format!(
+			"{} has no valid record, its bytes were moved to {:?}", id, copy
+		)));
+		assert_eq!(fs::read(&copy).unwrap(), payload);
+		let items = faccess::load_collections(&store_dir).unwrap().items;
+		assert_eq!(items.len(), 9);
+		assert!(!items.contains_key(&id));
+	}
+
+	#[test]
+	fn holes_between_the_records_kept() {
+		let dir = tempfile::tempdir().unwrap();
+		let (store_dir, _) = populated(dir.path());
+		let (_, meta, path, start) = first_item(&store_dir);
+		faccess::write_data(&path, start + 6, &[0xff]).unwrap();
+
+		let index = repair_collections(&store_dir, &mut Vec::new()).unwrap();
+		let holes = &index.files[&meta.file].holes;
+		assert_eq!(holes.get(&start), Some(&(meta.index + meta.data_length - start)));
+		assert_eq!(holes, &faccess::load_collections(&store_dir).unwrap().files[&meta.file].holes);
+		let index = repair_singletons(&store_dir, &mut Vec::new()).unwrap();
+		assert!(index.files.values().all(|file| file.holes.is_empty()));
+	}
+}
@@ -1,6 +1,672 @@
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+
 use clap::ArgMatches;
 
+use crate::cli;
+use crate::check;
+use crate::backup;
+use crate::backup::Step;
+use crate::archive::{ self, ArchiveReader };
+use crate::basics::{ self, Store, StoreType };
+use crate::serve::{ self, mutlog::{ self, Entry } };
+
+// Directories of a store which are created empty when missing
+pub const STORE_DIRS: [&str; 9] = [
+	"singletons", "collections", "edges", "keyvalues", "timeseries",
+	"blobs", "checksums", "logs", "tmp"
+];
+
+// Checks that the running OrixDB version can serve the restored store
+fn check_version(store: &Store) -> bool {
+	let conf = basics::get_conf();
+	if store.major != conf.major || store.minor > conf.minor {
+		cli::red_err(format!(
+			"The store was made with version {}.{} of {}, which is not \
+			compatible with the current version {}.{}.\n\
+			Please restore it with a matching version.",
+			store.major, store.minor, conf.display_name, conf.major, conf.minor
+		));
+		return false;
+	}
+	if store.minor < conf.minor {
+		cli::yellow_err(format!(
+			"The minor version of the store is lower than the current {} version.\n\
+			Please consider upgrading it with the `upgrade` subcommand once restored.",
+			conf.display_name
+		));
+	}
+	return true;
+}
+
+// Extracts every file of a verified archive
+pub fn extract_archive(source: &Path, target: &Path) -> io::Result<usize> {
+	let mut archive = ArchiveReader::open(source)?;
+	let mut count = 0;
+	while let Some(entry) = archive.next_entry()? {
+		let path = target.join(&entry.path);
+		if let Some(parent) = path.parent() { fs::create_dir_all(parent)?; }
+		let mut file = fs::File::create(&path)?;
+		archive.extract(&entry, &mut file)?;
+		file.sync_all()?;
+		count += 1;
+	}
+	return Ok(count);
+}
+
+// Copies every file of the full backup of a backup store,
+// then replays its incremental backups
+pub fn copy_backup(source: &Path, target: &Path, until: u64) -> io::Result<usize> {
+	let files = archive::store_files(source)?.into_iter()
+		.filter(|f| !f.starts_with("chain/"))
+		.collect::<Vec<String>>()
+	;
+	for relative in &files {
+		let path = target.join(relative);
+		if let Some(parent) = path.parent() { fs::create_dir_all(parent)?; }
+		fs::copy(source.join(relative), &path)?;
+	}
+	for sequence in 1..=until {
+		backup::read_delta(&backup::delta_path(source, sequence), Some(target))?;
+	}
+	return Ok(files.len());
+}
+
+// Checks the incremental backups of a backup store up to the given one,
+// and returns the sequence number of the last one to replay
+pub fn check_chain(source: &Path, until: Option<u64>) -> Result<u64, String> {
+	let last = match backup::load_state(source) {
+		Ok(state) => state.sequence,
+		Err(_) if until.unwrap_or(0) == 0 => return Ok(0), // Plain backup store
+		Err(e) => return Err(format!("The backup store has no valid chain: {}", e))
+	};
+	let until = until.unwrap_or(last);
+	if until > last {
+		return Err(format!(
+			"The backup store holds incremental backups up to {} only.", last
+		));
+	}
+	for sequence in 1..=until {
+		let header = backup::read_delta(&backup::delta_path(source, sequence), None)
+			.map_err(|e| format!("Invalid incremental backup {}: {}", sequence, e))?
+		;
+		if header.sequence != sequence {
+			return Err(format!("Incremental backup {} is out of place.", sequence));
+		}
+	}
+	return Ok(until);
+}
+
+// Finds the latest backup made at the given time or before, in a backup store
+// or in a directory of backup stores. Returns that store and its step.
+fn pick_backup(source: &Path, until: u64) -> Result<(PathBuf, Step), String> {
+	let stores = match source.join("manifest.json").exists() {
+		true => vec![source.to_path_buf()],
+		false => fs::read_dir(source)
+			.map_err(|e| format!("Failed to list {:?}: {}", source, e))?
+			.flatten().map(|e| e.path())
+			.filter(|p| p.join("manifest.json").exists())
+			.collect()
+	};
+	let mut picked: Option<(PathBuf, Step)> = None;
+	for store_dir in stores {
+		let state = match backup::load_state(&store_dir) {
+			Ok(state) => state,
+			Err(_) => continue
+		};
+		let step = state.steps.into_iter().rfind(|s| s.created <= until);
+		if let Some(step) = step {
+			if picked.as_ref().is_none_or(|(_, p)| p.created < step.created) {
+				picked = Some((store_dir, step));
+			}
+		}
+	}
+	return picked.ok_or(format!("No backup in {:?} was made at {} or before.", source, until));
+}
+
+// Reads the mutations to replay after a backup step, up to the given time,
+// and checks that none is missing in between
+fn read_mutations(
+	log_dir: &Path, store: &Store, step: &Step, until: u64
+) -> Result<Vec<Entry>, String> {
+	if let Ok(logged) = basics::load_store(&log_dir.join("../..")) {
+		if logged.id != store.id {
+			return Err("The mutation logs belong to another store.".to_owned());
+		}
+	}
+	let entries = mutlog::read_entries(log_dir)
+		.map_err(|e| format!("Failed to read the mutation logs: {}", e))?
+		.into_iter()
+		.filter(|e| e.lsn > step.lsn && e.time <= until)
+		.collect::<Vec<Entry>>()
+	;
+	for (expected, entry) in (step.lsn + 1..).zip(&entries) {
+		if entry.lsn != expected {
+			return Err(format!(
+				"The mutation logs miss the mutation {}, they don't cover the time \
+				between the backup and {}.", expected, until
+			));
+		}
+		if entry.name == "uploadBlob" {
+			let file = entry.args.get("file").and_then(|f| f.as_str()).unwrap_or_default();
+			if !mutlog::blobs_dir(log_dir).join(file).is_file() {
+				return Err(format!("The blob uploaded by the mutation {} is missing.", entry.lsn));
+			}
+		}
+	}
+	return Ok(entries);
+}
+
+// Replays mutations over a restored store, and carries its mutation logs
+// over up to the last one, for its sequence numbers to go on from there
+fn replay_mutations(
+	target: &Path, log_dir: &Path, step: &Step, entries: &[Entry]
+) -> Result<(), String> {
+	let store = basics::load_store(target)?;
+	let mut engine = serve::open_engine(basics::get_conf(), target.to_path_buf(), store, false)?;
+	for entry in entries {
+		mutlog::replay(&mut engine, entry, log_dir)?;
+	}
+	let last = entries.last().map(|e| e.lsn).unwrap_or(step.lsn);
+	return mutlog::copy_entries(log_dir, &mutlog::log_dir(target), last)
+		.map_err(|e| format!("Failed to copy the mutation logs: {}", e))
+	;
+}
+
+// Creates the directories missing from restored files
+pub fn create_dirs(target: &Path) -> io::Result<()> {
+	for dir in STORE_DIRS {
+		fs::create_dir_all(target.join(dir))?;
+	}
+	return Ok(());
+}
+
+// Turns the restored files into a store that can be served
+fn finish_store(target: &Path, mut store: Store) -> io::Result<()> {
+	create_dirs(target)?;
+	if store.kind == StoreType::Backup || store.kind == StoreType::Archive {
+		store.kind = StoreType::Live;
+	}
+	let text = serde_json::to_string_pretty(&store).map_err(io::Error::other)?;
+	fs::write(target.join("manifest.json"), text)?;
+	return Ok(());
+}
+
+// Removes what was written in the target directory after a failure
+pub fn clean_target(target: &Path, created: bool) {
+	if created {
+		let _ = fs::remove_dir_all(target);
+		return;
+	}
+	if let Ok(entries) = fs::read_dir(target) {
+		for entry in entries.flatten() {
+			let path = entry.path();
+			let _ = if path.is_dir() { fs::remove_dir_all(&path) }
+				else { fs::remove_file(&path) }
+			;
+		}
+	}
+}
+
 pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
-	println!("{:#?}", matches);
+
+	// --> Checking the command line arguments
+	// ---------------------------------------
+
+	let mut source = PathBuf::from(matches.get_one::<String>("source").unwrap());
+	if !source.exists() {
+		cli::red_err(
+			"The source supplied doesn't lead to an archive or a backup store.".to_owned()
+		);
+		return std::process::ExitCode::FAILURE;
+	}
+
+	let target: PathBuf;
+	if matches.contains_id("directory") {
+		target = PathBuf::from(matches.get_one::<String>("directory").unwrap());
+	}
+	else { target = PathBuf::from(".").canonicalize().unwrap(); }
+	if target.is_file() {
+		cli::red_err(
+			"The store path resolves to a file.\n".to_owned()
+			+ "A store can't be restored in a file but in a directory."
+		);
+		return std::process::ExitCode::FAILURE;
+	}
+	let sequence = match matches.get_one::<String>("sequence").map(|s| s.parse::<u64>()) {
+		None => None,
+		Some(Ok(sequence)) => Some(sequence),
+		Some(Err(_)) => {
+			cli::red_err("The last backup to replay must be a sequence number.".to_owned());
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+	let until = match matches.get_one::<String>("until").map(|u| u.parse::<u64>()) {
+		None => None,
+		Some(Ok(until)) => Some(until),
+		Some(Err(_)) => {
+			cli::red_err("The time to restore must be a Unix time in milliseconds.".to_owned());
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+	let mut log_dir = matches.get_one::<String>("logs").map(PathBuf::from);
+	if until.is_some() {
+		if sequence.is_some() || source.is_file() {
+			cli::red_err(
+				"A time can only be restored from backup stores, without `--sequence`.".to_owned()
+			);
+			return std::process::ExitCode::FAILURE;
+		}
+		// Either a store or its mutation logs directory
+		log_dir = log_dir.map(|dir| {
+			if dir.join("manifest.json").exists() { mutlog::log_dir(&dir) } else { dir }
+		});
+		if !log_dir.as_ref().is_some_and(|dir| dir.is_dir()) {
+			cli::red_err(
+				"Restoring a time needs the mutation logs of the store, \
+				given with `--logs`.".to_owned()
+			);
+			return std::process::ExitCode::FAILURE;
+		}
+	}
+	let created = !target.exists();
+	if !created {
+		let is_empty = target.read_dir().map(|mut d| d.next().is_none()).unwrap_or(false);
+		if !is_empty {
+			cli::red_err(
+				"The store directory is not empty.\n".to_owned()
+				+ "Then a store can't be restored there."
+			);
+			return std::process::ExitCode::FAILURE;
+		}
+	}
+
+
+	// --> Verifying the source before writing anything
+	// ------------------------------------------------
+
+	let store: Store;
+	let mut replayed = 0u64;
+	let mut mutations = Vec::<Entry>::new();
+	let mut picked: Option<Step> = None;
+	if source.is_file() {
+		if sequence.is_some() {
+			cli::red_err("Archives have no incremental backups to replay.".to_owned());
+			return std::process::ExitCode::FAILURE;
+		}
+		store = match ArchiveReader::open(&source) {
+			Ok(archive) => archive.header.store,
+			Err(e) => {
+				cli::red_err(format!("Invalid archive {:?}: {}", source, e));
+				return std::process::ExitCode::FAILURE;
+			}
+		};
+		if !check_version(&store) { return std::process::ExitCode::FAILURE; }
+		match archive::read_archive(&source, true) {
+			Ok((_, entries)) => {
+				cli::green_out(format!(
+					"Archive verified: {} files of store \"{}\".", entries.len(), store.name
+				));
+			},
+			Err(e) => {
+				cli::red_err(format!("Invalid archive {:?}: {}", source, e));
+				return std::process::ExitCode::FAILURE;
+			}
+		}
+	}
+	else {
+		// With a time, the backup to start from is the latest one made before it
+		if let Some(until) = until {
+			match pick_backup(&source, until) {
+				Ok((store_dir, step)) => {
+					source = store_dir;
+					picked = Some(step);
+				},
+				Err(message) => {
+					cli::red_err(message);
+					return std::process::ExitCode::FAILURE;
+				}
+			}
+		}
+		let sequence = picked.as_ref().map(|step| step.sequence).or(sequence);
+		store = match basics::load_store(&source) {
+			Ok(store) => store,
+			Err(message) => {
+				cli::red_err(message);
+				return std::process::ExitCode::FAILURE;
+			}
+		};
+		if store.kind != StoreType::Backup {
+			cli::red_err(
+				"Only archives and backup stores can be restored.\n".to_owned()
+				+ "Use the `copy` subcommand to duplicate other stores."
+			);
+			return std::process::ExitCode::FAILURE;
+		}
+		if !check_version(&store) { return std::process::ExitCode::FAILURE; }
+		let report = check::verify(&source);
+		if report.errors() > 0 {
+			for issue in report.issues.iter().filter(|i| i.severity == check::Severity::Error) {
+				cli::red_err(format!("[{}] {}: {}", issue.section, issue.path, issue.message));
+			}
+			cli::red_err("The backup store is corrupted, nothing was restored.".to_owned());
+			return std::process::ExitCode::FAILURE;
+		}
+		replayed = match check_chain(&source, sequence) {
+			Ok(replayed) => replayed,
+			Err(message) => {
+				cli::red_err(message + "\nNothing was restored.");
+				return std::process::ExitCode::FAILURE;
+			}
+		};
+		if let (Some(until), Some(log_dir), Some(step)) = (until, &log_dir, &picked) {
+			mutations = match read_mutations(log_dir, &store, step, until) {
+				Ok(mutations) => mutations,
+				Err(message) => {
+					cli::red_err(message + "\nNothing was restored.");
+					return std::process::ExitCode::FAILURE;
+				}
+			};
+		}
+		cli::green_out(format!(
+			"Backup store \"{}\" verified, with {} incremental backups and {} mutations \
+			to replay.", store.name, replayed, mutations.len()
+		));
+	}
+
+
+	// --> Writing and checking the restored store
+	// -------------------------------------------
+
+	let name = store.name.clone();
+	let restored = fs::create_dir_all(&target)
+		.and_then(|_| {
+			if source.is_file() { extract_archive(&source, &target) }
+			else { copy_backup(&source, &target, replayed) }
+		})
+		.and_then(|count| finish_store(&target, store).map(|_| count))
+		.and_then(|count| match (&log_dir, &picked) {
+			(Some(log_dir), Some(step)) => replay_mutations(&target, log_dir, step, &mutations)
+				.map(|_| count).map_err(io::Error::other),
+			_ => Ok(count)
+		})
+	;
+	let count = match restored {
+		Ok(count) => count,
+		Err(e) => {
+			clean_target(&target, created);
+			cli::red_err(format!("Failed to restore the store: {}", e));
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+
+	let report = check::verify(&target);
+	if report.errors() > 0 {
+		clean_target(&target, created);
+		cli::red_err(format!(
+			"The restored store has {} errors, so it was removed.",
+			report.errors()
+		));
+		return std::process::ExitCode::FAILURE;
+	}
+	cli::green_out(format!(
+		"Store \"{}\" restored in {:?} ({} files).", name, target, count
+	));
 	return std::process::ExitCode::SUCCESS;
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::HashMap;
+	use std::process::ExitCode;
+	use std::sync::RwLock;
+	use clap::{ Arg, Command };
+	use serde_json::{ json, Value };
+	use crate::serve::{ Engine, testing, backup::{ hot_backup, incremental_backup } };
+
+	// A store with a few entries, and an archive of it
+	fn archived(dir: &Path) -> (PathBuf, PathBuf) {
+		let store_dir = dir.join("store");
+		let mut engine = testing::create_store(&store_dir, json!({ "name": "restored" }));
+		for i in 0..5 {
+			let args = json!({ "key": format!("key{}", i), "value": "x".repeat(i * 100) });
+			testing::mutate(&mut engine, "setKey", args).unwrap();
+		}
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "notes" })).unwrap();
+		testing::insert(&mut engine, "notes", json!({ "text": "hello" }));
+		let target = dir.join("store.orix");
+		let files = archive::store_files(&store_dir).unwrap();
+		archive::write_archive(&store_dir, &engine.store, &files, &target).unwrap();
+		return (store_dir, target);
+	}
+
+	fn restore(source: &Path, target: &Path, options: &[&str]) -> ExitCode {
+		let mut args = vec!["restore", source.to_str().unwrap(), target.to_str().unwrap()];
+		args.extend(options);
+		let matches = Command::new("restore")
+			.arg(Arg::new("source"))
+			.arg(Arg::new("directory"))
+			.arg(Arg::new("sequence").long("sequence"))
+			.arg(Arg::new("until").long("until"))
+			.arg(Arg::new("logs").long("logs"))
+			.get_matches_from(args)
+		;
+		return main(&matches);
+	}
+
+	fn set_kind(store_dir: &Path, kind: &str) {
+		let path = store_dir.join("manifest.json");
+		let text = fs::read_to_string(&path).unwrap();
+		let mut manifest = serde_json::from_str::<serde_json::Value>(&text).unwrap();
+		manifest["kind"] = json!(kind);
+		fs::write(&path, manifest.to_string()).unwrap();
+	}
+
+	#[test]
+	fn restores_archives() {
+		let dir = tempfile::tempdir().unwrap();
+		let (store_dir, source) = archived(dir.path());
+		let target = dir.path().join("target");
+		assert_eq!(restore(&source, &target, &[]), ExitCode::SUCCESS);
+		assert_eq!(check::verify(&target).errors(), 0);
+		assert_eq!(basics::load_store(&target).unwrap().kind, StoreType::Live);
+		let files = archive::store_files(&store_dir).unwrap();
+		for file in files.iter().filter(|f| *f != "manifest.json") {
+			assert_eq!(fs::read(target.join(file)).unwrap(), fs::read(store_dir.join(file)).unwrap());
+		}
+		for dir in STORE_DIRS { assert!(target.join(dir).is_dir(), "{}", dir); }
+
+		// Only in an empty directory
+		assert_eq!(restore(&source, &target, &[]), ExitCode::FAILURE);
+
+		// Nothing is written from a corrupted archive
+		let mut bytes = fs::read(&source).unwrap();
+		let (_, entries) = archive::read_archive(&source, true).unwrap();
+		let largest = entries.iter().max_by_key(|e| e.compressed).unwrap();
+		bytes[(largest.offset + largest.compressed / 2) as usize] ^= 0x55;
+		fs::write(&source, bytes).unwrap();
+		let target = dir.path().join("corrupted");
+		assert_eq!(restore(&source, &target, &[]), ExitCode::FAILURE);
+		assert!(!target.exists());
+	}
+
+	#[test]
+	fn restores_backup_stores_only() {
+		let dir = tempfile::tempdir().unwrap();
+		let (store_dir, _) = archived(dir.path());
+		let target = dir.path().join("target");
+		assert_eq!(restore(&store_dir, &target, &[]), ExitCode::FAILURE);
+		assert!(!target.exists());
+
+		set_kind(&store_dir, "Backup");
+		assert_eq!(restore(&store_dir, &target, &[]), ExitCode::SUCCESS);
+		assert_eq!(basics::load_store(&target).unwrap().kind, StoreType::Live);
+		assert_eq!(check::verify(&target).errors(), 0);
+	}
+
+	// A served store, backed up as a whole in `dir/backup`
+	fn backed_up(dir: &Path) -> (RwLock<Engine>, PathBuf) {
+		let mut engine = testing::create_store(&dir.join("store"), json!({}));
+		for i in 0..50 {
+			let value = json!({ "n": i, "text": "x".repeat(i * 20) });
+			testing::mutate(&mut engine, "setKey", json!({ "key": format!("key{}", i), "value": value }))
+				.unwrap()
+			;
+		}
+		let engine = RwLock::new(engine);
+		let backup_dir = dir.join("backup");
+		hot_backup(&engine, &backup_dir).unwrap();
+		return (engine, backup_dir);
+	}
+
+	fn mutate(engine: &RwLock<Engine>, name: &str, args: Value) {
+		testing::mutate(&mut engine.write().unwrap(), name, args).unwrap();
+	}
+
+	// The contents of the files of a store, but its manifest
+	fn contents(store_dir: &Path) -> HashMap<String, Vec<u8>> {
+		return archive::store_files(store_dir).unwrap().into_iter()
+			.filter(|f| f != "manifest.json" && !f.starts_with("chain/"))
+			.map(|f| { let bytes = fs::read(store_dir.join(&f)).unwrap(); (f, bytes) })
+			.collect()
+		;
+	}
+
+	#[test]
+	fn restores_incremental_backups() {
+		let dir = tempfile::tempdir().unwrap();
+		let (engine, backup_dir) = backed_up(dir.path());
+		let store_dir = engine.read().unwrap().store_dir.clone();
+		let full = contents(&store_dir);
+
+		// Values updated in place, grown, removed and added
+		mutate(&engine, "setKey", json!({ "key": "key3", "value": { "n": -3 } }));
+		mutate(&engine, "setKey", json!({ "key": "key7", "value": "y".repeat(5000) }));
+		mutate(&engine, "deleteKey", json!({ "key": "key12" }));
+		mutate(&engine, "setKey", json!({ "key": "new", "value": [1, 2, 3] }));
+		assert_eq!(incremental_backup(&engine, &backup_dir).unwrap().0, 1);
+		let first = contents(&store_dir);
+
+		mutate(&engine, "deleteKey", json!({ "key": "key40" }));
+		mutate(&engine, "setKey", json!({ "key": "key3", "value": "z".repeat(300) }));
+		assert_eq!(incremental_backup(&engine, &backup_dir).unwrap().0, 2);
+		let second = contents(&store_dir);
+		assert_ne!(first, second);
+
+		for (until, expected) in [(0, &full), (1, &first), (2, &second)] {
+			let target = dir.path().join(format!("restored{}", until));
+			assert_eq!(check_chain(&backup_dir, Some(until)).unwrap(), until);
+			copy_backup(&backup_dir, &target, until).unwrap();
+			assert_eq!(&contents(&target), expected, "up to {}", until);
+		}
+		assert_eq!(check_chain(&backup_dir, None).unwrap(), 2);
+		assert!(check_chain(&backup_dir, Some(3)).is_err());
+	}
+
+	#[test]
+	fn backs_up_the_tracked_writes() {
+		let dir = tempfile::tempdir().unwrap();
+		let (engine, backup_dir) = backed_up(dir.path());
+		let store_dir = engine.read().unwrap().store_dir.clone();
+
+		// A write in place, which keeps the size and modification time of the files
+		let path = engine.read().unwrap().data_files()[0].clone();
+		let sums = crate::faccess::checksum_path(&store_dir, &path);
+		let modified = [&path, &sums].map(|p| fs::metadata(p).unwrap().modified().unwrap());
+		let content = fs::read(&path).unwrap();
+		let index = content.windows(4).position(|w| w == b"xxxx").unwrap() as u64;
+		crate::faccess::write_bytes(&path, index, b"yyyy", false).unwrap();
+		engine.read().unwrap().update_checksums(&path, index, 4).unwrap();
+		for (file, modified) in [&path, &sums].into_iter().zip(modified) {
+			fs::File::options().write(true).open(file).unwrap().set_modified(modified).unwrap();
+		}
+		incremental_backup(&engine, &backup_dir).unwrap();
+		let target = dir.path().join("restored1");
+		copy_backup(&backup_dir, &target, 1).unwrap();
+		assert_eq!(contents(&target), contents(&store_dir));
+
+		// Nothing is tracked by another server, so all the files are copied
+		let engine = RwLock::new(testing::open_store(&store_dir));
+		let (sequence, files, _) = incremental_backup(&engine, &backup_dir).unwrap();
+		assert_eq!((sequence, files), (2, contents(&store_dir).len()));
+		let target = dir.path().join("restored2");
+		copy_backup(&backup_dir, &target, 2).unwrap();
+		assert_eq!(contents(&target), contents(&store_dir));
+	}
+
+	#[test]
+	fn rejects_broken_chains() {
+		let dir = tempfile::tempdir().unwrap();
+		let (engine, backup_dir) = backed_up(dir.path());
+		for i in 0..2 {
+			mutate(&engine, "setKey", json!({ "key": format!("key{}", i), "value": i * 10 }));
+			incremental_backup(&engine, &backup_dir).unwrap();
+		}
+		let first = backup::delta_path(&backup_dir, 1);
+		let second = backup::delta_path(&backup_dir, 2);
+		let (one, two) = (fs::read(&first).unwrap(), fs::read(&second).unwrap());
+
+		// Out of order
+		fs::write(&first, &two).unwrap();
+		fs::write(&second, &one).unwrap();
+		let error = check_chain(&backup_dir, None).unwrap_err();
+		assert!(error.contains("Incremental backup 1 is out of place."), "{}", error);
+
+		// Missing
+		fs::write(&second, &two).unwrap();
+		fs::remove_file(&first).unwrap();
+		let error = check_chain(&backup_dir, None).unwrap_err();
+		assert!(error.contains("Invalid incremental backup 1"), "{}", error);
+		assert!(check_chain(&backup_dir, Some(0)).is_ok());
+		assert!(copy_backup(&backup_dir, &dir.path().join("restored"), 2).is_err());
+
+		// Truncated
+		fs::write(&first, &one[..one.len() - 1]).unwrap();
+		assert!(check_chain(&backup_dir, None).is_err());
+	}
+
+	#[test]
+	fn restores_points_in_time() {
+		let dir = tempfile::tempdir().unwrap();
+		let store_dir = dir.path().join("store");
+		let settings = json!({ "mutation_logs": { "retention": 0 } });
+		let engine = RwLock::new(testing::create_store(&store_dir, settings));
+		mutate(&engine, "setKey", json!({ "key": "a", "value": 1 }));
+		let backup_dir = dir.path().join("backup");
+		hot_backup(&engine, &backup_dir).unwrap();
+		for i in 0..4 {
+			std::thread::sleep(std::time::Duration::from_millis(2));
+			mutate(&engine, "setKey", json!({ "key": "b", "value": i }));
+		}
+		drop(engine);
+		let log_dir = mutlog::log_dir(&store_dir);
+		let entries = mutlog::read_entries(&log_dir).unwrap();
+		assert_eq!(entries.iter().map(|e| e.lsn).collect::<Vec<u64>>(), [1, 2, 3, 4, 5]);
+
+		// Up to the mutation setting b to 1
+		let logs = store_dir.to_str().unwrap();
+		let at = |target: &str, until: u64| restore(
+			&backup_dir, &dir.path().join(target), &["--until", &until.to_string(), "--logs", logs]
+		);
+		assert_eq!(at("target", entries[2].time), ExitCode::SUCCESS);
+		let target = dir.path().join("target");
+		let engine = testing::open_store(&target);
+		let now = basics::now_millis();
+		assert_eq!(serve::query::keyvalue(&engine, "a", now).unwrap(), json!(1));
+		assert_eq!(serve::query::keyvalue(&engine, "b", now).unwrap(), json!(1));
+		assert_eq!(at("early", 0), ExitCode::FAILURE);
+
+		// Without the mutation 3, the logs don't reach the end anymore
+		let path = fs::read_dir(&log_dir).unwrap().flatten().map(|e| e.path())
+			.find(|p| p.extension().is_some_and(|e| e == "log")).unwrap()
+		;
+		let text = fs::read_to_string(&path).unwrap();
+		let kept = text.lines().filter(|l| !l.starts_with("{\"lsn\":3,")).collect::<Vec<&str>>();
+		assert_eq!(kept.len(), 4);
+		fs::write(&path, kept.join("\n") + "\n").unwrap();
+		assert_eq!(at("gap", entries[4].time), ExitCode::FAILURE);
+		assert!(!dir.path().join("gap").exists());
+	}
+}
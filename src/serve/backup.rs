@@ -0,0 +1,407 @@
+use std::fs;
+use std::io::{ self, Read, Seek, SeekFrom };
+use std::path::{ Path, PathBuf };
+use std::collections::{ BTreeSet, HashMap, HashSet };
+use std::sync::{ Arc, RwLock };
+
+use serde_json::json;
+use tiny_http::{ Method, Request };
+
+use crate::crypt;
+use crate::archive;
+use crate::basics::{ self, StoreType };
+use crate::faccess::{ self, BLOCK_SIZE };
+use crate::backup::{ self, ChainState, DeltaHeader, DeltaWriter, Step, Version };
+use super::{ Engine, respond_json };
+
+// Largest read done at once while copying a file into a delta
+const COPY_SIZE: u64 = 1024 * 1024;
+
+// Parts of the data files written since the last backup of a chain,
+// so that the next incremental backup only copies them
+#[derive(Debug)]
+#[derive(Default)]
+pub struct Changes {
+	since: Option<(String, u64)>, // Chain id and sequence of that backup
+	blocks: HashMap<String, BTreeSet<u64>>, // By path relative to the store
+	rewritten: HashSet<String>, // Files rewritten as a whole
+	copying: Option<HashSet<String>> // Files written during the first pass of a hot backup
+}
+
+impl Changes {
+	// Nothing is tracked for the chain until a backup starts one.
+	// A write of no bytes is a truncation, whose new size is backed up.
+	pub fn track(&mut self, relative: String, index: u64, length: u64) {
+		if let Some(copying) = &mut self.copying { copying.insert(relative.clone()); }
+		if self.since.is_none() { return; }
+		let blocks = self.blocks.entry(relative).or_default();
+		if length == 0 { return; }
+		blocks.extend(index / BLOCK_SIZE..=(index + length - 1) / BLOCK_SIZE);
+	}
+
+	pub fn track_rewrite(&mut self, relative: String) {
+		if let Some(copying) = &mut self.copying { copying.insert(relative.clone()); }
+		if self.since.is_none() { return; }
+		self.rewritten.insert(relative);
+	}
+
+	fn reset(&mut self, chain: String, sequence: u64) {
+		self.since = Some((chain, sequence));
+		self.blocks.clear();
+		self.rewritten.clear();
+	}
+}
+
+fn copy_file(store_dir: &Path, target: &Path, relative: &str) -> io::Result<()> {
+	let path = target.join(relative);
+	if let Some(parent) = path.parent() { fs::create_dir_all(parent)?; }
+	fs::copy(store_dir.join(relative), path)?;
+	return Ok(());
+}
+
+// Copies the served store into a new backup store, without blocking the readers.
+// The files are first copied while clients keep writing, then the ones which
+// changed in between are copied again while writes are held, so that the backup
+// matches a single point in time. The server tracks the files written during the
+// first pass, whatever their size and modification time. Returns the number of files and of bytes copied.
+pub fn hot_backup(engine: &RwLock<Engine>, target: &Path) -> Result<(usize, u64), String> {
+	if target.is_file() {
+		return Err("The backup path resolves to a file.".to_owned());
+	}
+	if target.read_dir().map(|mut d| d.next().is_some()).unwrap_or(false) {
+		return Err("The backup directory is not empty.".to_owned());
+	}
+	let mut partial = target.to_path_buf().into_os_string();
+	partial.push(".part");
+	let partial = PathBuf::from(partial);
+	if partial.exists() {
+		return Err(format!("{:?} already exists.", partial));
+	}
+
+	let store_dir = engine.read().unwrap().store_dir.clone();
+	let copied = copy_store(engine, &store_dir, &partial)
+		.and_then(|copied| {
+			if target.exists() { fs::remove_dir(target)?; }
+			fs::rename(&partial, target)?;
+			Ok(copied)
+		})
+	;
+	return copied.map_err(|e| {
+		let _ = fs::remove_dir_all(&partial);
+		format!("Failed to back the store up: {}", e)
+	});
+}
+
+fn copy_store(
+	engine: &RwLock<Engine>, store_dir: &Path, target: &Path
+) -> io::Result<(usize, u64)> {
+	let copied = copy_files(engine, store_dir, target)?;
+	return copy_written(engine, store_dir, target, copied);
+}
+
+// First pass, while the store keeps changing. The writes are tracked from
+// before it, as none is in progress. Returns the files copied, the ones
+// removed in between are skipped.
+fn copy_files(
+	engine: &RwLock<Engine>, store_dir: &Path, target: &Path
+) -> io::Result<HashSet<String>> {
+	engine.read().unwrap().changes.lock().unwrap().copying = Some(HashSet::new());
+	let copied = fs::create_dir_all(target)
+		.and_then(|_| archive::store_files(store_dir))
+		.and_then(|files| {
+			let mut copied = HashSet::<String>::new();
+			for relative in files {
+				match copy_file(store_dir, target, &relative) {
+					Ok(()) => { copied.insert(relative); },
+					Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+					Err(e) => return Err(e)
+				}
+			}
+			Ok(copied)
+		})
+	;
+	if copied.is_err() { engine.read().unwrap().changes.lock().unwrap().copying = None; }
+	return copied;
+}
+
+// Second pass, with writes held: copies the files written or created since the first
+fn copy_written(
+	engine: &RwLock<Engine>, store_dir: &Path, target: &Path, mut copied: HashSet<String>
+) -> io::Result<(usize, u64)> {
+	let engine = engine.read().unwrap();
+	let written = engine.changes.lock().unwrap().copying.take().unwrap_or_default();
+	let files = archive::store_files(store_dir)?;
+	let mut state = ChainState {
+		chain: faccess::new_id(),
+		sequence: 0,
+		files: HashMap::new(),
+		steps: vec![Step { sequence: 0, created: basics::now_millis(), lsn: engine.lsn() }]
+	};
+	let mut bytes = 0u64;
+	for relative in &files {
+		if !copied.remove(relative) || written.contains(relative) {
+			copy_file(store_dir, target, relative)?;
+		}
+		let now = backup::file_version(&store_dir.join(relative))?;
+		bytes += now.0;
+		if relative != "manifest.json" { state.files.insert(relative.clone(), now); }
+	}
+	for relative in &copied {
+		fs::remove_file(target.join(relative))?;
+	}
+	let mut store = serde_json::to_value(&engine.store).map_err(io::Error::other)?;
+	store["kind"] = json!(StoreType::Backup);
+	store.as_object_mut().unwrap().remove("backups");
+	let text = serde_json::to_string_pretty(&store).map_err(io::Error::other)?;
+	fs::write(target.join("manifest.json"), text)?;
+
+	// This backup starts a new chain of incremental backups
+	backup::save_state(target, &state)?;
+	engine.changes.lock().unwrap().reset(state.chain, 0);
+	drop(engine);
+
+	for dir in ["checksums", "logs", "tmp"] {
+		fs::create_dir_all(target.join(dir))?;
+	}
+	return Ok((files.len(), bytes));
+}
+
+// Groups consecutive blocks into ranges, within the size of their file.
+// The blocks of a sealed file are found where they are stored.
+fn block_ranges(blocks: &BTreeSet<u64>, size: u64, sealed: bool) -> Vec<(u64, u64)> {
+	let mut ranges = Vec::<(u64, u64)>::new();
+	for block in blocks {
+		let (start, end) = match sealed {
+			true => crypt::sealed_span(*block),
+			false => (block * BLOCK_SIZE, (block + 1) * BLOCK_SIZE)
+		};
+		if start >= size { break; }
+		let end = end.min(size);
+		match ranges.last_mut() {
+			Some(last) if last.1 == start => last.1 = end,
+			_ => ranges.push((start, end))
+		}
+	}
+	return ranges;
+}
+
+// Copies a range of a file as it is stored, sealed or not
+fn copy_range(
+	delta: &mut DeltaWriter, path: &Path, relative: &str, start: u64, end: u64
+) -> io::Result<u64> {
+	let mut file = fs::File::open(path)?;
+	file.seek(SeekFrom::Start(start))?;
+	let mut offset = start;
+	while offset < end {
+		let mut bytes = vec![0u8; (end - offset).min(COPY_SIZE) as usize];
+		file.read_exact(&mut bytes)?;
+		delta.range(relative, offset, &bytes)?;
+		offset += bytes.len() as u64;
+	}
+	return Ok(end - start);
+}
+
+// Adds the changes made since the last backup of a backup store to its chain.
+// When the server tracked them since then, the data files ranges written and the
+// files rewritten or created are copied, and no other. Otherwise all the files are
+// copied as a whole. Writes are held in the meantime. Returns the sequence number of the backup, and the number of files
+// and of bytes it holds.
+pub fn incremental_backup(
+	engine: &RwLock<Engine>, backup_dir: &Path
+) -> Result<(u64, usize, u64), String> {
+	let failed = |e: io::Error| format!("Failed to back the store up: {}", e);
+	let manifest = basics::load_store(backup_dir)?;
+	let mut state = backup::load_state(backup_dir)
+		.map_err(|e| format!("The backup store has no valid chain: {}", e))?
+	;
+
+	let engine = engine.read().unwrap();
+	if manifest.kind != StoreType::Backup || manifest.id != engine.store.id {
+		return Err(format!("{:?} isn't a backup of this store.", backup_dir));
+	}
+	let store_dir = engine.store_dir.clone();
+	let mut changes = engine.changes.lock().unwrap();
+	let tracked = changes.since == Some((state.chain.clone(), state.sequence));
+
+	let mut header = DeltaHeader {
+		sequence: state.sequence + 1,
+		created: basics::now_millis(),
+		sizes: HashMap::new(),
+		removed: Vec::new()
+	};
+	let mut plan = Vec::<(String, Vec<(u64, u64)>)>::new();
+	let mut versions = HashMap::<String, Version>::new();
+	for relative in archive::store_files(&store_dir).map_err(failed)? {
+		if relative == "manifest.json" { continue; }
+		let now = backup::file_version(&store_dir.join(&relative)).map_err(failed)?;
+		let previous = state.files.get(&relative).copied();
+		versions.insert(relative.clone(), now);
+		let sealed = crypt::is_sealed(&store_dir.join(&relative));
+		let ranges = match changes.blocks.get(&relative) {
+			_ if !tracked || previous.is_none() || changes.rewritten.contains(&relative) => {
+				vec![(0, now.0)]
+			},
+			Some(blocks) => block_ranges(blocks, now.0, sealed),
+			None => continue
+		};
+		header.sizes.insert(relative.clone(), now.0);
+		plan.push((relative, ranges));
+	}
+	header.removed = state.files.keys()
+		.filter(|f| !versions.contains_key(*f)).cloned().collect()
+	;
+
+	let target = backup::delta_path(backup_dir, header.sequence);
+	let mut partial = target.clone().into_os_string();
+	partial.push(".part");
+	let partial = PathBuf::from(partial);
+	let written = fs::create_dir_all(backup_dir.join("chain"))
+		.and_then(|_| DeltaWriter::create(&partial, &header))
+		.and_then(|mut delta| {
+			let mut bytes = 0u64;
+			for (relative, ranges) in &plan {
+				let path = store_dir.join(relative);
+				for (start, end) in ranges {
+					bytes += copy_range(&mut delta, &path, relative, *start, *end)?;
+				}
+			}
+			delta.finish()?;
+			Ok(bytes)
+		})
+		.and_then(|bytes| {
+			fs::rename(&partial, &target)?;
+			state.sequence = header.sequence;
+			state.files = versions;
+			state.steps.push(Step {
+				sequence: header.sequence, created: header.created, lsn: engine.lsn()
+			});
+			backup::save_state(backup_dir, &state)?;
+			Ok(bytes)
+		})
+	;
+	let bytes = match written {
+		Ok(bytes) => bytes,
+		Err(e) => {
+			let _ = fs::remove_file(&partial);
+			return Err(failed(e));
+		}
+	};
+	changes.reset(state.chain.clone(), state.sequence);
+	return Ok((state.sequence, plan.len(), bytes));
+}
+
+// Handles `POST /backup`, whose body is `{ "directory": "<absolute path>" }`,
+// with `"incremental": true` to add to the chain of an existing backup store.
+// Only local clients may trigger backups, as they write on the server's disk.
+pub fn handle(engine: &Arc<RwLock<Engine>>, mut request: Request) {
+	if *request.method() != Method::Post {
+		return respond_json(request, 405, &json!({ "error": "Method not allowed" }));
+	}
+	let local = request.remote_addr().map(|a| a.ip().is_loopback()).unwrap_or(false);
+	if !local {
+		return respond_json(request, 403, &json!({
+			"error": "Backups can only be requested from the server's host"
+		}));
+	}
+	let mut body = String::new();
+	if request.as_reader().read_to_string(&mut body).is_err() {
+		return respond_json(request, 400, &json!({ "error": "Unreadable body" }));
+	}
+	let body: serde_json::Value = serde_json::from_str(&body).unwrap_or_default();
+	let target = match body["directory"].as_str().map(PathBuf::from) {
+		Some(target) if target.is_absolute() => target,
+		_ => return respond_json(request, 400, &json!({
+			"error": "An absolute `directory` is expected"
+		}))
+	};
+
+	// Incremental backups need a full one to start from
+	let incremental = body["incremental"].as_bool().unwrap_or(false)
+		&& target.join("manifest.json").exists()
+	;
+	let made = match incremental {
+		true => incremental_backup(engine, &target),
+		false => hot_backup(engine, &target).map(|(files, bytes)| (0, files, bytes))
+	};
+	match made {
+		Ok((sequence, files, bytes)) => {
+			engine.read().unwrap().log(format!("Backup made in {:?}", target));
+			respond_json(request, 201, &json!({
+				"directory": target, "sequence": sequence, "files": files, "bytes": bytes
+			}));
+		},
+		Err(message) => respond_json(request, 500, &json!({ "error": message }))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::check;
+	use crate::basics;
+	use super::super::{ query, testing };
+
+	#[test]
+	fn backup_stores() {
+		let dir = tempfile::tempdir().unwrap();
+		let store_dir = dir.path().join("store");
+		let mut engine = testing::create_store(&store_dir, json!({
+			"backups": { "directory": dir.path().join("scheduled"), "interval": 60, "keep": 2 }
+		}));
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "notes" })).unwrap();
+		let id = testing::insert(&mut engine, "notes", json!({ "text": "x".repeat(5000) }));
+		let engine = RwLock::new(engine);
+
+		let target = dir.path().join("backup");
+		let (files, bytes) = hot_backup(&engine, &target).unwrap();
+		assert_eq!(files, archive::store_files(&store_dir).unwrap().len());
+		assert!(bytes > 5000);
+		assert!(!dir.path().join("backup.part").exists());
+		let store = basics::load_store(&target).unwrap();
+		assert_eq!(store.kind, StoreType::Backup);
+		assert!(store.backups.is_none());
+		assert_eq!(check::verify(&target).errors(), 0);
+		let backup = testing::open_store(&target);
+		assert_eq!(query::item_data(&backup, &id).unwrap()["text"], "x".repeat(5000));
+
+		// The target must be a new or empty directory
+		let message = hot_backup(&engine, &target).err().unwrap();
+		assert_eq!(message, "The backup directory is not empty.");
+		fs::write(dir.path().join("file"), b"").unwrap();
+		assert!(hot_backup(&engine, &dir.path().join("file")).is_err());
+		fs::create_dir(dir.path().join("empty")).unwrap();
+		assert!(hot_backup(&engine, &dir.path().join("empty")).is_ok());
+	}
+
+	#[test]
+	fn writes_during_the_first_pass() {
+		let dir = tempfile::tempdir().unwrap();
+		let store_dir = dir.path().join("store");
+		let mut engine = testing::create_store(&store_dir, json!({}));
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "notes" })).unwrap();
+		let id = testing::insert(&mut engine, "notes", json!({ "text": "x".repeat(5000) }));
+		let engine = RwLock::new(engine);
+
+		// A write in place, which keeps the size and modification time of its file
+		let target = dir.path().join("backup");
+		let copied = copy_files(&engine, &store_dir, &target).unwrap();
+		let path = engine.read().unwrap().data_files()[0].clone();
+		let modified = fs::metadata(&path).unwrap().modified().unwrap();
+		let content = fs::read(&path).unwrap();
+		let index = content.windows(4).position(|w| w == b"xxxx").unwrap() as u64;
+		faccess::write_bytes(&path, index, b"yyyy", false).unwrap();
+		engine.read().unwrap().update_checksums(&path, index, 4).unwrap();
+		fs::File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+
+		copy_written(&engine, &store_dir, &target, copied).unwrap();
+		for relative in archive::store_files(&store_dir).unwrap() {
+			if relative == "manifest.json" { continue; }
+			let copy = fs::read(target.join(&relative)).unwrap();
+			assert_eq!(copy, fs::read(store_dir.join(&relative)).unwrap(), "{}", relative);
+		}
+		assert!(engine.read().unwrap().changes.lock().unwrap().copying.is_none());
+		let backup = testing::open_store(&target);
+		let text = query::item_data(&backup, &id).unwrap()["text"].as_str().unwrap().to_owned();
+		assert!(text.contains("yyyy"));
+	}
+}
@@ -0,0 +1,391 @@
+use std::fs;
+use std::io::{ self, Read, Write };
+use std::sync::{ Arc, RwLock };
+
+use serde_json::{ Map, Value, json };
+use tiny_http::{ Header, Method, Request, Response, StatusCode };
+
+use crate::basics::{ self, DataType };
+use crate::crypt;
+use crate::faccess::{ self, Area, BlobMeta, Chunk, RecordTag };
+use super::{ Engine, respond_json, transac };
+use super::query::Resolved;
+
+// Size of the pieces blobs are split into, in their data files
+pub const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+// Largest read done at once while streaming a blob
+const READ_SIZE: u64 = 1024 * 1024;
+
+// Routes the requests made on `/blobs`:
+// - `POST /blobs` uploads a blob and returns its id
+// - `GET /blobs/<id>` downloads it, with support for single byte ranges
+// - `DELETE /blobs/<id>` deletes it
+pub fn handle(engine: &Arc<RwLock<Engine>>, request: Request, rest: &str) {
+	let id = rest.trim_start_matches('/').to_owned();
+	match (request.method().clone(), id.is_empty()) {
+		(Method::Post, true) => upload(engine, request),
+		(Method::Get, false) | (Method::Head, false) => download(engine, request, &id),
+		(Method::Delete, false) => {
+			// Deleted as a mutation, so that it is logged
+			let args = Map::from_iter([("id".to_owned(), Value::from(id.clone()))]);
+			let removed = transac::mutate(&mut engine.write().unwrap(), "deleteBlob", &args);
+			match removed {
+				Ok(Resolved::Value(Value::Bool(true))) => {
+					respond_json(request, 200, &json!({ "deleted": id }))
+				},
+				Ok(_) => respond_json(request, 404, &json!({ "error": "Unknown blob" })),
+				Err(message) => respond_json(request, 500, &json!({ "error": message }))
+			}
+		},
+		_ => respond_json(request, 405, &json!({ "error": "Method not allowed" }))
+	}
+}
+
+fn header(request: &Request, name: &str) -> Option<String> {
+	return request.headers().iter()
+		.find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+		.map(|h| h.value.as_str().to_owned())
+	;
+}
+
+// Reads until the buffer is full or the body ends
+fn fill(reader: &mut dyn Read, buffer: &mut [u8]) -> io::Result<usize> {
+	let mut filled = 0;
+	while filled < buffer.len() {
+		match reader.read(&mut buffer[filled..]) {
+			Ok(0) => break,
+			Ok(n) => filled += n,
+			Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+			Err(e) => return Err(e)
+		}
+	}
+	return Ok(filled);
+}
+
+// Writes a chunk in a data file and returns its location
+type WriteChunk<'a> = dyn FnMut(RecordTag, &[u8]) -> Result<(String, u64), String> + 'a;
+
+// Writes the content of a blob chunk by chunk, copying it on the way when
+// a copy is given. Returns the chunks written and the size of the blob,
+// along with the failure which stopped the writing.
+fn write_chunks(
+	write: &mut WriteChunk,
+	id: &str, reader: &mut dyn Read, max_size: u64, mut copy: Option<&mut crypt::File>
+) -> (Vec<Chunk>, u64, Option<(u16, String)>) {
+	let mut chunks = Vec::<Chunk>::new();
+	let mut size = 0u64;
+	let mut buffer = vec![0u8; CHUNK_SIZE];
+	let failure = loop {
+		let filled = match fill(reader, &mut buffer) {
+			Ok(filled) => filled,
+			Err(_) => break Some((400, "Unreadable body".to_owned()))
+		};
+		if filled == 0 { break None; }
+		size += filled as u64;
+		if size > max_size {
+			break Some((413, format!("Blobs can't be larger than {} bytes.", max_size)));
+		}
+		if let Some(Err(e)) = copy.as_mut().map(|c| c.write_all(&buffer[..filled])) {
+			break Some((500, format!("Failed to log the blob: {}", e)));
+		}
+		let tag = RecordTag {
+			id: id.to_owned(), owner: chunks.len().to_string(), data_type: DataType::Null.to_u8()
+		};
+		match write(tag, &buffer[..filled]) {
+			Ok((file, index)) => chunks.push(Chunk { file, index, length: filled as u64 }),
+			Err(message) => break Some((500, message))
+		}
+		if filled < CHUNK_SIZE { break None; }
+	};
+	return (chunks, size, failure);
+}
+
+// Indexes a blob whose chunks were written, or frees them after a failure
+fn commit_blob(
+	engine: &mut Engine, id: &str, meta: BlobMeta, mut failure: Option<(u16, String)>
+) -> Result<(), (u16, String)> {
+	if failure.is_none() && engine.blobs.blobs.contains_key(id) {
+		failure = Some((500, "Blob id collision, please retry.".to_owned()));
+	}
+	if failure.is_none() {
+		engine.blobs.blobs.insert(id.to_owned(), meta.clone());
+		if let Err(message) = engine.save_index(Area::Blobs) {
+			engine.blobs.blobs.remove(id);
+			failure = Some((500, message));
+		}
+	}
+	if let Some(failure) = failure {
+		for chunk in meta.chunks {
+			engine.free_data(Area::Blobs, &chunk.file, chunk.index, chunk.length);
+		}
+		return Err(failure);
+	}
+	engine.log(format!("Blob uploaded: {} ({} bytes)", id, meta.size));
+	return Ok(());
+}
+
+fn upload(engine: &Arc<RwLock<Engine>>, mut request: Request) {
+	let mut content_type = header(&request, "Content-Type")
+		.unwrap_or("application/octet-stream".to_owned())
+	;
+	while content_type.len() > u8::MAX as usize { content_type.pop(); }
+	let max_size = engine.read().unwrap().conf.max_object_size;
+	let id = faccess::new_id_in(&engine.read().unwrap().blobs.blobs);
+
+	// The content is kept along the mutation logs, to be uploaded again
+	// when they are replayed
+	let copy_path = engine.read().unwrap().mutation_log.as_ref()
+		.map(|log| log.blobs_dir().join(format!("{}-{}", id, basics::now_millis())))
+	;
+	let mut copy = match copy_path.as_deref().map(crypt::File::create).transpose() {
+		Ok(copy) => copy,
+		Err(e) => return respond_json(request, 500, &json!({
+			"error": format!("Failed to log the blob: {}", e)
+		}))
+	};
+
+	// The body is stored chunk by chunk, the store being locked
+	// only while each chunk is written
+	let (chunks, size, failure) = write_chunks(
+		&mut |tag, data| engine.write().unwrap().write_data(Area::Blobs, tag, data),
+		&id, request.as_reader(), max_size, copy.as_mut()
+	);
+	let failure = failure.or_else(|| {
+		let synced = copy.as_ref().map(|c| c.sync_all()).transpose();
+		synced.err().map(|e| (500, format!("Failed to log the blob: {}", e)))
+	});
+
+	let mut engine = engine.write().unwrap();
+	let meta = BlobMeta { content_type: content_type.clone(), size, chunks };
+	if let Err((status, message)) = commit_blob(&mut engine, &id, meta, failure) {
+		drop(engine);
+		if let Some(path) = copy_path { let _ = fs::remove_file(path); }
+		return respond_json(request, status, &json!({ "error": message }));
+	}
+	if let Some(path) = copy_path {
+		engine.log_upload(&id, &content_type, &path.file_name().unwrap().to_string_lossy());
+	}
+	drop(engine);
+	return respond_json(request, 201, &json!({
+		"id": id, "size": size, "contentType": content_type
+	}));
+}
+
+// Stores a blob under the given id, as a replayed upload
+pub fn insert_blob(
+	engine: &mut Engine, id: &str, content_type: &str, reader: &mut dyn Read
+) -> Result<(), String> {
+	let max_size = engine.conf.max_object_size;
+	let (chunks, size, failure) = write_chunks(
+		&mut |tag, data| engine.write_data(Area::Blobs, tag, data), id, reader, max_size, None
+	);
+	let meta = BlobMeta { content_type: content_type.to_owned(), size, chunks };
+	return commit_blob(engine, id, meta, failure).map_err(|(_, message)| message);
+}
+
+// Parses a `Range` header holding a single byte range.
+// Returns the start and end (excluded) of the range,
+// or `None` when it can't be satisfied.
+fn parse_range(value: &str, size: u64) -> Option<(u64, u64)> {
+	let spec = value.trim().strip_prefix("bytes=")?;
+	let (start, end) = spec.split_once('-')?;
+	let (start, end) = (start.trim(), end.trim());
+	if start.is_empty() {
+		let suffix = end.parse::<u64>().ok()?;
+		if suffix == 0 || size == 0 { return None; }
+		return Some((size - suffix.min(size), size));
+	}
+	let start = start.parse::<u64>().ok()?;
+	let end = if end.is_empty() { size }
+		else { end.parse::<u64>().ok()?.saturating_add(1).min(size) }
+	;
+	if start >= end { return None; }
+	return Some((start, end));
+}
+
+fn download(engine: &Arc<RwLock<Engine>>, request: Request, id: &str) {
+	let meta = match engine.read().unwrap().blobs.blobs.get(id) {
+		Some(meta) => meta.clone(),
+		None => return respond_json(request, 404, &json!({ "error": "Unknown blob" }))
+	};
+
+	let mut headers = vec![
+		Header::from_bytes(&b"Content-Type"[..], meta.content_type.as_bytes()).unwrap(),
+		Header::from_bytes(&b"Accept-Ranges"[..], &b"bytes"[..]).unwrap()
+	];
+	let mut status = 200;
+	let (mut start, mut end) = (0, meta.size);
+	// Several ranges at once are not supported: the whole blob is sent instead
+	if let Some(range) = header(&request, "Range").filter(|r| !r.contains(',')) {
+		match parse_range(&range, meta.size) {
+			Some(bounds) => {
+				(start, end) = bounds;
+				status = 206;
+				headers.push(Header::from_bytes(
+					&b"Content-Range"[..],
+					format!("bytes {}-{}/{}", start, end - 1, meta.size).as_bytes()
+				).unwrap());
+			},
+			None => {
+				let _ = request.respond(
+					Response::empty(416).with_header(Header::from_bytes(
+						&b"Content-Range"[..],
+						format!("bytes */{}", meta.size).as_bytes()
+					).unwrap())
+				);
+				return;
+			}
+		}
+	}
+
+	let reader = BlobReader {
+		engine: engine.clone(),
+		id: id.to_owned(),
+		position: start,
+		end,
+		buffer: Vec::new(),
+		consumed: 0
+	};
+	let _ = request.respond(Response::new(
+		StatusCode(status), headers, reader, Some((end - start) as usize), None
+	));
+}
+
+// Streams a part of a blob, reading its data files as the client goes
+struct BlobReader {
+	engine: Arc<RwLock<Engine>>,
+	id: String,
+	position: u64,
+	end: u64,
+	buffer: Vec<u8>,
+	consumed: usize
+}
+
+impl BlobReader {
+	// The chunks are looked up at each read, as the defragmenter may move them
+	fn refill(&mut self) -> io::Result<()> {
+		let engine = self.engine.read().unwrap();
+		let chunks = match engine.blobs.blobs.get(&self.id) {
+			Some(meta) => &meta.chunks,
+			None => return Err(io::Error::other("The blob has been deleted"))
+		};
+		let mut offset = 0u64;
+		let chunk = chunks.iter().find(|chunk| {
+			offset += chunk.length;
+			self.position < offset
+		}).ok_or(io::Error::other("The blob is shorter than expected"))?;
+		let within = self.position - (offset - chunk.length);
+		let length = (chunk.length - within).min(self.end - self.position).min(READ_SIZE);
+
+		self.buffer = engine.read_data(
+			Area::Blobs, &chunk.file, chunk.index + within, length
+		).map_err(io::Error::other)?;
+		self.consumed = 0;
+		return Ok(());
+	}
+}
+
+impl Read for BlobReader {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.position >= self.end { return Ok(0); }
+		if self.consumed >= self.buffer.len() { self.refill()?; }
+		let available = &self.buffer[self.consumed..];
+		let count = available.len().min(buf.len());
+		buf[..count].copy_from_slice(&available[..count]);
+		self.consumed += count;
+		self.position += count as u64;
+		return Ok(count);
+	}
+}
+
+// Deletes a blob, and returns whether it existed.
+// The items referencing it are left as they are.
+pub fn remove_blob(engine: &mut Engine, id: &str) -> Result<bool, String> {
+	let old = match engine.blobs.blobs.remove(id) {
+		Some(meta) => meta,
+		None => return Ok(false)
+	};
+	if let Err(e) = engine.save_index(Area::Blobs) {
+		engine.blobs.blobs.insert(id.to_owned(), old);
+		return Err(e);
+	}
+	for chunk in old.chunks {
+		engine.free_data(Area::Blobs, &chunk.file, chunk.index, chunk.length);
+	}
+
+	engine.log(format!("Blob deleted: {}", id));
+	return Ok(true);
+}
+
+pub fn blob_json(engine: &Engine, id: &str) -> Value {
+	return match engine.blobs.blobs.get(id) {
+		Some(meta) => json!({
+			"id": id,
+			"size": meta.size,
+			"contentType": meta.content_type,
+			"url": format!("/blobs/{}", id)
+		}),
+		None => Value::Null
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::testing;
+
+	#[test]
+	fn ranges() {
+		assert_eq!(parse_range("bytes=0-9", 100), Some((0, 10)));
+		assert_eq!(parse_range("bytes=95-", 100), Some((95, 100)));
+		assert_eq!(parse_range("bytes=-10", 100), Some((90, 100)));
+		assert_eq!(parse_range("bytes=-500", 100), Some((0, 100)));
+		assert_eq!(parse_range("bytes=50-500", 100), Some((50, 100)));
+		assert_eq!(parse_range("bytes=100-", 100), None);
+		assert_eq!(parse_range("bytes=9-3", 100), None);
+		assert_eq!(parse_range("bytes=-0", 100), None);
+		assert_eq!(parse_range("items=0-9", 100), None);
+	}
+
+	#[test]
+	fn ranged_reads_across_chunks() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut engine = testing::create_store(dir.path(), json!({}));
+		let data = (0..20u8).collect::<Vec<u8>>();
+		let mut chunks = Vec::<Chunk>::new();
+		for piece in data.chunks(8) {
+			let tag = RecordTag {
+				id: "blob".to_owned(), owner: chunks.len().to_string(), data_type: DataType::Null.to_u8()
+			};
+			let (file, index) = engine.write_data(Area::Blobs, tag, piece).unwrap();
+			chunks.push(Chunk { file, index, length: piece.len() as u64 });
+		}
+		let meta = BlobMeta {
+			content_type: "application/octet-stream".to_owned(),
+			size: 20,
+			chunks
+		};
+		engine.blobs.blobs.insert("blob".to_owned(), meta.clone());
+		let engine = Arc::new(RwLock::new(engine));
+		let reader = |start: u64, end: u64| BlobReader {
+			engine: engine.clone(),
+			id: "blob".to_owned(),
+			position: start,
+			end,
+			buffer: Vec::new(),
+			consumed: 0
+		};
+
+		for (start, end) in [(0, 20), (5, 17), (8, 16), (19, 20)] {
+			let mut read = Vec::<u8>::new();
+			reader(start, end).read_to_end(&mut read).unwrap();
+			assert_eq!(read, data[start as usize..end as usize]);
+		}
+
+		// A download fails once its blob is deleted
+		assert!(remove_blob(&mut engine.write().unwrap(), "blob").unwrap());
+		assert!(!remove_blob(&mut engine.write().unwrap(), "blob").unwrap());
+		assert!(reader(0, 20).read_to_end(&mut Vec::new()).is_err());
+		assert_eq!(blob_json(&engine.read().unwrap(), "blob"), Value::Null);
+	}
+}
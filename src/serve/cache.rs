@@ -0,0 +1,250 @@
+use std::io;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex };
+use std::collections::{ BTreeMap, HashMap };
+
+use serde_json::{ json, Value };
+
+use crate::faccess::{ self, BLOCK_SIZE };
+
+// A block of a data file, as read from the disk
+struct Page {
+	bytes: Arc<[u8]>,
+	// Time of its last use, ordering the evictions
+	tick: u64
+}
+
+#[derive(Default)]
+struct Pages {
+	pages: HashMap<(PathBuf, u64), Page>,
+	// Map relating the last use of each page to its file and block number,
+	// the least recently used first
+	order: BTreeMap<u64, (PathBuf, u64)>,
+	tick: u64,
+	size: u64,
+	hits: u64,
+	misses: u64,
+	evictions: u64
+}
+
+impl Pages {
+	fn remove(&mut self, key: &(PathBuf, u64)) {
+		if let Some(page) = self.pages.remove(key) {
+			self.order.remove(&page.tick);
+			self.size -= page.bytes.len() as u64;
+		}
+	}
+}
+
+// Cache of the blocks of the data files read last, up to a memory budget,
+// evicting the least recently used ones. The blocks are checked against
+// their checksums once, when read from the disk, and dropped as soon as
+// some bytes are written in them.
+pub struct PageCache {
+	budget: u64,
+	state: Mutex<Pages>
+}
+
+impl PageCache {
+	// A cache holding up to `budget` bytes of blocks
+	pub fn new(budget: u64) -> PageCache {
+		return PageCache { budget, state: Mutex::new(Pages::default()) };
+	}
+
+	// A cached block, marked as used
+	fn lookup(&self, path: &Path, block: u64) -> Option<Arc<[u8]>> {
+		let mut state = self.state.lock().unwrap();
+		state.tick += 1;
+		let tick = state.tick;
+		let key = (path.to_path_buf(), block);
+		let previous = match state.pages.get_mut(&key) {
+			Some(page) => std::mem::replace(&mut page.tick, tick),
+			None => {
+				state.misses += 1;
+				return None;
+			}
+		};
+		state.hits += 1;
+		state.order.remove(&previous);
+		state.order.insert(tick, key.clone());
+		return Some(state.pages[&key].bytes.clone());
+	}
+
+	// Caches a block read from the disk, evicting the least recently used
+	// ones beyond the budget
+	fn insert(&self, path: &Path, block: u64, bytes: Arc<[u8]>) {
+		let length = bytes.len() as u64;
+		if length > self.budget { return; }
+		let mut state = self.state.lock().unwrap();
+		let key = (path.to_path_buf(), block);
+		state.remove(&key);
+		while state.size + length > self.budget {
+			let oldest = match state.order.first_key_value() {
+				Some((_, oldest)) => oldest.clone(),
+				None => break
+			};
+			state.remove(&oldest);
+			state.evictions += 1;
+		}
+		state.tick += 1;
+		let tick = state.tick;
+		state.order.insert(tick, key.clone());
+		state.pages.insert(key, Page { bytes, tick });
+		state.size += length;
+	}
+
+	// Reads `length` bytes at `index` in a data file, from the cached blocks
+	// or else the disk. Blocks are verified against their checksums when
+	// `store_dir` is given.
+	pub fn read(
+		&self, store_dir: Option<&Path>, path: &Path, index: u64, length: u64
+	) -> io::Result<Vec<u8>> {
+		let end = index.checked_add(length)
+			.ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))?
+		;
+		let mut buffer = Vec::<u8>::with_capacity(length as usize);
+		for block in index / BLOCK_SIZE..end.div_ceil(BLOCK_SIZE) {
+			let page = match self.lookup(path, block) {
+				Some(page) => page,
+				None => {
+					let page: Arc<[u8]> = faccess::read_block(store_dir, path, block)?.into();
+					self.insert(path, block, page.clone());
+					page
+				}
+			};
+			let start = block * BLOCK_SIZE;
+			let from = (index.max(start) - start) as usize;
+			let to = (end - start).min(BLOCK_SIZE) as usize;
+			if to > page.len() { return Err(io::Error::from(io::ErrorKind::UnexpectedEof)); }
+			buffer.extend_from_slice(&page[from..to]);
+		}
+		return Ok(buffer);
+	}
+
+	// Drops the blocks of a file holding the given bytes, after they were
+	// written. A length of zero drops every block from the index on, after
+	// the file was truncated.
+	pub fn invalidate(&self, path: &Path, index: u64, length: u64) {
+		let mut state = self.state.lock().unwrap();
+		let first = index / BLOCK_SIZE;
+		let stale: Vec<(PathBuf, u64)> = match length {
+			0 => state.pages.keys()
+				.filter(|(file, block)| file == path && *block >= first)
+				.cloned()
+				.collect(),
+			_ => (first..(index + length).div_ceil(BLOCK_SIZE))
+				.map(|block| (path.to_path_buf(), block))
+				.collect()
+		};
+		for key in &stale { state.remove(key); }
+	}
+
+	pub fn to_json(&self) -> Value {
+		let state = self.state.lock().unwrap();
+		return json!({
+			"budget": self.budget,
+			"size": state.size,
+			"pages": state.pages.len(),
+			"hits": state.hits,
+			"misses": state.misses,
+			"evictions": state.evictions
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+
+	use super::*;
+	use crate::faccess::Area;
+	use super::super::{ defrag, query, testing };
+
+	fn stats(cache: &PageCache) -> [u64; 5] {
+		let stats = cache.to_json();
+		return ["size", "pages", "hits", "misses", "evictions"]
+			.map(|key| stats[key].as_u64().unwrap())
+		;
+	}
+
+	#[test]
+	fn least_recently_used_evictions() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("data");
+		let content = (0..4 * BLOCK_SIZE).map(|i| (i / BLOCK_SIZE) as u8).collect::<Vec<u8>>();
+		fs::write(&path, &content).unwrap();
+		let cache = PageCache::new(2 * BLOCK_SIZE);
+
+		// A read across two blocks caches both of them
+		let read = cache.read(None, &path, BLOCK_SIZE - 2, 4).unwrap();
+		assert_eq!(read, [0, 0, 1, 1]);
+		assert_eq!(stats(&cache), [2 * BLOCK_SIZE, 2, 0, 2, 0]);
+		assert_eq!(cache.read(None, &path, 10, 2).unwrap(), [0, 0]);
+		assert_eq!(stats(&cache), [2 * BLOCK_SIZE, 2, 1, 2, 0]);
+
+		// Block 1 is now the least recently used, and goes first
+		assert_eq!(cache.read(None, &path, 2 * BLOCK_SIZE, 1).unwrap(), [2]);
+		assert_eq!(stats(&cache), [2 * BLOCK_SIZE, 2, 1, 3, 1]);
+		cache.read(None, &path, 0, 1).unwrap();
+		assert_eq!(stats(&cache)[2..], [2, 3, 1]);
+		cache.read(None, &path, BLOCK_SIZE, 1).unwrap();
+		assert_eq!(stats(&cache)[2..], [2, 4, 2]);
+
+		assert!(cache.read(None, &path, 4 * BLOCK_SIZE - 1, 2).is_err());
+		assert!(cache.read(None, &path, u64::MAX, 2).is_err());
+		// Nothing is cached without a budget
+		let cache = PageCache::new(0);
+		assert_eq!(cache.read(None, &path, 0, 1).unwrap(), [0]);
+		assert_eq!(stats(&cache), [0, 0, 0, 1, 0]);
+	}
+
+	#[test]
+	fn invalidated_blocks() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("data");
+		fs::write(&path, vec![7u8; 3 * BLOCK_SIZE as usize]).unwrap();
+		let cache = PageCache::new(16 * BLOCK_SIZE);
+		cache.read(None, &path, 0, 3 * BLOCK_SIZE).unwrap();
+		assert_eq!(stats(&cache)[1], 3);
+
+		faccess::write_bytes(&path, BLOCK_SIZE + 5, &[8], false).unwrap();
+		cache.invalidate(&path, BLOCK_SIZE + 5, 1);
+		assert_eq!(stats(&cache)[1], 2);
+		assert_eq!(cache.read(None, &path, BLOCK_SIZE + 4, 3).unwrap(), [7, 8, 7]);
+
+		// A truncation drops the blocks from its index on
+		cache.invalidate(&path, BLOCK_SIZE + 1, 0);
+		assert_eq!(stats(&cache)[1], 1);
+		cache.invalidate(&dir.path().join("other"), 0, 0);
+		assert_eq!(stats(&cache)[1], 1);
+	}
+
+	#[test]
+	fn cached_item_reads() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut engine = testing::create_store(dir.path(), json!({ "ordering": true }));
+		engine.cache = Some(PageCache::new(1024 * 1024));
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "notes" })).unwrap();
+		let ids = (0..3)
+			.map(|n| testing::insert(&mut engine, "notes", json!({ "n": n, "text": "a".repeat(3000) })))
+			.collect::<Vec<String>>()
+		;
+		for id in &ids { query::item_data(&engine, id).unwrap(); }
+		let hits = stats(engine.cache.as_ref().unwrap())[2];
+		assert_eq!(query::item_data(&engine, &ids[1]).unwrap()["n"], 1);
+		assert!(stats(engine.cache.as_ref().unwrap())[2] > hits);
+
+		// Writes and defragmentation drop the blocks they change
+		let args = json!({ "id": ids[1], "data": { "n": 10 } });
+		testing::mutate(&mut engine, "updateItem", args).unwrap();
+		assert_eq!(query::item_data(&engine, &ids[1]).unwrap(), json!({ "n": 10 }));
+		testing::mutate(&mut engine, "deleteItem", json!({ "id": ids[0] })).unwrap();
+		let file = engine.collections.items[&ids[2]].file.clone();
+		while !engine.files(Area::Collections)[&file].holes.is_empty() {
+			defrag::close_hole(&mut engine, Area::Collections, &file, u64::MAX).unwrap().unwrap();
+		}
+		assert_eq!(query::item_data(&engine, &ids[1]).unwrap(), json!({ "n": 10 }));
+		assert_eq!(query::item_data(&engine, &ids[2]).unwrap()["n"], 2);
+		assert_eq!(stats(engine.cache.as_ref().unwrap())[4], 0);
+	}
+}
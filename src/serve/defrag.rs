@@ -0,0 +1,320 @@
+use std::collections::{ HashMap, HashSet };
+
+use crate::crypt;
+use crate::faccess::{ self, Area };
+use super::Engine;
+
+// The areas whose data files are kept defragmented. The time series
+// windows are only appended to, and never have holes.
+const AREAS: [Area; 5] = [
+	Area::Singletons, Area::Collections, Area::Edges, Area::KeyValues, Area::Blobs
+];
+
+// An entry of an area, or a chunk of a blob, and where its record lies
+struct Entry {
+	key: String, // Singleton, item, edge or blob id, or key
+	chunk: usize, // Number of the chunk, for the blobs
+	start: u64, // Index of the record header
+	index: u64,
+	length: u64
+}
+
+// Every entry of an area, with the file holding it
+fn entries(engine: &Engine, area: Area) -> Vec<(&str, Entry)> {
+	let entry = |key: &str, owner: &str, chunk: usize, index: u64, length: u64| Entry {
+		key: key.to_owned(),
+		chunk,
+		start: index.saturating_sub(faccess::header_length(key, owner)),
+		index,
+		length
+	};
+	return match area {
+		Area::Singletons => engine.singletons.singletons.iter()
+			.map(|(id, m)| (m.file.as_str(), entry(id, &m.name, 0, m.index, m.data_length)))
+			.collect(),
+		Area::Collections => engine.collections.items.iter()
+			.map(|(id, m)| (m.file.as_str(), entry(id, &m.collection, 0, m.index, m.data_length)))
+			.collect(),
+		Area::Edges => engine.edges.edges.iter()
+			.map(|(id, m)| (m.file.as_str(), entry(id, &m.label, 0, m.index, m.data_length)))
+			.collect(),
+		Area::KeyValues => engine.keyvalues.entries.iter()
+			.map(|(key, m)| (m.file.as_str(), entry(key, "", 0, m.index, m.data_length)))
+			.collect(),
+		Area::Blobs => engine.blobs.blobs.iter()
+			.flat_map(|(id, m)| m.chunks.iter().enumerate().map(move |(number, c)| (
+				c.file.as_str(), entry(id, &number.to_string(), number, c.index, c.length)
+			)))
+			.collect()
+	};
+}
+
+// Points an entry to a new index in its file
+fn move_entry(engine: &mut Engine, area: Area, entry: &Entry, index: u64) {
+	match area {
+		Area::Singletons => if let Some(meta) = engine.singletons.singletons.get_mut(&entry.key) {
+			meta.index = index;
+		},
+		Area::Collections => if let Some(meta) = engine.collections.items.get_mut(&entry.key) {
+			meta.index = index;
+		},
+		Area::Edges => if let Some(meta) = engine.edges.edges.get_mut(&entry.key) {
+			meta.index = index;
+		},
+		Area::KeyValues => if let Some(meta) = engine.keyvalues.entries.get_mut(&entry.key) {
+			meta.index = index;
+		},
+		Area::Blobs => {
+			let chunk = engine.blobs.blobs.get_mut(&entry.key)
+				.and_then(|meta| meta.chunks.get_mut(entry.chunk))
+			;
+			if let Some(chunk) = chunk { chunk.index = index; }
+		}
+	}
+}
+
+// The file the entries of an area are appended to, in the stores keeping
+// them in order: the one holding the latest record, found from the headers
+// of the last record of each file
+pub fn tail(engine: &mut Engine, area: Area) -> Option<String> {
+	if let Some(tail) = engine.tails.get(&area) { return Some(tail.clone()); }
+	let mut last = HashMap::<&str, u64>::new();
+	for (file, entry) in entries(engine, area) {
+		let index = last.entry(file).or_default();
+		*index = (*index).max(entry.index);
+	}
+	let tail = last.into_iter()
+		.filter(|(file, _)| engine.files(area).get(*file).is_some_and(|meta| meta.framed))
+		.filter_map(|(file, index)| {
+			let (_, header) = faccess::read_header(&engine.data_path(area, file), index)
+				.ok().flatten()?
+			;
+			Some((header.stamp, file.to_owned()))
+		})
+		.max()
+		.map(|(_, file)| file)
+	;
+	if let Some(file) = &tail { engine.tails.insert(area, file.clone()); }
+	return tail;
+}
+
+// A file having holes, unless it was left aside
+pub fn next_file(engine: &Engine, left: &HashSet<(Area, String)>) -> Option<(Area, String)> {
+	for area in AREAS {
+		let file = engine.files(area).iter()
+			.filter(|(id, meta)| meta.framed && !meta.holes.is_empty()
+				&& !left.contains(&(area, id.to_string()))
+			)
+			.map(|(id, _)| id.clone())
+			.next()
+		;
+		if let Some(file) = file { return Some((area, file)); }
+	}
+	return None;
+}
+
+// Writes a run of records at a new place of their file, then points their
+// entries to it and frees the previous copy, which starts at `from`
+fn move_records(
+	engine: &mut Engine, area: Area, file: &str, run: &[Entry], from: u64, to: u64, bytes: &[u8]
+) -> Result<(), String> {
+	// Where the run starts in the index
+	let base = run[0].start;
+	let path = engine.data_path(area, file);
+	let length = bytes.len() as u64;
+	faccess::write_bytes(&path, to, bytes, engine.durable())
+		.map_err(|e| format!("Failed to write in {}/{}: {}", area.dir(), file, e))
+		.and_then(|_| engine.update_checksums(&path, to, length))?
+	;
+
+	// The records are written either in a hole or past the end of the file
+	let meta = engine.files_mut(area).get_mut(file).unwrap();
+	match meta.holes.remove(&to) {
+		Some(free) if free > length => { meta.holes.insert(to + length, free - length); },
+		Some(_) => {},
+		None => meta.size = meta.size.max(to + length)
+	}
+	for entry in run { move_entry(engine, area, entry, entry.index - base + to); }
+	if let Err(message) = engine.save_index(area) {
+		for entry in run { move_entry(engine, area, entry, entry.index - base + from); }
+		faccess::release(engine.files_mut(area), file, to, length);
+		return Err(message);
+	}
+	for entry in run {
+		engine.free_data(area, file, entry.index - base + from, entry.length);
+	}
+	return Ok(());
+}
+
+// Drops the free space at the end of a file, and returns its size
+fn truncate(engine: &mut Engine, area: Area, file: &str) -> Result<u64, String> {
+	let meta = engine.files_mut(area).get_mut(file).unwrap();
+	let size = meta.size;
+	let from = match meta.holes.iter().find(|(index, free)| **index + **free >= size) {
+		Some((index, _)) => *index,
+		None => return Ok(0)
+	};
+	meta.holes.remove(&from);
+	meta.size = from;
+	if let Err(message) = engine.save_index(area) {
+		let meta = engine.files_mut(area).get_mut(file).unwrap();
+		meta.size = size;
+		meta.holes.insert(from, size - from);
+		return Err(message);
+	}
+	// The index comes first: bytes left past its size are harmless
+	let path = engine.data_path(area, file);
+	if let Some(mapped) = &engine.mapped { mapped.unmap(&path); }
+	crypt::File::open_rw(&path)
+		.and_then(|mut data| data.set_len(from))
+		.map_err(|e| format!("Failed to truncate {}/{}: {}", area.dir(), file, e))
+		.and_then(|_| engine.update_checksums(&path, from, 0))?
+	;
+	return Ok(size - from);
+}
+
+// Closes the first hole of a file, by moving down the records following it,
+// up to `budget` bytes of them. The free space reaching the end of the file
+// is dropped. Returns the number of bytes written, or `None` when the bytes
+// after the hole aren't an indexed record, like the chunks of a blob being
+// uploaded.
+pub fn close_hole(
+	engine: &mut Engine, area: Area, file: &str, budget: u64
+) -> Result<Option<u64>, String> {
+	let (hole, free, size) = match engine.files(area).get(file) {
+		Some(meta) => match meta.holes.iter().min() {
+			Some((hole, free)) => (*hole, *free, meta.size),
+			None => return Ok(Some(0))
+		},
+		None => return Ok(Some(0))
+	};
+	if hole + free >= size {
+		let dropped = truncate(engine, area, file)?;
+		engine.log(format!(
+			"Defragmenter: {} free bytes dropped from {}/{}", dropped, area.dir(), file
+		));
+		return Ok(Some(0));
+	}
+
+	// The records right after the hole, whose order is kept
+	let from = hole + free;
+	let mut end = from;
+	let mut run = entries(engine, area).into_iter()
+		.filter(|(f, entry)| *f == file && entry.start >= from)
+		.map(|(_, entry)| entry)
+		.collect::<Vec<Entry>>()
+	;
+	run.sort_by_key(|entry| entry.start);
+	let run = run.into_iter()
+		.take_while(|entry| {
+			let next = entry.index + entry.length;
+			if entry.start != end || (end > from && next - from > budget) { return false; }
+			end = next;
+			true
+		})
+		.collect::<Vec<Entry>>()
+	;
+	if run.is_empty() { return Ok(None); }
+
+	let bytes = engine.read_data(area, file, from, end - from)?;
+	for entry in &run {
+		let header = faccess::decode_header(&bytes[(entry.start - from) as usize..]);
+		if !header.is_some_and(|h| h.live && h.length == entry.length && h.tag.id == entry.key) {
+			return Err(format!(
+				"Defragmenter: the record of {} in {}/{} doesn't match the index.",
+				entry.key, area.dir(), file
+			));
+		}
+	}
+
+	// Records larger than the hole would overwrite themselves: they are
+	// first copied past the end of the file, then moved down from there
+	let length = end - from;
+	if length <= free {
+		move_records(engine, area, file, &run, from, hole, &bytes)?;
+		return Ok(Some(length));
+	}
+	move_records(engine, area, file, &run, from, size, &bytes)?;
+	move_records(engine, area, file, &run, size, hole, &bytes)?;
+	truncate(engine, area, file)?;
+	return Ok(Some(2 * length));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+	use crate::check;
+	use super::super::{ query, testing };
+
+	// An ordered store with a hole, left by the second of four items
+	fn holed(dir: &std::path::Path) -> (Engine, Vec<String>, String) {
+		let mut engine = testing::create_store(dir, json!({ "ordering": true }));
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "notes" })).unwrap();
+		let ids = [100, 3000, 200, 300].iter().enumerate()
+			.map(|(n, size)| testing::insert(
+				&mut engine, "notes", json!({ "n": n, "text": "x".repeat(*size) })
+			))
+			.collect::<Vec<String>>()
+		;
+		testing::mutate(&mut engine, "deleteItem", json!({ "id": ids[1] })).unwrap();
+		let file = engine.collections.items[&ids[0]].file.clone();
+		assert_eq!(engine.files(Area::Collections)[&file].holes.len(), 1);
+		return (engine, ids, file);
+	}
+
+	fn assert_intact(engine: &Engine, ids: &[String]) {
+		for (n, id) in ids.iter().enumerate().filter(|(n, _)| *n != 1) {
+			assert_eq!(query::item_data(engine, id).unwrap()["n"], n);
+		}
+	}
+
+	#[test]
+	fn closed_holes() {
+		let dir = tempfile::tempdir().unwrap();
+		let (mut engine, ids, file) = holed(dir.path());
+		let size = engine.files(Area::Collections)[&file].size;
+
+		// The two records after the hole fit in it, and are moved down together
+		let moved = close_hole(&mut engine, Area::Collections, &file, u64::MAX).unwrap().unwrap();
+		assert!(moved > 500);
+		assert_intact(&engine, &ids);
+		// Then the free space left at the end is dropped
+		assert_eq!(close_hole(&mut engine, Area::Collections, &file, u64::MAX).unwrap(), Some(0));
+		let meta = &engine.files(Area::Collections)[&file];
+		assert!(meta.holes.is_empty());
+		assert!(meta.size < size - 3000);
+		let path = engine.data_path(Area::Collections, &file);
+		assert_eq!(std::fs::metadata(path).unwrap().len(), meta.size);
+		assert!(next_file(&engine, &HashSet::new()).is_none());
+		drop(engine);
+
+		assert_eq!(check::verify(dir.path()).errors(), 0);
+		assert_intact(&testing::open_store(dir.path()), &ids);
+	}
+
+	#[test]
+	fn budgets_and_large_records() {
+		let dir = tempfile::tempdir().unwrap();
+		let (mut engine, ids, file) = holed(dir.path());
+		assert_eq!(next_file(&engine, &HashSet::new()), Some((Area::Collections, file.clone())));
+
+		// A budget only ever holds back the records after the first one
+		close_hole(&mut engine, Area::Collections, &file, 1).unwrap().unwrap();
+		assert_eq!(engine.files(Area::Collections)[&file].holes.len(), 1);
+		assert_intact(&engine, &ids);
+
+		// A record larger than the hole goes past the end of the file first
+		testing::mutate(&mut engine, "deleteItem", json!({ "id": ids[0] })).unwrap();
+		let big = testing::insert(&mut engine, "notes", json!({ "n": 9, "text": "y".repeat(5000) }));
+		while !engine.files(Area::Collections)[&file].holes.is_empty() {
+			close_hole(&mut engine, Area::Collections, &file, u64::MAX).unwrap().unwrap();
+		}
+		assert_eq!(query::item_data(&engine, &big).unwrap()["n"], 9);
+		for (n, id) in ids.iter().enumerate().skip(2) {
+			assert_eq!(query::item_data(&engine, id).unwrap()["n"], n);
+		}
+		drop(engine);
+		assert_eq!(check::verify(dir.path()).errors(), 0);
+	}
+}
@@ -0,0 +1,144 @@
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, RwLock };
+use std::collections::HashMap;
+
+use memmap2::Mmap;
+
+use crate::faccess::{ self, BLOCK_SIZE };
+
+// Memory maps of the singleton and collection data files, for the reads to
+// copy the bytes of an item out of the page cache without a system call.
+// Files are mapped on their first read, and mapped again once they grew
+// past their map. Only the stores without encryption have their files mapped.
+#[derive(Default)]
+pub struct MappedFiles {
+	maps: RwLock<HashMap<PathBuf, Arc<Mmap>>>
+}
+
+impl MappedFiles {
+	// The map of a file covering at least `end` bytes, mapping it if needed
+	fn map(&self, path: &Path, end: u64) -> io::Result<Arc<Mmap>> {
+		if let Some(map) = self.maps.read().unwrap().get(path) {
+			if map.len() as u64 >= end { return Ok(map.clone()); }
+		}
+		let mut maps = self.maps.write().unwrap();
+		let file = fs::File::open(path)?;
+		// SAFETY: the server holds the store lock, so no other process rewrites
+		// the data files meanwhile (see `lite::lock`). Within the server, they
+		// only shrink in the defragmentation, which holds the engine's write
+		// lock and drops the map first (see `unmap`), so no read can touch the
+		// pages of a truncated map.
+		let map = Arc::new(unsafe { Mmap::map(&file)? });
+		maps.insert(path.to_path_buf(), map.clone());
+		return Ok(map);
+	}
+
+	// Reads `length` bytes at `index` in a data file, verified against its
+	// checksums when `store_dir` is given
+	pub fn read(
+		&self, store_dir: Option<&Path>, path: &Path, index: u64, length: u64
+	) -> io::Result<Vec<u8>> {
+		let end = index.checked_add(length)
+			.ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))?
+		;
+		let map = self.map(path, end)?;
+		let size = map.len() as u64;
+		if end > size { return Err(io::Error::from(io::ErrorKind::UnexpectedEof)); }
+		if let Some(store_dir) = store_dir {
+			let first = index / BLOCK_SIZE;
+			let start = first * BLOCK_SIZE;
+			let stop = end.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+			// The last block of the map may have grown since, its checksum
+			// is then checked on the file itself
+			if stop > size { return faccess::read_verified(store_dir, path, index, length); }
+			faccess::check_blocks(
+				store_dir, path, first, &map[start as usize..stop as usize]
+			)?;
+		}
+		return Ok(map[index as usize..end as usize].to_vec());
+	}
+
+	// Drops the map of a file, before it gets truncated
+	pub fn unmap(&self, path: &Path) {
+		self.maps.write().unwrap().remove(path);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+	use crate::faccess::Area;
+	use super::super::{ defrag, query, testing };
+
+	fn mapped_len(mapped: &MappedFiles, path: &Path) -> Option<usize> {
+		return mapped.maps.read().unwrap().get(path).map(|map| map.len());
+	}
+
+	#[test]
+	fn remapped_after_growth() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("data");
+		fs::write(&path, b"first").unwrap();
+		let mapped = MappedFiles::default();
+		assert_eq!(mapped.read(None, &path, 1, 3).unwrap(), b"irs");
+		assert_eq!(mapped_len(&mapped, &path), Some(5));
+		assert!(mapped.read(None, &path, 3, 3).is_err());
+
+		let mut content = b"first".to_vec();
+		content.extend(b" then second");
+		fs::write(&path, &content).unwrap();
+		// Reads within the map keep it, the ones past it map the file again
+		assert_eq!(mapped.read(None, &path, 0, 5).unwrap(), b"first");
+		assert_eq!(mapped_len(&mapped, &path), Some(5));
+		assert_eq!(mapped.read(None, &path, 11, 6).unwrap(), b"second");
+		assert_eq!(mapped_len(&mapped, &path), Some(17));
+		assert!(mapped.read(None, &path, u64::MAX, 2).is_err());
+		mapped.unmap(&path);
+		assert_eq!(mapped_len(&mapped, &path), None);
+	}
+
+	#[test]
+	fn mapped_item_reads() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut engine = testing::create_store(dir.path(), json!({ "ordering": true }));
+		engine.mapped = Some(MappedFiles::default());
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "notes" })).unwrap();
+		let first = testing::insert(&mut engine, "notes", json!({ "n": 0, "text": "a".repeat(6000) }));
+		assert_eq!(query::item_data(&engine, &first).unwrap()["n"], 0);
+		let file = engine.collections.items[&first].file.clone();
+		let path = engine.data_path(Area::Collections, &file);
+		let size = mapped_len(engine.mapped.as_ref().unwrap(), &path).unwrap();
+
+		// The items written after the file was mapped are read from a new map
+		let ids = (1..4)
+			.map(|n| testing::insert(&mut engine, "notes", json!({ "n": n, "text": "b".repeat(3000) })))
+			.collect::<Vec<String>>()
+		;
+		assert_eq!(query::item_data(&engine, &ids[2]).unwrap()["n"], 3);
+		assert!(mapped_len(engine.mapped.as_ref().unwrap(), &path).unwrap() > size);
+
+		// Checksums are verified on mapped reads too. The file is changed in
+		// place, as truncating a mapped file would break its map.
+		let index = engine.collections.items[&ids[0]].index + 10;
+		let byte = fs::read(&path).unwrap()[index as usize];
+		let flip = |byte: u8| faccess::write_bytes(&path, index, &[byte], false).unwrap();
+		flip(byte ^ 0x01);
+		let read = query::item_data(&engine, &ids[0]);
+		assert!(read.err().unwrap().contains("Checksum mismatch"));
+		flip(byte);
+
+		// A truncated file is mapped again on the next read
+		testing::mutate(&mut engine, "deleteItem", json!({ "id": ids[2] })).unwrap();
+		while !engine.files(Area::Collections)[&file].holes.is_empty() {
+			defrag::close_hole(&mut engine, Area::Collections, &file, u64::MAX).unwrap().unwrap();
+		}
+		assert_eq!(mapped_len(engine.mapped.as_ref().unwrap(), &path), None);
+		for (n, id) in ids.iter().enumerate().take(2) {
+			assert_eq!(query::item_data(&engine, id).unwrap()["n"], n + 1);
+		}
+		assert_eq!(query::item_data(&engine, &first).unwrap()["n"], 0);
+	}
+}
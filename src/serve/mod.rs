@@ -1,50 +1,75 @@
 use std::fs;
-use std::path::PathBuf;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex, RwLock };
 use std::collections::HashMap;
-use std::io::{self, Read};
-use std::string::FromUtf8Error;
 
 use clap::ArgMatches;
-use byteorder::{ ReadBytesExt, BigEndian };
 
 use crate::cli;
-use crate::basics::{ self, StoreType };
-
-#[derive(Debug)]
-#[derive(Clone)]
-struct FileMeta {
-	size: u64,
-	reads: u16,
-	writing: bool,
-	holes: HashMap<
-		u64, // index
-		u64 // length
-	>
+use crate::lite;
+use crate::crypt;
+use crate::archive;
+use crate::workers::{ self, ScrubStatus };
+use crate::geo::GeoIndex;
+use crate::faccess::{
+	self, Area, FileMeta, SingletonIndex, CollectionIndex, EdgeIndex, KeyValueIndex,
+	BlobIndex, SeriesIndex
+};
+use crate::basics::{ self, Conf, Store, StoreType };
+
+pub mod query;
+pub mod transac;
+pub mod blobs;
+pub mod backup;
+pub mod mutlog;
+pub mod packed;
+pub mod defrag;
+pub mod mapped;
+pub mod cache;
+#[cfg(test)]
+pub mod testing;
+
+// Everything the server knows about the store it serves
+pub struct Engine {
+	pub conf: Conf,
+	pub store_dir: PathBuf,
+	pub store: Store,
+	pub verbose: bool,
+	pub singletons: SingletonIndex,
+	pub collections: CollectionIndex,
+	pub edges: EdgeIndex,
+	pub keyvalues: KeyValueIndex,
+	pub blobs: BlobIndex,
+	pub timeseries: SeriesIndex,
+	// Map relating each collection id to the index of its geo-points
+	pub geo: HashMap<String, GeoIndex>,
+	pub scrub: Mutex<ScrubStatus>,
+	pub changes: Mutex<backup::Changes>,
+	pub context: Mutex<mutlog::MutationContext>,
+	// Set when the store keeps mutation logs, for point-in-time recovery
+	pub mutation_log: Option<mutlog::MutationLog>,
+	// Set for the stores of the Archive type, read from their archive file
+	pub packed: Option<packed::PackedStore>,
+	// Set when the item data files are read through memory maps
+	pub mapped: Option<mapped::MappedFiles>,
+	// Set when the blocks of the data files read last are kept in memory
+	pub cache: Option<cache::PageCache>,
+	// Map relating each area to the file its entries are appended to,
+	// in the stores keeping them in order
+	pub tails: HashMap<Area, String>
 }
 
-#[derive(Debug)]
-// #[derive(Clone)]
-struct DataType {
-	value: u8
-}
-
-#[derive(Debug)]
-#[derive(Clone)]
-struct SingletonMeta {
-	name: String,
-	data_type: u8,
-	file: String,
-	index: u64,
-	data_length: u64
-}
-
-#[derive(Debug)]
-// #[derive(Clone)]
-struct CollectionMeta {
-	data_type: u8,
-	file: String,
-	index: u64,
-	data_length: u64
+// Error of the mutations sent to a store served in read-only mode
+pub const READ_ONLY: &str = "The store is an archive, it can only be read.";
+
+// Creates a directory of the store and its empty index, if they are missing
+fn prepare_dir(store_dir: &Path, dir: &str) -> Result<(), PathBuf> {
+	let mut path = store_dir.to_path_buf();
+	path.push(dir);
+	if !path.exists() && fs::create_dir_all(&path).is_err() { return Err(path); }
+	path.push("rixindex");
+	if !path.exists() && fs::File::create(&path).is_err() { return Err(path); }
+	return Ok(());
 }
 
 fn store_read_err(store_item: PathBuf) -> String {
@@ -54,6 +79,153 @@ fn store_read_err(store_item: PathBuf) -> String {
 	);
 }
 
+// Loads the indexes of a store, for serving it or working on it offline
+#[allow(unused_assignments)]
+pub fn open_engine(
+	conf: Conf, store_dir: PathBuf, store: Store, verbose: bool
+) -> Result<Engine, String> {
+	let mut store_item: PathBuf; // A `pathbuf` to index resources in the store
+	crypt::unlock(&store_dir, &store, None)?;
+
+	// --> Loading the index and files of the singletons
+	// -------------------------------------------------
+
+	store_item = store_dir.clone();
+	store_item.push("singletons/rixindex");
+	if !store_item.exists() {
+		return Err(
+			"The file: \"".to_owned()
+			+ store_item.to_str().unwrap()
+			+ "\" was not found !"
+		);
+	}
+	let singletons_try = faccess::load_singletons(&store_dir);
+	if singletons_try.is_err() {
+		return Err(store_read_err(store_item));
+	}
+
+
+	// --> Loading the indices and files of the collections
+	// ----------------------------------------------------
+
+	store_item = store_dir.clone();
+	store_item.push("collections/rixindex");
+	if !store_item.exists() {
+		return Err(
+			"The file: \"".to_owned()
+				+ store_item.to_str().unwrap()
+				+ "\" was not found !"
+		);
+	}
+	let collections_try = faccess::load_collections(&store_dir);
+	if collections_try.is_err() {
+		return Err(store_read_err(store_item));
+	}
+
+
+	// Stores created before the geo-point index need it built from their items
+	store_item = store_dir.clone();
+	store_item.push("collections/geoindex");
+	let geo_try = match &collections_try {
+		Ok(collections) => faccess::load_geoindex(&store_dir, collections),
+		Err(_) => Ok(None)
+	};
+	if geo_try.is_err() {
+		return Err(store_read_err(store_item));
+	}
+	let geo_loaded = geo_try.unwrap();
+	let geo_rebuild = geo_loaded.is_none();
+
+
+	// --> Loading the edges and their adjacency lists
+	// -----------------------------------------------
+
+	// Stores created before the graph model have no `edges/` directory
+	if let Err(failed) = prepare_dir(&store_dir, Area::Edges.dir()) {
+		return Err(
+			"Failed to create: \"".to_owned()
+			+ failed.to_str().unwrap()
+			+ "\""
+		);
+	}
+	store_item = store_dir.clone();
+	store_item.push("edges/rixindex");
+	let edges_try = faccess::load_edges(&store_dir);
+	if edges_try.is_err() {
+		return Err(store_read_err(store_item));
+	}
+
+
+	// --> Loading the key-value entries
+	// ---------------------------------
+
+	// Stores created before the key-value model have no `keyvalues/` directory
+	if let Err(failed) = prepare_dir(&store_dir, Area::KeyValues.dir()) {
+		return Err(
+			"Failed to create: \"".to_owned()
+			+ failed.to_str().unwrap()
+			+ "\""
+		);
+	}
+	store_item = store_dir.clone();
+	store_item.push("keyvalues/rixindex");
+	let keyvalues_try = faccess::load_keyvalues(&store_dir);
+	if keyvalues_try.is_err() {
+		return Err(store_read_err(store_item));
+	}
+
+	// --> Loading the blobs
+	// ---------------------
+
+	// Stores created before the blobs have no `blobs/` directory
+	if let Err(failed) = prepare_dir(&store_dir, Area::Blobs.dir()) {
+		return Err(
+			"Failed to create: \"".to_owned()
+			+ failed.to_str().unwrap()
+			+ "\""
+		);
+	}
+	store_item = store_dir.clone();
+	store_item.push("blobs/rixindex");
+	let blobs_try = faccess::load_blobs(&store_dir);
+	if blobs_try.is_err() {
+		return Err(store_read_err(store_item));
+	}
+
+
+	// --> Loading the time series
+	// ---------------------------
+
+	// Stores created before the time series have no `timeseries/` directory
+	if let Err(failed) = prepare_dir(&store_dir, "timeseries") {
+		return Err(
+			"Failed to create: \"".to_owned()
+			+ failed.to_str().unwrap()
+			+ "\""
+		);
+	}
+	store_item = store_dir.clone();
+	store_item.push("timeseries/rixindex");
+	let timeseries_try = faccess::load_timeseries(&store_dir);
+	if timeseries_try.is_err() {
+		return Err(store_read_err(store_item));
+	}
+
+	let mut engine = Engine {
+		singletons: singletons_try.unwrap(),
+		collections: collections_try.unwrap(),
+		edges: edges_try.unwrap(),
+		keyvalues: keyvalues_try.unwrap(),
+		blobs: blobs_try.unwrap(),
+		timeseries: timeseries_try.unwrap(),
+		geo: geo_loaded.unwrap_or_default(),
+		..Engine::new(conf, store_dir, store, verbose)
+	};
+
+	if geo_rebuild { engine.rebuild_geoindex()?; }
+	return Ok(engine);
+}
+
 #[allow(unused_assignments)]
 #[allow(unused_variables)]
 pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
@@ -74,43 +246,8 @@ pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
 	let cluster_port_scan: bool; // Port scanning toggle for API connection
 	let verbose: bool; // Whether or not the terminal is verbose
 
-	// Map relating each singleton id to its metadata
-	let mut singletons = HashMap::<String, SingletonMeta>::new();
-	// Map relating each singleton file name to its metadata
-	let mut singleton_files = HashMap::<String, FileMeta>::new();
-
-	// Map relating each Collection to its metadata
-	let mut collections_list = HashMap::<String, String>::new();
-	// Map relating each collection item id to its location
-	let mut collections = HashMap::<
-		String, (DataType, CollectionMeta)
-	>::new();
-	// Map relating each hole location in collections, to its size
-	// let mut collections_files = HashMap::<
-	// 	String, HashMap<(String, u64), u64>
-	// >::new();
-
 	let mut store_item: PathBuf; // A `pathbuf` to index resources in the store
 	let store_text_content: String; // A String to store their content
-	// let mut store_file_name: String; // A string store temporarily file names
-	// let mut store_file_length: u64;
-	let mut store_file_handle: io::BufReader<fs::File>;
-	let mut store_bin_content = Vec::<u8>::new();
-	let mut io_read_try: io::Result<usize>;
-	let mut bo_read8_try: io::Result<u8>;
-	let mut bo_read_try: io::Result<u64>;
-	let mut store_item_number: u64;
-	let mut store_str_length: u8;
-	let mut store_id_str: String;
-	let mut store_str_draft: Vec<u8>;
-	let mut store_str_parse: Result<String, FromUtf8Error>;
-	let mut store_singleton_meta = SingletonMeta {
-		name: String::new(),
-		data_type: 0u8,
-		file: String::new(),
-		index: 0,
-		data_length: 0
-	};
 
 
 	// --> Checking and loading the store and its manifest
@@ -149,7 +286,7 @@ pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
 		return std::process::ExitCode::FAILURE;
 	}
 	store_text_content = store_text_try.unwrap();
-	let store_manifest_try = serde_json::from_str(&*store_text_content);
+	let store_manifest_try = serde_json::from_str(&store_text_content);
 	if store_manifest_try.is_err() {
 		cli::red_err(
 			"Failed to parse the manifest.".to_owned()
@@ -161,9 +298,10 @@ pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
 	store = store_manifest_try.unwrap();
 
 	// Checking if the store's type allows data serving
-	if store.kind == StoreType::Backup || store.kind == StoreType::Archive {
+	if store.kind == StoreType::Backup {
 		cli::red_err(
-			"A backup or an archive store can't be served.".to_owned()
+			"A backup store can't be served.".to_owned()
+			+ " Restore it first with the `restore` subcommand."
 		);
 		return std::process::ExitCode::FAILURE;
 	}
@@ -233,6 +371,12 @@ pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
 	}
 
 	// Checking the supplied cluster port
+	if store.kind == StoreType::Lite && matches.contains_id("cluster-port") {
+		cli::red_err(
+			"A Lite store runs on a single node, it has no cluster port.".to_owned()
+		);
+		return std::process::ExitCode::FAILURE;
+	}
 	if matches.contains_id("cluster-port") {
 		port_text = matches.get_one::<String>("cluster-port").unwrap();
 		port_digest = basics::parse_port(port_text, "cluster");
@@ -245,226 +389,456 @@ pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
 		cluster_port_scan = store.defaults.cluster_scan;
 	}
 
+	// Memory maps are only used for the files stored in clear
+	let mapped_reads = *matches.get_one::<bool>("mmap").unwrap() || store.defaults.mapped_reads;
+	if mapped_reads && store.encryption.is_some() {
+		cli::yellow_err(
+			"The files of an encrypted store can't be read through memory maps.".to_owned()
+		);
+	}
+	let mapped_reads = mapped_reads && store.encryption.is_none();
 
-
-	//########## ----- PART 2: LOADING THE STORE'S METADATA ----- ##########//
-	//######################################################################//
-
-
-	// --> Loading the index and files of the singletons
-	// -------------------------------------------------
-
-	store_item = store_dir.clone();
-	store_item.push("singletons/rixindex");
-	if !store_item.exists() {
+	// Checking the supplied cache size, or the one of the manifest
+	let cache_size = match matches.get_one::<String>("cache-size") {
+		Some(text) => text.parse::<u64>().ok(),
+		None => Some(store.defaults.cache_size)
+	};
+	let cache_size = match cache_size.and_then(|size| size.checked_mul(1024 * 1024)) {
+		Some(bytes) => bytes,
+		None => {
+			cli::red_err(
+				"Invalid cache size: it must be a number of MiB, below 2^44.".to_owned()
+			);
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+	// Both would keep the same blocks in memory
+	if mapped_reads && cache_size > 0 {
 		cli::red_err(
-			"The file: \"".to_owned()
-			+ store_item.to_str().unwrap()
-			+ "\" was not found !"
+			"The cache and the memory maps can't be used together.".to_owned()
+			+ " Disable one of them, with `--cache-size 0` for the cache."
 		);
 		return std::process::ExitCode::FAILURE;
 	}
-	store_file_handle = io::BufReader::new(fs::File::open(&store_item)
-		.unwrap()
-	);
-	store_bin_content.resize(12, 0);
 
-	// Loading the files list for singletons
-	bo_read_try = store_file_handle.read_u64::<BigEndian>();
-	if bo_read_try.is_err() {
-		cli::red_err(store_read_err(store_item));
+	// Loading the key of an encrypted store
+	let key_file = matches.get_one::<String>("key-file").map(PathBuf::from);
+	if let Err(message) = crypt::unlock(&store_dir, &store, key_file.as_deref()) {
+		cli::red_err(message);
 		return std::process::ExitCode::FAILURE;
 	}
-	store_item_number = bo_read_try.unwrap();
-	for i in 0..store_item_number {
-		io_read_try = store_file_handle.read(&mut store_bin_content[0..12]);
-		if io_read_try.is_err() {
-			cli::red_err(store_read_err(store_item));
-			return std::process::ExitCode::FAILURE;
-		}
-		store_str_draft = store_bin_content[0..12 as usize].to_vec();
-		store_str_parse = String::from_utf8(store_str_draft);
-		if store_str_parse.is_err() {
-			cli::red_err(store_read_err(store_item));
-			return std::process::ExitCode::FAILURE;
-		}
-		store_id_str = store_str_parse.unwrap();
 
-		bo_read_try = store_file_handle.read_u64::<BigEndian>();
-		if bo_read_try.is_err() {
-			cli::red_err(store_read_err(store_item));
-			return std::process::ExitCode::FAILURE;
-		}
 
-		singleton_files.insert(store_id_str, FileMeta {
-			size: bo_read_try.unwrap(),
-			reads: 0,
-			writing: false,
-			holes: HashMap::<u64, u64>::new()
-		});
-	}
 
-	// Loading the data index for singletons
-	bo_read_try = store_file_handle.read_u64::<BigEndian>();
-	if bo_read_try.is_err() {
-		cli::red_err(store_read_err(store_item));
-		return std::process::ExitCode::FAILURE;
-	}
-	store_item_number = bo_read_try.unwrap();
-	for i in 0..store_item_number {
-		bo_read8_try = store_file_handle.read_u8();
-		if bo_read8_try.is_err() {
-			cli::red_err(store_read_err(store_item));
-			return std::process::ExitCode::FAILURE;
-		}
-		store_str_length = bo_read8_try.unwrap();
+	//########## ----- PART 2: LOADING THE STORE'S METADATA ----- ##########//
+	//######################################################################//
 
-		if store_bin_content.len() < store_str_length as usize {
-			store_bin_content.resize(store_str_length as usize, 0);
-		}
-		io_read_try = store_file_handle.read(
-			&mut store_bin_content[0..store_str_length as usize]
-		);
-		if io_read_try.is_err() {
-			cli::red_err(store_read_err(store_item));
-			return std::process::ExitCode::FAILURE;
-		}
-		store_str_draft = store_bin_content[0..store_str_length as usize].to_vec();
-		store_str_parse = String::from_utf8(store_str_draft);
-		if store_str_parse.is_err() {
-			cli::red_err(store_read_err(store_item));
-			return std::process::ExitCode::FAILURE;
-		}
-		store_singleton_meta.name = store_str_parse.unwrap();
 
-		io_read_try = store_file_handle.read(&mut store_bin_content[0..12]);
-		if io_read_try.is_err() {
-			cli::red_err(store_read_err(store_item));
+	// A store is served by a single process, for as long as the lock is held.
+	// A Lite store may be embedded by a program instead.
+	let _lock = match lite::lock(&store_dir) {
+		Ok(file) => file,
+		Err(message) => {
+			cli::red_err(message);
 			return std::process::ExitCode::FAILURE;
 		}
-		store_str_draft = store_bin_content[0..12 as usize].to_vec();
-		store_str_parse = String::from_utf8(store_str_draft);
-		if store_str_parse.is_err() {
-			cli::red_err(store_read_err(store_item));
+	};
+	// Archives are served in read-only mode, from their archive file
+	let opened = match store.kind {
+		StoreType::Archive => packed::open_packed(conf, store_dir, store, verbose),
+		_ => open_engine(conf, store_dir, store, verbose)
+	};
+	let mut engine = match opened {
+		Ok(engine) => engine,
+		Err(message) => {
+			cli::red_err(message);
 			return std::process::ExitCode::FAILURE;
 		}
-		store_id_str = store_str_parse.unwrap();
-
-		bo_read8_try = store_file_handle.read_u8();
-		if bo_read8_try.is_err() {
-			cli::red_err(store_read_err(store_item));
-			return std::process::ExitCode::FAILURE;
+	};
+	if let Some(policy) = engine.store.mutation_logs.as_ref().filter(|_| !engine.read_only()) {
+		match mutlog::MutationLog::open(&engine.store_dir, policy.retention) {
+			Ok(log) => engine.mutation_log = Some(log),
+			Err(e) => {
+				cli::red_err(format!("Failed to open the mutation logs: {}", e));
+				return std::process::ExitCode::FAILURE;
+			}
 		}
-		store_singleton_meta.data_type = bo_read8_try.unwrap();
+	}
+	if mapped_reads && !engine.read_only() {
+		engine.mapped = Some(mapped::MappedFiles::default());
+	}
+	if cache_size > 0 && !engine.read_only() {
+		engine.cache = Some(cache::PageCache::new(cache_size));
+	}
+	let engine = Arc::new(RwLock::new(engine));
 
-		io_read_try = store_file_handle.read(&mut store_bin_content[0..12]);
-		if io_read_try.is_err() {
-			cli::red_err(store_read_err(store_item));
-			return std::process::ExitCode::FAILURE;
-		}
-		store_str_draft = store_bin_content[0..12 as usize].to_vec();
-		store_str_parse = String::from_utf8(store_str_draft);
-		if store_str_parse.is_err() {
-			cli::red_err(store_read_err(store_item));
-			return std::process::ExitCode::FAILURE;
-		}
-		store_singleton_meta.file = store_str_parse.unwrap();
 
-		bo_read_try = store_file_handle.read_u64::<BigEndian>();
-		if bo_read_try.is_err() {
-			cli::red_err(store_read_err(store_item));
-			return std::process::ExitCode::FAILURE;
-		}
-		store_singleton_meta.index = bo_read_try.unwrap();
 
-		bo_read_try = store_file_handle.read_u64::<BigEndian>();
-		if bo_read_try.is_err() {
-			cli::red_err(store_read_err(store_item));
-			return std::process::ExitCode::FAILURE;
-		}
-		store_singleton_meta.data_length = bo_read_try.unwrap();
+	//########## ----- PART 3: SERVING ----- ##########//
+	//#################################################//
 
-		singletons.insert(store_id_str, store_singleton_meta.clone());
-	}
 
+	// --> Binding the API port
+	// ------------------------
 
-	// --> Loading the indices and files of the collections
-	// ----------------------------------------------------
-
-	store_item = store_dir.clone();
-	store_item.push("collections/rixindex");
-	if !store_item.exists() {
+	let mut api_server = tiny_http::Server::http(("0.0.0.0", api_port));
+	if api_server.is_err() && api_port_scan {
+		for port in api_port.saturating_add(1)..=u16::MAX {
+			api_server = tiny_http::Server::http(("0.0.0.0", port));
+			if api_server.is_ok() { break; }
+		}
+	}
+	if api_server.is_err() {
 		cli::red_err(
-			"The file: \"".to_owned()
-				+ store_item.to_str().unwrap()
-				+ "\" was not found !"
+			"Failed to listen on the API port: ".to_owned()
+			+ &api_server.err().unwrap().to_string()
 		);
 		return std::process::ExitCode::FAILURE;
 	}
-	store_file_handle = io::BufReader::new(fs::File::open(&store_item)
-		.unwrap()
+	let api_server = Arc::new(api_server.unwrap());
+
+	cli::green_out(format!(
+		"{} is serving \"{}\" on {}{}",
+		engine.read().unwrap().conf.display_name,
+		engine.read().unwrap().store.name,
+		api_server.server_addr(),
+		if engine.read().unwrap().read_only() { " (read-only)" } else { "" }
+	));
+
+
+	// --> Starting the background workers
+	// -----------------------------------
+
+	// None of them has anything to do in an archive
+	if !engine.read().unwrap().read_only() {
+		workers::spawn_expiry(engine.clone());
+		workers::spawn_rollups(engine.clone());
+		workers::spawn_scrubber(engine.clone());
+		workers::spawn_backups(engine.clone());
+		workers::spawn_defragmenter(engine.clone());
+	}
+
+
+	// --> Handling client requests
+	// ----------------------------
+
+	let threads = std::thread::available_parallelism()
+		.map(|n| n.get()).unwrap_or(4)
+	;
+	let mut handles = Vec::<std::thread::JoinHandle<()>>::new();
+	for _ in 0..threads {
+		let api_server = api_server.clone();
+		let engine = engine.clone();
+		handles.push(std::thread::spawn(move || {
+			while let Ok(request) = api_server.recv() {
+				handle(&engine, request);
+			}
+		}));
+	}
+	for handle in handles { let _ = handle.join(); }
+
+	return std::process::ExitCode::SUCCESS;
+}
+
+pub fn respond_json(
+	request: tiny_http::Request, status: u16, body: &serde_json::Value
+) {
+	let header = tiny_http::Header::from_bytes(
+		&b"Content-Type"[..], &b"application/json"[..]
+	).unwrap();
+	let _ = request.respond(
+		tiny_http::Response::from_string(body.to_string())
+			.with_status_code(status)
+			.with_header(header)
 	);
-	bo_read_try = store_file_handle.read_u64::<BigEndian>();
-	if bo_read_try.is_err() {
-		cli::red_err(store_read_err(store_item));
-		return std::process::ExitCode::FAILURE;
+}
+
+fn handle(engine: &Arc<RwLock<Engine>>, mut request: tiny_http::Request) {
+	let path = request.url().split('?').next().unwrap_or("").to_owned();
+	// Only the GraphQL queries and the downloads can be served by archives
+	let writing = path != "/graphql" && !matches!(
+		request.method(), tiny_http::Method::Get | tiny_http::Method::Head
+	);
+	if writing && engine.read().unwrap().read_only() {
+		return respond_json(request, 403, &serde_json::json!({ "error": READ_ONLY }));
 	}
-	store_item_number = bo_read_try.unwrap();
-	for i in 0..store_item_number {
-		io_read_try = store_file_handle.read(
-			&mut store_bin_content[0..12 as usize]
+	if path == "/blobs" || path.starts_with("/blobs/") {
+		return blobs::handle(engine, request, &path["/blobs".len()..]);
+	}
+	if path == "/backup" {
+		return backup::handle(engine, request);
+	}
+	if path == "/status" {
+		let engine = engine.read().unwrap();
+		let status = serde_json::json!({
+			"name": engine.store.name,
+			"id": engine.store.id,
+			"kind": engine.store.kind,
+			"checksumming": engine.store.checksumming,
+			"scrub": engine.scrub.lock().unwrap().to_json(),
+			"cache": engine.cache.as_ref().map(|cache| cache.to_json())
+		});
+		drop(engine);
+		return respond_json(request, 200, &status);
+	}
+	if path != "/graphql" {
+		return respond_json(
+			request, 404, &serde_json::json!({ "error": "Not found" })
 		);
-		if io_read_try.is_err() {
-			cli::red_err(store_read_err(store_item));
-			return std::process::ExitCode::FAILURE;
+	}
+	if *request.method() != tiny_http::Method::Post {
+		return respond_json(
+			request, 405, &serde_json::json!({ "error": "Method not allowed" })
+		);
+	}
+
+	let mut body = String::new();
+	if request.as_reader().read_to_string(&mut body).is_err() {
+		return respond_json(
+			request, 400, &serde_json::json!({ "error": "Unreadable body" })
+		);
+	}
+	let body: serde_json::Value = match serde_json::from_str(&body) {
+		Ok(body) => body,
+		Err(_) => return respond_json(
+			request, 400, &serde_json::json!({ "error": "Invalid JSON body" })
+		)
+	};
+
+	let empty = serde_json::Map::new();
+	let response = query::execute(
+		engine,
+		body["query"].as_str().unwrap_or(""),
+		body["variables"].as_object().unwrap_or(&empty),
+		body["operationName"].as_str()
+	);
+	return respond_json(request, 200, &response);
+}
+
+impl Engine {
+	// An engine with empty indexes, for the store to be loaded in
+	pub fn new(conf: Conf, store_dir: PathBuf, store: Store, verbose: bool) -> Engine {
+		return Engine {
+			conf,
+			store_dir,
+			store,
+			verbose,
+			singletons: SingletonIndex::default(),
+			collections: CollectionIndex::default(),
+			edges: EdgeIndex::default(),
+			keyvalues: KeyValueIndex::default(),
+			blobs: BlobIndex::default(),
+			timeseries: SeriesIndex::default(),
+			geo: HashMap::new(),
+			scrub: Mutex::new(ScrubStatus::default()),
+			changes: Mutex::new(backup::Changes::default()),
+			context: Mutex::new(mutlog::MutationContext::default()),
+			mutation_log: None,
+			packed: None,
+			mapped: None,
+			cache: None,
+			tails: HashMap::new()
+		};
+	}
+
+	// Archives are served without being expanded, and never modified
+	pub fn read_only(&self) -> bool {
+		return self.packed.is_some();
+	}
+
+	pub fn files(&self, area: Area) -> &HashMap<String, FileMeta> {
+		return match area {
+			Area::Singletons => &self.singletons.files,
+			Area::Collections => &self.collections.files,
+			Area::Edges => &self.edges.files,
+			Area::KeyValues => &self.keyvalues.files,
+			Area::Blobs => &self.blobs.files
+		};
+	}
+
+	pub fn files_mut(&mut self, area: Area) -> &mut HashMap<String, FileMeta> {
+		return match area {
+			Area::Singletons => &mut self.singletons.files,
+			Area::Collections => &mut self.collections.files,
+			Area::Edges => &mut self.edges.files,
+			Area::KeyValues => &mut self.keyvalues.files,
+			Area::Blobs => &mut self.blobs.files
+		};
+	}
+
+	pub fn data_path(&self, area: Area, file: &str) -> PathBuf {
+		let mut path = self.store_dir.clone();
+		path.push(area.dir());
+		path.push(file);
+		return path;
+	}
+
+	pub fn read_data(
+		&self, area: Area, file: &str, index: u64, length: u64
+	) -> Result<Vec<u8>, String> {
+		let path = self.data_path(area, file);
+		let read = if let Some(packed) = &self.packed {
+			packed.read_data(&self.relative_path(&path), index, length)
 		}
-		store_str_draft = store_bin_content[0..12 as usize].to_vec();
-		store_str_parse = String::from_utf8(store_str_draft);
-		if store_str_parse.is_err() {
-			cli::red_err(store_read_err(store_item));
-			return std::process::ExitCode::FAILURE;
+		else if let Some(cache) = self.cache.as_ref().filter(|_| area != Area::Blobs) {
+			let store_dir = Some(&*self.store_dir).filter(|_| self.store.checksumming);
+			cache.read(store_dir, &path, index, length)
 		}
-		store_id_str = store_str_parse.unwrap();
+		else if let Some(mapped) = self.mapped.as_ref()
+			.filter(|_| area == Area::Singletons || area == Area::Collections)
+		{
+			let store_dir = Some(&*self.store_dir).filter(|_| self.store.checksumming);
+			mapped.read(store_dir, &path, index, length)
+		}
+		else if self.store.checksumming {
+			faccess::read_verified(&self.store_dir, &path, index, length)
+		}
+		else { faccess::read_data(&path, index, length) };
+		return read
+			.map_err(|e| format!(
+				"Failed to read {} bytes at {} in {}/{}: {}",
+				length, index, area.dir(), file, e
+			))
+		;
+	}
 
-		bo_read8_try = store_file_handle.read_u8();
-		if bo_read8_try.is_err() {
-			cli::red_err(store_read_err(store_item));
-			return std::process::ExitCode::FAILURE;
+	// Updates the checksums of a data file after some bytes were written in it
+	// Called after every write in a data file, which is also
+	// recorded for the incremental backups
+	pub fn update_checksums(&self, path: &Path, index: u64, length: u64) -> Result<(), String> {
+		self.changes.lock().unwrap().track(self.relative_path(path), index, length);
+		if let Some(cache) = &self.cache { cache.invalidate(path, index, length); }
+		if !self.store.checksumming { return Ok(()); }
+		self.track_rewrite(&faccess::checksum_path(&self.store_dir, path));
+		return faccess::update_checksums(&self.store_dir, path, index, length)
+			.map_err(|e| format!("Failed to update the checksums of {:?}: {}", path, e))
+		;
+	}
+
+	// Records a file rewritten as a whole, for the backups
+	pub fn track_rewrite(&self, path: &Path) {
+		self.changes.lock().unwrap().track_rewrite(self.relative_path(path));
+	}
+
+	pub fn relative_path(&self, path: &Path) -> String {
+		return archive::slash_path(path.strip_prefix(&self.store_dir).unwrap_or(path));
+	}
+
+	// Checks a whole data file against its checksums, if it exists
+	pub fn verify_file(&self, path: &Path) -> Result<(), String> {
+		if !self.store.checksumming || !path.exists() { return Ok(()); }
+		return faccess::verify_file(&self.store_dir, path).map_err(|e| e.to_string());
+	}
+
+	// Points of a time series window, sorted by time
+	pub fn read_points(&self, path: &Path) -> Result<Vec<(u64, f64)>, String> {
+		if let Some(packed) = &self.packed {
+			return packed.read_file(&self.relative_path(path))
+				.and_then(|content| faccess::decode_points(&content))
+				.map_err(|e| e.to_string())
+			;
 		}
-		store_str_length = bo_read8_try.unwrap();
+		self.verify_file(path)?;
+		return faccess::read_points(path).map_err(|e| e.to_string());
+	}
 
-		if store_bin_content.len() < store_str_length as usize {
-			store_bin_content.resize(store_str_length as usize, 0);
+	// Buckets of a time series rollup, if it has any
+	pub fn read_buckets(&self, path: &Path) -> Result<Vec<faccess::Bucket>, String> {
+		if let Some(packed) = &self.packed {
+			let relative = self.relative_path(path);
+			if !packed.contains(&relative) { return Ok(Vec::new()); }
+			return packed.read_file(&relative)
+				.and_then(|content| faccess::decode_buckets(&content))
+				.map_err(|e| e.to_string())
+			;
 		}
-		io_read_try = store_file_handle.read(
-			&mut store_bin_content[0..store_str_length as usize]
-		);
-		if io_read_try.is_err() {
-			cli::red_err(store_read_err(store_item));
-			return std::process::ExitCode::FAILURE;
+		self.verify_file(path)?;
+		return faccess::read_buckets(path).map_err(|e| e.to_string());
+	}
+
+	// Paths of all the data files of the store
+	pub fn data_files(&self) -> Vec<PathBuf> {
+		let mut files = Vec::<PathBuf>::new();
+		for area in [
+			Area::Singletons, Area::Collections, Area::Edges, Area::KeyValues, Area::Blobs
+		] {
+			let ids = match area {
+				Area::Singletons => self.singletons.files.keys(),
+				Area::Collections => self.collections.files.keys(),
+				Area::Edges => self.edges.files.keys(),
+				Area::KeyValues => self.keyvalues.files.keys(),
+				Area::Blobs => self.blobs.files.keys()
+			};
+			files.extend(ids.map(|file| self.data_path(area, file)));
 		}
-		store_str_draft = store_bin_content[0..store_str_length as usize].to_vec();
-		store_str_parse = String::from_utf8(store_str_draft);
-		if store_str_parse.is_err() {
-			cli::red_err(store_read_err(store_item));
-			return std::process::ExitCode::FAILURE;
+		for (id, meta) in &self.timeseries.series {
+			files.extend(meta.windows.keys().map(|start| self.window_path(id, *start)));
+			files.extend(meta.rollups.iter().map(|r| self.rollup_path(id, r.interval)));
 		}
-		collections_list.insert(store_id_str, store_str_parse.unwrap());
+		return files;
 	}
 
-	for s in singleton_files {
-		println!("{}", s.0);
-		println!("{:?}", s.1);
+	// Data file holding the points of a time series window
+	pub fn window_path(&self, series: &str, start: u64) -> PathBuf {
+		let mut path = self.store_dir.clone();
+		path.push("timeseries");
+		path.push(series);
+		path.push(start.to_string());
+		return path;
 	}
-	println!("\n");
-	for s in singletons {
-		println!("{}", s.0);
-		println!("{:?}", s.1);
+
+	// Data file holding the buckets of a time series rollup
+	pub fn rollup_path(&self, series: &str, interval: u64) -> PathBuf {
+		let mut path = self.store_dir.clone();
+		path.push("timeseries");
+		path.push(series);
+		path.push(format!("rollup-{}", interval));
+		return path;
 	}
-	println!("\n");
-	for s in collections_list {
-		println!("{} => {}", s.0, s.1);
+
+	pub fn save_series(&self) -> Result<(), String> {
+		self.track_rewrite(&self.store_dir.join("timeseries").join("rixindex"));
+		return faccess::save_timeseries(&self.store_dir, &self.timeseries)
+			.map_err(|e| format!("Failed to save the index of the time series: {}", e))
+		;
 	}
 
-	return std::process::ExitCode::SUCCESS;
-}
\ No newline at end of file
+	pub fn save_geoindex(&self) -> Result<(), String> {
+		self.track_rewrite(&self.store_dir.join("collections").join("geoindex"));
+		return faccess::save_geoindex(&self.store_dir, &self.geo)
+			.map_err(|e| format!("Failed to save the geo-point index: {}", e))
+		;
+	}
+
+	pub fn rebuild_geoindex(&mut self) -> Result<(), String> {
+		self.geo.clear();
+		let items = self.collections.items.iter()
+			.map(|(id, meta)| (id.clone(), meta.collection.clone()))
+			.collect::<Vec<(String, String)>>()
+		;
+		for (id, collection) in items {
+			let data = query::item_data(self, &id)?;
+			self.geo.entry(collection).or_default().insert(&id, &data);
+		}
+		// Archives are never written, their index is kept in memory only
+		if self.packed.is_some() { return Ok(()); }
+		return self.save_geoindex();
+	}
+
+	pub fn save_index(&self, area: Area) -> Result<(), String> {
+		self.track_rewrite(&self.store_dir.join(area.dir()).join("rixindex"));
+		if area == Area::Edges {
+			self.track_rewrite(&self.store_dir.join("edges").join("adjacency"));
+		}
+		let saved = match area {
+			Area::Singletons => faccess::save_singletons(&self.store_dir, &self.singletons),
+			Area::Collections => faccess::save_collections(&self.store_dir, &self.collections),
+			Area::Edges => faccess::save_edges(&self.store_dir, &self.edges),
+			Area::KeyValues => faccess::save_keyvalues(&self.store_dir, &self.keyvalues),
+			Area::Blobs => faccess::save_blobs(&self.store_dir, &self.blobs)
+		};
+		return saved.map_err(|e| format!(
+			"Failed to save the index of the {}: {}", area.dir(), e
+		));
+	}
+}
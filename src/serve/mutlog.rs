@@ -0,0 +1,336 @@
+use std::fs;
+use std::io::{ self, Write };
+use std::path::{ Path, PathBuf };
+use std::collections::{ HashMap, VecDeque };
+
+use serde::{ Serialize, Deserialize };
+use serde_json::{ Map, Value };
+
+use crate::cli;
+use crate::basics;
+use crate::crypt;
+use crate::faccess;
+use super::{ Engine, blobs, transac };
+use super::query::string_arg;
+
+// Time covered by each log file, in milliseconds
+const ROTATION: u64 = 3600 * 1000;
+
+// A logged mutation. It is replayed with the time and the ids it had,
+// so that it has the same effects again.
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+	pub lsn: u64, // Sequence number of the entry, across the log files
+	pub time: u64, // Unix time in milliseconds
+	pub name: String,
+	pub args: Map<String, Value>,
+	pub ids: Vec<String> // Ids generated by the mutation, in order
+}
+
+// Time and ids of the mutation being applied
+#[derive(Debug)]
+#[derive(Default)]
+pub struct MutationContext {
+	time: Option<u64>,
+	replayed: VecDeque<String>, // Ids to give out again while replaying
+	generated: Vec<String>
+}
+
+// Appends the mutations of a store to `logs/mutations/<start ms>.log`,
+// one JSON entry per line
+pub struct MutationLog {
+	dir: PathBuf,
+	retention: u64, // Milliseconds, 0 to keep the logs forever
+	file: Option<(u64, crypt::File)>, // Current file and its start time
+	pub lsn: u64 // Sequence number of the last entry
+}
+
+pub fn log_dir(store_dir: &Path) -> PathBuf {
+	return store_dir.join("logs/mutations");
+}
+
+// Where the content of uploaded blobs is kept for replaying
+pub fn blobs_dir(log_dir: &Path) -> PathBuf {
+	return log_dir.join("blobs");
+}
+
+// Lists the log files, from the oldest to the latest
+fn log_files(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+	let mut files = Vec::<(u64, PathBuf)>::new();
+	for entry in fs::read_dir(dir)? {
+		let entry = entry?;
+		let name = entry.file_name().to_string_lossy().into_owned();
+		if let Some(start) = name.strip_suffix(".log").and_then(|s| s.parse::<u64>().ok()) {
+			files.push((start, entry.path()));
+		}
+	}
+	files.sort();
+	return Ok(files);
+}
+
+// Reads the entries of a log file. A last line cut by a crash is dropped
+// and its length returned, so that it can be truncated.
+fn read_file(path: &Path) -> io::Result<(Vec<Entry>, u64)> {
+	let text = crypt::read_to_string(path)?;
+	let mut entries = Vec::<Entry>::new();
+	let mut valid = 0u64;
+	for line in text.split_inclusive('\n') {
+		match serde_json::from_str::<Entry>(line) {
+			Ok(entry) if line.ends_with('\n') => entries.push(entry),
+			_ if valid + line.len() as u64 == text.len() as u64 => break,
+			_ => return Err(io::Error::new(
+				io::ErrorKind::InvalidData, format!("Invalid entry in {:?}", path)
+			))
+		}
+		valid += line.len() as u64;
+	}
+	return Ok((entries, text.len() as u64 - valid));
+}
+
+// Reads all the entries of a mutation log directory, in order
+pub fn read_entries(dir: &Path) -> io::Result<Vec<Entry>> {
+	let mut entries = Vec::<Entry>::new();
+	for (_, path) in log_files(dir)? {
+		entries.extend(read_file(&path)?.0);
+	}
+	return Ok(entries);
+}
+
+// Copies the entries of a mutation log directory up to the given sequence
+// number, with the blobs they uploaded, so that the log of a restored store
+// goes on from there
+pub fn copy_entries(from: &Path, to: &Path, last: u64) -> io::Result<()> {
+	fs::create_dir_all(blobs_dir(to))?;
+	for (_, path) in log_files(from)? {
+		let entries = read_file(&path)?.0.into_iter()
+			.filter(|e| e.lsn <= last)
+			.collect::<Vec<Entry>>()
+		;
+		if entries.is_empty() { continue; }
+		let mut text = Vec::<u8>::new();
+		for entry in &entries {
+			serde_json::to_writer(&mut text, entry).map_err(io::Error::other)?;
+			text.push(b'\n');
+		}
+		crypt::write(&to.join(path.file_name().unwrap()), &text)?;
+		for entry in entries.iter().filter(|e| e.name == "uploadBlob") {
+			if let Some(file) = entry.args.get("file").and_then(|f| f.as_str()) {
+				fs::copy(blobs_dir(from).join(file), blobs_dir(to).join(file))?;
+			}
+		}
+	}
+	return Ok(());
+}
+
+impl MutationLog {
+	pub fn open(store_dir: &Path, retention_hours: u64) -> io::Result<MutationLog> {
+		let dir = log_dir(store_dir);
+		fs::create_dir_all(blobs_dir(&dir))?;
+		let mut lsn = 0u64;
+		if let Some((_, path)) = log_files(&dir)?.pop() {
+			let (entries, torn) = read_file(&path)?;
+			if torn > 0 {
+				let mut file = crypt::File::open_rw(&path)?;
+				file.set_len(file.len()? - torn)?;
+			}
+			lsn = entries.last().map(|e| e.lsn).unwrap_or(0);
+		}
+		return Ok(MutationLog {
+			dir,
+			retention: retention_hours * 3600 * 1000,
+			file: None,
+			lsn
+		});
+	}
+
+	pub fn blobs_dir(&self) -> PathBuf {
+		return blobs_dir(&self.dir);
+	}
+
+	pub fn append(
+		&mut self, time: u64, name: &str, args: &Map<String, Value>, ids: Vec<String>
+	) -> io::Result<()> {
+		let rotate = match &self.file {
+			Some((start, _)) => time >= start + ROTATION,
+			None => true
+		};
+		if rotate {
+			let path = self.dir.join(format!("{}.log", time));
+			let file = crypt::File::append(&path)?;
+			self.file = Some((time, file));
+			self.prune(time)?;
+		}
+		let entry = Entry {
+			lsn: self.lsn + 1, time, name: name.to_owned(), args: args.clone(), ids
+		};
+		let mut line = serde_json::to_vec(&entry).map_err(io::Error::other)?;
+		line.push(b'\n');
+		// An entry reaches the disk before the mutation is answered
+		let file = &mut self.file.as_mut().unwrap().1;
+		file.write_all(&line)?;
+		file.sync_data()?;
+		self.lsn += 1;
+		return Ok(());
+	}
+
+	// Removes the log files whose entries are all older than the retention,
+	// along with the blobs uploaded before the oldest file kept
+	fn prune(&self, now: u64) -> io::Result<()> {
+		if self.retention == 0 { return Ok(()); }
+		let cutoff = now.saturating_sub(self.retention);
+		let files = log_files(&self.dir)?;
+		let mut kept_from = files.first().map(|(start, _)| *start).unwrap_or(0);
+		for pair in files.windows(2) {
+			if pair[1].0 > cutoff { break; }
+			fs::remove_file(&pair[0].1)?;
+			kept_from = pair[1].0;
+		}
+		for entry in fs::read_dir(self.blobs_dir())? {
+			let entry = entry?;
+			let name = entry.file_name().to_string_lossy().into_owned();
+			let uploaded = name.rsplit_once('-').and_then(|(_, t)| t.parse::<u64>().ok());
+			if uploaded.is_some_and(|t| t < kept_from) { fs::remove_file(entry.path())?; }
+		}
+		return Ok(());
+	}
+}
+
+impl Engine {
+	// Current time, or the one of the mutation being applied
+	pub fn now(&self) -> u64 {
+		return self.context.lock().unwrap().time.unwrap_or_else(basics::now_millis);
+	}
+
+	// Generates an id which isn't taken yet, or gives back the one
+	// the replayed mutation had
+	pub fn new_id<T>(&self, taken: &HashMap<String, T>) -> String {
+		let mut context = self.context.lock().unwrap();
+		let id = match context.replayed.pop_front() {
+			Some(id) => id,
+			None => faccess::new_id_in(taken)
+		};
+		context.generated.push(id.clone());
+		return id;
+	}
+
+	// Sequence number of the last mutation logged, 0 without mutation logs
+	pub fn lsn(&self) -> u64 {
+		return self.mutation_log.as_ref().map(|log| log.lsn).unwrap_or(0);
+	}
+
+	// Fixes the time of a mutation about to be applied
+	pub fn start_mutation(&self) {
+		let mut context = self.context.lock().unwrap();
+		if context.time.is_none() { context.time = Some(basics::now_millis()); }
+		context.generated.clear();
+	}
+
+	// Logs a blob upload, whose content was copied in the given file
+	pub fn log_upload(&mut self, id: &str, content_type: &str, file: &str) {
+		self.start_mutation();
+		self.context.lock().unwrap().generated.push(id.to_owned());
+		let args = Map::from_iter([
+			("contentType".to_owned(), Value::from(content_type)),
+			("file".to_owned(), Value::from(file))
+		]);
+		self.end_mutation("uploadBlob", &args, true);
+	}
+
+	// Logs a mutation which was committed, when the store keeps mutation logs
+	pub fn end_mutation(&mut self, name: &str, args: &Map<String, Value>, committed: bool) {
+		let (time, ids) = {
+			let mut context = self.context.lock().unwrap();
+			let time = context.time.take().unwrap_or_else(basics::now_millis);
+			(time, std::mem::take(&mut context.generated))
+		};
+		if let Some(log) = self.mutation_log.as_mut().filter(|_| committed) {
+			if let Err(e) = log.append(time, name, args, ids) {
+				cli::red_err(format!("Failed to log the mutation {}: {}", name, e));
+			}
+		}
+	}
+}
+
+fn replay_upload(engine: &mut Engine, entry: &Entry, log_dir: &Path) -> Result<(), String> {
+	let id = entry.ids.first().ok_or("The blob id is missing.")?;
+	let content_type = string_arg(&entry.args, "contentType")?;
+	let path = blobs_dir(log_dir).join(string_arg(&entry.args, "file")?);
+	let mut file = crypt::File::open(&path)
+		.map_err(|e| format!("Failed to read {:?}: {}", path, e))?
+	;
+	return blobs::insert_blob(engine, id, &content_type, &mut file);
+}
+
+// Applies a logged mutation again, with its time and ids
+pub fn replay(engine: &mut Engine, entry: &Entry, log_dir: &Path) -> Result<(), String> {
+	{
+		let mut context = engine.context.lock().unwrap();
+		context.time = Some(entry.time);
+		context.replayed = entry.ids.iter().cloned().collect();
+	}
+	let replayed = match entry.name.as_str() {
+		"uploadBlob" => replay_upload(engine, entry, log_dir),
+		name => transac::mutate(engine, name, &entry.args).map(|_| ())
+	};
+	let mut context = engine.context.lock().unwrap();
+	context.time = None;
+	context.replayed.clear();
+	return replayed.map_err(|e| format!("Failed to replay the mutation {}: {}", entry.lsn, e));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::testing;
+
+	#[test]
+	fn sequence_numbers_continue_after_a_torn_entry() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut log = MutationLog::open(dir.path(), 0).unwrap();
+		let args = Map::from_iter([("key".to_owned(), Value::from("a"))]);
+		for time in [1000, 2000, 3000] {
+			log.append(time, "deleteKey", &args, Vec::new()).unwrap();
+		}
+		assert_eq!(log.lsn, 3);
+		drop(log);
+
+		// A crash in the middle of an entry
+		let path = log_dir(dir.path()).join("1000.log");
+		let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+		file.write_all(b"{\"lsn\":4,\"time\":40").unwrap();
+		drop(file);
+		let mut log = MutationLog::open(dir.path(), 0).unwrap();
+		assert_eq!(log.lsn, 3);
+		log.append(4000, "deleteKey", &args, vec!["id".to_owned()]).unwrap();
+		let entries = read_entries(&log_dir(dir.path())).unwrap();
+		assert_eq!(entries.iter().map(|e| e.lsn).collect::<Vec<u64>>(), [1, 2, 3, 4]);
+		assert_eq!(entries[3].ids, ["id"]);
+
+		// Entries are only dropped at the end of the log
+		let text = fs::read_to_string(&path).unwrap();
+		fs::write(&path, text.replacen("\"lsn\":2", "\"lsn\":", 1)).unwrap();
+		assert!(read_entries(&log_dir(dir.path())).is_err());
+	}
+
+	#[test]
+	fn failed_mutations_are_not_logged() {
+		let dir = tempfile::tempdir().unwrap();
+		let settings = serde_json::json!({ "mutation_logs": { "retention": 0 } });
+		let mut engine = testing::create_store(dir.path(), settings);
+		let set = |engine: &mut Engine, value: u64| testing::mutate(
+			engine, "setKey", serde_json::json!({ "key": "a", "value": value })
+		);
+		set(&mut engine, 1).unwrap();
+		let index = dir.path().join("keyvalues/rixindex");
+		testing::block(&index);
+		assert!(set(&mut engine, 2).is_err());
+		testing::unblock(&index);
+		set(&mut engine, 3).unwrap();
+
+		let entries = read_entries(&log_dir(dir.path())).unwrap();
+		let values = entries.iter().map(|e| e.args["value"].clone()).collect::<Vec<Value>>();
+		assert_eq!(values, [1, 3]);
+		assert_eq!(entries.iter().map(|e| e.lsn).collect::<Vec<u64>>(), [1, 2]);
+	}
+}
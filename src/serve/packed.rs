@@ -0,0 +1,246 @@
+use std::io::{ self, Read };
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex };
+use std::collections::{ HashMap, VecDeque };
+
+use crate::archive::{ ArchiveReader, ArchiveEntry, ARCHIVE_FILE };
+use crate::crypt;
+use crate::basics::{ Conf, Store };
+use crate::faccess::{ self, POINT_SIZE };
+use super::Engine;
+
+// Bytes of decompressed files kept in memory
+const CACHE_SIZE: u64 = 64 * 1024 * 1024;
+
+// Content of a store of the Archive type, read from its archive file, which
+// is never expanded on the disk. The files are decompressed in memory when
+// read, and the latest ones are kept for the next reads.
+pub struct PackedStore {
+	path: PathBuf,
+	reader: Mutex<ArchiveReader>,
+	entries: HashMap<String, ArchiveEntry>,
+	cache: Mutex<Cache>
+}
+
+#[derive(Default)]
+struct Cache {
+	files: HashMap<String, Arc<Vec<u8>>>,
+	used: VecDeque<String>, // From the least to the most recently used
+	size: u64
+}
+
+impl Cache {
+	fn get(&mut self, path: &str) -> Option<Arc<Vec<u8>>> {
+		let content = self.files.get(path)?.clone();
+		self.used.retain(|p| p != path);
+		self.used.push_back(path.to_owned());
+		return Some(content);
+	}
+
+	// Keeps a file, dropping the least recently used ones over the budget
+	fn insert(&mut self, path: &str, content: Arc<Vec<u8>>) {
+		self.size += content.len() as u64;
+		self.files.insert(path.to_owned(), content);
+		self.used.push_back(path.to_owned());
+		while self.size > CACHE_SIZE && self.used.len() > 1 {
+			let oldest = self.used.pop_front().unwrap();
+			if let Some(dropped) = self.files.remove(&oldest) {
+				self.size -= dropped.len() as u64;
+			}
+		}
+	}
+}
+
+impl PackedStore {
+	pub fn open(store_dir: &Path) -> io::Result<PackedStore> {
+		let path = store_dir.join(ARCHIVE_FILE);
+		let mut reader = ArchiveReader::open(&path)?;
+		let mut entries = HashMap::<String, ArchiveEntry>::new();
+		while let Some(entry) = reader.next_entry()? {
+			entries.insert(entry.path.clone(), entry);
+		}
+		return Ok(PackedStore {
+			path,
+			reader: Mutex::new(reader),
+			entries,
+			cache: Mutex::new(Cache::default())
+		});
+	}
+
+	pub fn contains(&self, path: &str) -> bool {
+		return self.entries.contains_key(path);
+	}
+
+	// Whole content of a file, checked against its size and CRC when decompressed
+	pub fn read_file(&self, path: &str) -> io::Result<Arc<Vec<u8>>> {
+		if let Some(content) = self.cache.lock().unwrap().get(path) { return Ok(content); }
+		let entry = self.entries.get(path).ok_or(io::Error::new(
+			io::ErrorKind::NotFound, format!("{} is not in {:?}", path, self.path)
+		))?;
+		let mut content = Vec::<u8>::with_capacity(entry.size as usize);
+		self.reader.lock().unwrap().extract(entry, &mut content)?;
+		let content = Arc::new(crypt::unseal(&content)?);
+		self.cache.lock().unwrap().insert(path, content.clone());
+		return Ok(content);
+	}
+
+	pub fn read_data(&self, path: &str, index: u64, length: u64) -> io::Result<Vec<u8>> {
+		let content = self.read_file(path)?;
+		return match content.get(index as usize..(index + length) as usize) {
+			Some(bytes) => Ok(bytes.to_vec()),
+			None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Beyond the end of the file"))
+		};
+	}
+
+	// Names and sizes of the files right in a directory
+	pub fn list(&self, dir: &str) -> Vec<(&str, u64)> {
+		let prefix = format!("{}/", dir);
+		return self.entries.values()
+			.filter_map(|entry| {
+				let name = entry.path.strip_prefix(&prefix)?;
+				if name.contains('/') { return None; }
+				Some((name, entry.size))
+			})
+			.collect()
+		;
+	}
+
+	// Reads an index file, the missing or empty ones giving an empty index
+	fn index<T: Default>(
+		&self, path: &str, read: impl Fn(&mut &[u8]) -> io::Result<T>
+	) -> Result<T, String> {
+		if !self.contains(path) { return Ok(T::default()); }
+		let content = self.read_file(path)
+			.map_err(|e| format!("Failed to read {}: {}", path, e))?
+		;
+		if content.is_empty() { return Ok(T::default()); }
+		return read(&mut content.as_slice())
+			.map_err(|e| format!("Failed to read {}: {}", path, e))
+		;
+	}
+}
+
+// Loads a store of the Archive type from its archive file, to serve it
+// in read-only mode
+pub fn open_packed(
+	conf: Conf, store_dir: PathBuf, store: Store, verbose: bool
+) -> Result<Engine, String> {
+	crypt::unlock(&store_dir, &store, None)?;
+	let packed = PackedStore::open(&store_dir)
+		.map_err(|e| format!("Failed to open the archive of the store: {}", e))?
+	;
+
+	let singletons = packed.index("singletons/rixindex", |r| faccess::read_singletons(r))?;
+	let collections = packed.index("collections/rixindex", |r| faccess::read_collections(r))?;
+	let mut edges = packed.index("edges/rixindex", |r| faccess::read_edges(r))?;
+	let adjacency = match packed.contains("edges/adjacency") {
+		true => Some(packed.read_file("edges/adjacency").map_err(|e| e.to_string())?),
+		false => None
+	};
+	let mut lists = adjacency.as_ref().filter(|content| !content.is_empty())
+		.map(|content| content.as_slice())
+	;
+	faccess::read_adjacency_lists(&mut edges, lists.as_mut().map(|r| r as &mut dyn Read))
+		.map_err(|e| format!("Failed to read edges/adjacency: {}", e))?
+	;
+	let keyvalues = packed.index("keyvalues/rixindex", |r| faccess::read_keyvalues(r))?;
+	let blobs = packed.index("blobs/rixindex", |r| faccess::read_blobs(r))?;
+
+	// The windows of the series are the files named after their start time.
+	// The ones of an encrypted store are all sealed.
+	let mut timeseries = packed.index("timeseries/rixindex", |r| faccess::read_series_list(r))?;
+	for (id, meta) in timeseries.series.iter_mut() {
+		for (name, size) in packed.list(&format!("timeseries/{}", id)) {
+			let size = if store.encryption.is_some() { crypt::plain_len(size) } else { size };
+			if let Ok(start) = name.parse::<u64>() {
+				meta.windows.insert(start, size / POINT_SIZE);
+			}
+		}
+	}
+
+	let geo_rebuild = !packed.contains("collections/geoindex");
+	let geo = packed.index("collections/geoindex", |r| faccess::read_geoindex(r, &collections))?;
+
+	let mut engine = Engine {
+		singletons,
+		collections,
+		edges,
+		keyvalues,
+		blobs,
+		timeseries,
+		geo,
+		packed: Some(packed),
+		..Engine::new(conf, store_dir, store, verbose)
+	};
+	if geo_rebuild { engine.rebuild_geoindex()?; }
+	return Ok(engine);
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use std::sync::RwLock;
+	use serde_json::json;
+	use crate::archive;
+	use crate::basics::{ self, StoreType };
+	use super::*;
+	use super::super::{ READ_ONLY, testing };
+
+	// A store of the Archive type, made from a Live one with a few entries
+	fn archived(dir: &Path) -> PathBuf {
+		let source = dir.join("source");
+		let mut engine = testing::create_store(&source, json!({}));
+		testing::mutate(&mut engine, "setKey", json!({ "key": "greeting", "value": "hello" })).unwrap();
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "notes" })).unwrap();
+		testing::insert(&mut engine, "notes", json!({ "text": "x".repeat(5000) }));
+		testing::mutate(&mut engine, "createSeries", json!({ "name": "cpu", "window": 60 })).unwrap();
+		let points = json!([{ "time": 60_000, "value": 1.5 }, { "time": 130_000, "value": 2 }]);
+		testing::mutate(&mut engine, "appendPoints", json!({ "series": "cpu", "points": points })).unwrap();
+		drop(engine);
+
+		let mut store = basics::load_store(&source).unwrap();
+		store.kind = StoreType::Archive;
+		let store_dir = dir.join("archived");
+		fs::create_dir_all(&store_dir).unwrap();
+		let files = archive::store_files(&source).unwrap();
+		archive::write_archive(&source, &store, &files, &store_dir.join(ARCHIVE_FILE)).unwrap();
+		fs::write(store_dir.join("manifest.json"), serde_json::to_string(&store).unwrap()).unwrap();
+		return store_dir;
+	}
+
+	#[test]
+	fn archives_are_served_read_only() {
+		let dir = tempfile::tempdir().unwrap();
+		let store_dir = archived(dir.path());
+		let store = basics::load_store(&store_dir).unwrap();
+		let engine = open_packed(basics::get_conf(), store_dir.clone(), store, false).unwrap();
+		assert!(engine.read_only());
+		let engine = RwLock::new(engine);
+
+		let response = testing::run(&engine, "{ keyValue(key: \"greeting\") \
+			items(collection: \"notes\") { data } points(series: \"cpu\") }"
+		);
+		assert_eq!(response["data"]["keyValue"], "hello");
+		assert_eq!(response["data"]["items"][0]["data"]["text"], "x".repeat(5000));
+		assert_eq!(response["data"]["points"], json!([
+			{ "time": 60_000, "value": 1.5 }, { "time": 130_000, "value": 2.0 }
+		]));
+
+		let set = testing::mutate(
+			&mut engine.write().unwrap(), "setKey", json!({ "key": "greeting", "value": "bye" })
+		);
+		assert_eq!(set.err().as_deref(), Some(READ_ONLY));
+		let response = testing::run(&engine, "mutation { deleteKey(key: \"greeting\") }");
+		assert_eq!(response["errors"][0]["message"], READ_ONLY);
+		let response = testing::run(&engine, "{ keyValue(key: \"greeting\") }");
+		assert_eq!(response["data"]["keyValue"], "hello");
+
+		// Nothing is written next to the archive
+		let mut names = fs::read_dir(&store_dir).unwrap()
+			.map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+			.collect::<Vec<String>>()
+		;
+		names.sort();
+		assert_eq!(names, ["manifest.json", ARCHIVE_FILE]);
+	}
+}
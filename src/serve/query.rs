@@ -0,0 +1,1023 @@
+use std::collections::{ HashMap, HashSet };
+use std::sync::RwLock;
+
+use graphql_parser::query as gql;
+use serde_json::{ Map, Value, json };
+
+use crate::basics;
+use crate::faccess::{ self, Area };
+use crate::geo::{ self, GeoIndex };
+use super::{ Engine, transac, blobs };
+
+// Traversals can't follow more edges than this in a row
+pub const MAX_DEPTH: u64 = 16;
+
+// Selections can't be nested deeper than this, fragments included
+pub const MAX_NESTING: u64 = 32;
+
+// What a field resolves to, before its sub-selection is applied
+pub enum Resolved {
+	Value(Value),
+	Item(String),
+	Near(String, f64), // Item id, and its distance in meters from a point
+	Edge(String, u64), // Edge id, and its distance from the traversal start
+	List(Vec<Resolved>)
+}
+
+pub struct Context<'a> {
+	variables: Map<String, Value>,
+	fragments: HashMap<&'a str, &'a gql::FragmentDefinition<'a, String>>
+}
+
+fn error_response(message: String) -> Value {
+	return json!({ "data": null, "errors": [{ "message": message }] });
+}
+
+pub fn execute(
+	engine: &RwLock<Engine>,
+	source: &str,
+	variables: &Map<String, Value>,
+	operation: Option<&str>
+) -> Value {
+	let document = match gql::parse_query::<String>(source) {
+		Ok(document) => document,
+		Err(error) => return error_response(error.to_string())
+	};
+
+	let mut fragments = HashMap::new();
+	let mut operations = Vec::<&gql::OperationDefinition<String>>::new();
+	for definition in &document.definitions {
+		match definition {
+			gql::Definition::Fragment(fragment) => {
+				fragments.insert(fragment.name.as_str(), fragment);
+			},
+			gql::Definition::Operation(op) => operations.push(op)
+		}
+	}
+
+	let chosen = match operation {
+		Some(name) => operations.iter().find(|op| {
+			let op_name = match op {
+				gql::OperationDefinition::Query(q) => q.name.as_deref(),
+				gql::OperationDefinition::Mutation(m) => m.name.as_deref(),
+				gql::OperationDefinition::Subscription(s) => s.name.as_deref(),
+				gql::OperationDefinition::SelectionSet(_) => None
+			};
+			op_name == Some(name)
+		}),
+		None if operations.len() == 1 => operations.first(),
+		None => return error_response(
+			"An operation name is required when the document holds several.".to_owned()
+		)
+	};
+	let chosen = match chosen {
+		Some(op) => *op,
+		None => return error_response("Unknown operation.".to_owned())
+	};
+
+	let mut ctx = Context { variables: variables.clone(), fragments };
+	let definitions = match chosen {
+		gql::OperationDefinition::Query(q) => &q.variable_definitions,
+		gql::OperationDefinition::Mutation(m) => &m.variable_definitions,
+		_ => &Vec::new()
+	};
+	for definition in definitions {
+		if ctx.variables.contains_key(&definition.name) { continue; }
+		if let Some(default) = &definition.default_value {
+			let value = match to_json(&ctx, default) {
+				Ok(value) => value,
+				Err(message) => return error_response(message)
+			};
+			ctx.variables.insert(definition.name.clone(), value);
+		}
+	}
+
+	let set = match chosen {
+		gql::OperationDefinition::SelectionSet(set) => set,
+		gql::OperationDefinition::Query(q) => &q.selection_set,
+		gql::OperationDefinition::Mutation(m) => &m.selection_set,
+		gql::OperationDefinition::Subscription(s) => &s.selection_set
+	};
+	if let Err(message) = nesting(&ctx, set, &mut HashMap::new()) {
+		return error_response(message);
+	}
+
+	let result = match chosen {
+		gql::OperationDefinition::SelectionSet(set) => {
+			select_root(&ctx, &engine.read().unwrap(), set)
+		},
+		gql::OperationDefinition::Query(q) => {
+			select_root(&ctx, &engine.read().unwrap(), &q.selection_set)
+		},
+		gql::OperationDefinition::Mutation(m) => {
+			mutate_root(&ctx, &mut engine.write().unwrap(), &m.selection_set)
+		},
+		gql::OperationDefinition::Subscription(_) => Err(
+			"Subscriptions are not supported.".to_owned()
+		)
+	};
+
+	return match result {
+		Ok(data) => json!({ "data": data }),
+		Err(message) => error_response(message)
+	};
+}
+
+
+// --> Walking through the selections
+// ----------------------------------
+
+// Depth of a selection set, in fields. Rejects the fragments which spread
+// themselves, directly or not, and the sets nested deeper than MAX_NESTING.
+// The depth of each fragment is computed once, `None` while it's in progress.
+fn nesting<'a>(
+	ctx: &Context<'a>,
+	set: &'a gql::SelectionSet<'a, String>,
+	depths: &mut HashMap<&'a str, Option<u64>>
+) -> Result<u64, String> {
+	let mut deepest = 0;
+	for selection in &set.items {
+		let depth = match selection {
+			gql::Selection::Field(field) => {
+				let depth = 1 + nesting(ctx, &field.selection_set, depths)?;
+				if depth > MAX_NESTING {
+					return Err(format!(
+						"The selections can't be nested more than {} levels deep.", MAX_NESTING
+					));
+				}
+				depth
+			},
+			gql::Selection::InlineFragment(inline) => {
+				nesting(ctx, &inline.selection_set, depths)?
+			},
+			gql::Selection::FragmentSpread(spread) => {
+				let name = spread.fragment_name.as_str();
+				match depths.get(name) {
+					Some(Some(depth)) => *depth,
+					Some(None) => return Err(format!("Fragment cycle through {}", name)),
+					None => {
+						let fragment = ctx.fragments.get(name)
+							.ok_or(format!("Unknown fragment: {}", name))?
+						;
+						depths.insert(name, None);
+						let depth = nesting(ctx, &fragment.selection_set, depths)?;
+						depths.insert(name, Some(depth));
+						depth
+					}
+				}
+			}
+		};
+		deepest = deepest.max(depth);
+	}
+	return Ok(deepest);
+}
+
+// Flattens fragment spreads and inline fragments.
+// The data is schemaless, so type conditions are ignored.
+fn fields<'a>(
+	ctx: &Context<'a>, set: &'a gql::SelectionSet<'a, String>
+) -> Result<Vec<&'a gql::Field<'a, String>>, String> {
+	let mut list = Vec::new();
+	flatten(ctx, set, &mut HashSet::new(), &mut list)?;
+	return Ok(list);
+}
+
+// A fragment spread twice in the same selection set adds its fields once
+fn flatten<'a>(
+	ctx: &Context<'a>,
+	set: &'a gql::SelectionSet<'a, String>,
+	spread: &mut HashSet<&'a str>,
+	list: &mut Vec<&'a gql::Field<'a, String>>
+) -> Result<(), String> {
+	for selection in &set.items {
+		match selection {
+			gql::Selection::Field(field) => list.push(field),
+			gql::Selection::InlineFragment(inline) => {
+				flatten(ctx, &inline.selection_set, spread, list)?;
+			},
+			gql::Selection::FragmentSpread(fragment) => {
+				let name = fragment.fragment_name.as_str();
+				if !spread.insert(name) { continue; }
+				let fragment = ctx.fragments.get(name)
+					.ok_or(format!("Unknown fragment: {}", name))?
+				;
+				flatten(ctx, &fragment.selection_set, spread, list)?;
+			}
+		}
+	}
+	return Ok(());
+}
+
+fn response_key<'a>(field: &'a gql::Field<'a, String>) -> &'a str {
+	return field.alias.as_deref().unwrap_or(&field.name);
+}
+
+fn to_json(ctx: &Context, value: &gql::Value<String>) -> Result<Value, String> {
+	return Ok(match value {
+		gql::Value::Variable(name) => ctx.variables.get(name)
+			.cloned().unwrap_or(Value::Null),
+		gql::Value::Int(n) => Value::from(
+			n.as_i64().ok_or("Integer out of range".to_owned())?
+		),
+		gql::Value::Float(f) => Value::from(*f),
+		gql::Value::String(s) => Value::String(s.clone()),
+		gql::Value::Boolean(b) => Value::Bool(*b),
+		gql::Value::Null => Value::Null,
+		gql::Value::Enum(e) => Value::String(e.clone()),
+		gql::Value::List(list) => Value::Array(
+			list.iter().map(|v| to_json(ctx, v)).collect::<Result<_, _>>()?
+		),
+		gql::Value::Object(object) => Value::Object(
+			object.iter()
+				.map(|(k, v)| Ok((k.clone(), to_json(ctx, v)?)))
+				.collect::<Result<_, String>>()?
+		)
+	});
+}
+
+pub fn arguments(
+	ctx: &Context, field: &gql::Field<String>
+) -> Result<Map<String, Value>, String> {
+	let mut args = Map::new();
+	for (name, value) in &field.arguments {
+		args.insert(name.clone(), to_json(ctx, value)?);
+	}
+	return Ok(args);
+}
+
+fn select_root<'a>(
+	ctx: &Context<'a>, engine: &Engine, set: &'a gql::SelectionSet<'a, String>
+) -> Result<Value, String> {
+	let mut data = Map::new();
+	for field in fields(ctx, set)? {
+		let args = arguments(ctx, field)?;
+		let resolved = resolve_root(engine, &field.name, &args)?;
+		data.insert(
+			response_key(field).to_owned(),
+			complete(ctx, engine, resolved, &field.selection_set)?
+		);
+	}
+	return Ok(Value::Object(data));
+}
+
+// Mutation fields are run one after the other, as required by GraphQL
+fn mutate_root<'a>(
+	ctx: &Context<'a>, engine: &mut Engine, set: &'a gql::SelectionSet<'a, String>
+) -> Result<Value, String> {
+	let mut data = Map::new();
+	for field in fields(ctx, set)? {
+		let args = arguments(ctx, field)?;
+		let resolved = transac::mutate(engine, &field.name, &args)?;
+		data.insert(
+			response_key(field).to_owned(),
+			complete(ctx, engine, resolved, &field.selection_set)?
+		);
+	}
+	return Ok(Value::Object(data));
+}
+
+pub fn complete<'a>(
+	ctx: &Context<'a>,
+	engine: &Engine,
+	resolved: Resolved,
+	set: &'a gql::SelectionSet<'a, String>
+) -> Result<Value, String> {
+	match resolved {
+		Resolved::List(list) => return Ok(Value::Array(
+			list.into_iter()
+				.map(|r| complete(ctx, engine, r, set))
+				.collect::<Result<_, _>>()?
+		)),
+		Resolved::Value(value) => {
+			if set.items.is_empty() { return Ok(value); }
+			return match value {
+				Value::Null => Ok(Value::Null),
+				Value::Array(list) => Ok(Value::Array(
+					list.into_iter()
+						.map(|v| complete(ctx, engine, Resolved::Value(v), set))
+						.collect::<Result<_, _>>()?
+				)),
+				Value::Object(object) => {
+					let mut data = Map::new();
+					for field in fields(ctx, set)? {
+						let value = match field.name.as_str() {
+							"__typename" => Value::from("Object"),
+							name => object.get(name).cloned().unwrap_or(Value::Null)
+						};
+						data.insert(
+							response_key(field).to_owned(),
+							complete(
+								ctx, engine, Resolved::Value(value),
+								&field.selection_set
+							)?
+						);
+					}
+					Ok(Value::Object(data))
+				},
+				_ => Err("A scalar value has no fields to select.".to_owned())
+			};
+		},
+		Resolved::Item(id) => return complete_item(ctx, engine, &id, None, set),
+		Resolved::Near(id, distance) => {
+			return complete_item(ctx, engine, &id, Some(distance), set);
+		},
+		Resolved::Edge(id, depth) => {
+			if set.items.is_empty() { return edge_json(engine, &id, depth); }
+			let mut data = Map::new();
+			for field in fields(ctx, set)? {
+				let resolved = resolve_edge(engine, &id, depth, &field.name)?;
+				data.insert(
+					response_key(field).to_owned(),
+					complete(ctx, engine, resolved, &field.selection_set)?
+				);
+			}
+			return Ok(Value::Object(data));
+		}
+	}
+}
+
+fn complete_item<'a>(
+	ctx: &Context<'a>,
+	engine: &Engine,
+	id: &str,
+	distance: Option<f64>,
+	set: &'a gql::SelectionSet<'a, String>
+) -> Result<Value, String> {
+	if set.items.is_empty() {
+		let mut data = item_json(engine, id)?;
+		if let Some(distance) = distance { data["distance"] = Value::from(distance); }
+		return Ok(data);
+	}
+	let mut data = Map::new();
+	for field in fields(ctx, set)? {
+		let resolved = match (field.name.as_str(), distance) {
+			("distance", Some(distance)) => Resolved::Value(Value::from(distance)),
+			(name, _) => resolve_item(engine, id, name, &arguments(ctx, field)?)?
+		};
+		data.insert(
+			response_key(field).to_owned(),
+			complete(ctx, engine, resolved, &field.selection_set)?
+		);
+	}
+	return Ok(Value::Object(data));
+}
+
+
+// --> Argument helpers
+// --------------------
+
+pub fn string_arg(args: &Map<String, Value>, name: &str) -> Result<String, String> {
+	return args.get(name).and_then(|v| v.as_str()).map(|s| s.to_owned())
+		.ok_or(format!("The argument \"{}\" must be a string.", name))
+	;
+}
+
+pub fn optional_string_arg(
+	args: &Map<String, Value>, name: &str
+) -> Result<Option<String>, String> {
+	return match args.get(name) {
+		None | Some(Value::Null) => Ok(None),
+		Some(_) => Ok(Some(string_arg(args, name)?))
+	};
+}
+
+pub fn optional_u64_arg(
+	args: &Map<String, Value>, name: &str
+) -> Result<Option<u64>, String> {
+	return match args.get(name) {
+		None | Some(Value::Null) => Ok(None),
+		Some(value) => value.as_u64().map(Some).ok_or(format!(
+			"The argument \"{}\" must be a positive integer.", name
+		))
+	};
+}
+
+fn float_field(object: &Value, name: &str) -> Result<f64, String> {
+	return object.get(name).and_then(|v| v.as_f64())
+		.ok_or(format!("The field \"{}\" must be a number.", name))
+	;
+}
+
+fn geo_field(object: &Value) -> Result<String, String> {
+	return object.get("field").and_then(|v| v.as_str()).map(|s| s.to_owned())
+		.ok_or("The geo-point \"field\" must be a string.".to_owned())
+	;
+}
+
+
+// --> Spatial filters
+// -------------------
+
+// Filters the items of a collection by a bounding box (`within`:
+// `{ field, west, south, east, north }`) and/or around a point (`near`:
+// `{ field, lon, lat, radius }`, radius in meters and optional).
+// With `near`, the items are sorted by distance, which is returned too.
+fn spatial(
+	geo: Option<&GeoIndex>, within: Option<&Value>, near: Option<&Value>
+) -> Result<Vec<(String, Option<f64>)>, String> {
+	let mut boxes = Vec::<(String, (f64, f64, f64, f64))>::new();
+	if let Some(within) = within {
+		let area = (
+			float_field(within, "west")?, float_field(within, "south")?,
+			float_field(within, "east")?, float_field(within, "north")?
+		);
+		if area.1 > area.3 || [area.0, area.2].iter().any(|l| l.abs() > 180.0)
+			|| [area.1, area.3].iter().any(|l| l.abs() > 90.0)
+		{
+			return Err("The bounding box is invalid.".to_owned());
+		}
+		boxes.push((geo_field(within)?, area));
+	}
+	let mut center = None;
+	if let Some(near) = near {
+		let point = (float_field(near, "lon")?, float_field(near, "lat")?);
+		if point.0.abs() > 180.0 || point.1.abs() > 90.0 {
+			return Err("The point to search around is invalid.".to_owned());
+		}
+		let radius = match near.get("radius") {
+			None | Some(Value::Null) => None,
+			Some(_) => Some(float_field(near, "radius")?)
+		};
+		let field = geo_field(near)?;
+		if let Some(radius) = radius {
+			boxes.push((field.clone(), geo::bounding_box(point, radius)));
+		}
+		center = Some((field, point, radius));
+	}
+
+	let geo = match geo {
+		Some(geo) => geo,
+		None => return Ok(Vec::new())
+	};
+	let candidates = match boxes.first() {
+		Some((_, area)) => geo.candidates(*area),
+		None => geo.points.keys().cloned().collect()
+	};
+	let mut found = Vec::<(String, Option<f64>)>::new();
+	for item in candidates {
+		let outside = boxes.iter().any(|(field, area)| {
+			geo.point(&item, field).is_none_or(|p| !geo::in_box(p, *area))
+		});
+		if outside { continue; }
+		let distance = match &center {
+			Some((field, point, radius)) => {
+				let distance = match geo.point(&item, field) {
+					Some(p) => geo::distance(*point, p),
+					None => continue
+				};
+				if radius.is_some_and(|r| distance > r) { continue; }
+				Some(distance)
+			},
+			None => None
+		};
+		found.push((item, distance));
+	}
+	found.sort_by(|a, b| {
+		a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal).then(a.0.cmp(&b.0))
+	});
+	return Ok(found);
+}
+
+
+// --> Reading the data
+// --------------------
+
+// A collection, as returned by the API
+pub fn collection_json(engine: &Engine, id: &str) -> Value {
+	return json!({
+		"id": id,
+		"name": engine.collections.list.get(id),
+		"count": engine.collections.items.values()
+			.filter(|item| item.collection == id).count(),
+		"compression": engine.collections.compression_of(id).name()
+	});
+}
+
+pub fn item_data(engine: &Engine, id: &str) -> Result<Value, String> {
+	let meta = engine.collections.items.get(id)
+		.ok_or(format!("Unknown item: {}", id))?
+	;
+	let bytes = engine.read_data(
+		Area::Collections, &meta.file, meta.index, meta.data_length
+	)?;
+	return basics::decode_value(meta.data_type, &bytes)
+		.ok_or(format!("The data of the item {} is corrupted.", id))
+	;
+}
+
+pub fn edge_properties(engine: &Engine, id: &str) -> Result<Value, String> {
+	let meta = engine.edges.edges.get(id)
+		.ok_or(format!("Unknown edge: {}", id))?
+	;
+	let bytes = engine.read_data(
+		Area::Edges, &meta.file, meta.index, meta.data_length
+	)?;
+	return basics::decode_value(meta.data_type, &bytes)
+		.ok_or(format!("The properties of the edge {} are corrupted.", id))
+	;
+}
+
+pub fn singleton_value(engine: &Engine, name: &str) -> Result<Value, String> {
+	let id = match engine.singletons.find(name) {
+		Some(id) => id,
+		None => return Ok(Value::Null)
+	};
+	let meta = &engine.singletons.singletons[id];
+	let bytes = engine.read_data(
+		Area::Singletons, &meta.file, meta.index, meta.data_length
+	)?;
+	return basics::decode_value(meta.data_type, &bytes)
+		.ok_or(format!("The data of the singleton {} is corrupted.", name))
+	;
+}
+
+// The value of a key, or null when it is missing or expired
+pub fn keyvalue(engine: &Engine, key: &str, now: u64) -> Result<Value, String> {
+	let meta = match engine.keyvalues.live(key, now) {
+		Some(meta) => meta,
+		None => return Ok(Value::Null)
+	};
+	let bytes = engine.read_data(
+		Area::KeyValues, &meta.file, meta.index, meta.data_length
+	)?;
+	return basics::decode_value(meta.data_type, &bytes)
+		.ok_or(format!("The value of the key {} is corrupted.", key))
+	;
+}
+
+pub fn series_json(engine: &Engine, id: &str) -> Value {
+	let meta = &engine.timeseries.series[id];
+	return json!({
+		"name": meta.name,
+		"window": meta.window / 1000,
+		"retention": meta.retention / 86_400_000,
+		"rollups": meta.rollups.iter().map(|r| json!({
+			"interval": r.interval / 1000,
+			"retention": r.retention / 86_400_000
+		})).collect::<Vec<Value>>(),
+		"points": meta.windows.values().sum::<u64>()
+	});
+}
+
+// Points of a series within [from, to), in milliseconds
+fn series_points(
+	engine: &Engine, id: &str, from: u64, to: u64
+) -> Result<Vec<(u64, f64)>, String> {
+	let meta = &engine.timeseries.series[id];
+	let mut points = Vec::<(u64, f64)>::new();
+	let first = from - from % meta.window;
+	if first >= to { return Ok(points); }
+	for (start, _) in meta.windows.range(first..to) {
+		let path = engine.window_path(id, *start);
+		let window = engine.read_points(&path)
+			.map_err(|e| format!("Failed to read the series {}: {}", meta.name, e))?
+		;
+		points.extend(window.into_iter().filter(|p| p.0 >= from && p.0 < to));
+	}
+	return Ok(points);
+}
+
+// Buckets of `interval` milliseconds within [from, to). Stored rollups
+// are used for the rolled up windows, the points for the others.
+fn series_buckets(
+	engine: &Engine, id: &str, interval: u64, from: u64, to: u64
+) -> Result<Vec<faccess::Bucket>, String> {
+	let meta = &engine.timeseries.series[id];
+	let mut buckets = Vec::<faccess::Bucket>::new();
+	let mut raw_from = from;
+	if meta.rollups.iter().any(|r| r.interval == interval) {
+		let path = engine.rollup_path(id, interval);
+		let stored = engine.read_buckets(&path)
+			.map_err(|e| format!("Failed to read the rollups of {}: {}", meta.name, e))?
+		;
+		buckets.extend(stored.into_iter().filter(|b| {
+			b.start >= from && b.start < to && b.start < meta.rolled_until
+		}));
+		raw_from = from.max(meta.rolled_until);
+	}
+	if raw_from < to {
+		let points = series_points(engine, id, raw_from, to)?;
+		buckets.extend(faccess::downsample(&points, interval));
+	}
+	return Ok(buckets);
+}
+
+fn item_json(engine: &Engine, id: &str) -> Result<Value, String> {
+	let collection = &engine.collections.items[id].collection;
+	return Ok(json!({
+		"id": id,
+		"collection": engine.collections.list[collection],
+		"data": item_data(engine, id)?
+	}));
+}
+
+fn edge_json(engine: &Engine, id: &str, depth: u64) -> Result<Value, String> {
+	let meta = &engine.edges.edges[id];
+	return Ok(json!({
+		"id": id,
+		"label": meta.label,
+		"from": meta.from,
+		"to": meta.to,
+		"depth": depth,
+		"properties": edge_properties(engine, id)?
+	}));
+}
+
+
+// --> Resolvers
+// -------------
+
+fn resolve_root(
+	engine: &Engine, name: &str, args: &Map<String, Value>
+) -> Result<Resolved, String> {
+	return Ok(match name {
+		"__typename" => Resolved::Value(Value::from("Query")),
+		"singleton" => Resolved::Value(
+			singleton_value(engine, &string_arg(args, "name")?)?
+		),
+		"keyValue" => Resolved::Value(
+			keyvalue(engine, &string_arg(args, "key")?, basics::now_millis())?
+		),
+		"keyTtl" => {
+			// Remaining seconds, rounded up; null for keys without expiry
+			let now = basics::now_millis();
+			Resolved::Value(
+				match engine.keyvalues.live(&string_arg(args, "key")?, now) {
+					Some(meta) if meta.expiry != 0 => {
+						Value::from((meta.expiry - now).div_ceil(1000))
+					},
+					_ => Value::Null
+				}
+			)
+		},
+		"keys" => {
+			let prefix = optional_string_arg(args, "prefix")?.unwrap_or_default();
+			let now = basics::now_millis();
+			let mut keys = engine.keyvalues.entries.keys()
+				.filter(|key| key.starts_with(&prefix))
+				.filter(|key| engine.keyvalues.live(key, now).is_some())
+				.cloned()
+				.collect::<Vec<String>>()
+			;
+			keys.sort();
+			Resolved::Value(Value::from(keys))
+		},
+		"blob" => Resolved::Value(blobs::blob_json(engine, &string_arg(args, "id")?)),
+		"series" => {
+			let mut ids = engine.timeseries.series.keys().collect::<Vec<_>>();
+			ids.sort_by_key(|id| &engine.timeseries.series[*id].name);
+			Resolved::Value(Value::Array(
+				ids.into_iter().map(|id| series_json(engine, id)).collect()
+			))
+		},
+		"points" | "rollup" => {
+			let series = string_arg(args, "series")?;
+			let id = engine.timeseries.find(&series)
+				.ok_or(format!("Unknown series: {}", series))?
+			;
+			let from = optional_u64_arg(args, "from")?.unwrap_or(0);
+			let to = optional_u64_arg(args, "to")?.unwrap_or(u64::MAX);
+			if from > to {
+				return Err("The start of the time range can't be after its end.".to_owned());
+			}
+			let limit = optional_u64_arg(args, "limit")?
+				.map(|l| l as usize).unwrap_or(usize::MAX)
+			;
+			if name == "points" {
+				let points = series_points(engine, id, from, to)?;
+				Resolved::Value(Value::Array(points.into_iter().take(limit)
+					.map(|(time, value)| json!({ "time": time, "value": value }))
+					.collect()
+				))
+			}
+			else {
+				let interval = optional_u64_arg(args, "interval")?.unwrap_or(0)
+					.saturating_mul(1000)
+				;
+				if interval == 0 {
+					return Err("The rollup interval must be a positive number of seconds.".to_owned());
+				}
+				let buckets = series_buckets(engine, id, interval, from, to)?;
+				Resolved::Value(Value::Array(buckets.into_iter().take(limit)
+					.map(|b| json!({
+						"time": b.start,
+						"min": b.min,
+						"max": b.max,
+						"avg": b.sum / b.count as f64,
+						"sum": b.sum,
+						"count": b.count
+					}))
+					.collect()
+				))
+			}
+		},
+		"collections" => {
+			let mut list = engine.collections.list.iter().collect::<Vec<_>>();
+			list.sort_by(|a, b| a.1.cmp(b.1));
+			Resolved::Value(Value::Array(list.into_iter()
+				.map(|(id, _)| collection_json(engine, id))
+				.collect()
+			))
+		},
+		"items" => {
+			let name = string_arg(args, "collection")?;
+			let coll_id = engine.collections.find(&name)
+				.ok_or(format!("Unknown collection: {}", name))?
+			;
+			let within = args.get("within").filter(|v| !v.is_null());
+			let near = args.get("near").filter(|v| !v.is_null());
+			let found = if within.is_some() || near.is_some() {
+				spatial(engine.geo.get(coll_id), within, near)?
+			}
+			else {
+				let mut ids = engine.collections.items.iter()
+					.filter(|(_, meta)| meta.collection == *coll_id)
+					.map(|(id, _)| (id.clone(), None))
+					.collect::<Vec<(String, Option<f64>)>>()
+				;
+				ids.sort_by(|a, b| a.0.cmp(&b.0));
+				ids
+			};
+			let offset = optional_u64_arg(args, "offset")?.unwrap_or(0) as usize;
+			let limit = optional_u64_arg(args, "limit")?
+				.map(|l| l as usize).unwrap_or(usize::MAX)
+			;
+			Resolved::List(found.into_iter().skip(offset).take(limit)
+				.map(|(id, distance)| match distance {
+					Some(distance) => Resolved::Near(id, distance),
+					None => Resolved::Item(id)
+				})
+				.collect()
+			)
+		},
+		"item" => {
+			let id = string_arg(args, "id")?;
+			if engine.collections.items.contains_key(&id) { Resolved::Item(id) }
+			else { Resolved::Value(Value::Null) }
+		},
+		"edge" => {
+			let id = string_arg(args, "id")?;
+			if engine.edges.edges.contains_key(&id) { Resolved::Edge(id, 0) }
+			else { Resolved::Value(Value::Null) }
+		},
+		"edges" => {
+			let label = optional_string_arg(args, "label")?;
+			let mut ids = engine.edges.edges.iter()
+				.filter(|(_, meta)| label.as_ref().is_none_or(|l| *l == meta.label))
+				.map(|(id, _)| id.clone())
+				.collect::<Vec<String>>()
+			;
+			ids.sort();
+			Resolved::List(ids.into_iter().map(|id| Resolved::Edge(id, 0)).collect())
+		},
+		_ => return Err(format!("Unknown query field: {}", name))
+	});
+}
+
+fn resolve_item(
+	engine: &Engine, id: &str, name: &str, args: &Map<String, Value>
+) -> Result<Resolved, String> {
+	return Ok(match name {
+		"__typename" => Resolved::Value(Value::from("Item")),
+		"id" => Resolved::Value(Value::from(id)),
+		"collection" => {
+			let collection = &engine.collections.items[id].collection;
+			Resolved::Value(Value::from(engine.collections.list[collection].clone()))
+		},
+		"data" => Resolved::Value(item_data(engine, id)?),
+		"outgoing" | "incoming" => {
+			let label = optional_string_arg(args, "label")?;
+			let depth = optional_u64_arg(args, "depth")?.unwrap_or(1);
+			if depth == 0 || depth > MAX_DEPTH {
+				return Err(format!(
+					"The traversal depth must be between 1 and {}.", MAX_DEPTH
+				));
+			}
+			Resolved::List(traverse(
+				engine, id, name == "outgoing", label.as_deref(), depth
+			))
+		},
+		_ => Resolved::Value(
+			item_data(engine, id)?.get(name).cloned().unwrap_or(Value::Null)
+		)
+	});
+}
+
+fn resolve_edge(
+	engine: &Engine, id: &str, depth: u64, name: &str
+) -> Result<Resolved, String> {
+	let meta = &engine.edges.edges[id];
+	return Ok(match name {
+		"__typename" => Resolved::Value(Value::from("Edge")),
+		"id" => Resolved::Value(Value::from(id)),
+		"label" => Resolved::Value(Value::from(meta.label.clone())),
+		"depth" => Resolved::Value(Value::from(depth)),
+		"from" => Resolved::Item(meta.from.clone()),
+		"to" => Resolved::Item(meta.to.clone()),
+		"properties" => Resolved::Value(edge_properties(engine, id)?),
+		_ => Resolved::Value(
+			edge_properties(engine, id)?.get(name).cloned().unwrap_or(Value::Null)
+		)
+	});
+}
+
+// Breadth-first walk along the edges, each item being visited once.
+// Returns the edges crossed, with their distance from the start.
+fn traverse(
+	engine: &Engine, start: &str, outgoing: bool, label: Option<&str>, depth: u64
+) -> Vec<Resolved> {
+	let adjacency = if outgoing { &engine.edges.outgoing }
+		else { &engine.edges.incoming }
+	;
+	let mut visited = HashSet::from([start.to_owned()]);
+	let mut frontier = vec![start.to_owned()];
+	let mut found = Vec::<Resolved>::new();
+	for level in 1..=depth {
+		let mut next = Vec::<String>::new();
+		for item in &frontier {
+			for edge_id in adjacency.get(item).into_iter().flatten() {
+				let meta = &engine.edges.edges[edge_id];
+				if label.is_some_and(|l| l != meta.label) { continue; }
+				found.push(Resolved::Edge(edge_id.clone(), level));
+				let other = if outgoing { &meta.to } else { &meta.from };
+				if visited.insert(other.clone()) { next.push(other.clone()); }
+			}
+		}
+		if next.is_empty() { break; }
+		frontier = next;
+	}
+	return found;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::testing;
+
+	// Four people knowing each other in a cycle, the first one also
+	// liking the third one. Returns the store and their ids.
+	fn graph(dir: &std::path::Path) -> (RwLock<Engine>, Vec<String>) {
+		let mut engine = testing::create_store(dir, json!({}));
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "people" })).unwrap();
+		let ids = (0..4)
+			.map(|n| testing::insert(&mut engine, "people", json!({ "n": n })))
+			.collect::<Vec<String>>()
+		;
+		for (from, to, label) in [(0, 1, "knows"), (1, 2, "knows"), (2, 3, "knows"), (3, 0, "knows"), (0, 2, "likes")] {
+			let args = json!({ "from": ids[from], "to": ids[to], "label": label });
+			testing::mutate(&mut engine, "createEdge", args).unwrap();
+		}
+		return (RwLock::new(engine), ids);
+	}
+
+	// The depth and end of the edges a traversal went through
+	fn walked(response: &Value, field: &str) -> Vec<(u64, String)> {
+		return response["data"]["item"][field].as_array().unwrap().iter()
+			.map(|edge| (
+				edge["depth"].as_u64().unwrap(),
+				edge["end"]["id"].as_str().unwrap().to_owned()
+			))
+			.collect()
+		;
+	}
+
+	#[test]
+	fn traversals() {
+		let dir = tempfile::tempdir().unwrap();
+		let (engine, ids) = graph(dir.path());
+		let query = |selection: &str| testing::run(&engine, &format!(
+			"{{ item(id: \"{}\") {{ {} }} }}", ids[0], selection
+		));
+
+		let response = query("outgoing(label: \"knows\", depth: 3) { depth end: to { id } }");
+		assert_eq!(walked(&response, "outgoing"), [
+			(1, ids[1].clone()), (2, ids[2].clone()), (3, ids[3].clone())
+		]);
+		// The walk stops once it comes back to the start
+		let response = query(&format!(
+			"outgoing(label: \"knows\", depth: {}) {{ depth end: to {{ id }} }}", MAX_DEPTH
+		));
+		assert_eq!(walked(&response, "outgoing").last().unwrap(), &(4, ids[0].clone()));
+
+		let response = query("outgoing { depth end: to { id } }");
+		let mut ends = walked(&response, "outgoing");
+		ends.sort();
+		let mut expected = vec![(1, ids[1].clone()), (1, ids[2].clone())];
+		expected.sort();
+		assert_eq!(ends, expected);
+
+		let response = query("incoming(depth: 2) { depth end: from { id } }");
+		let mut ends = walked(&response, "incoming");
+		ends.sort();
+		let mut expected = vec![(1, ids[3].clone()), (2, ids[2].clone())];
+		expected.sort();
+		assert_eq!(ends, expected);
+	}
+
+	#[test]
+	fn traversal_depth() {
+		let dir = tempfile::tempdir().unwrap();
+		let (engine, ids) = graph(dir.path());
+		for depth in [0, MAX_DEPTH + 1] {
+			let response = testing::run(&engine, &format!(
+				"{{ item(id: \"{}\") {{ outgoing(depth: {}) {{ id }} }} }}", ids[0], depth
+			));
+			assert_eq!(
+				response["errors"][0]["message"],
+				format!("The traversal depth must be between 1 and {}.", MAX_DEPTH)
+			);
+		}
+	}
+
+	#[test]
+	fn fragments() {
+		let dir = tempfile::tempdir().unwrap();
+		let (engine, ids) = graph(dir.path());
+		let plain = testing::run(&engine, &format!(
+			"{{ item(id: \"{}\") {{ id outgoing(label: \"likes\") {{ label }} }} }}", ids[0]
+		));
+		let spread = testing::run(&engine, &format!(
+			"query {{ ...Root }}
+			fragment Root on Query {{ item(id: \"{}\") {{ ...Person }} }}
+			fragment Person on Item {{ id ... on Item {{ outgoing(label: \"likes\") {{ label }} }} }}",
+			ids[0]
+		));
+		assert_eq!(plain["data"]["item"]["outgoing"][0]["label"], "likes");
+		assert_eq!(spread, plain);
+		let unknown = testing::run(&engine, "{ ...Missing }");
+		assert_eq!(unknown["errors"][0]["message"], "Unknown fragment: Missing");
+
+		// Fragments spreading themselves, and selections nested too deep
+		let cycle = testing::run(&engine, &format!(
+			"query {{ item(id: \"{}\") {{ ...A }} }}
+			fragment A on Item {{ id ...B }}
+			fragment B on Item {{ outgoing(label: \"likes\") {{ to {{ ...A }} }} }}",
+			ids[0]
+		));
+		assert_eq!(cycle["errors"][0]["message"], "Fragment cycle through A");
+		let nested = format!(
+			"{{ item(id: \"{}\") {{ {} id {} }} }}", ids[0],
+			"outgoing { to { ".repeat(16), "} } ".repeat(16)
+		);
+		assert_eq!(testing::run(&engine, &nested)["errors"][0]["message"], format!(
+			"The selections can't be nested more than {} levels deep.", MAX_NESTING
+		));
+		// Fragments spread many times over are flattened once per selection set
+		let shared = (1..40)
+			.map(|n| format!("fragment F{} on Item {{ ...F{} ...F{} }}", n, n - 1, n - 1))
+			.collect::<Vec<String>>().join("\n")
+		;
+		let wide = testing::run(&engine, &format!(
+			"{{ item(id: \"{}\") {{ ...F39 }} }}\nfragment F0 on Item {{ id }}\n{}", ids[0], shared
+		));
+		assert_eq!(wide["errors"].as_array().map(|e| e.len()), None, "{}", wide);
+	}
+
+	#[test]
+	fn spatial_queries() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut engine = testing::create_store(dir.path(), json!({}));
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "cities" })).unwrap();
+		let mut ids = Vec::<String>::new();
+		let cities = [("Paris", 2.35, 48.85), ("London", -0.13, 51.51), ("Berlin", 13.40, 52.52)];
+		for (name, lon, lat) in cities {
+			let location = json!({ "type": "Point", "coordinates": [lon, lat] });
+			let data = json!({ "name": name, "location": location });
+			ids.push(testing::insert(&mut engine, "cities", data));
+		}
+		let zone = json!({ "type": "Polygon",
+			"coordinates": [[[2.0, 48.0], [3.0, 48.0], [3.0, 49.0], [2.0, 48.0]]] });
+		let data = json!({ "name": "Zone", "zone": zone });
+		let zone_id = testing::insert(&mut engine, "cities", data);
+		drop(engine);
+
+		// The geo-point index is saved along the items
+		let engine = RwLock::new(testing::open_store(dir.path()));
+		let found = |filter: &str| {
+			let response = testing::run(&engine, &format!(
+				"{{ items(collection: \"cities\", {}) {{ id distance }} }}", filter
+			));
+			return response["data"]["items"].as_array().unwrap().iter()
+				.map(|item| (
+					item["id"].as_str().unwrap().to_owned(),
+					item["distance"].as_f64()
+				))
+				.collect::<Vec<(String, Option<f64>)>>()
+			;
+		};
+		let near = found("near: { field: \"location\", lon: 2.35, lat: 48.85, radius: 400000 }");
+		assert_eq!(near.iter().map(|i| &i.0).collect::<Vec<_>>(), [&ids[0], &ids[1]]);
+		assert!(near[0].1.unwrap() < 1.0);
+		assert!((near[1].1.unwrap() - 343_000.0).abs() < 5000.0);
+		let within = "within: { field: \"location\", west: 10, south: 50, east: 15, north: 55 }";
+		assert_eq!(found(within).iter().map(|i| &i.0).collect::<Vec<_>>(), [&ids[2]]);
+		let response = testing::run(&engine, "{ items(collection: \"cities\", \
+			within: { field: \"location\", west: 0, south: 60, east: 1, north: 50 }) { id } }"
+		);
+		assert_eq!(response["errors"][0]["message"], "The bounding box is invalid.");
+
+		// Polygons come back as they went in
+		let engine = engine.read().unwrap();
+		assert_eq!(item_data(&engine, &zone_id).unwrap()["zone"], zone);
+	}
+}
@@ -0,0 +1,85 @@
+use std::fs;
+use std::path::Path;
+use std::sync::RwLock;
+
+use serde_json::{ json, Map, Value };
+
+use crate::basics::{ self, Store };
+use crate::faccess;
+use super::{ Engine, mutlog, query, transac };
+
+// Creates an empty store in `dir` and opens it. The manifest is the one of
+// a new Live store, with the fields given in `settings` replaced.
+pub fn create_store(dir: &Path, settings: Value) -> Engine {
+	let conf = basics::get_conf();
+	let mut manifest = json!({
+		"name": "test",
+		"id": "test",
+		"hash": faccess::new_id(),
+		"major": conf.major,
+		"minor": conf.minor,
+		"kind": "Live",
+		"ordering": false,
+		"checksumming": true,
+		"logging": "Normal",
+		"defaults": {
+			"verbosity": false,
+			"api_port": 7900,
+			"api_scan": false,
+			"cluster_port": 7979,
+			"cluster_scan": false
+		}
+	});
+	for (key, value) in settings.as_object().into_iter().flatten() {
+		manifest[key] = value.clone();
+	}
+	for area in ["singletons", "collections"] {
+		fs::create_dir_all(dir.join(area)).unwrap();
+		fs::File::create(dir.join(area).join("rixindex")).unwrap();
+	}
+	fs::write(dir.join("manifest.json"), manifest.to_string()).unwrap();
+	return open_store(dir);
+}
+
+// Opens the store of `dir` again
+pub fn open_store(dir: &Path) -> Engine {
+	let manifest = fs::read_to_string(dir.join("manifest.json")).unwrap();
+	let store = serde_json::from_str::<Store>(&manifest).unwrap();
+	let mut engine = super::open_engine(basics::get_conf(), dir.to_path_buf(), store, false).unwrap();
+	if let Some(policy) = &engine.store.mutation_logs {
+		engine.mutation_log = Some(mutlog::MutationLog::open(dir, policy.retention).unwrap());
+	}
+	return engine;
+}
+
+// Applies a mutation, with its arguments given as a JSON object
+pub fn mutate(engine: &mut Engine, name: &str, args: Value) -> Result<query::Resolved, String> {
+	let args = serde_json::from_value::<Map<String, Value>>(args).unwrap();
+	return transac::mutate(engine, name, &args);
+}
+
+// Inserts an item in a collection, and returns its id
+pub fn insert(engine: &mut Engine, collection: &str, data: Value) -> String {
+	let args = json!({ "collection": collection, "data": data });
+	return match mutate(engine, "insertItem", args).unwrap() {
+		query::Resolved::Item(id) => id,
+		_ => panic!("insertItem resolves to an item")
+	};
+}
+
+// Runs a GraphQL request without variables, and returns its response
+pub fn run(engine: &RwLock<Engine>, source: &str) -> Value {
+	return query::execute(engine, source, &Map::new(), None);
+}
+
+// Makes the writes of a file fail, by putting a directory in its place
+pub fn block(path: &Path) {
+	fs::rename(path, path.with_extension("blocked")).unwrap();
+	fs::create_dir_all(path.join("blocked")).unwrap();
+}
+
+// Puts a file blocked by `block` back in place
+pub fn unblock(path: &Path) {
+	fs::remove_dir_all(path).unwrap();
+	fs::rename(path.with_extension("blocked"), path).unwrap();
+}
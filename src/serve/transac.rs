@@ -0,0 +1,1245 @@
+use std::path::PathBuf;
+
+use serde_json::{ Map, Value, json };
+
+use crate::cli;
+use crate::crypt;
+use crate::basics::{ self, Compression };
+use crate::geo;
+use crate::faccess::{
+	self, Area, RecordTag, SingletonMeta, CollectionMeta, EdgeMeta, KeyValueMeta,
+	SeriesMeta, Rollup
+};
+use super::{ Engine, READ_ONLY, blobs, defrag };
+use super::query::{ self, Resolved, string_arg, optional_u64_arg };
+
+impl Engine {
+	// Stores some bytes, after a header describing them, in a data file
+	// of the given area, and returns the location of the bytes
+	pub fn write_data(
+		&mut self, area: Area, tag: RecordTag, data: &[u8]
+	) -> Result<(String, u64), String> {
+		if data.len() as u64 > self.conf.max_object_size {
+			return Err(format!(
+				"Objects can't be larger than {} bytes.", self.conf.max_object_size
+			));
+		}
+		let record = faccess::encode_record(&tag, self.now(), data)
+			.map_err(|e| e.to_string())?
+		;
+		let header = record.len() as u64 - data.len() as u64;
+		let max_file_size = self.conf.max_file_size;
+		let (file, start) = match self.store.ordering {
+			true => {
+				let tail = defrag::tail(self, area);
+				let (file, start) = faccess::append(
+					self.files_mut(area), tail.as_deref(), record.len() as u64, max_file_size
+				);
+				self.tails.insert(area, file.clone());
+				(file, start)
+			},
+			false => faccess::allocate(self.files_mut(area), record.len() as u64, max_file_size)
+		};
+		let path = self.data_path(area, &file);
+		let mut written = Ok(());
+		if !path.exists() {
+			written = faccess::write_data(&path, 0, faccess::DATA_MAGIC);
+		}
+		let written = written
+			.and_then(|_| faccess::write_bytes(&path, start, &record, self.durable()))
+			.map_err(|e| format!("Failed to write in {}/{}: {}", area.dir(), file, e))
+			.and_then(|_| self.update_checksums(&path, start, record.len() as u64))
+		;
+		if let Err(message) = written {
+			faccess::release(self.files_mut(area), &file, start, record.len() as u64);
+			return Err(message);
+		}
+		return Ok((file, start + header));
+	}
+
+	// Frees the bytes at the given location, along with their header
+	pub fn free_data(&mut self, area: Area, file: &str, index: u64, length: u64) {
+		let path = self.data_path(area, file);
+		let framed = self.files_mut(area).get(file).is_some_and(|meta| meta.framed);
+		let header = match framed {
+			true => faccess::read_header(&path, index).ok().flatten()
+				.filter(|(_, header)| header.live && header.length == length),
+			false => None
+		};
+		match header {
+			Some((start, _)) => {
+				let marked = faccess::mark_freed(&path, start)
+					.map_err(|e| e.to_string())
+					.and_then(|_| self.update_checksums(&path, start, 1))
+				;
+				if let Err(message) = marked {
+					cli::yellow_err(format!("Failed to free a record of {}/{}: {}", area.dir(), file, message));
+				}
+				faccess::release(self.files_mut(area), file, start, index + length - start);
+			},
+			None => {
+				if framed {
+					cli::yellow_err(format!(
+						"No valid header for the record at {} in {}/{}", index, area.dir(), file
+					));
+				}
+				faccess::release(self.files_mut(area), file, index, length);
+			}
+		}
+	}
+
+	// Whether data writes reach the disk before a mutation returns.
+	// Lite stores leave it to the system, trading durability for speed.
+	pub fn durable(&self) -> bool {
+		return self.store.kind != basics::StoreType::Lite;
+	}
+
+	pub fn log(&self, message: String) {
+		if self.verbose { cli::blue_out(message); }
+	}
+}
+
+fn check_name(name: &str, what: &str) -> Result<(), String> {
+	if name.is_empty() || name.len() > u8::MAX as usize {
+		return Err(format!(
+			"The {} must be between 1 and {} bytes long.", what, u8::MAX
+		));
+	}
+	return Ok(());
+}
+
+// Applies a mutation, and logs it once committed when the store keeps mutation logs
+pub fn mutate(
+	engine: &mut Engine, name: &str, args: &Map<String, Value>
+) -> Result<Resolved, String> {
+	if name == "__typename" { return Ok(Resolved::Value(Value::from("Mutation"))); }
+	if engine.read_only() { return Err(READ_ONLY.to_owned()); }
+	engine.start_mutation();
+	let resolved = apply(engine, name, args);
+	// The mutations roll back whatever fails before their commit point,
+	// and nothing fails after it: the ones resolved are the ones committed
+	let committed = resolved.is_ok();
+	engine.end_mutation(name, args, committed);
+	return resolved;
+}
+
+fn apply(
+	engine: &mut Engine, name: &str, args: &Map<String, Value>
+) -> Result<Resolved, String> {
+	return match name {
+		"setSingleton" => set_singleton(engine, args),
+		"deleteSingleton" => delete_singleton(engine, args),
+		"createCollection" => create_collection(engine, args),
+		"setCompression" => set_compression(engine, args),
+		"insertItem" => insert_item(engine, args),
+		"updateItem" => update_item(engine, args),
+		"deleteItem" => delete_item(engine, args),
+		"createEdge" => create_edge(engine, args),
+		"deleteEdge" => delete_edge(engine, args),
+		"setKey" => set_key(engine, args),
+		"deleteKey" => delete_key(engine, args),
+		"increment" => add_to_key(engine, args, 1),
+		"decrement" => add_to_key(engine, args, -1),
+		"compareAndSwap" => compare_and_swap(engine, args),
+		"createSeries" => create_series(engine, args),
+		"dropSeries" => drop_series(engine, args),
+		"appendPoints" => append_points(engine, args),
+		"deleteBlob" => Ok(Resolved::Value(Value::Bool(
+			blobs::remove_blob(engine, &string_arg(args, "id")?)?
+		))),
+		_ => Err(format!("Unknown mutation field: {}", name))
+	};
+}
+
+
+// --> Singletons
+// --------------
+
+fn set_singleton(
+	engine: &mut Engine, args: &Map<String, Value>
+) -> Result<Resolved, String> {
+	let name = string_arg(args, "name")?;
+	check_name(&name, "singleton name")?;
+	let value = args.get("value").cloned().unwrap_or(Value::Null);
+	let (data_type, bytes) = basics::encode_value(&value);
+	let id = engine.singletons.find(&name).cloned()
+		.unwrap_or_else(|| engine.new_id(&engine.singletons.singletons))
+	;
+	let (file, index) = engine.write_data(Area::Singletons, RecordTag {
+		id: id.clone(), owner: name.clone(), data_type: data_type.to_u8()
+	}, &bytes)?;
+
+	let previous = engine.singletons.singletons.insert(id.clone(), SingletonMeta {
+		name: name.clone(),
+		data_type: data_type.to_u8(),
+		file: file.clone(),
+		index,
+		data_length: bytes.len() as u64
+	});
+	if let Err(e) = engine.save_index(Area::Singletons) {
+		match previous {
+			Some(old) => engine.singletons.singletons.insert(id, old),
+			None => engine.singletons.singletons.remove(&id)
+		};
+		engine.free_data(Area::Singletons, &file, index, bytes.len() as u64);
+		return Err(e);
+	}
+	if let Some(old) = previous {
+		engine.free_data(Area::Singletons, &old.file, old.index, old.data_length);
+	}
+
+	engine.log(format!("Singleton set: {}", name));
+	return Ok(Resolved::Value(value));
+}
+
+fn delete_singleton(
+	engine: &mut Engine, args: &Map<String, Value>
+) -> Result<Resolved, String> {
+	let name = string_arg(args, "name")?;
+	let id = match engine.singletons.find(&name) {
+		Some(id) => id.clone(),
+		None => return Ok(Resolved::Value(Value::Bool(false)))
+	};
+	let old = engine.singletons.singletons.remove(&id).unwrap();
+	if let Err(e) = engine.save_index(Area::Singletons) {
+		engine.singletons.singletons.insert(id, old);
+		return Err(e);
+	}
+	engine.free_data(Area::Singletons, &old.file, old.index, old.data_length);
+
+	engine.log(format!("Singleton deleted: {}", name));
+	return Ok(Resolved::Value(Value::Bool(true)));
+}
+
+
+// --> Collections and their items
+// -------------------------------
+
+// The `compression` argument: `none`, `lz4` or `zstd`
+fn compression_arg(args: &Map<String, Value>) -> Result<Compression, String> {
+	return match args.get("compression").filter(|v| !v.is_null()) {
+		None => Ok(Compression::None),
+		Some(value) => value.as_str().and_then(Compression::from_name)
+			.ok_or("The compression must be none, lz4 or zstd.".to_owned())
+	};
+}
+
+fn create_collection(
+	engine: &mut Engine, args: &Map<String, Value>
+) -> Result<Resolved, String> {
+	let name = string_arg(args, "name")?;
+	check_name(&name, "collection name")?;
+	let compression = compression_arg(args)?;
+	if engine.collections.find(&name).is_some() {
+		return Err(format!("The collection {} already exists.", name));
+	}
+	let id = engine.new_id(&engine.collections.list);
+	engine.collections.list.insert(id.clone(), name.clone());
+	if compression != Compression::None {
+		engine.collections.compression.insert(id.clone(), compression);
+	}
+	if let Err(e) = engine.save_index(Area::Collections) {
+		engine.collections.list.remove(&id);
+		engine.collections.compression.remove(&id);
+		return Err(e);
+	}
+
+	engine.log(format!("Collection created: {}", name));
+	return Ok(Resolved::Value(json!({
+		"id": id, "name": name, "count": 0, "compression": compression.name()
+	})));
+}
+
+// Changes how the items written in a collection from now on are compressed.
+// The items already written are read as they were stored.
+fn set_compression(
+	engine: &mut Engine, args: &Map<String, Value>
+) -> Result<Resolved, String> {
+	let name = string_arg(args, "collection")?;
+	let compression = compression_arg(args)?;
+	let id = engine.collections.find(&name).cloned()
+		.ok_or(format!("Unknown collection: {}", name))?
+	;
+	let previous = match compression {
+		Compression::None => engine.collections.compression.remove(&id),
+		_ => engine.collections.compression.insert(id.clone(), compression)
+	};
+	if let Err(e) = engine.save_index(Area::Collections) {
+		match previous {
+			Some(previous) => engine.collections.compression.insert(id, previous),
+			None => engine.collections.compression.remove(&id)
+		};
+		return Err(e);
+	}
+
+	engine.log(format!("Collection compression set: {} ({})", name, compression.name()));
+	return Ok(Resolved::Value(query::collection_json(engine, &id)));
+}
+
+// The bytes of an item and their `data_type` byte, compressed as
+// set for its collection
+fn encode_item(engine: &Engine, collection: &str, data: &Value) -> (u8, Vec<u8>) {
+	let (data_type, bytes) = basics::encode_value(data);
+	return basics::compress(engine.collections.compression_of(collection), data_type, bytes);
+}
+
+fn insert_item(
+	engine: &mut Engine, args: &Map<String, Value>
+) -> Result<Resolved, String> {
+	let name = string_arg(args, "collection")?;
+	let collection = engine.collections.find(&name).cloned()
+		.ok_or(format!("Unknown collection: {}", name))?
+	;
+	let data = args.get("data").cloned().unwrap_or(Value::Null);
+	geo::check_points(&data)?;
+	let (data_type, bytes) = encode_item(engine, &collection, &data);
+	let id = engine.new_id(&engine.collections.items);
+	let (file, index) = engine.write_data(Area::Collections, RecordTag {
+		id: id.clone(), owner: collection.clone(), data_type
+	}, &bytes)?;
+
+	engine.collections.items.insert(id.clone(), CollectionMeta {
+		collection: collection.clone(),
+		data_type,
+		file: file.clone(),
+		index,
+		data_length: bytes.len() as u64
+	});
+	// The geo-points are saved before the index of the collections, which
+	// commits the insertion
+	let geo = engine.geo.entry(collection.clone()).or_default();
+	geo.insert(&id, &data);
+	let located = geo.points.contains_key(&id);
+	let mut saved = Ok(());
+	if located { saved = engine.save_geoindex(); }
+	if saved.is_ok() { saved = engine.save_index(Area::Collections); }
+	if let Err(e) = saved {
+		engine.collections.items.remove(&id);
+		if located {
+			engine.geo.entry(collection).or_default().remove(&id);
+			if let Err(e) = engine.save_geoindex() { cli::red_err(e); }
+		}
+		engine.free_data(Area::Collections, &file, index, bytes.len() as u64);
+		return Err(e);
+	}
+
+	engine.log(format!("Item inserted in {}: {}", name, id));
+	return Ok(Resolved::Item(id));
+}
+
+fn update_item(
+	engine: &mut Engine, args: &Map<String, Value>
+) -> Result<Resolved, String> {
+	let id = string_arg(args, "id")?;
+	let collection = match engine.collections.items.get(&id) {
+		Some(meta) => meta.collection.clone(),
+		None => return Err(format!("Unknown item: {}", id))
+	};
+	let data = args.get("data").cloned().unwrap_or(Value::Null);
+	geo::check_points(&data)?;
+	let (data_type, bytes) = encode_item(engine, &collection, &data);
+	let (file, index) = engine.write_data(Area::Collections, RecordTag {
+		id: id.clone(), owner: collection.clone(), data_type
+	}, &bytes)?;
+
+	let old = engine.collections.items.insert(id.clone(), CollectionMeta {
+		collection: collection.clone(),
+		data_type,
+		file: file.clone(),
+		index,
+		data_length: bytes.len() as u64
+	}).unwrap();
+	let geo = engine.geo.entry(collection.clone()).or_default();
+	let points = geo.points.get(&id).cloned();
+	geo.remove(&id);
+	geo.insert(&id, &data);
+	let moved = points.is_some() || geo.points.contains_key(&id);
+	let mut saved = Ok(());
+	if moved { saved = engine.save_geoindex(); }
+	if saved.is_ok() { saved = engine.save_index(Area::Collections); }
+	if let Err(e) = saved {
+		engine.collections.items.insert(id.clone(), old);
+		if moved {
+			let geo = engine.geo.entry(collection).or_default();
+			geo.remove(&id);
+			if let Some(points) = points { geo.insert_points(&id, points); }
+			if let Err(e) = engine.save_geoindex() { cli::red_err(e); }
+		}
+		engine.free_data(Area::Collections, &file, index, bytes.len() as u64);
+		return Err(e);
+	}
+	engine.free_data(Area::Collections, &old.file, old.index, old.data_length);
+
+	engine.log(format!("Item updated: {}", id));
+	return Ok(Resolved::Item(id));
+}
+
+fn delete_item(
+	engine: &mut Engine, args: &Map<String, Value>
+) -> Result<Resolved, String> {
+	let id = string_arg(args, "id")?;
+	let old = match engine.collections.items.remove(&id) {
+		Some(meta) => meta,
+		None => return Ok(Resolved::Value(Value::Bool(false)))
+	};
+
+	// The edges and the geo-points of the item go away with it. Their indexes
+	// are saved before the one of the collections, which commits the deletion.
+	let mut attached = engine.edges.outgoing.get(&id).cloned().unwrap_or_default();
+	attached.extend(engine.edges.incoming.get(&id).cloned().unwrap_or_default());
+	attached.sort();
+	attached.dedup();
+	let mut freed = Vec::<EdgeMeta>::new();
+	for edge in &attached {
+		engine.edges.unlink(edge);
+		freed.push(engine.edges.edges.remove(edge).unwrap());
+	}
+	let points = engine.geo.get_mut(&old.collection).and_then(|geo| {
+		let points = geo.points.get(&id).cloned();
+		geo.remove(&id);
+		points
+	});
+	let mut saved = Ok(());
+	if !attached.is_empty() { saved = engine.save_index(Area::Edges); }
+	if saved.is_ok() && points.is_some() { saved = engine.save_geoindex(); }
+	if saved.is_ok() { saved = engine.save_index(Area::Collections); }
+
+	if let Err(e) = saved {
+		engine.collections.items.insert(id.clone(), old.clone());
+		for (edge, meta) in attached.iter().zip(freed) {
+			engine.edges.edges.insert(edge.clone(), meta);
+			engine.edges.link(edge);
+		}
+		if let Some(points) = points {
+			engine.geo.entry(old.collection).or_default().insert_points(&id, points);
+			if let Err(e) = engine.save_geoindex() { cli::red_err(e); }
+		}
+		if !attached.is_empty() {
+			if let Err(e) = engine.save_index(Area::Edges) { cli::red_err(e); }
+		}
+		return Err(e);
+	}
+	engine.free_data(Area::Collections, &old.file, old.index, old.data_length);
+	for edge in freed {
+		engine.free_data(Area::Edges, &edge.file, edge.index, edge.data_length);
+	}
+
+	engine.log(format!("Item deleted: {}", id));
+	return Ok(Resolved::Value(Value::Bool(true)));
+}
+
+
+// --> Edges
+// ---------
+
+fn create_edge(
+	engine: &mut Engine, args: &Map<String, Value>
+) -> Result<Resolved, String> {
+	let from = string_arg(args, "from")?;
+	let to = string_arg(args, "to")?;
+	let label = string_arg(args, "label")?;
+	check_name(&label, "edge label")?;
+	for item in [&from, &to] {
+		if !engine.collections.items.contains_key(item) {
+			return Err(format!("Unknown item: {}", item));
+		}
+	}
+	let properties = args.get("properties").cloned().unwrap_or(Value::Null);
+	if !properties.is_null() && !properties.is_object() {
+		return Err("The edge properties must be an object.".to_owned());
+	}
+	let (data_type, bytes) = basics::encode_value(&properties);
+	let id = engine.new_id(&engine.edges.edges);
+	let (file, index) = engine.write_data(Area::Edges, RecordTag {
+		id: id.clone(), owner: label.clone(), data_type: data_type.to_u8()
+	}, &bytes)?;
+
+	engine.edges.edges.insert(id.clone(), EdgeMeta {
+		label: label.clone(),
+		from,
+		to,
+		data_type: data_type.to_u8(),
+		file: file.clone(),
+		index,
+		data_length: bytes.len() as u64
+	});
+	engine.edges.link(&id);
+	if let Err(e) = engine.save_index(Area::Edges) {
+		engine.edges.unlink(&id);
+		engine.edges.edges.remove(&id);
+		engine.free_data(Area::Edges, &file, index, bytes.len() as u64);
+		return Err(e);
+	}
+
+	engine.log(format!("Edge created ({}): {}", label, id));
+	return Ok(Resolved::Edge(id, 0));
+}
+
+fn delete_edge(
+	engine: &mut Engine, args: &Map<String, Value>
+) -> Result<Resolved, String> {
+	let id = string_arg(args, "id")?;
+	if !engine.edges.edges.contains_key(&id) {
+		return Ok(Resolved::Value(Value::Bool(false)));
+	}
+	engine.edges.unlink(&id);
+	let old = engine.edges.edges.remove(&id).unwrap();
+	if let Err(e) = engine.save_index(Area::Edges) {
+		engine.edges.edges.insert(id.clone(), old);
+		engine.edges.link(&id);
+		return Err(e);
+	}
+	engine.free_data(Area::Edges, &old.file, old.index, old.data_length);
+
+	engine.log(format!("Edge deleted: {}", id));
+	return Ok(Resolved::Value(Value::Bool(true)));
+}
+
+
+// --> Key-value entries
+// ---------------------
+
+// Turns the optional `ttl` argument (in seconds) into an expiry time
+fn expiry_arg(args: &Map<String, Value>, now: u64) -> Result<u64, String> {
+	return Ok(match optional_u64_arg(args, "ttl")? {
+		Some(0) => return Err("The ttl must be at least one second.".to_owned()),
+		Some(ttl) => now.saturating_add(ttl.saturating_mul(1000)),
+		None => 0
+	});
+}
+
+fn put_key(
+	engine: &mut Engine, key: &str, value: &Value, expiry: u64
+) -> Result<(), String> {
+	check_name(key, "key")?;
+	let (data_type, bytes) = basics::encode_value(value);
+	let (file, index) = engine.write_data(Area::KeyValues, RecordTag {
+		id: key.to_owned(), owner: String::new(), data_type: data_type.to_u8()
+	}, &bytes)?;
+	let previous = engine.keyvalues.entries.insert(key.to_owned(), KeyValueMeta {
+		data_type: data_type.to_u8(),
+		file: file.clone(),
+		index,
+		data_length: bytes.len() as u64,
+		expiry
+	});
+	if let Err(e) = engine.save_index(Area::KeyValues) {
+		match previous {
+			Some(old) => engine.keyvalues.entries.insert(key.to_owned(), old),
+			None => engine.keyvalues.entries.remove(key)
+		};
+		engine.free_data(Area::KeyValues, &file, index, bytes.len() as u64);
+		return Err(e);
+	}
+	if let Some(old) = previous {
+		engine.free_data(Area::KeyValues, &old.file, old.index, old.data_length);
+	}
+	return Ok(());
+}
+
+fn set_key(
+	engine: &mut Engine, args: &Map<String, Value>
+) -> Result<Resolved, String> {
+	let key = string_arg(args, "key")?;
+	let value = args.get("value").cloned().unwrap_or(Value::Null);
+	let expiry = expiry_arg(args, engine.now())?;
+	put_key(engine, &key, &value, expiry)?;
+
+	engine.log(format!("Key set: {}", key));
+	return Ok(Resolved::Value(value));
+}
+
+fn delete_key(
+	engine: &mut Engine, args: &Map<String, Value>
+) -> Result<Resolved, String> {
+	let key = string_arg(args, "key")?;
+	let live = engine.keyvalues.live(&key, engine.now()).is_some();
+	let old = match engine.keyvalues.entries.remove(&key) {
+		Some(meta) => meta,
+		None => return Ok(Resolved::Value(Value::Bool(false)))
+	};
+	if let Err(e) = engine.save_index(Area::KeyValues) {
+		engine.keyvalues.entries.insert(key, old);
+		return Err(e);
+	}
+	engine.free_data(Area::KeyValues, &old.file, old.index, old.data_length);
+
+	engine.log(format!("Key deleted: {}", key));
+	return Ok(Resolved::Value(Value::Bool(live)));
+}
+
+// Adds `by` (default: 1) times `sign` to an integer entry.
+// A missing or expired key starts from 0 and gets the `ttl` if one is given,
+// while an existing key keeps its expiry time.
+fn add_to_key(
+	engine: &mut Engine, args: &Map<String, Value>, sign: i64
+) -> Result<Resolved, String> {
+	let key = string_arg(args, "key")?;
+	let by = match args.get("by") {
+		None | Some(Value::Null) => 1,
+		Some(by) => by.as_i64()
+			.ok_or("The argument \"by\" must be an integer.".to_owned())?
+	};
+	let now = engine.now();
+	let (current, expiry) = match engine.keyvalues.live(&key, now) {
+		Some(meta) => (
+			query::keyvalue(engine, &key, now)?.as_i64().ok_or(format!(
+				"The value of the key {} is not an integer.", key
+			))?,
+			meta.expiry
+		),
+		None => (0, expiry_arg(args, now)?)
+	};
+	let next = by.checked_mul(sign).and_then(|d| current.checked_add(d))
+		.ok_or(format!("The value of the key {} would overflow.", key))?
+	;
+	put_key(engine, &key, &Value::from(next), expiry)?;
+
+	engine.log(format!("Key changed: {} = {}", key, next));
+	return Ok(Resolved::Value(Value::from(next)));
+}
+
+// Replaces the value of a key only if it currently equals `expected`.
+// A null `expected` matches a missing or expired key.
+fn compare_and_swap(
+	engine: &mut Engine, args: &Map<String, Value>
+) -> Result<Resolved, String> {
+	let key = string_arg(args, "key")?;
+	let expected = args.get("expected").cloned().unwrap_or(Value::Null);
+	let value = args.get("value").cloned().unwrap_or(Value::Null);
+	let now = engine.now();
+	if query::keyvalue(engine, &key, now)? != expected {
+		return Ok(Resolved::Value(Value::Bool(false)));
+	}
+	let expiry = match (optional_u64_arg(args, "ttl")?, engine.keyvalues.live(&key, now)) {
+		(None, Some(meta)) => meta.expiry,
+		_ => expiry_arg(args, now)?
+	};
+	put_key(engine, &key, &value, expiry)?;
+
+	engine.log(format!("Key swapped: {}", key));
+	return Ok(Resolved::Value(Value::Bool(true)));
+}
+
+// Removes every expired entry, and returns how many there were
+pub fn expire_keys(engine: &mut Engine, now: u64) -> Result<usize, String> {
+	let expired = engine.keyvalues.entries.iter()
+		.filter(|(_, meta)| meta.expiry != 0 && meta.expiry <= now)
+		.map(|(key, _)| key.clone())
+		.collect::<Vec<String>>()
+	;
+	if expired.is_empty() { return Ok(0); }
+
+	let mut freed = Vec::<KeyValueMeta>::new();
+	for key in &expired {
+		freed.push(engine.keyvalues.entries.remove(key).unwrap());
+	}
+	if let Err(e) = engine.save_index(Area::KeyValues) {
+		for (key, old) in expired.into_iter().zip(freed) {
+			engine.keyvalues.entries.insert(key, old);
+		}
+		return Err(e);
+	}
+	for old in freed {
+		engine.free_data(Area::KeyValues, &old.file, old.index, old.data_length);
+	}
+
+	engine.log(format!("Keys expired: {}", expired.len()));
+	return Ok(expired.len());
+}
+
+
+// --> Time series
+// ---------------
+
+const SECOND: u64 = 1000;
+const DAY: u64 = 86_400_000;
+
+// Windows are only rolled up, and then possibly dropped,
+// once they have been closed for this long
+pub const ROLLUP_DELAY: u64 = 60 * SECOND;
+
+fn create_series(
+	engine: &mut Engine, args: &Map<String, Value>
+) -> Result<Resolved, String> {
+	let name = string_arg(args, "name")?;
+	check_name(&name, "series name")?;
+	if engine.timeseries.find(&name).is_some() {
+		return Err(format!("The series {} already exists.", name));
+	}
+
+	let window = optional_u64_arg(args, "window")?.unwrap_or(86_400)
+		.checked_mul(SECOND).filter(|w| *w > 0)
+		.ok_or("The window must be a positive number of seconds.".to_owned())?
+	;
+	let retention = optional_u64_arg(args, "retention")?.unwrap_or(0)
+		.saturating_mul(DAY)
+	;
+	let mut rollups = Vec::<Rollup>::new();
+	for rollup in args.get("rollups").and_then(|r| r.as_array()).into_iter().flatten() {
+		let interval = rollup["interval"].as_u64().unwrap_or(0).saturating_mul(SECOND);
+		if interval == 0 || window % interval != 0 {
+			return Err(
+				"Each rollup interval must be a number of seconds dividing the window.".to_owned()
+			);
+		}
+		rollups.push(Rollup {
+			interval,
+			retention: rollup["retention"].as_u64().unwrap_or(0).saturating_mul(DAY)
+		});
+	}
+	if rollups.len() > u8::MAX as usize {
+		return Err(format!("A series can't have more than {} rollups.", u8::MAX));
+	}
+
+	let id = engine.new_id(&engine.timeseries.series);
+	let mut dir = engine.store_dir.clone();
+	dir.push("timeseries");
+	dir.push(&id);
+	std::fs::create_dir_all(&dir)
+		.map_err(|e| format!("Failed to create the series directory: {}", e))?
+	;
+	engine.timeseries.series.insert(id.clone(), SeriesMeta {
+		name: name.clone(),
+		window,
+		retention,
+		rollups,
+		rolled_until: 0,
+		windows: Default::default()
+	});
+	if let Err(e) = engine.save_series() {
+		engine.timeseries.series.remove(&id);
+		return Err(e);
+	}
+
+	engine.log(format!("Series created: {}", name));
+	return Ok(Resolved::Value(query::series_json(engine, &id)));
+}
+
+fn drop_series(
+	engine: &mut Engine, args: &Map<String, Value>
+) -> Result<Resolved, String> {
+	let name = string_arg(args, "name")?;
+	let id = match engine.timeseries.find(&name) {
+		Some(id) => id.clone(),
+		None => return Ok(Resolved::Value(Value::Bool(false)))
+	};
+	let old = engine.timeseries.series.remove(&id).unwrap();
+	if let Err(e) = engine.save_series() {
+		engine.timeseries.series.insert(id, old);
+		return Err(e);
+	}
+	let mut dir = engine.store_dir.clone();
+	dir.push("timeseries");
+	dir.push(&id);
+	let _ = std::fs::remove_dir_all(&dir);
+	let _ = faccess::remove_checksums(&engine.store_dir, &dir);
+
+	engine.log(format!("Series dropped: {}", name));
+	return Ok(Resolved::Value(Value::Bool(true)));
+}
+
+// Appends points, given as `{ time, value }` objects, with `time`
+// in milliseconds since the Unix epoch (default: now)
+fn append_points(
+	engine: &mut Engine, args: &Map<String, Value>
+) -> Result<Resolved, String> {
+	let name = string_arg(args, "series")?;
+	let id = engine.timeseries.find(&name).cloned()
+		.ok_or(format!("Unknown series: {}", name))?
+	;
+	let now = engine.now();
+	let meta = &engine.timeseries.series[&id];
+
+	let mut windows = std::collections::BTreeMap::<u64, Vec<(u64, f64)>>::new();
+	for point in args.get("points").and_then(|p| p.as_array()).into_iter().flatten() {
+		let time = match &point["time"] {
+			Value::Null => now,
+			time => time.as_u64()
+				.ok_or("Point times must be positive integers.".to_owned())?
+		};
+		let value = point["value"].as_f64()
+			.ok_or("Point values must be numbers.".to_owned())?
+		;
+		let start = time - time % meta.window;
+		if start < meta.rolled_until {
+			return Err(format!(
+				"The points before {} are already rolled up.", meta.rolled_until
+			));
+		}
+		windows.entry(start).or_default().push((time, value));
+	}
+
+	// The windows are written in turn, and cut back if one of them fails
+	let mut sizes = Vec::<(PathBuf, Option<u64>)>::new();
+	let mut appended = Ok(());
+	for (start, points) in &windows {
+		let path = engine.window_path(&id, *start);
+		sizes.push((path.clone(), crypt::len(&path).ok()));
+		appended = faccess::append_points(&path, points)
+			.map_err(|e| format!("Failed to append points to {}: {}", name, e))
+			.and_then(|offset| engine.update_checksums(
+				&path, offset, points.len() as u64 * faccess::POINT_SIZE
+			))
+		;
+		if appended.is_err() { break; }
+	}
+	if let Err(e) = appended {
+		cut_back(engine, &sizes);
+		return Err(e);
+	}
+	let mut count = 0u64;
+	let meta = engine.timeseries.series.get_mut(&id).unwrap();
+	for (start, points) in windows {
+		*meta.windows.entry(start).or_default() += points.len() as u64;
+		count += points.len() as u64;
+	}
+
+	engine.log(format!("Points appended to {}: {}", name, count));
+	return Ok(Resolved::Value(Value::from(count)));
+}
+
+// Cuts files back to their former sizes after a write failed midway.
+// The files which didn't exist are removed.
+fn cut_back(engine: &Engine, sizes: &[(PathBuf, Option<u64>)]) {
+	for (path, size) in sizes {
+		let cut = match size {
+			Some(size) => crypt::File::open_rw(path)
+				.and_then(|mut file| { file.set_len(*size)?; file.sync_data() })
+				.map_err(|e| e.to_string())
+				.and_then(|_| engine.update_checksums(path, *size, 0)),
+			None => std::fs::remove_file(path)
+				.and_then(|_| faccess::remove_checksums(&engine.store_dir, path))
+				.map_err(|e| e.to_string())
+		};
+		if let Err(e) = cut {
+			cli::red_err(format!("Failed to cut {:?} back after a failed write: {}", path, e));
+		}
+	}
+}
+
+// Rolls up the closed windows of every series, then applies the
+// retention policies. Returns the number of windows rolled up.
+pub fn roll_series(engine: &mut Engine, now: u64) -> Result<usize, String> {
+	let mut rolled = 0usize;
+	let ids = engine.timeseries.series.keys().cloned().collect::<Vec<String>>();
+	for id in ids {
+		let meta = engine.timeseries.series[&id].clone();
+		let closed = now.saturating_sub(ROLLUP_DELAY);
+		let until = closed - closed % meta.window;
+		if until <= meta.rolled_until { continue; }
+
+		// The rollups are cut back unless the new `rolled_until` is saved
+		let sizes = meta.rollups.iter()
+			.map(|rollup| engine.rollup_path(&id, rollup.interval))
+			.map(|path| { let size = crypt::len(&path).ok(); (path, size) })
+			.collect::<Vec<(PathBuf, Option<u64>)>>()
+		;
+		let mut windows = 0usize;
+		let mut appended = Ok(());
+		for (start, _) in meta.windows.range(meta.rolled_until..until) {
+			let window = engine.window_path(&id, *start);
+			let points = match engine.read_points(&window) {
+				Ok(points) => points,
+				Err(e) => {
+					appended = Err(format!("Failed to read the series {}: {}", meta.name, e));
+					break;
+				}
+			};
+			for rollup in &meta.rollups {
+				let buckets = faccess::downsample(&points, rollup.interval);
+				let path = engine.rollup_path(&id, rollup.interval);
+				appended = faccess::append_buckets(&path, &buckets)
+					.map_err(|e| format!("Failed to roll up {}: {}", meta.name, e))
+					.and_then(|offset| engine.update_checksums(
+						&path, offset, buckets.len() as u64 * faccess::BUCKET_SIZE
+					))
+				;
+				if appended.is_err() { break; }
+			}
+			if appended.is_err() { break; }
+			windows += 1;
+		}
+		engine.timeseries.series.get_mut(&id).unwrap().rolled_until = until;
+		if let Err(e) = appended.and_then(|_| engine.save_series()) {
+			engine.timeseries.series.get_mut(&id).unwrap().rolled_until = meta.rolled_until;
+			cut_back(engine, &sizes);
+			return Err(e);
+		}
+		rolled += windows;
+
+		if meta.retention != 0 {
+			let cutoff = now.saturating_sub(meta.retention);
+			let expired = meta.windows.range(..until).map(|(s, _)| *s)
+				.filter(|s| s + meta.window <= cutoff)
+				.collect::<Vec<u64>>()
+			;
+			for start in expired {
+				let window = engine.window_path(&id, start);
+				let _ = std::fs::remove_file(&window);
+				let _ = faccess::remove_checksums(&engine.store_dir, &window);
+				engine.timeseries.series.get_mut(&id).unwrap().windows.remove(&start);
+			}
+		}
+		for rollup in meta.rollups.iter().filter(|r| r.retention != 0) {
+			let path = engine.rollup_path(&id, rollup.interval);
+			let cutoff = now.saturating_sub(rollup.retention);
+			let buckets = engine.read_buckets(&path)
+				.map_err(|e| format!("Failed to read the rollups of {}: {}", meta.name, e))?
+			;
+			let kept = buckets.iter().filter(|b| b.start + rollup.interval > cutoff)
+				.copied().collect::<Vec<faccess::Bucket>>()
+			;
+			if kept.len() < buckets.len() {
+				engine.track_rewrite(&path);
+				faccess::write_buckets(&engine.store_dir, &path, &kept)
+					.map_err(|e| format!("Failed to trim the rollups of {}: {}", meta.name, e))?
+				;
+				if engine.store.checksumming {
+					engine.track_rewrite(&faccess::checksum_path(&engine.store_dir, &path));
+					faccess::rebuild_checksums(&engine.store_dir, &path)
+						.map_err(|e| format!("Failed to update the checksums of {}: {}", meta.name, e))?
+					;
+				}
+			}
+		}
+	}
+
+	if rolled > 0 { engine.log(format!("Series windows rolled up: {}", rolled)); }
+	return Ok(rolled);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::check;
+	use super::super::{ query, testing };
+
+	#[test]
+	fn deleted_items_take_their_edges_along() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut engine = testing::create_store(dir.path(), json!({}));
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "nodes" })).unwrap();
+		let ids = (0..3)
+			.map(|n| testing::insert(&mut engine, "nodes", json!({ "n": n })))
+			.collect::<Vec<String>>()
+		;
+		for (from, to) in [(0, 1), (1, 2), (2, 0)] {
+			let args = json!({ "from": ids[from], "to": ids[to], "label": "next" });
+			testing::mutate(&mut engine, "createEdge", args).unwrap();
+		}
+		let args = json!({ "from": ids[0], "to": "unknown", "label": "next" });
+		assert!(testing::mutate(&mut engine, "createEdge", args).is_err());
+
+		let deleted = testing::mutate(&mut engine, "deleteItem", json!({ "id": ids[1] }));
+		assert!(matches!(deleted, Ok(Resolved::Value(Value::Bool(true)))));
+		let engine = testing::open_store(dir.path());
+		assert_eq!(engine.collections.items.len(), 2);
+		assert_eq!(engine.edges.edges.len(), 1);
+		let edge = engine.edges.edges.values().next().unwrap();
+		assert_eq!((&edge.from, &edge.to), (&ids[2], &ids[0]));
+		assert!(!engine.edges.outgoing.contains_key(&ids[1]));
+		assert!(!engine.edges.incoming.contains_key(&ids[1]));
+	}
+
+	#[test]
+	fn failed_deletions_keep_the_item_whole() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut engine = testing::create_store(dir.path(), json!({}));
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "places" })).unwrap();
+		let at = json!({ "type": "Point", "coordinates": [2.35, 48.85] });
+		let ids = [
+			testing::insert(&mut engine, "places", json!({ "at": at })),
+			testing::insert(&mut engine, "places", json!({ "n": 1 }))
+		];
+		let args = json!({ "from": ids[0], "to": ids[1], "label": "near" });
+		testing::mutate(&mut engine, "createEdge", args).unwrap();
+
+		// The deletion fails after saving the edges, then after saving the geo-points
+		for index in ["collections/geoindex", "collections/rixindex"] {
+			let index = dir.path().join(index);
+			testing::block(&index);
+			assert!(testing::mutate(&mut engine, "deleteItem", json!({ "id": ids[0] })).is_err());
+			testing::unblock(&index);
+		}
+		for engine in [engine, testing::open_store(dir.path())] {
+			assert!(engine.collections.items.contains_key(&ids[0]));
+			assert_eq!(engine.edges.edges.len(), 1);
+			assert!(engine.edges.outgoing.contains_key(&ids[0]));
+			let places = engine.collections.find("places").unwrap();
+			assert!(engine.geo[places].points.contains_key(&ids[0]));
+		}
+
+		let mut engine = testing::open_store(dir.path());
+		testing::mutate(&mut engine, "deleteItem", json!({ "id": ids[0] })).unwrap();
+		let engine = testing::open_store(dir.path());
+		assert_eq!(engine.collections.items.len(), 1);
+		assert!(engine.edges.edges.is_empty());
+		let places = engine.collections.find("places").unwrap();
+		assert!(engine.geo.get(places).is_none_or(|geo| geo.points.is_empty()));
+	}
+
+	#[test]
+	fn failed_writes_keep_the_geo_points() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut engine = testing::create_store(dir.path(), json!({}));
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "places" })).unwrap();
+		let places = engine.collections.find("places").unwrap().clone();
+		let point = |lon: f64| json!({ "at": { "type": "Point", "coordinates": [lon, 48.85] } });
+		let id = testing::insert(&mut engine, "places", point(2.35));
+
+		// Both indexes fail in turn, for an update and for an insertion
+		for index in ["collections/geoindex", "collections/rixindex"] {
+			let index = dir.path().join(index);
+			testing::block(&index);
+			let args = json!({ "id": id, "data": point(4.83) });
+			assert!(testing::mutate(&mut engine, "updateItem", args).is_err());
+			let args = json!({ "collection": "places", "data": point(5.37) });
+			assert!(testing::mutate(&mut engine, "insertItem", args).is_err());
+			testing::unblock(&index);
+		}
+		for engine in [engine, testing::open_store(dir.path())] {
+			assert_eq!(engine.collections.items.len(), 1);
+			assert_eq!(engine.geo[&places].points.len(), 1);
+			assert_eq!(engine.geo[&places].point(&id, "at"), Some((2.35, 48.85)));
+		}
+
+		// The points of an item missing from the index of the collections,
+		// as left by an interrupted insertion, are dropped
+		let mut engine = testing::open_store(dir.path());
+		let other = testing::insert(&mut engine, "places", point(5.37));
+		engine.collections.items.remove(&other);
+		engine.save_index(Area::Collections).unwrap();
+		let engine = testing::open_store(dir.path());
+		assert!(!engine.geo[&places].points.contains_key(&other));
+		assert!(engine.geo[&places].points.contains_key(&id));
+	}
+
+	#[test]
+	fn singletons() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut engine = testing::create_store(dir.path(), json!({}));
+		for value in [json!({ "theme": "dark" }), json!([1, 2])] {
+			let args = json!({ "name": "settings", "value": value });
+			testing::mutate(&mut engine, "setSingleton", args).unwrap();
+		}
+		let mut engine = testing::open_store(dir.path());
+		assert_eq!(engine.singletons.singletons.len(), 1);
+		assert_eq!(query::singleton_value(&engine, "settings").unwrap(), json!([1, 2]));
+		let deleted = testing::mutate(&mut engine, "deleteSingleton", json!({ "name": "settings" }));
+		assert!(matches!(deleted, Ok(Resolved::Value(Value::Bool(true)))));
+		assert_eq!(query::singleton_value(&engine, "settings").unwrap(), Value::Null);
+	}
+
+	#[test]
+	fn expired_keys() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut engine = testing::create_store(dir.path(), json!({}));
+		let args = json!({ "key": "session", "value": "abc", "ttl": 60 });
+		testing::mutate(&mut engine, "setKey", args).unwrap();
+		testing::mutate(&mut engine, "setKey", json!({ "key": "kept", "value": 1 })).unwrap();
+		let args = json!({ "key": "session", "value": "abc", "ttl": 0 });
+		assert!(testing::mutate(&mut engine, "setKey", args).is_err());
+
+		let now = basics::now_millis();
+		assert_eq!(query::keyvalue(&engine, "session", now).unwrap(), json!("abc"));
+		let later = now + 61_000;
+		assert_eq!(query::keyvalue(&engine, "session", later).unwrap(), Value::Null);
+		assert_eq!(expire_keys(&mut engine, now).unwrap(), 0);
+		assert_eq!(expire_keys(&mut engine, later).unwrap(), 1);
+		let engine = testing::open_store(dir.path());
+		assert_eq!(engine.keyvalues.entries.len(), 1);
+		assert!(engine.keyvalues.entries.contains_key("kept"));
+	}
+
+	#[test]
+	fn counters() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut engine = testing::create_store(dir.path(), json!({}));
+		let mut counter = |name: &str, args: Value| {
+			return match testing::mutate(&mut engine, name, args) {
+				Ok(Resolved::Value(value)) => Ok(value),
+				Ok(_) => panic!("{} resolves to a value", name),
+				Err(e) => Err(e)
+			};
+		};
+		assert_eq!(counter("increment", json!({ "key": "hits" })), Ok(json!(1)));
+		assert_eq!(counter("increment", json!({ "key": "hits", "by": 5 })), Ok(json!(6)));
+		assert_eq!(counter("decrement", json!({ "key": "hits", "by": 10 })), Ok(json!(-4)));
+
+		let args = json!({ "key": "hits", "expected": 1, "value": 0 });
+		assert_eq!(counter("compareAndSwap", args), Ok(json!(false)));
+		let args = json!({ "key": "hits", "expected": -4, "value": i64::MAX });
+		assert_eq!(counter("compareAndSwap", args), Ok(json!(true)));
+		assert!(counter("increment", json!({ "key": "hits" })).unwrap_err().contains("overflow"));
+		let args = json!({ "key": "name", "expected": null, "value": "orix" });
+		assert_eq!(counter("compareAndSwap", args), Ok(json!(true)));
+		assert!(counter("decrement", json!({ "key": "name" })).unwrap_err().contains("not an integer"));
+
+		let engine = testing::open_store(dir.path());
+		let now = basics::now_millis();
+		assert_eq!(query::keyvalue(&engine, "hits", now).unwrap(), json!(i64::MAX));
+		assert_eq!(query::keyvalue(&engine, "name", now).unwrap(), json!("orix"));
+	}
+
+	#[test]
+	fn series_rollups_and_retention() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut engine = testing::create_store(dir.path(), json!({}));
+		let args = json!({ "name": "cpu", "window": 60, "retention": 1,
+			"rollups": [{ "interval": 10 }] });
+		testing::mutate(&mut engine, "createSeries", args).unwrap();
+		let args = json!({ "name": "bad", "window": 60, "rollups": [{ "interval": 7 }] });
+		assert!(testing::mutate(&mut engine, "createSeries", args).is_err());
+
+		// A multiple of the window, so that the points fill two windows
+		let base = 1_699_999_980_000u64;
+		let points = [(1000, 1.0), (2000, 3.0), (15_000, 10.0), (65_000, 7.0)].iter()
+			.map(|(t, v)| json!({ "time": base + t, "value": v }))
+			.collect::<Vec<Value>>()
+		;
+		let appended = testing::mutate(&mut engine, "appendPoints",
+			json!({ "series": "cpu", "points": points })
+		);
+		assert!(matches!(appended, Ok(Resolved::Value(n)) if n == json!(4)));
+
+		assert_eq!(roll_series(&mut engine, base + 60_000).unwrap(), 0);
+		assert_eq!(roll_series(&mut engine, base + 120_000 + ROLLUP_DELAY).unwrap(), 2);
+		let late = json!({ "series": "cpu", "points": [{ "time": base + 3000, "value": 0 }] });
+		assert!(testing::mutate(&mut engine, "appendPoints", late).is_err());
+
+		// The retention drops the points, but keeps the rollups
+		roll_series(&mut engine, base + 3 * DAY).unwrap();
+		let engine = std::sync::RwLock::new(testing::open_store(dir.path()));
+		let source = format!(
+			"{{ points(series: \"cpu\") rollup(series: \"cpu\", interval: 10, from: {}) }}", base
+		);
+		let response = testing::run(&engine, &source);
+		assert_eq!(response["data"]["points"], json!([]));
+		let rollup = response["data"]["rollup"].as_array().unwrap().iter()
+			.map(|b| (
+				b["time"].as_u64().unwrap() - base,
+				b["count"].as_u64().unwrap(),
+				b["sum"].as_f64().unwrap()
+			))
+			.collect::<Vec<(u64, u64, f64)>>()
+		;
+		assert_eq!(rollup, vec![(0, 2, 4.0), (10_000, 1, 10.0), (60_000, 1, 7.0)]);
+	}
+
+	#[test]
+	fn failed_series_writes_are_cut_back() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut engine = testing::create_store(dir.path(), json!({}));
+		let args = json!({ "name": "cpu", "window": 60, "rollups": [{ "interval": 10 }] });
+		testing::mutate(&mut engine, "createSeries", args).unwrap();
+		let id = engine.timeseries.find("cpu").unwrap().clone();
+		let base = 1_699_999_980_000u64;
+		let append = |engine: &mut Engine, times: &[u64]| testing::mutate(engine, "appendPoints",
+			json!({ "series": "cpu", "points": times.iter()
+				.map(|t| json!({ "time": base + t, "value": 1 })).collect::<Vec<Value>>() })
+		);
+		append(&mut engine, &[1000]).unwrap();
+
+		// The third window can't be written: the first two are cut back
+		let blocked = engine.window_path(&id, base + 120_000);
+		std::fs::create_dir_all(blocked.join("blocked")).unwrap();
+		assert!(append(&mut engine, &[2000, 61_000, 121_000]).is_err());
+		std::fs::remove_dir_all(&blocked).unwrap();
+		assert_eq!(crypt::len(&engine.window_path(&id, base)).unwrap(), faccess::POINT_SIZE);
+		assert!(!engine.window_path(&id, base + 60_000).exists());
+		assert_eq!(engine.timeseries.series[&id].windows.len(), 1);
+		assert_eq!(check::verify(dir.path()).errors(), 0);
+
+		// A roll up whose index fails to save leaves no buckets behind
+		let index = dir.path().join("timeseries/rixindex");
+		testing::block(&index);
+		assert!(roll_series(&mut engine, base + 60_000 + ROLLUP_DELAY).is_err());
+		assert_eq!(engine.timeseries.series[&id].rolled_until, 0);
+		assert!(!engine.rollup_path(&id, 10_000).exists());
+		assert!(testing::mutate(&mut engine, "dropSeries", json!({ "name": "cpu" })).is_err());
+		assert!(engine.timeseries.find("cpu").is_some());
+		testing::unblock(&index);
+		assert_eq!(roll_series(&mut engine, base + 60_000 + ROLLUP_DELAY).unwrap(), 1);
+		assert_eq!(check::verify(dir.path()).errors(), 0);
+
+		let engine = testing::open_store(dir.path());
+		let meta = &engine.timeseries.series[&id];
+		assert_eq!((meta.rolled_until, meta.windows.len()), (base + 60_000, 1));
+		assert_eq!(engine.read_buckets(&engine.rollup_path(&id, 10_000)).unwrap().len(), 1);
+	}
+
+	#[test]
+	fn compressed_collections() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut engine = testing::create_store(dir.path(), json!({}));
+		let args = json!({ "name": "logs", "compression": "zstd" });
+		testing::mutate(&mut engine, "createCollection", args).unwrap();
+		let args = json!({ "name": "other", "compression": "gzip" });
+		assert!(testing::mutate(&mut engine, "createCollection", args).is_err());
+
+		let text = "All work and no play makes Jack a dull boy. ".repeat(100);
+		let zstd = testing::insert(&mut engine, "logs", json!({ "text": text }));
+		let args = json!({ "collection": "logs", "compression": "lz4" });
+		let set = testing::mutate(&mut engine, "setCompression", args).unwrap();
+		assert!(matches!(set, Resolved::Value(v) if v["compression"] == "lz4"));
+		let lz4 = testing::insert(&mut engine, "logs", json!({ "text": text }));
+		// Bytes compression doesn't make smaller are stored as they are
+		let small = testing::insert(&mut engine, "logs", json!(7));
+
+		let items = &engine.collections.items;
+		assert_eq!(basics::Compression::of(items[&zstd].data_type), Some(Compression::Zstd));
+		assert_eq!(basics::Compression::of(items[&lz4].data_type), Some(Compression::Lz4));
+		assert_eq!(basics::Compression::of(items[&small].data_type), Some(Compression::None));
+		assert!(items[&zstd].data_length < 500 && items[&lz4].data_length < 1000);
+
+		// The items keep the compression they were written with
+		let args = json!({ "collection": "logs", "compression": "none" });
+		testing::mutate(&mut engine, "setCompression", args).unwrap();
+		let plain = testing::insert(&mut engine, "logs", json!({ "text": text }));
+		assert!(engine.collections.items[&plain].data_length > text.len() as u64);
+		drop(engine);
+
+		let engine = testing::open_store(dir.path());
+		for id in [&zstd, &lz4, &plain] {
+			assert_eq!(query::item_data(&engine, id).unwrap()["text"], text);
+		}
+		assert_eq!(query::item_data(&engine, &small).unwrap(), 7);
+		let logs = engine.collections.find("logs").unwrap();
+		assert_eq!(engine.collections.compression_of(logs), Compression::None);
+		assert_eq!(crate::check::verify(dir.path()).errors(), 0);
+	}
+
+	#[test]
+	fn compression_round_trips() {
+		let value = json!({ "list": vec![0; 200] });
+		let (data_type, bytes) = basics::encode_value(&value);
+		for compression in [Compression::None, Compression::Lz4, Compression::Zstd] {
+			let (stored, compressed) = basics::compress(compression, data_type, bytes.clone());
+			assert_eq!(Compression::of(stored), Some(compression));
+			assert_eq!(basics::DataType::of(stored), Some(data_type));
+			assert_eq!(compression == Compression::None, compressed == bytes);
+			let decompressed = basics::decompress(stored, &compressed).unwrap();
+			assert_eq!(decompressed.as_ref(), bytes.as_slice());
+			assert_eq!(basics::decode_value(stored, &compressed).unwrap(), value);
+		}
+
+		// Bytes without redundancy can't be compressed
+		let mut state = 0x2545f4914f6cdd1du64;
+		let noise = (0..4096).map(|_| {
+			state ^= state << 13;
+			state ^= state >> 7;
+			state ^= state << 17;
+			(state >> 24) as u8
+		}).collect::<Vec<u8>>();
+		for compression in [Compression::Lz4, Compression::Zstd] {
+			let (stored, kept) = basics::compress(compression, basics::DataType::Text, noise.clone());
+			assert_eq!(Compression::of(stored), Some(Compression::None));
+			assert_eq!(kept, noise);
+		}
+		// Corrupted compressed bytes are reported as such
+		let (stored, mut compressed) = basics::compress(Compression::Lz4, data_type, bytes.clone());
+		compressed.truncate(compressed.len() / 2);
+		assert!(basics::decompress(stored, &compressed).is_none());
+	}
+}
@@ -1,6 +1,321 @@
+use std::fs;
+use std::path::{ Path, PathBuf };
+
 use clap::ArgMatches;
 
+use crate::cli;
+use crate::check;
+use crate::archive;
+use crate::lite;
+use crate::crypt;
+use crate::faccess;
+use crate::basics::{ self, Store, StoreType };
+
+mod v0_1;
+mod v0_2;
+
+// A step turning the stores of a version into the next one. It returns the
+// changes it made, or the ones it would make when it's a dry run.
+pub struct Migration {
+	pub from: (u16, u16),
+	pub to: (u16, u16),
+	pub summary: &'static str,
+	pub run: fn(&Path, &Store, bool) -> Result<Vec<String>, String>
+}
+
+// Every migration, from the oldest version
+pub const MIGRATIONS: [Migration; 2] = [
+	Migration {
+		from: (0, 0),
+		to: (0, 1),
+		summary: "Headers for the records of the legacy data files",
+		run: v0_1::run
+	},
+	Migration {
+		from: (0, 1),
+		to: (0, 2),
+		summary: "Compression of the collection items",
+		run: v0_2::run
+	}
+];
+
+// Chains the migrations leading from a version to another
+pub fn plan(from: (u16, u16), to: (u16, u16)) -> Result<Vec<&'static Migration>, String> {
+	let mut steps = Vec::<&Migration>::new();
+	let mut version = from;
+	while version < to {
+		match MIGRATIONS.iter().find(|m| m.from == version) {
+			Some(migration) => {
+				steps.push(migration);
+				version = migration.to;
+			},
+			None => return Err(format!(
+				"No migration is known from version {}.{}.", version.0, version.1
+			))
+		}
+	}
+	if version != to {
+		return Err(format!("The migrations lead to version {}.{}.", version.0, version.1));
+	}
+	return Ok(steps);
+}
+
+fn write_manifest(store_dir: &Path, store: &Store) -> Result<(), String> {
+	let text = serde_json::to_string_pretty(store).map_err(|e| e.to_string())?;
+	return faccess::write_atomic(store_dir, &store_dir.join("manifest.json"), text.as_bytes())
+		.map_err(|e| format!("Failed to write the manifest: {}", e))
+	;
+}
+
+// Archives the store as it is before the upgrade, so that it can be restored
+fn backup(store_dir: &Path, store: &Store, target: &Path) -> Result<(), String> {
+	if target.exists() { return Err(format!("{:?} already exists.", target)); }
+	if let Some(parent) = target.parent() {
+		fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+	}
+	let mut partial = target.to_path_buf().into_os_string();
+	partial.push(".part");
+	let partial = PathBuf::from(partial);
+	let written = archive::store_files(store_dir)
+		.and_then(|files| archive::write_archive(store_dir, store, &files, &partial))
+		.and_then(|_| fs::rename(&partial, target))
+	;
+	if let Err(e) = written {
+		let _ = fs::remove_file(&partial);
+		return Err(format!("Failed to back the store up: {}", e));
+	}
+	return Ok(());
+}
+
 pub fn main(matches: &ArgMatches) -> std::process::ExitCode {
-	println!("{:#?}", matches);
+
+	// --> Checking the command line arguments
+	// ---------------------------------------
+
+	let conf = basics::get_conf();
+	let dry_run = *matches.get_one::<bool>("dry-run").unwrap();
+	let store_dir: PathBuf;
+	if matches.contains_id("directory") {
+		store_dir = PathBuf::from(matches.get_one::<String>("directory").unwrap());
+	}
+	else { store_dir = PathBuf::from(".").canonicalize().unwrap(); }
+	let mut store = match basics::load_store(&store_dir) {
+		Ok(store) => store,
+		Err(message) => {
+			cli::red_err(message);
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+
+	let from = (store.major, store.minor);
+	let to = (conf.major, conf.minor);
+	if from == to {
+		cli::green_out(format!(
+			"The store is already at the version {}.{} of {}.", to.0, to.1, conf.display_name
+		));
+		return std::process::ExitCode::SUCCESS;
+	}
+	if from > to {
+		cli::red_err(format!(
+			"The store is at the version {}.{}, newer than the one of {} ({}.{}).\n\
+			Please consider updating your software.",
+			from.0, from.1, conf.display_name, to.0, to.1
+		));
+		return std::process::ExitCode::FAILURE;
+	}
+	if store.kind == StoreType::Backup || store.kind == StoreType::Archive {
+		cli::red_err(
+			"Only live and lite stores can be upgraded.\n".to_owned()
+			+ "Convert the store first, with the `convert` subcommand."
+		);
+		return std::process::ExitCode::FAILURE;
+	}
+	if store_dir.join("tmp/replaced").exists() {
+		cli::red_err(
+			"A previous upgrade or conversion of the store was interrupted.\n".to_owned()
+			+ "Please check the store, then remove `tmp/replaced`."
+		);
+		return std::process::ExitCode::FAILURE;
+	}
+	if let Err(message) = crypt::unlock(&store_dir, &store, None) {
+		cli::red_err(message);
+		return std::process::ExitCode::FAILURE;
+	}
+	let steps = match plan(from, to) {
+		Ok(steps) => steps,
+		Err(message) => {
+			cli::red_err(message);
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+
+
+	// --> Listing the changes of a dry run
+	// ------------------------------------
+
+	if dry_run {
+		for migration in &steps {
+			cli::cyan_out(format!(
+				"{}.{} -> {}.{}: {}",
+				migration.from.0, migration.from.1, migration.to.0, migration.to.1,
+				migration.summary
+			));
+			// Each step is run against the store as it is now
+			match (migration.run)(&store_dir, &store, true) {
+				Ok(changes) if changes.is_empty() => println!("  Nothing to change"),
+				Ok(changes) => for change in changes { println!("  {}", change); },
+				Err(message) => {
+					cli::red_err(message);
+					return std::process::ExitCode::FAILURE;
+				}
+			}
+		}
+		return std::process::ExitCode::SUCCESS;
+	}
+
+
+	// --> Backing up, then upgrading the store
+	// ----------------------------------------
+
+	// The store must not be served, nor embedded, while it is migrated
+	let _lock = match lite::lock(&store_dir) {
+		Ok(file) => file,
+		Err(message) => {
+			cli::red_err(message);
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+
+	let target = match matches.get_one::<String>("backup") {
+		Some(path) => PathBuf::from(path),
+		None => store_dir.join(format!(
+			"logs/upgrades/{}.{}-{}.orix", from.0, from.1, basics::now_millis()
+		))
+	};
+	if let Err(message) = backup(&store_dir, &store, &target) {
+		cli::red_err(message);
+		return std::process::ExitCode::FAILURE;
+	}
+	cli::white_out(format!("Store backed up in {:?}.", target));
+
+	for migration in steps {
+		let ran = (migration.run)(&store_dir, &store, false).and_then(|changes| {
+			// The version only changes once the step is complete
+			let mut upgraded = store.clone();
+			(upgraded.major, upgraded.minor) = migration.to;
+			write_manifest(&store_dir, &upgraded)?;
+			store = upgraded;
+			Ok(changes)
+		});
+		match ran {
+			Ok(changes) => {
+				cli::green_out(format!(
+					"Upgraded to {}.{}: {}", migration.to.0, migration.to.1, migration.summary
+				));
+				for change in changes { println!("  {}", change); }
+			},
+			Err(message) => {
+				cli::red_err(format!(
+					"{}\nThe store was left at the version {}.{}. It can be restored \
+					from {:?} with the `restore` subcommand.",
+					message, store.major, store.minor, target
+				));
+				return std::process::ExitCode::FAILURE;
+			}
+		}
+	}
+
+	let report = check::verify(&store_dir);
+	if report.errors() > 0 {
+		cli::red_err(format!(
+			"The upgraded store has {} errors. Run `orixdb check` on it for details.",
+			report.errors()
+		));
+		return std::process::ExitCode::FAILURE;
+	}
 	return std::process::ExitCode::SUCCESS;
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use clap::{ Arg, ArgAction, Command };
+	use serde_json::json;
+	use crate::serve::testing;
+
+	fn upgrade(store_dir: &Path, options: &[&str]) -> std::process::ExitCode {
+		let command = Command::new("upgrade")
+			.arg(Arg::new("directory"))
+			.arg(Arg::new("dry-run").long("dry-run").action(ArgAction::SetTrue))
+			.arg(Arg::new("backup").long("backup"))
+		;
+		let mut args = vec!["upgrade", store_dir.to_str().unwrap()];
+		args.extend(options);
+		return main(&command.get_matches_from(args));
+	}
+
+	// A store of the version 0.0, which lacks the later data models
+	fn legacy(store_dir: &Path) {
+		let mut engine = testing::create_store(store_dir, json!({ "minor": 0 }));
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "users" })).unwrap();
+		testing::insert(&mut engine, "users", json!({ "name": "Ada" }));
+		drop(engine);
+		fs::remove_dir_all(store_dir.join("blobs")).unwrap();
+		fs::remove_file(store_dir.join("timeseries/rixindex")).unwrap();
+	}
+
+	#[test]
+	fn plans() {
+		assert_eq!(plan((0, 0), (0, 1)).unwrap().len(), 1);
+		assert_eq!(plan((0, 0), (0, 2)).unwrap().len(), 2);
+		assert!(plan((0, 1), (0, 1)).unwrap().is_empty());
+		assert_eq!(
+			plan((0, 2), (0, 3)).err().unwrap(), "No migration is known from version 0.2."
+		);
+	}
+
+	#[test]
+	fn dry_runs_change_nothing() {
+		let dir = tempfile::tempdir().unwrap();
+		legacy(dir.path());
+		let store = basics::load_store(dir.path()).unwrap();
+		let changes = v0_1::run(dir.path(), &store, true).unwrap();
+		assert_eq!(changes.len(), 1);
+		assert!(changes[0].starts_with("Create the missing directories and indexes: blobs,"));
+
+		assert_eq!(upgrade(dir.path(), &["--dry-run"]), std::process::ExitCode::SUCCESS);
+		assert_eq!(basics::load_store(dir.path()).unwrap().minor, 0);
+		assert!(!dir.path().join("blobs").exists());
+		assert!(!dir.path().join("logs/upgrades").exists());
+	}
+
+	#[test]
+	fn upgrades() {
+		let dir = tempfile::tempdir().unwrap();
+		legacy(dir.path());
+		let lock = lite::lock(dir.path()).unwrap();
+		assert_eq!(upgrade(dir.path(), &[]), std::process::ExitCode::FAILURE);
+		assert_eq!(basics::load_store(dir.path()).unwrap().minor, 0);
+		assert!(!dir.path().join("logs/upgrades").exists());
+		drop(lock);
+		assert_eq!(upgrade(dir.path(), &[]), std::process::ExitCode::SUCCESS);
+		assert_eq!(basics::load_store(dir.path()).unwrap().minor, basics::get_conf().minor);
+		assert!(dir.path().join("blobs/rixindex").exists());
+		assert!(dir.path().join("timeseries/rixindex").exists());
+		let backups = fs::read_dir(dir.path().join("logs/upgrades")).unwrap()
+			.map(|entry| entry.unwrap().file_name().into_string().unwrap())
+			.collect::<Vec<String>>()
+		;
+		assert_eq!(backups.len(), 1);
+		assert!(backups[0].starts_with("0.0-") && backups[0].ends_with(".orix"));
+		assert_eq!(check::verify(dir.path()).errors(), 0);
+
+		let engine = std::sync::RwLock::new(testing::open_store(dir.path()));
+		let response = testing::run(&engine, "{ items(collection: \"users\") { data } }");
+		assert_eq!(response["data"]["items"][0]["data"]["name"], "Ada");
+
+		// Nothing is left to do
+		assert_eq!(upgrade(dir.path(), &[]), std::process::ExitCode::SUCCESS);
+		assert_eq!(fs::read_dir(dir.path().join("logs/upgrades")).unwrap().count(), 1);
+	}
+}
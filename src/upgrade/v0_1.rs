@@ -0,0 +1,82 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::collections::HashMap;
+
+use crate::restore;
+use crate::optimize;
+use crate::basics::Store;
+use crate::faccess::{ self, Area, FileMeta };
+
+// Areas holding an index, which stores created before their data model lack
+const INDEXED: [&str; 6] = [
+	"singletons", "collections", "edges", "keyvalues", "timeseries", "blobs"
+];
+
+fn area_files(store_dir: &Path, area: Area) -> io::Result<HashMap<String, FileMeta>> {
+	if !store_dir.join(area.dir()).join("rixindex").exists() { return Ok(HashMap::new()); }
+	return match area {
+		Area::Singletons => faccess::load_singletons(store_dir).map(|index| index.files),
+		Area::Collections => faccess::load_collections(store_dir).map(|index| index.files),
+		Area::Edges => faccess::load_edges(store_dir).map(|index| index.files),
+		Area::KeyValues => faccess::load_keyvalues(store_dir).map(|index| index.files),
+		Area::Blobs => faccess::load_blobs(store_dir).map(|index| index.files)
+	};
+}
+
+// Data files written before the records had headers
+fn unframed_files(store_dir: &Path) -> Result<Vec<String>, String> {
+	let mut unframed = Vec::<String>::new();
+	for area in [
+		Area::Singletons, Area::Collections, Area::Edges, Area::KeyValues, Area::Blobs
+	] {
+		let files = area_files(store_dir, area)
+			.map_err(|e| format!("Failed to read the index of the {}: {}", area.dir(), e))?
+		;
+		for (id, meta) in files {
+			if !meta.framed { unframed.push(format!("{}/{}", area.dir(), id)); }
+		}
+	}
+	unframed.sort();
+	return Ok(unframed);
+}
+
+// 0.0 -> 0.1: the records of the legacy data files get headers, and the
+// directories and indexes of the later data models are created
+pub fn run(store_dir: &Path, store: &Store, dry_run: bool) -> Result<Vec<String>, String> {
+	let mut changes = Vec::<String>::new();
+	let missing = restore::STORE_DIRS.iter()
+		.filter(|dir| !store_dir.join(dir).is_dir())
+		.map(|dir| dir.to_string())
+		.chain(INDEXED.iter()
+			.filter(|dir| !store_dir.join(dir).join("rixindex").exists())
+			.map(|dir| format!("{}/rixindex", dir))
+		)
+		.collect::<Vec<String>>()
+	;
+	if !missing.is_empty() {
+		changes.push(format!("Create the missing directories and indexes: {}", missing.join(", ")));
+	}
+	let unframed = unframed_files(store_dir)?;
+	if !unframed.is_empty() {
+		changes.push(format!(
+			"Rewrite the data files, to add headers to the records of: {}",
+			unframed.join(", ")
+		));
+	}
+	if dry_run { return Ok(changes); }
+
+	restore::create_dirs(store_dir)
+		.and_then(|_| {
+			for dir in INDEXED {
+				let index = store_dir.join(dir).join("rixindex");
+				if !index.exists() { fs::File::create(index)?; }
+			}
+			Ok(())
+		})
+		.map_err(|e| format!("Failed to create the directories of the store: {}", e))?
+	;
+	// Rewritten, every record gets a header
+	if !unframed.is_empty() { optimize::rewrite(store_dir, store, false)?; }
+	return Ok(changes);
+}
@@ -0,0 +1,10 @@
+use std::path::Path;
+
+use crate::basics::Store;
+
+// 0.1 -> 0.2: the items of a collection can be stored compressed, which the
+// index of the collections records at its end. The indexes written before are
+// read as having no compressed collection, so nothing changes on the disk.
+pub fn run(_store_dir: &Path, _store: &Store, _dry_run: bool) -> Result<Vec<String>, String> {
+	return Ok(Vec::new());
+}
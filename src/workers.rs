@@ -0,0 +1,450 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{ Arc, RwLock };
+use std::time::Duration;
+use std::collections::HashSet;
+
+use serde_json::{ Value, json };
+
+use crate::cli;
+use crate::basics;
+use crate::crypt;
+use crate::faccess::{ self, Area, BLOCK_SIZE };
+use crate::serve::{ Engine, backup, defrag, transac };
+
+// How often the expired key-value entries are removed
+const EXPIRY_PERIOD: Duration = Duration::from_secs(1);
+// How often the time series are rolled up and trimmed
+const ROLLUP_PERIOD: Duration = Duration::from_secs(10);
+
+// Removes the expired key-value entries in the background.
+// Expired keys are already hidden from readers in between.
+pub fn spawn_expiry(engine: Arc<RwLock<Engine>>) {
+	std::thread::spawn(move || loop {
+		std::thread::sleep(EXPIRY_PERIOD);
+		let now = basics::now_millis();
+		let due = engine.read().unwrap().keyvalues.entries.values()
+			.any(|meta| meta.expiry != 0 && meta.expiry <= now)
+		;
+		if !due { continue; }
+		if let Err(message) = transac::expire_keys(&mut engine.write().unwrap(), now) {
+			cli::red_err(message);
+		}
+	});
+}
+
+// Rolls up the closed windows of the time series,
+// and drops the ones past their retention period
+pub fn spawn_rollups(engine: Arc<RwLock<Engine>>) {
+	std::thread::spawn(move || loop {
+		std::thread::sleep(ROLLUP_PERIOD);
+		let now = basics::now_millis();
+		if let Err(message) = transac::roll_series(&mut engine.write().unwrap(), now) {
+			cli::red_err(message);
+		}
+	});
+}
+
+// Blocks checked at once while scrubbing, with the store locked for reading
+const SCRUB_BATCH: u64 = 64;
+// Largest number of bytes scrubbed per second
+const SCRUB_RATE: u64 = 8 * 1024 * 1024;
+// Time before the first scrubbing pass, and between two passes
+const SCRUB_DELAY: Duration = Duration::from_secs(60);
+const SCRUB_PAUSE: Duration = Duration::from_secs(6 * 3600);
+// Largest number of mismatches kept in the status
+const SCRUB_MAX_MISMATCHES: usize = 1000;
+
+// A block found failing its checksum
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Mismatch {
+	pub path: String, // Relative to the store's directory
+	pub block: u64,
+	pub first_seen: u64, // Unix times in milliseconds
+	pub last_seen: u64
+}
+
+// Progress and findings of the scrubber, shown by the status endpoint
+#[derive(Debug)]
+#[derive(Default)]
+pub struct ScrubStatus {
+	pub active: bool,
+	pub passes: u64, // Number of complete passes
+	pub pass_started: u64,
+	pub last_pass_finished: u64,
+	pub bytes: u64, // Bytes checked during the current pass
+	pub files: u64, // Files checked during the current pass
+	pub mismatches: Vec<Mismatch>
+}
+
+impl ScrubStatus {
+	pub fn to_json(&self) -> Value {
+		return json!({
+			"active": self.active,
+			"passes": self.passes,
+			"passStarted": self.pass_started,
+			"lastPassFinished": self.last_pass_finished,
+			"bytes": self.bytes,
+			"files": self.files,
+			"mismatches": self.mismatches.iter().map(|m| json!({
+				"path": m.path,
+				"block": m.block,
+				"firstSeen": m.first_seen,
+				"lastSeen": m.last_seen
+			})).collect::<Vec<Value>>()
+		});
+	}
+
+	// Forgets the mismatches of a file within the given blocks, but the
+	// ones still failing, and returns the blocks which verify again
+	fn clear(&mut self, relative: &str, blocks: std::ops::Range<u64>, failing: &[u64]) -> Vec<u64> {
+		let mut cleared = Vec::<u64>::new();
+		self.mismatches.retain(|m| {
+			let fixed = m.path == relative && blocks.contains(&m.block)
+				&& !failing.contains(&m.block)
+			;
+			if fixed { cleared.push(m.block); }
+			return !fixed;
+		});
+		return cleared;
+	}
+}
+
+// Appends a line to the scrubbing log of the store
+fn scrub_log(engine: &Engine, line: String) {
+	let mut path = engine.store_dir.clone();
+	path.push("logs");
+	let _ = fs::create_dir_all(&path);
+	path.push("scrub.log");
+	let written = crypt::File::append(&path)
+		.and_then(|mut file| writeln!(file, "{} {}", basics::now_millis(), line))
+	;
+	if let Err(e) = written {
+		cli::red_err(format!("Failed to write in {:?}: {}", path, e));
+	}
+}
+
+// Checks a batch of blocks, and returns the number of bytes checked,
+// or `None` once the end of the file is reached
+fn scrub_batch(engine: &Engine, path: &Path, first: u64) -> Option<u64> {
+	let relative = path.strip_prefix(&engine.store_dir).unwrap_or(path)
+		.display().to_string()
+	;
+	// The mismatches past the end of the file, or of a file now gone,
+	// went away with their blocks
+	let forget = |from: u64| {
+		let cleared = engine.scrub.lock().unwrap().clear(&relative, from..u64::MAX, &[]);
+		for block in cleared {
+			scrub_log(engine, format!("cleared {} block {} (truncated)", relative, block));
+		}
+	};
+	let size = crypt::len(path).unwrap_or(0);
+	if first * BLOCK_SIZE >= size {
+		forget(size.div_ceil(BLOCK_SIZE));
+		return None;
+	}
+	let blocks = match faccess::corrupted_blocks_in(&engine.store_dir, path, first, SCRUB_BATCH) {
+		Ok(Some(blocks)) => blocks,
+		Ok(None) => return None, // No checksums for this file
+		Err(e) => {
+			// The file may have been deleted in between
+			if path.exists() {
+				scrub_log(engine, format!("error {:?}: {}", path, e));
+			}
+			else { forget(0); }
+			return None;
+		}
+	};
+
+	let now = basics::now_millis();
+	let mut status = engine.scrub.lock().unwrap();
+	// The blocks repaired or written again since they were found failing
+	for block in status.clear(&relative, first..first + SCRUB_BATCH, &blocks) {
+		let message = format!("cleared {} block {}", relative, block);
+		cli::green_out(format!("Scrubbing: checksum mismatch {}", message));
+		scrub_log(engine, message);
+	}
+	for block in blocks {
+		let known = status.mismatches.iter_mut()
+			.find(|m| m.path == relative && m.block == block)
+		;
+		if let Some(mismatch) = known {
+			mismatch.last_seen = now;
+			continue;
+		}
+		let message = format!(
+			"mismatch {} block {} (bytes {} to {})",
+			relative, block, block * BLOCK_SIZE, (block + 1) * BLOCK_SIZE - 1
+		);
+		cli::red_err(format!("Scrubbing: checksum {}", message));
+		scrub_log(engine, message);
+		if status.mismatches.len() < SCRUB_MAX_MISMATCHES {
+			status.mismatches.push(Mismatch {
+				path: relative.clone(), block, first_seen: now, last_seen: now
+			});
+		}
+	}
+	return Some((size - first * BLOCK_SIZE).min(SCRUB_BATCH * BLOCK_SIZE));
+}
+
+fn scrub_pass(engine: &RwLock<Engine>) {
+	let files = {
+		let engine = engine.read().unwrap();
+		let mut status = engine.scrub.lock().unwrap();
+		status.active = true;
+		status.pass_started = basics::now_millis();
+		status.bytes = 0;
+		status.files = 0;
+		let files = engine.data_files();
+		// The mismatches of the files removed since can't be fixed anymore
+		let relative = files.iter()
+			.map(|path| path.strip_prefix(&engine.store_dir).unwrap_or(path).display().to_string())
+			.collect::<HashSet<String>>()
+		;
+		status.mismatches.retain(|m| relative.contains(&m.path));
+		files
+	};
+
+	for path in files {
+		let mut first = 0u64;
+		loop {
+			let checked = {
+				let engine = engine.read().unwrap();
+				let checked = scrub_batch(&engine, &path, first);
+				engine.scrub.lock().unwrap().bytes += checked.unwrap_or(0);
+				checked
+			};
+			let bytes = match checked {
+				Some(bytes) => bytes,
+				None => break
+			};
+			first += SCRUB_BATCH;
+			std::thread::sleep(Duration::from_secs_f64(bytes as f64 / SCRUB_RATE as f64));
+		}
+		engine.read().unwrap().scrub.lock().unwrap().files += 1;
+	}
+
+	let engine = engine.read().unwrap();
+	let line = {
+		let mut status = engine.scrub.lock().unwrap();
+		status.active = false;
+		status.passes += 1;
+		status.last_pass_finished = basics::now_millis();
+		format!(
+			"pass {} finished: {} bytes in {} files, {} mismatches known",
+			status.passes, status.bytes, status.files, status.mismatches.len()
+		)
+	};
+	scrub_log(&engine, line);
+}
+
+// Re-reads the data files at a limited pace, and checks them against their
+// checksums, so that silent corruption is found before the data is needed
+pub fn spawn_scrubber(engine: Arc<RwLock<Engine>>) {
+	if !engine.read().unwrap().store.checksumming { return; }
+	std::thread::spawn(move || {
+		std::thread::sleep(SCRUB_DELAY);
+		loop {
+			scrub_pass(&engine);
+			std::thread::sleep(SCRUB_PAUSE);
+		}
+	});
+}
+
+// Largest number of bytes moved at once by the defragmenter, with the store
+// locked for writing. Larger records are moved one at a time.
+const DEFRAG_BATCH: u64 = 256 * 1024;
+// Largest number of bytes written per second while defragmenting
+const DEFRAG_RATE: u64 = 4 * 1024 * 1024;
+// Time between two looks for holes, once none is left to close
+const DEFRAG_PAUSE: Duration = Duration::from_secs(30);
+
+// Closes the holes of the data files as they appear, in the stores keeping
+// their entries in order, by moving down the records following them.
+// The writes are tracked like any other, for the checksums and backups.
+pub fn spawn_defragmenter(engine: Arc<RwLock<Engine>>) {
+	if !engine.read().unwrap().store.ordering { return; }
+	std::thread::spawn(move || {
+		// Files whose first hole can't be closed for now, until the next pause
+		let mut left = HashSet::<(Area, String)>::new();
+		loop {
+			let next = defrag::next_file(&engine.read().unwrap(), &left);
+			let (area, file) = match next {
+				Some(next) => next,
+				None => {
+					left.clear();
+					std::thread::sleep(DEFRAG_PAUSE);
+					continue;
+				}
+			};
+			let closed = defrag::close_hole(&mut engine.write().unwrap(), area, &file, DEFRAG_BATCH);
+			let bytes = match closed {
+				Ok(Some(bytes)) => bytes,
+				Ok(None) => {
+					left.insert((area, file));
+					0
+				},
+				Err(message) => {
+					cli::red_err(message);
+					left.insert((area, file));
+					0
+				}
+			};
+			std::thread::sleep(Duration::from_secs_f64(bytes as f64 / DEFRAG_RATE as f64));
+		}
+	});
+}
+
+// Lists the scheduled backups, from the oldest to the latest
+fn list_backups(
+	directory: &Path, prefix: &str
+) -> std::io::Result<Vec<std::path::PathBuf>> {
+	let mut backups = Vec::<(u64, std::path::PathBuf)>::new();
+	for entry in fs::read_dir(directory)? {
+		let entry = entry?;
+		let name = entry.file_name().to_string_lossy().into_owned();
+		let stamp = name.strip_prefix(prefix).and_then(|s| s.parse::<u64>().ok());
+		if let Some(stamp) = stamp {
+			if entry.file_type()?.is_dir() { backups.push((stamp, entry.path())); }
+		}
+	}
+	backups.sort();
+	return Ok(backups.into_iter().map(|(_, path)| path).collect());
+}
+
+// Removes the oldest scheduled backups, beyond the number to keep
+fn prune_backups(directory: &Path, prefix: &str, keep: usize) -> std::io::Result<()> {
+	let backups = list_backups(directory, prefix)?;
+	let excess = backups.len().saturating_sub(keep);
+	for path in backups.into_iter().take(excess) {
+		fs::remove_dir_all(path)?;
+	}
+	return Ok(());
+}
+
+// Makes the backups declared in the manifest, in `<directory>/<store id>-<Unix ms>`.
+// Each full backup is followed by the declared number of incremental ones.
+pub fn spawn_backups(engine: Arc<RwLock<Engine>>) {
+	let (schedule, store_dir, id) = {
+		let engine = engine.read().unwrap();
+		match &engine.store.backups {
+			Some(schedule) if schedule.interval > 0 => (
+				schedule.clone(), engine.store_dir.clone(), engine.store.id.clone()
+			),
+			_ => return
+		}
+	};
+	let directory = store_dir.join(&schedule.directory);
+	let prefix = format!("{}-", id);
+	std::thread::spawn(move || loop {
+		std::thread::sleep(Duration::from_secs(schedule.interval * 60));
+		if let Err(e) = fs::create_dir_all(&directory) {
+			cli::red_err(format!("Failed to create {:?}: {}", directory, e));
+			continue;
+		}
+
+		let chained = list_backups(&directory, &prefix).ok()
+			.and_then(|backups| backups.last().cloned())
+			.filter(|latest| crate::backup::load_state(latest)
+				.is_ok_and(|state| state.sequence < schedule.increments)
+			)
+		;
+		if let Some(latest) = chained {
+			match backup::incremental_backup(&engine, &latest) {
+				Ok((sequence, _, _)) => engine.read().unwrap().log(format!(
+					"Incremental backup {} made in {:?}", sequence, latest
+				)),
+				Err(message) => cli::red_err(message)
+			}
+			continue;
+		}
+
+		let target = directory.join(format!("{}{}", prefix, basics::now_millis()));
+		match backup::hot_backup(&engine, &target) {
+			Ok(_) => engine.read().unwrap().log(format!("Backup made in {:?}", target)),
+			Err(message) => {
+				cli::red_err(message);
+				continue;
+			}
+		}
+		if schedule.keep > 0 {
+			if let Err(e) = prune_backups(&directory, &prefix, schedule.keep) {
+				cli::red_err(format!("Failed to remove old backups: {}", e));
+			}
+		}
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::serve::testing;
+
+	#[test]
+	fn scrubbing_passes() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut engine = testing::create_store(dir.path(), json!({}));
+		testing::mutate(&mut engine, "createCollection", json!({ "name": "notes" })).unwrap();
+		let id = testing::insert(&mut engine, "notes", json!({ "text": "x".repeat(10000) }));
+		let engine = RwLock::new(engine);
+		scrub_pass(&engine);
+		{
+			let engine = engine.read().unwrap();
+			let status = engine.scrub.lock().unwrap();
+			assert_eq!((status.passes, status.active, status.files), (1, false, 1));
+			assert!(status.bytes >= 10000);
+			assert!(status.mismatches.is_empty());
+		}
+
+		// A block of the item's file gets corrupted
+		let path = {
+			let engine = engine.read().unwrap();
+			let meta = &engine.collections.items[&id];
+			engine.data_path(faccess::Area::Collections, &meta.file)
+		};
+		let mut bytes = fs::read(&path).unwrap();
+		bytes[BLOCK_SIZE as usize + 1] ^= 0x01;
+		fs::write(&path, &bytes).unwrap();
+		scrub_pass(&engine);
+		let relative = path.strip_prefix(dir.path()).unwrap().display().to_string();
+		let first_seen = {
+			let engine = engine.read().unwrap();
+			let status = engine.scrub.lock().unwrap();
+			assert_eq!(status.mismatches.len(), 1);
+			let mismatch = &status.mismatches[0];
+			assert_eq!((mismatch.path.as_str(), mismatch.block), (relative.as_str(), 1));
+			mismatch.first_seen
+		};
+
+		// Known mismatches are only seen again
+		std::thread::sleep(Duration::from_millis(5));
+		scrub_pass(&engine);
+		let engine = engine.read().unwrap();
+		let status = engine.scrub.lock().unwrap();
+		assert_eq!(status.passes, 3);
+		assert_eq!(status.mismatches.len(), 1);
+		assert_eq!(status.mismatches[0].first_seen, first_seen);
+		assert!(status.mismatches[0].last_seen > first_seen);
+		let log = fs::read_to_string(dir.path().join("logs/scrub.log")).unwrap();
+		assert_eq!(log.matches(&format!("mismatch {} block 1 ", relative)).count(), 1);
+		assert_eq!(log.matches("pass ").count(), 3);
+	}
+
+	#[test]
+	fn pruned_backups() {
+		let dir = tempfile::tempdir().unwrap();
+		for name in ["test-100", "test-3000", "test-20", "other-10", "test-x"] {
+			fs::create_dir(dir.path().join(name)).unwrap();
+		}
+		fs::write(dir.path().join("test-5"), b"").unwrap();
+		prune_backups(dir.path(), "test-", 2).unwrap();
+		let mut left = fs::read_dir(dir.path()).unwrap()
+			.map(|entry| entry.unwrap().file_name().into_string().unwrap())
+			.collect::<Vec<String>>()
+		;
+		left.sort();
+		assert_eq!(left, ["other-10", "test-100", "test-3000", "test-5", "test-x"]);
+	}
+}